@@ -0,0 +1,55 @@
+use weechat::{
+    buffer::Buffer,
+    hooks::{Command, CommandCallback, CommandSettings},
+    Args, Weechat,
+};
+
+use crate::Servers;
+
+pub struct AwayCommand {
+    servers: Servers,
+}
+
+impl AwayCommand {
+    pub const DESCRIPTION: &'static str = "Toggle the away status";
+
+    pub fn create(servers: &Servers) -> Result<Command, ()> {
+        let settings = CommandSettings::new("away")
+            .description(Self::DESCRIPTION)
+            .add_argument("[<message>]")
+            .arguments_description(
+                "message: The away message, without a message the away \
+                 status is removed",
+            );
+
+        Command::new(
+            settings,
+            Self {
+                servers: servers.clone(),
+            },
+        )
+    }
+}
+
+impl CommandCallback for AwayCommand {
+    fn callback(&mut self, _: &Weechat, buffer: &Buffer, arguments: Args) {
+        let server = if let Some(s) = self.servers.find_server(buffer) {
+            s
+        } else {
+            Weechat::print("Must be executed on Matrix buffer");
+            return;
+        };
+
+        let message = arguments.skip(1).collect::<Vec<String>>().join(" ");
+        let message = if message.is_empty() {
+            None
+        } else {
+            Some(message)
+        };
+
+        let away = || async move {
+            server.set_away(message).await;
+        };
+        Weechat::spawn(away()).detach();
+    }
+}
@@ -0,0 +1,176 @@
+use std::convert::TryFrom;
+
+use clap::{App as Argparse, AppSettings as ArgParseSettings, Arg, ArgMatches};
+use matrix_sdk::identifiers::UserId;
+
+use weechat::{
+    buffer::Buffer,
+    hooks::{Command, CommandCallback, CommandSettings},
+    Args, Weechat,
+};
+
+use crate::{
+    connection::{RoomOptions, RoomPreset},
+    Servers,
+};
+
+pub struct CreateCommand {
+    servers: Servers,
+}
+
+impl CreateCommand {
+    pub const DESCRIPTION: &'static str = "Create a new room";
+
+    pub fn create(servers: &Servers) -> Result<Command, ()> {
+        let settings = CommandSettings::new("create")
+            .description(Self::DESCRIPTION)
+            .add_argument(
+                "[--public] [--preset private|trusted|public] \
+                 [--alias <alias>] [--name <name>] [--topic <topic>] \
+                 [--invite <user-id>...] [--encrypted]",
+            )
+            .arguments_description(
+                "public: Publish the room in the room directory of the server\n\
+                 preset: Who can join the room and see its history, defaults \
+                 to public for public rooms and to private otherwise\n\
+                 alias: The local part of the alias of the room\n\
+                 name: The name of the room\n\
+                 topic: The topic of the room\n\
+                 invite: Users that should be invited to the room\n\
+                 encrypted: Enable encryption in the room",
+            )
+            .add_completion(
+                "--public|--preset|--alias|--name|--topic|--invite|--encrypted",
+            );
+
+        Command::new(
+            settings,
+            Self {
+                servers: servers.clone(),
+            },
+        )
+    }
+
+    fn args() -> Vec<Arg<'static, 'static>> {
+        vec![
+            Arg::with_name("public").long("public"),
+            Arg::with_name("preset")
+                .long("preset")
+                .takes_value(true)
+                .possible_values(&["private", "trusted", "public"]),
+            Arg::with_name("alias").long("alias").takes_value(true),
+            Arg::with_name("name")
+                .long("name")
+                .takes_value(true)
+                .multiple(true),
+            Arg::with_name("topic")
+                .long("topic")
+                .takes_value(true)
+                .multiple(true),
+            Arg::with_name("invite")
+                .long("invite")
+                .takes_value(true)
+                .multiple(true),
+            Arg::with_name("encrypted").long("encrypted"),
+        ]
+    }
+
+    fn run(&self, buffer: &Buffer, args: &ArgMatches) {
+        let server = if let Some(s) = self.servers.find_server(buffer) {
+            s
+        } else {
+            Weechat::print("Must be executed on Matrix buffer");
+            return;
+        };
+
+        let public = args.is_present("public");
+        let encrypted = args.is_present("encrypted");
+
+        let preset = match args.value_of("preset") {
+            Some("private") => RoomPreset::PrivateChat,
+            Some("trusted") => RoomPreset::TrustedPrivateChat,
+            Some(_) => RoomPreset::PublicChat,
+            None if public => RoomPreset::PublicChat,
+            None => RoomPreset::PrivateChat,
+        };
+
+        let public_preset = matches!(preset, RoomPreset::PublicChat);
+
+        if public && !public_preset {
+            server.print_error(
+                "A room in the room directory needs to use the public preset, \
+                 otherwise nobody can join it",
+            );
+            return;
+        }
+
+        let mut invite = Vec::new();
+
+        for user_id in args.values_of("invite").into_iter().flatten() {
+            match UserId::try_from(user_id) {
+                Ok(u) => invite.push(u),
+                Err(e) => {
+                    server.print_error(&format!(
+                        "Invalid user id {}: {}",
+                        user_id, e
+                    ));
+                    return;
+                }
+            }
+        }
+
+        if encrypted && public_preset {
+            server.print_network(
+                "Warning: anyone can join a public room, encryption won't \
+                 keep its messages private",
+            );
+        }
+
+        let joined = |name: &str| {
+            args.values_of(name)
+                .map(|v| v.collect::<Vec<_>>().join(" "))
+        };
+
+        let options = RoomOptions {
+            public,
+            preset: Some(preset),
+            alias: args.value_of("alias").map(|a| a.to_owned()),
+            name: joined("name"),
+            topic: joined("topic"),
+            invite,
+            encrypted,
+        };
+
+        let create = || async move {
+            server.create_room(options).await;
+        };
+        Weechat::spawn(create()).detach();
+    }
+}
+
+impl CommandCallback for CreateCommand {
+    fn callback(&mut self, _: &Weechat, buffer: &Buffer, arguments: Args) {
+        let argparse = Argparse::new("create")
+            .about(Self::DESCRIPTION)
+            .global_setting(ArgParseSettings::DisableHelpFlags)
+            .global_setting(ArgParseSettings::DisableVersion)
+            .args(&Self::args());
+
+        let matches = match argparse.get_matches_from_safe(arguments) {
+            Ok(m) => m,
+            Err(e) => {
+                Weechat::print(
+                    &Weechat::execute_modifier(
+                        "color_decode_ansi",
+                        "1",
+                        &e.to_string(),
+                    )
+                    .unwrap(),
+                );
+                return;
+            }
+        };
+
+        self.run(buffer, &matches);
+    }
+}
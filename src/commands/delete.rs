@@ -0,0 +1,89 @@
+use std::convert::TryFrom;
+
+use matrix_sdk::identifiers::EventId;
+
+use weechat::{
+    buffer::Buffer,
+    hooks::{Command, CommandCallback, CommandSettings},
+    Args, Weechat,
+};
+
+use crate::Servers;
+
+pub struct DeleteCommand {
+    servers: Servers,
+}
+
+impl DeleteCommand {
+    pub const DESCRIPTION: &'static str =
+        "Delete (redact) a message in the current room";
+
+    fn create(servers: &Servers, name: &str) -> Result<Command, ()> {
+        let settings = CommandSettings::new(name)
+            .description(Self::DESCRIPTION)
+            .add_argument("[-yes] [<event-id>] [<reason>]")
+            .arguments_description(
+                "    -yes: Delete the message without asking for a \
+                 confirmation, without an event id and reason the message \
+                 that waits for a confirmation is deleted\n\
+                 event-id: The id of the message that should be deleted, if \
+                 omitted your last message is used\n\
+                 reason: The reason for deleting the message",
+            );
+
+        Command::new(
+            settings,
+            Self {
+                servers: servers.clone(),
+            },
+        )
+    }
+
+    pub fn create_delete(servers: &Servers) -> Result<Command, ()> {
+        Self::create(servers, "delete")
+    }
+
+    pub fn create_redact(servers: &Servers) -> Result<Command, ()> {
+        Self::create(servers, "redact")
+    }
+}
+
+impl CommandCallback for DeleteCommand {
+    fn callback(&mut self, _: &Weechat, buffer: &Buffer, arguments: Args) {
+        let room = if let Some(r) = self.servers.find_room(buffer) {
+            r
+        } else {
+            Weechat::print("Must be executed on a Matrix room buffer");
+            return;
+        };
+
+        let mut arguments = arguments.skip(1).peekable();
+
+        let confirmed = arguments.peek().map_or(false, |a| a == "-yes");
+
+        if confirmed {
+            arguments.next();
+        }
+
+        let event_id = arguments
+            .peek()
+            .filter(|a| a.starts_with('$'))
+            .and_then(|a| EventId::try_from(a.as_str()).ok());
+
+        if event_id.is_some() {
+            arguments.next();
+        }
+
+        let reason = arguments.collect::<Vec<String>>().join(" ");
+        let reason = if reason.is_empty() {
+            None
+        } else {
+            Some(reason)
+        };
+
+        let delete = || async move {
+            room.redact(event_id, reason, confirmed).await;
+        };
+        Weechat::spawn(delete()).detach();
+    }
+}
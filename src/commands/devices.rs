@@ -27,9 +27,9 @@ impl DevicesCommand {
             .add_argument("delete <device-id>")
             .add_argument("set-name <device-id> <name>")
             .arguments_description(
-                "device-id: The unique id of the device that should be deleted.
-                name:
-                ",
+                "device-id: The unique id of the device that should be \
+                 deleted or renamed.
+     name: The new human readable name of the device",
             )
             .add_completion("list")
             .add_completion("delete %(matrix-own-devices)")
@@ -70,6 +70,24 @@ impl DevicesCommand {
         }
     }
 
+    fn set_name(
+        servers: &Servers,
+        buffer: &Buffer,
+        device_id: DeviceIdBox,
+        name: String,
+    ) {
+        let server = servers.find_server(buffer);
+
+        if let Some(s) = server {
+            let set_name = || async move {
+                s.set_device_name(device_id, name).await;
+            };
+            Weechat::spawn(set_name()).detach();
+        } else {
+            Weechat::print("Must be executed on Matrix buffer")
+        }
+    }
+
     pub fn run(buffer: &Buffer, servers: &Servers, args: &ArgMatches) {
         match args.subcommand() {
             ("list", _) => Self::list(servers, buffer),
@@ -84,6 +102,18 @@ impl DevicesCommand {
                     .collect();
                 Self::delete(servers, buffer, devices);
             }
+            ("set-name", Some(args)) => {
+                let device_id = args
+                    .value_of("device-id")
+                    .expect("Args didn't contain a device id");
+                let name = args
+                    .values_of("name")
+                    .expect("Args didn't contain a device name")
+                    .collect::<Vec<_>>()
+                    .join(" ");
+
+                Self::set_name(servers, buffer, device_id.into(), name);
+            }
             _ => Weechat::print(&format!(
                 "{}Subcommand isn't implemented",
                 Weechat::prefix("error")
@@ -106,7 +136,7 @@ impl DevicesCommand {
             SubCommand::with_name("set-name")
                 .about("Set the human readable name of the given device")
                 .arg(Arg::with_name("device-id").required(true))
-                .arg(Arg::with_name("name").required(true)),
+                .arg(Arg::with_name("name").required(true).multiple(true)),
         ]
     }
 }
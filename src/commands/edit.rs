@@ -0,0 +1,75 @@
+use std::convert::TryFrom;
+
+use matrix_sdk::identifiers::EventId;
+
+use weechat::{
+    buffer::Buffer,
+    hooks::{Command, CommandCallback, CommandSettings},
+    Args, Weechat,
+};
+
+use crate::Servers;
+
+pub struct EditCommand {
+    servers: Servers,
+}
+
+impl EditCommand {
+    pub const DESCRIPTION: &'static str =
+        "Edit one of your messages in the current room";
+
+    pub fn create(servers: &Servers) -> Result<Command, ()> {
+        let settings = CommandSettings::new("edit")
+            .description(Self::DESCRIPTION)
+            .add_argument("[<event-id>] <message>")
+            .arguments_description(
+                "event-id: The id of the message that should be edited, if \
+                 omitted your last message is used\n\
+                 message: The new content of the message",
+            );
+
+        Command::new(
+            settings,
+            Self {
+                servers: servers.clone(),
+            },
+        )
+    }
+}
+
+impl CommandCallback for EditCommand {
+    fn callback(&mut self, _: &Weechat, buffer: &Buffer, arguments: Args) {
+        let room = if let Some(r) = self.servers.find_room(buffer) {
+            r
+        } else {
+            Weechat::print("Must be executed on a Matrix room buffer");
+            return;
+        };
+
+        let mut arguments = arguments.skip(1).peekable();
+
+        let event_id = arguments
+            .peek()
+            .filter(|a| a.starts_with('$'))
+            .and_then(|a| EventId::try_from(a.as_str()).ok());
+
+        if event_id.is_some() {
+            arguments.next();
+        }
+
+        let message = arguments.collect::<Vec<String>>().join(" ");
+
+        if message.is_empty() {
+            Weechat::print(&format!(
+                "{}Usage: /edit [<event-id>] <message>",
+                Weechat::prefix("error")
+            ));
+            return;
+        }
+
+        let edit = || async move {
+            room.edit_message(event_id, message).await;
+        };
+        Weechat::spawn(edit()).detach();
+    }
+}
@@ -0,0 +1,70 @@
+use weechat::{
+    buffer::Buffer,
+    hooks::{Command, CommandCallback, CommandSettings},
+    Args, Weechat,
+};
+
+use crate::Servers;
+
+pub struct EncryptionCommand {
+    servers: Servers,
+}
+
+impl EncryptionCommand {
+    pub const DESCRIPTION: &'static str =
+        "Show or enable the encryption of the current room";
+
+    pub fn create(servers: &Servers) -> Result<Command, ()> {
+        let settings = CommandSettings::new("encryption")
+            .description(Self::DESCRIPTION)
+            .add_argument("status")
+            .add_argument("enable -yes")
+            .arguments_description(
+                "status: Show whether the room is encrypted
+enable: Enable encryption in the room, this can't be undone and needs to be \
+                 confirmed with -yes",
+            )
+            .add_completion("status")
+            .add_completion("enable");
+
+        Command::new(
+            settings,
+            Self {
+                servers: servers.clone(),
+            },
+        )
+    }
+}
+
+impl CommandCallback for EncryptionCommand {
+    fn callback(&mut self, _: &Weechat, buffer: &Buffer, arguments: Args) {
+        let room = if let Some(r) = self.servers.find_room(buffer) {
+            r
+        } else {
+            Weechat::print("Must be executed on a Matrix room buffer");
+            return;
+        };
+
+        let arguments: Vec<String> = arguments.skip(1).collect();
+        let arguments: Vec<&str> =
+            arguments.iter().map(|a| a.as_str()).collect();
+
+        match arguments.as_slice() {
+            [] | ["status"] => room.print_encryption_status(),
+            ["enable", "-yes"] => {
+                let enable = || async move {
+                    room.enable_encryption().await;
+                };
+                Weechat::spawn(enable()).detach();
+            }
+            ["enable"] => room.print_error(
+                "Encryption can't be disabled once it's enabled, use \
+                 /encryption enable -yes to confirm",
+            ),
+            _ => Weechat::print(&format!(
+                "{}Usage: /encryption status|enable -yes",
+                Weechat::prefix("error")
+            )),
+        }
+    }
+}
@@ -0,0 +1,98 @@
+use std::convert::TryFrom;
+
+use matrix_sdk::identifiers::UserId;
+
+use weechat::{
+    buffer::Buffer,
+    hooks::{Command, CommandCallback, CommandSettings},
+    Args, Weechat,
+};
+
+use crate::Servers;
+
+pub struct IgnoreCommand {
+    servers: Servers,
+    ignore: bool,
+}
+
+impl IgnoreCommand {
+    pub const IGNORE_DESCRIPTION: &'static str =
+        "Hide the messages of a user, without arguments the ignored users are \
+         listed";
+    pub const UNIGNORE_DESCRIPTION: &'static str =
+        "Show the messages of an ignored user again";
+
+    fn create(
+        servers: &Servers,
+        name: &str,
+        description: &str,
+        ignore: bool,
+    ) -> Result<Command, ()> {
+        let settings = CommandSettings::new(name)
+            .description(description)
+            .add_argument(if ignore { "[<user-id>]" } else { "<user-id>" })
+            .arguments_description("user-id: The id of the user")
+            .add_completion("%(matrix-users)");
+
+        Command::new(
+            settings,
+            Self {
+                servers: servers.clone(),
+                ignore,
+            },
+        )
+    }
+
+    pub fn create_ignore(servers: &Servers) -> Result<Command, ()> {
+        Self::create(servers, "ignore", Self::IGNORE_DESCRIPTION, true)
+    }
+
+    pub fn create_unignore(servers: &Servers) -> Result<Command, ()> {
+        Self::create(servers, "unignore", Self::UNIGNORE_DESCRIPTION, false)
+    }
+}
+
+impl CommandCallback for IgnoreCommand {
+    fn callback(&mut self, _: &Weechat, buffer: &Buffer, mut arguments: Args) {
+        let server = if let Some(s) = self.servers.find_server(buffer) {
+            s
+        } else {
+            Weechat::print("Must be executed on Matrix buffer");
+            return;
+        };
+
+        let user_id = match arguments.nth(1) {
+            Some(u) => u,
+            None if self.ignore => {
+                server.print_ignored_users();
+                return;
+            }
+            None => {
+                Weechat::print(&format!(
+                    "{}Usage: /unignore <user-id>",
+                    Weechat::prefix("error")
+                ));
+                return;
+            }
+        };
+
+        let user_id = match UserId::try_from(user_id.as_str()) {
+            Ok(u) => u,
+            Err(e) => {
+                Weechat::print(&format!(
+                    "{}Invalid user id {}: {}",
+                    Weechat::prefix("error"),
+                    user_id,
+                    e
+                ));
+                return;
+            }
+        };
+
+        let ignore = self.ignore;
+        let set_ignored = || async move {
+            server.set_ignored(user_id, ignore).await;
+        };
+        Weechat::spawn(set_ignored()).detach();
+    }
+}
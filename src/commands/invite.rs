@@ -0,0 +1,146 @@
+use std::convert::TryFrom;
+
+use clap::{App as Argparse, AppSettings as ArgParseSettings, Arg, ArgMatches};
+use matrix_sdk::identifiers::RoomId;
+
+use weechat::{
+    buffer::Buffer,
+    hooks::{Command, CommandCallback, CommandSettings},
+    Args, Weechat,
+};
+
+use crate::{MatrixServer, Servers};
+
+pub struct InviteCommand {
+    servers: Servers,
+    accept: bool,
+}
+
+impl InviteCommand {
+    pub const ACCEPT_DESCRIPTION: &'static str = "Accept a room invite";
+    pub const REJECT_DESCRIPTION: &'static str = "Reject a room invite";
+
+    fn create(servers: &Servers, accept: bool) -> Result<Command, ()> {
+        let (name, description) = if accept {
+            ("accept", Self::ACCEPT_DESCRIPTION)
+        } else {
+            ("reject", Self::REJECT_DESCRIPTION)
+        };
+
+        let settings = CommandSettings::new(name)
+            .description(description)
+            .add_argument("<room-id>")
+            .arguments_description(
+                "room-id: The id of the room the invite is for, can be \
+                 omitted if there is only a single pending invite",
+            );
+
+        Command::new(
+            settings,
+            Self {
+                servers: servers.clone(),
+                accept,
+            },
+        )
+    }
+
+    pub fn create_accept(servers: &Servers) -> Result<Command, ()> {
+        Self::create(servers, true)
+    }
+
+    pub fn create_reject(servers: &Servers) -> Result<Command, ()> {
+        Self::create(servers, false)
+    }
+
+    /// Find the room id the invite command should operate on.
+    ///
+    /// If no room id was given and there's only a single pending invite, the
+    /// room id of that invite is used.
+    fn room_id(server: &MatrixServer, args: &ArgMatches) -> Option<RoomId> {
+        if let Some(room_id) = args.value_of("room-id") {
+            match RoomId::try_from(room_id) {
+                Ok(r) => Some(r),
+                Err(e) => {
+                    server.print_error(&format!(
+                        "Invalid room id {}: {}",
+                        room_id, e
+                    ));
+                    None
+                }
+            }
+        } else {
+            let server_inner = server.inner();
+            let invites = server_inner.invites();
+
+            if invites.len() == 1 {
+                invites.keys().next().cloned()
+            } else {
+                server.print_error(&format!(
+                    "There are {} pending invites, please specify a room id",
+                    invites.len()
+                ));
+                None
+            }
+        }
+    }
+
+    fn run(&self, buffer: &Buffer, args: &ArgMatches) {
+        let server = if let Some(s) = self.servers.find_server(buffer) {
+            s
+        } else {
+            Weechat::print("Must be executed on Matrix buffer");
+            return;
+        };
+
+        let room_id = if let Some(r) = Self::room_id(&server, args) {
+            r
+        } else {
+            return;
+        };
+
+        if self.accept {
+            let accept = || async move {
+                server.accept_invite(room_id).await;
+            };
+            Weechat::spawn(accept()).detach();
+        } else {
+            let reject = || async move {
+                server.reject_invite(room_id).await;
+            };
+            Weechat::spawn(reject()).detach();
+        }
+    }
+}
+
+impl CommandCallback for InviteCommand {
+    fn callback(&mut self, _: &Weechat, buffer: &Buffer, arguments: Args) {
+        let (name, description) = if self.accept {
+            ("accept", Self::ACCEPT_DESCRIPTION)
+        } else {
+            ("reject", Self::REJECT_DESCRIPTION)
+        };
+
+        let argparse = Argparse::new(name)
+            .about(description)
+            .global_setting(ArgParseSettings::DisableHelpFlags)
+            .global_setting(ArgParseSettings::DisableVersion)
+            .arg(Arg::with_name("room-id"));
+
+        let matches = match argparse.get_matches_from_safe(arguments) {
+            Ok(m) => m,
+            Err(e) => {
+                Weechat::print(
+                    &Weechat::execute_modifier(
+                        "color_decode_ansi",
+                        "1",
+                        &e.to_string(),
+                    )
+                    .unwrap(),
+                );
+                return;
+            }
+        };
+
+        self.run(buffer, &matches)
+    }
+}
@@ -0,0 +1,73 @@
+use std::convert::TryFrom;
+
+use matrix_sdk::identifiers::RoomIdOrAliasId;
+
+use weechat::{
+    buffer::Buffer,
+    hooks::{Command, CommandCallback, CommandSettings},
+    Args, Weechat,
+};
+
+use crate::Servers;
+
+pub struct JoinCommand {
+    servers: Servers,
+}
+
+impl JoinCommand {
+    pub const DESCRIPTION: &'static str = "Join a room";
+
+    pub fn create(servers: &Servers) -> Result<Command, ()> {
+        let settings = CommandSettings::new("join")
+            .description(Self::DESCRIPTION)
+            .add_argument("<room>")
+            .arguments_description(
+                "room: The id (!room:server) or alias (#room:server) of the \
+                 room",
+            )
+            .add_completion("%(matrix-rooms)");
+
+        Command::new(
+            settings,
+            Self {
+                servers: servers.clone(),
+            },
+        )
+    }
+}
+
+impl CommandCallback for JoinCommand {
+    fn callback(&mut self, _: &Weechat, buffer: &Buffer, arguments: Args) {
+        let server = if let Some(s) = self.servers.find_server(buffer) {
+            s
+        } else {
+            Weechat::print("Must be executed on Matrix buffer");
+            return;
+        };
+
+        let mut arguments = arguments.skip(1);
+
+        let room = if let Some(r) = arguments.next() {
+            r
+        } else {
+            Weechat::print(&format!(
+                "{}Usage: /join <room>",
+                Weechat::prefix("error")
+            ));
+            return;
+        };
+
+        let room = match RoomIdOrAliasId::try_from(room.as_str()) {
+            Ok(r) => r,
+            Err(e) => {
+                server.print_error(&format!("Invalid room {}: {}", room, e));
+                return;
+            }
+        };
+
+        let join = || async move {
+            server.join_room(room).await;
+        };
+        Weechat::spawn(join()).detach();
+    }
+}
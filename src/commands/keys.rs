@@ -5,6 +5,8 @@ use clap::{
     SubCommand,
 };
 
+use matrix_sdk::identifiers::DeviceIdBox;
+
 use weechat::{
     buffer::Buffer,
     hooks::{Command, CommandCallback, CommandSettings},
@@ -18,8 +20,10 @@ pub struct KeysCommand {
 }
 
 impl KeysCommand {
-    pub const DESCRIPTION: &'static str = "Import or export E2EE keys.";
-    pub const COMPLETION: &'static str = "import|export %(filename)";
+    pub const DESCRIPTION: &'static str =
+        "Import or export E2EE keys, rename a device, or request missing room \
+         keys.";
+    pub const COMPLETION: &'static str = "import|export|set-name|request";
 
     pub fn create(servers: &Servers) -> Result<Command, ()> {
         let settings = CommandSettings::new("keys")
@@ -27,11 +31,18 @@ impl KeysCommand {
             .add_argument("import <file> <passphrase>")
             .add_argument("export <file> <passphrase>")
             .add_argument("set-name <device-id> <name>")
+            .add_argument("request <event-id-or-session>")
             .arguments_description(
-                "file: Path to a file that is or will contain the E2EE keys export",
+                "     file: Path to a file that is or will contain the E2EE keys export
+device-id: The unique id of the device that should be renamed
+     name: The new human readable name of the device
+ event-id: The id of a message that couldn't be decrypted
+  session: The id of the session whose room key should be requested",
             )
             .add_completion(Self::COMPLETION)
-            .add_completion("help import|export");
+            .add_completion("import|export %(filename)")
+            .add_completion("set-name %(matrix-own-devices)")
+            .add_completion("help import|export|set-name|request");
 
         Command::new(
             settings,
@@ -75,6 +86,27 @@ impl KeysCommand {
         Weechat::spawn(export()).detach();
     }
 
+    fn set_name(server: MatrixServer, device_id: DeviceIdBox, name: String) {
+        let set_name = || async move {
+            server.set_device_name(device_id, name).await;
+        };
+        Weechat::spawn(set_name()).detach();
+    }
+
+    fn request(buffer: &Buffer, servers: &Servers, id: String) {
+        let room = if let Some(r) = servers.find_room(buffer) {
+            r
+        } else {
+            Weechat::print("Must be executed on a Matrix room buffer");
+            return;
+        };
+
+        let request = || async move {
+            room.request_room_key(&id).await;
+        };
+        Weechat::spawn(request()).detach();
+    }
+
     pub fn run(buffer: &Buffer, servers: &Servers, args: &ArgMatches) {
         if let Some(server) = servers.find_server(buffer) {
             match args.subcommand() {
@@ -90,6 +122,25 @@ impl KeysCommand {
                     );
                     Self::export(server, file, passphrase);
                 }
+                ("set-name", Some(args)) => {
+                    let device_id = args
+                        .value_of("device-id")
+                        .expect("No device id was provided");
+                    let name = args
+                        .values_of("name")
+                        .expect("No device name was provided")
+                        .collect::<Vec<_>>()
+                        .join(" ");
+
+                    Self::set_name(server, device_id.into(), name);
+                }
+                ("request", Some(args)) => {
+                    let id = args
+                        .value_of("event-id-or-session")
+                        .expect("No event or session id was provided");
+
+                    Self::request(buffer, servers, id.to_owned());
+                }
                 _ => unreachable!(),
             }
         } else {
@@ -108,6 +159,16 @@ impl KeysCommand {
                 .about("Export your E2EE keys to the given file.")
                 .arg(Arg::with_name("file").required(true))
                 .arg(Arg::with_name("passphrase").required(true)),
+            SubCommand::with_name("set-name")
+                .about("Set the human readable name of the given device.")
+                .arg(Arg::with_name("device-id").required(true))
+                .arg(Arg::with_name("name").required(true).multiple(true)),
+            SubCommand::with_name("request")
+                .about(
+                    "Request the room key of undecryptable messages from \
+                     your other devices.",
+                )
+                .arg(Arg::with_name("event-id-or-session").required(true)),
         ]
     }
 }
@@ -13,7 +13,7 @@ use weechat::{
 use crate::{
     commands::{DevicesCommand, KeysCommand},
     config::ConfigHandle,
-    MatrixServer, Servers, PLUGIN_NAME,
+    Matrix, MatrixServer, Servers, PLUGIN_NAME,
 };
 
 pub struct MatrixCommand {
@@ -28,19 +28,28 @@ impl MatrixCommand {
     ) -> Result<Command, ()> {
         let matrix_settings = CommandSettings::new("matrix")
             .description("Matrix chat protocol command.")
-            .add_argument("server add <server-name> <hostname>[:<port>]")
+            .add_argument("server add <server-name> <homeserver-url>|<user-id>")
             .add_argument("server delete|list|listfull <server-name>")
-            .add_argument("connect <server-name>")
+            .add_argument("server info [<server-name>]")
+            .add_argument("connect [--offline] <server-name>")
             .add_argument("devices delete|list|set-name")
             .add_argument("keys import|export <file> <passphrase>")
+            .add_argument("keys set-name <device-id> <name>")
             .add_argument("disconnect <server-name>")
             .add_argument("reconnect <server-name>")
+            .add_argument("debug filter <directives>")
+            .add_argument("debug file <server-name>|off")
             .add_argument("help <matrix-command> [<matrix-subcommand>]")
             .arguments_description(&format!(
-                "      server: List, add, or remove Matrix servers.
-     connect: Connect to Matrix servers.
+                "      server: List, add, or remove Matrix servers, or show what a
+              homeserver supports.
+     connect: Connect to Matrix servers, with --offline the rooms are
+              only restored from the local store.
   disconnect: Disconnect from one or all Matrix servers.
-   reconnect: Reconnect to server(s).
+   reconnect: Replace the connection to a server with a new one.
+       debug: Change which log messages are shown, e.g. /matrix debug filter
+              matrix_sdk=debug,info, or write them to a file in the
+              directory of a server.
      devices: {}
         keys: {}
         help: Show detailed command help.\n
@@ -48,12 +57,14 @@ Use /matrix [command] help to find out more.\n",
                 DevicesCommand::DESCRIPTION,
                 KeysCommand::DESCRIPTION,
             ))
-            .add_completion("server |add|delete|list|listfull")
+            .add_completion("server |add|delete|list|listfull|info")
             .add_completion("devices |list|delete|set-name")
             .add_completion(&format!("keys {}", KeysCommand::COMPLETION))
+            .add_completion("keys import|export %(filename)")
             .add_completion("connect")
             .add_completion("disconnect")
             .add_completion("reconnect")
+            .add_completion("debug filter|file")
             .add_completion("help server|connect|disconnect|reconnect");
 
         Command::new(
@@ -72,8 +83,6 @@ Use /matrix [command] help to find out more.\n",
         let homeserver = args
             .value_of("homeserver")
             .expect("Homeserver not set but was required");
-        let homeserver = Url::parse(homeserver)
-            .expect("Can't parse Homeserver even if validation passed");
 
         let mut config_borrow = self.config.borrow_mut();
         let mut section = config_borrow
@@ -85,10 +94,19 @@ Use /matrix [command] help to find out more.\n",
         let mut servers = self.servers.borrow_mut();
         servers.insert(server_name.to_owned(), server);
 
-        let homeserver_option = section
-            .search_option(&format!("{}.homeserver", server_name))
-            .expect("Homeserver option wasn't created");
-        homeserver_option.set(homeserver.as_str(), true);
+        // A user id lets us discover the homeserver when connecting.
+        let (option_name, value) = if homeserver.starts_with('@') {
+            ("username", homeserver.to_owned())
+        } else {
+            let homeserver = Url::parse(homeserver)
+                .expect("Can't parse Homeserver even if validation passed");
+            ("homeserver", homeserver.to_string())
+        };
+
+        let option = section
+            .search_option(&format!("{}.{}", server_name, option_name))
+            .expect("Server option wasn't created");
+        option.set(&value, true);
 
         Weechat::print(&format!(
             "{}: Server {}{}{} has been added.",
@@ -154,15 +172,41 @@ Use /matrix [command] help to find out more.\n",
 
         Weechat::print("\nAll Matrix servers:");
 
-        // TODO print out some stats if the server is connected.
         for server in self.servers.borrow().values() {
             Weechat::print(&format!("    {}", server.get_info_str(details)));
         }
     }
 
-    fn server_command(&self, args: &ArgMatches) {
+    fn server_info(&self, buffer: &Buffer, args: &ArgMatches) {
+        let server = if let Some(server_name) = args.value_of("name") {
+            if let Some(s) = self.servers.borrow().get(server_name) {
+                s.clone()
+            } else {
+                self.server_not_found(server_name);
+                return;
+            }
+        } else if let Some(s) = self.servers.find_server(buffer) {
+            s
+        } else {
+            Weechat::print(&format!(
+                "{}{}: Specify a server name or run the command on a Matrix \
+                 buffer.",
+                Weechat::prefix("error"),
+                PLUGIN_NAME,
+            ));
+            return;
+        };
+
+        let info = || async move {
+            server.print_server_info().await;
+        };
+        Weechat::spawn(info()).detach();
+    }
+
+    fn server_command(&self, buffer: &Buffer, args: &ArgMatches) {
         match args.subcommand() {
             ("add", Some(subargs)) => self.add_server(subargs),
+            ("info", Some(subargs)) => self.server_info(buffer, subargs),
             ("delete", Some(subargs)) => self.delete_server(subargs),
             ("list", _) => self.list_servers(false),
             ("listfull", _) => self.list_servers(true),
@@ -181,17 +225,90 @@ Use /matrix [command] help to find out more.\n",
         ));
     }
 
+    fn debug_command(&self, args: &ArgMatches) {
+        let logging = &Matrix::get().logging;
+
+        match args.subcommand() {
+            ("filter", Some(subargs)) => {
+                let directives = subargs
+                    .values_of("directives")
+                    .expect("Filter directives not set but were required")
+                    .collect::<Vec<_>>()
+                    .join(",");
+
+                match logging.set_filter(&directives) {
+                    Ok(_) => Weechat::print(&format!(
+                        "{}: Log filter set to {}",
+                        PLUGIN_NAME, directives
+                    )),
+                    Err(e) => Weechat::print(&format!(
+                        "{}{}: Invalid log filter {}: {}",
+                        Weechat::prefix("error"),
+                        PLUGIN_NAME,
+                        directives,
+                        e
+                    )),
+                }
+            }
+            ("file", Some(subargs)) => {
+                let server_name = subargs
+                    .value_of("name")
+                    .expect("Server name not set but was required");
+
+                if server_name == "off" {
+                    logging.stop_logging_to_file();
+                    Weechat::print(&format!(
+                        "{}: Stopped writing log messages to a file",
+                        PLUGIN_NAME
+                    ));
+                    return;
+                }
+
+                let path =
+                    if let Some(s) = self.servers.borrow().get(server_name) {
+                        s.inner().get_server_path().join("debug.log")
+                    } else {
+                        self.server_not_found(server_name);
+                        return;
+                    };
+
+                match logging.log_to_file(&path) {
+                    Ok(_) => Weechat::print(&format!(
+                        "{}: Writing log messages to {}",
+                        PLUGIN_NAME,
+                        path.display()
+                    )),
+                    Err(e) => Weechat::print(&format!(
+                        "{}{}: Can't open the log file {}: {}",
+                        Weechat::prefix("error"),
+                        PLUGIN_NAME,
+                        path.display(),
+                        e
+                    )),
+                }
+            }
+            _ => unreachable!(),
+        }
+    }
+
     fn connect_command(&self, args: &ArgMatches) {
         let server_names = args
             .values_of("name")
             .expect("Server names not set but were required");
 
+        let offline = args.is_present("offline");
         let mut servers = self.servers.borrow_mut();
 
         for server_name in server_names {
             let server = servers.get_mut(server_name);
             if let Some(s) = server {
-                match s.connect() {
+                let result = if offline {
+                    s.connect_offline()
+                } else {
+                    s.connect()
+                };
+
+                match result {
                     Ok(_) => (),
                     Err(e) => Weechat::print(&format!("{:?}", e)),
                 }
@@ -216,6 +333,22 @@ Use /matrix [command] help to find out more.\n",
             self.server_not_found(server_name)
         }
     }
+
+    fn reconnect_command(&self, args: &ArgMatches) {
+        let server_name = args
+            .value_of("name")
+            .expect("Server name not set but was required");
+
+        let server = self.servers.borrow().get(server_name).cloned();
+
+        if let Some(s) = server {
+            if let Err(e) = s.reconnect() {
+                Weechat::print(&format!("{:?}", e));
+            }
+        } else {
+            self.server_not_found(server_name)
+        }
+    }
 }
 
 impl CommandCallback for MatrixCommand {
@@ -237,8 +370,11 @@ impl CommandCallback for MatrixCommand {
                     )
                     .arg(
                         Arg::with_name("homeserver")
+                            .value_name("homeserver|user-id")
                             .required(true)
-                            .validator(MatrixServer::parse_url),
+                            .validator(
+                                MatrixServer::parse_homeserver_or_user_id,
+                            ),
                     ),
             )
             .subcommand(
@@ -257,6 +393,11 @@ impl CommandCallback for MatrixCommand {
             .subcommand(
                 SubCommand::with_name("listfull")
                     .about("List detailed information about the configured Matrix servers."),
+            )
+            .subcommand(
+                SubCommand::with_name("info")
+                    .about("Show the spec versions and capabilities of a homeserver.")
+                    .arg(Arg::with_name("name").value_name("server-name")),
             );
 
         let argparse = Argparse::new("matrix")
@@ -284,6 +425,34 @@ impl CommandCallback for MatrixCommand {
                             .value_name("server-name")
                             .required(true)
                             .multiple(true),
+                    )
+                    .arg(
+                        Arg::with_name("offline")
+                            .long("offline")
+                            .help("Only restore the rooms from the local store, don't talk to the server."),
+                    ),
+            )
+            .subcommand(
+                SubCommand::with_name("debug")
+                    .about("Control the log output.")
+                    .setting(ArgParseSettings::SubcommandRequiredElseHelp)
+                    .subcommand(
+                        SubCommand::with_name("filter")
+                            .about("Set which log messages are shown.")
+                            .arg(
+                                Arg::with_name("directives")
+                                    .required(true)
+                                    .multiple(true),
+                            ),
+                    )
+                    .subcommand(
+                        SubCommand::with_name("file")
+                            .about("Write the log messages to a file in the directory of a server, or stop doing so with off.")
+                            .arg(
+                                Arg::with_name("name")
+                                    .value_name("server-name")
+                                    .required(true),
+                            ),
                     ),
             )
             .subcommand(
@@ -294,6 +463,15 @@ impl CommandCallback for MatrixCommand {
                             .value_name("server-name")
                             .required(true),
                     ),
+            )
+            .subcommand(
+                SubCommand::with_name("reconnect")
+                    .about("Replace the connection to a Matrix server with a new one")
+                    .arg(
+                        Arg::with_name("name")
+                            .value_name("server-name")
+                            .required(true),
+                    ),
             );
 
         let matches = match argparse.get_matches_from_safe(arguments) {
@@ -314,7 +492,9 @@ impl CommandCallback for MatrixCommand {
         match matches.subcommand() {
             ("connect", Some(subargs)) => self.connect_command(subargs),
             ("disconnect", Some(subargs)) => self.disconnect_command(subargs),
-            ("server", Some(subargs)) => self.server_command(subargs),
+            ("reconnect", Some(subargs)) => self.reconnect_command(subargs),
+            ("server", Some(subargs)) => self.server_command(buffer, subargs),
+            ("debug", Some(subargs)) => self.debug_command(subargs),
             ("devices", Some(subargs)) => {
                 DevicesCommand::run(buffer, &self.servers, subargs)
             }
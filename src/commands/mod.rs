@@ -1,18 +1,100 @@
 use crate::{config::ConfigHandle, Servers};
 use weechat::hooks::Command;
 
+mod away;
+mod create;
+mod delete;
 mod devices;
+mod edit;
+mod encryption;
+mod ignore;
+mod invite;
+mod join;
 mod keys;
 mod matrix;
+mod me;
+mod msg;
+mod name;
+mod names;
+mod notice;
+mod notify;
+mod pin;
+mod pinned;
+mod quote;
+mod reconnect;
+mod reply;
+mod resend;
+mod search;
+mod send_raw;
+mod thread;
+mod topic;
+mod upload;
+mod whois;
 
+use away::AwayCommand;
+use create::CreateCommand;
+use delete::DeleteCommand;
 use devices::DevicesCommand;
+use edit::EditCommand;
+use encryption::EncryptionCommand;
+use ignore::IgnoreCommand;
+use invite::InviteCommand;
+use join::JoinCommand;
 use keys::KeysCommand;
 use matrix::MatrixCommand;
+use me::MeCommand;
+use msg::MsgCommand;
+use name::NameCommand;
+use names::NamesCommand;
+use notice::NoticeCommand;
+use notify::NotifyCommand;
+use pin::PinCommand;
+use pinned::PinnedCommand;
+use quote::QuoteCommand;
+use reconnect::ReconnectCommand;
+use reply::ReplyCommand;
+use resend::ResendCommand;
+use search::SearchCommand;
+use send_raw::SendRawCommand;
+use thread::ThreadCommand;
+use topic::TopicCommand;
+use upload::UploadCommand;
+use whois::WhoisCommand;
 
 pub struct Commands {
     _matrix: Command,
     _keys: Command,
     _devices: Command,
+    _accept: Command,
+    _reject: Command,
+    _me: Command,
+    _names: Command,
+    _notice: Command,
+    _topic: Command,
+    _name: Command,
+    _reply: Command,
+    _whois: Command,
+    _msg: Command,
+    _upload: Command,
+    _encryption: Command,
+    _search: Command,
+    _edit: Command,
+    _delete: Command,
+    _redact: Command,
+    _create: Command,
+    _thread: Command,
+    _join: Command,
+    _away: Command,
+    _resend: Command,
+    _pin: Command,
+    _unpin: Command,
+    _pinned: Command,
+    _quote: Command,
+    _ignore: Command,
+    _unignore: Command,
+    _send_raw: Command,
+    _notify: Command,
+    _reconnect: Command,
 }
 
 impl Commands {
@@ -24,6 +106,36 @@ impl Commands {
             _matrix: MatrixCommand::create(servers, config)?,
             _devices: DevicesCommand::create(servers)?,
             _keys: KeysCommand::create(servers)?,
+            _accept: InviteCommand::create_accept(servers)?,
+            _reject: InviteCommand::create_reject(servers)?,
+            _me: MeCommand::create(servers)?,
+            _names: NamesCommand::create(servers)?,
+            _notice: NoticeCommand::create(servers)?,
+            _topic: TopicCommand::create(servers)?,
+            _name: NameCommand::create(servers)?,
+            _reply: ReplyCommand::create(servers)?,
+            _whois: WhoisCommand::create(servers)?,
+            _msg: MsgCommand::create(servers)?,
+            _upload: UploadCommand::create(servers)?,
+            _encryption: EncryptionCommand::create(servers)?,
+            _search: SearchCommand::create(servers)?,
+            _edit: EditCommand::create(servers)?,
+            _delete: DeleteCommand::create_delete(servers)?,
+            _redact: DeleteCommand::create_redact(servers)?,
+            _create: CreateCommand::create(servers)?,
+            _thread: ThreadCommand::create(servers)?,
+            _join: JoinCommand::create(servers)?,
+            _away: AwayCommand::create(servers)?,
+            _resend: ResendCommand::create(servers)?,
+            _pin: PinCommand::create_pin(servers)?,
+            _unpin: PinCommand::create_unpin(servers)?,
+            _pinned: PinnedCommand::create(servers)?,
+            _quote: QuoteCommand::create(servers)?,
+            _ignore: IgnoreCommand::create_ignore(servers)?,
+            _unignore: IgnoreCommand::create_unignore(servers)?,
+            _send_raw: SendRawCommand::create(servers)?,
+            _notify: NotifyCommand::create(servers)?,
+            _reconnect: ReconnectCommand::create(servers)?,
         })
     }
 }
@@ -0,0 +1,84 @@
+use std::convert::TryFrom;
+
+use matrix_sdk::identifiers::UserId;
+
+use weechat::{
+    buffer::Buffer,
+    hooks::{Command, CommandCallback, CommandSettings},
+    Args, Weechat,
+};
+
+use crate::Servers;
+
+pub struct MsgCommand {
+    servers: Servers,
+}
+
+impl MsgCommand {
+    pub const DESCRIPTION: &'static str =
+        "Open a direct chat with a user and optionally send a message";
+
+    pub fn create(servers: &Servers) -> Result<Command, ()> {
+        let settings = CommandSettings::new("msg")
+            .description(Self::DESCRIPTION)
+            .add_argument("<user-id> [<message>]")
+            .arguments_description(
+                "user-id: The id of the user\n\
+                 message: The message that should be sent to the user",
+            )
+            .add_completion("%(matrix-users)");
+
+        Command::new(
+            settings,
+            Self {
+                servers: servers.clone(),
+            },
+        )
+    }
+}
+
+impl CommandCallback for MsgCommand {
+    fn callback(&mut self, _: &Weechat, buffer: &Buffer, arguments: Args) {
+        let server = if let Some(s) = self.servers.find_server(buffer) {
+            s
+        } else {
+            Weechat::print("Must be executed on Matrix buffer");
+            return;
+        };
+
+        let mut arguments = arguments.skip(1);
+
+        let user_id = if let Some(u) = arguments.next() {
+            u
+        } else {
+            Weechat::print(&format!(
+                "{}Usage: /msg <user-id> [<message>]",
+                Weechat::prefix("error")
+            ));
+            return;
+        };
+
+        let user_id = match UserId::try_from(user_id.as_str()) {
+            Ok(u) => u,
+            Err(e) => {
+                server.print_error(&format!(
+                    "Invalid user id {}: {}",
+                    user_id, e
+                ));
+                return;
+            }
+        };
+
+        let message = arguments.collect::<Vec<String>>().join(" ");
+        let message = if message.is_empty() {
+            None
+        } else {
+            Some(message)
+        };
+
+        let open_dm = || async move {
+            server.open_dm(user_id, message).await;
+        };
+        Weechat::spawn(open_dm()).detach();
+    }
+}
@@ -0,0 +1,55 @@
+use weechat::{
+    buffer::Buffer,
+    hooks::{Command, CommandCallback, CommandSettings},
+    Args, Weechat,
+};
+
+use crate::Servers;
+
+pub struct NameCommand {
+    servers: Servers,
+}
+
+impl NameCommand {
+    pub const DESCRIPTION: &'static str = "Change the name of the current room";
+
+    pub fn create(servers: &Servers) -> Result<Command, ()> {
+        let settings = CommandSettings::new("name")
+            .description(Self::DESCRIPTION)
+            .add_argument("<name>")
+            .arguments_description("name: The new name of the room");
+
+        Command::new(
+            settings,
+            Self {
+                servers: servers.clone(),
+            },
+        )
+    }
+}
+
+impl CommandCallback for NameCommand {
+    fn callback(&mut self, _: &Weechat, buffer: &Buffer, arguments: Args) {
+        let room = if let Some(r) = self.servers.find_room(buffer) {
+            r
+        } else {
+            Weechat::print("Must be executed on a Matrix room buffer");
+            return;
+        };
+
+        let name = arguments.skip(1).collect::<Vec<String>>().join(" ");
+
+        if name.is_empty() {
+            Weechat::print(&format!(
+                "{}Usage: /name <name>",
+                Weechat::prefix("error")
+            ));
+            return;
+        }
+
+        let set_name = || async move {
+            room.set_name(name).await;
+        };
+        Weechat::spawn(set_name()).detach();
+    }
+}
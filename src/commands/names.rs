@@ -0,0 +1,38 @@
+use weechat::{
+    buffer::Buffer,
+    hooks::{Command, CommandCallback, CommandSettings},
+    Args, Weechat,
+};
+
+use crate::Servers;
+
+pub struct NamesCommand {
+    servers: Servers,
+}
+
+impl NamesCommand {
+    pub const DESCRIPTION: &'static str =
+        "List the members of the current room";
+
+    pub fn create(servers: &Servers) -> Result<Command, ()> {
+        let settings =
+            CommandSettings::new("names").description(Self::DESCRIPTION);
+
+        Command::new(
+            settings,
+            Self {
+                servers: servers.clone(),
+            },
+        )
+    }
+}
+
+impl CommandCallback for NamesCommand {
+    fn callback(&mut self, _: &Weechat, buffer: &Buffer, _: Args) {
+        if let Some(room) = self.servers.find_room(buffer) {
+            room.print_names();
+        } else {
+            Weechat::print("Must be executed on a Matrix room buffer");
+        }
+    }
+}
@@ -0,0 +1,55 @@
+use weechat::{
+    buffer::Buffer,
+    hooks::{Command, CommandCallback, CommandSettings},
+    Args, Weechat,
+};
+
+use crate::Servers;
+
+pub struct NoticeCommand {
+    servers: Servers,
+}
+
+impl NoticeCommand {
+    pub const DESCRIPTION: &'static str = "Send a notice to the current room";
+
+    pub fn create(servers: &Servers) -> Result<Command, ()> {
+        let settings = CommandSettings::new("notice")
+            .description(Self::DESCRIPTION)
+            .add_argument("<message>")
+            .arguments_description("message: The notice that should be sent");
+
+        Command::new(
+            settings,
+            Self {
+                servers: servers.clone(),
+            },
+        )
+    }
+}
+
+impl CommandCallback for NoticeCommand {
+    fn callback(&mut self, _: &Weechat, buffer: &Buffer, arguments: Args) {
+        let room = if let Some(r) = self.servers.find_room(buffer) {
+            r
+        } else {
+            Weechat::print("Must be executed on a Matrix room buffer");
+            return;
+        };
+
+        let message = arguments.skip(1).collect::<Vec<String>>().join(" ");
+
+        if message.is_empty() {
+            Weechat::print(&format!(
+                "{}Usage: /notice <message>",
+                Weechat::prefix("error")
+            ));
+            return;
+        }
+
+        let send = || async move {
+            room.send_notice(message).await;
+        };
+        Weechat::spawn(send()).detach();
+    }
+}
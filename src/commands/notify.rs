@@ -0,0 +1,68 @@
+use weechat::{
+    buffer::Buffer,
+    hooks::{Command, CommandCallback, CommandSettings},
+    Args, Weechat,
+};
+
+use crate::{room::NotificationLevel, Servers};
+
+pub struct NotifyCommand {
+    servers: Servers,
+}
+
+impl NotifyCommand {
+    pub const DESCRIPTION: &'static str =
+        "Show or change how much the current room notifies you";
+
+    pub fn create(servers: &Servers) -> Result<Command, ()> {
+        let settings = CommandSettings::new("notify")
+            .description(Self::DESCRIPTION)
+            .add_argument("[all|mentions|none]")
+            .arguments_description(
+                "     all: Get notified about every message
+mentions: Only get notified about messages that highlight you
+    none: Mute the room",
+            )
+            .add_completion("all|mentions|none");
+
+        Command::new(
+            settings,
+            Self {
+                servers: servers.clone(),
+            },
+        )
+    }
+}
+
+impl CommandCallback for NotifyCommand {
+    fn callback(&mut self, _: &Weechat, buffer: &Buffer, mut arguments: Args) {
+        let room = if let Some(r) = self.servers.find_room(buffer) {
+            r
+        } else {
+            Weechat::print("Must be executed on a Matrix room buffer");
+            return;
+        };
+
+        let level = match arguments.nth(1).as_deref() {
+            None => {
+                room.print_notification_level();
+                return;
+            }
+            Some("all") => NotificationLevel::All,
+            Some("mentions") => NotificationLevel::Mentions,
+            Some("none") => NotificationLevel::None,
+            Some(_) => {
+                Weechat::print(&format!(
+                    "{}Usage: /notify [all|mentions|none]",
+                    Weechat::prefix("error")
+                ));
+                return;
+            }
+        };
+
+        let change = || async move {
+            room.change_notification_level(level).await;
+        };
+        Weechat::spawn(change()).detach();
+    }
+}
@@ -0,0 +1,91 @@
+use std::convert::TryFrom;
+
+use matrix_sdk::identifiers::EventId;
+
+use weechat::{
+    buffer::Buffer,
+    hooks::{Command, CommandCallback, CommandSettings},
+    Args, Weechat,
+};
+
+use crate::Servers;
+
+pub struct PinCommand {
+    servers: Servers,
+    pin: bool,
+}
+
+impl PinCommand {
+    pub const PIN_DESCRIPTION: &'static str =
+        "Pin a message in the current room";
+    pub const UNPIN_DESCRIPTION: &'static str =
+        "Unpin a message in the current room";
+
+    fn create(
+        servers: &Servers,
+        name: &str,
+        description: &str,
+        pin: bool,
+    ) -> Result<Command, ()> {
+        let settings = CommandSettings::new(name)
+            .description(description)
+            .add_argument("<event-id>")
+            .arguments_description("event-id: The id of the message");
+
+        Command::new(
+            settings,
+            Self {
+                servers: servers.clone(),
+                pin,
+            },
+        )
+    }
+
+    pub fn create_pin(servers: &Servers) -> Result<Command, ()> {
+        Self::create(servers, "pin", Self::PIN_DESCRIPTION, true)
+    }
+
+    pub fn create_unpin(servers: &Servers) -> Result<Command, ()> {
+        Self::create(servers, "unpin", Self::UNPIN_DESCRIPTION, false)
+    }
+}
+
+impl CommandCallback for PinCommand {
+    fn callback(&mut self, _: &Weechat, buffer: &Buffer, mut arguments: Args) {
+        let room = if let Some(r) = self.servers.find_room(buffer) {
+            r
+        } else {
+            Weechat::print("Must be executed on a Matrix room buffer");
+            return;
+        };
+
+        let command = if self.pin { "pin" } else { "unpin" };
+
+        let event_id =
+            match arguments.nth(1).map(|a| EventId::try_from(a.as_str())) {
+                Some(Ok(e)) => e,
+                Some(Err(e)) => {
+                    Weechat::print(&format!(
+                        "{}Invalid event id: {}",
+                        Weechat::prefix("error"),
+                        e
+                    ));
+                    return;
+                }
+                None => {
+                    Weechat::print(&format!(
+                        "{}Usage: /{} <event-id>",
+                        Weechat::prefix("error"),
+                        command
+                    ));
+                    return;
+                }
+            };
+
+        let pin = self.pin;
+        let set_pinned = || async move {
+            room.set_pinned(event_id, pin).await;
+        };
+        Weechat::spawn(set_pinned()).detach();
+    }
+}
@@ -0,0 +1,44 @@
+use weechat::{
+    buffer::Buffer,
+    hooks::{Command, CommandCallback, CommandSettings},
+    Args, Weechat,
+};
+
+use crate::Servers;
+
+pub struct PinnedCommand {
+    servers: Servers,
+}
+
+impl PinnedCommand {
+    pub const DESCRIPTION: &'static str =
+        "List the pinned messages of the current room";
+
+    pub fn create(servers: &Servers) -> Result<Command, ()> {
+        let settings =
+            CommandSettings::new("pinned").description(Self::DESCRIPTION);
+
+        Command::new(
+            settings,
+            Self {
+                servers: servers.clone(),
+            },
+        )
+    }
+}
+
+impl CommandCallback for PinnedCommand {
+    fn callback(&mut self, _: &Weechat, buffer: &Buffer, _: Args) {
+        let room = if let Some(r) = self.servers.find_room(buffer) {
+            r
+        } else {
+            Weechat::print("Must be executed on a Matrix room buffer");
+            return;
+        };
+
+        let print = || async move {
+            room.print_pinned_events().await;
+        };
+        Weechat::spawn(print()).detach();
+    }
+}
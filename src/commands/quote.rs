@@ -0,0 +1,68 @@
+use std::convert::TryFrom;
+
+use matrix_sdk::identifiers::EventId;
+
+use weechat::{
+    buffer::Buffer,
+    hooks::{Command, CommandCallback, CommandSettings},
+    Args, Weechat,
+};
+
+use crate::Servers;
+
+pub struct QuoteCommand {
+    servers: Servers,
+}
+
+impl QuoteCommand {
+    pub const DESCRIPTION: &'static str =
+        "Quote a message in the input bar and reply to it";
+
+    pub fn create(servers: &Servers) -> Result<Command, ()> {
+        let settings = CommandSettings::new("quote")
+            .description(Self::DESCRIPTION)
+            .add_argument("[<event-id>]")
+            .arguments_description(
+                "event-id: The id of the message that should be quoted, if \
+                 omitted the last message of another room member is used\n\n\
+                 The next message that is sent is a reply to the quoted \
+                 message, removing the quote from the input sends a regular \
+                 message instead.",
+            );
+
+        Command::new(
+            settings,
+            Self {
+                servers: servers.clone(),
+            },
+        )
+    }
+}
+
+impl CommandCallback for QuoteCommand {
+    fn callback(&mut self, _: &Weechat, buffer: &Buffer, mut arguments: Args) {
+        let room = if let Some(r) = self.servers.find_room(buffer) {
+            r
+        } else {
+            Weechat::print("Must be executed on a Matrix room buffer");
+            return;
+        };
+
+        let event_id = match arguments.nth(1) {
+            Some(e) => match EventId::try_from(e.as_str()) {
+                Ok(e) => Some(e),
+                Err(e) => {
+                    Weechat::print(&format!(
+                        "{}Invalid event id: {}",
+                        Weechat::prefix("error"),
+                        e
+                    ));
+                    return;
+                }
+            },
+            None => None,
+        };
+
+        room.quote_into_input(event_id);
+    }
+}
@@ -0,0 +1,63 @@
+use weechat::{
+    buffer::Buffer,
+    hooks::{Command, CommandCallback, CommandSettings},
+    Args, Weechat,
+};
+
+use crate::{Servers, PLUGIN_NAME};
+
+pub struct ReconnectCommand {
+    servers: Servers,
+}
+
+impl ReconnectCommand {
+    pub const DESCRIPTION: &'static str =
+        "Replace the connection to a server with a new one, e.g. after the \
+         network changed";
+
+    pub fn create(servers: &Servers) -> Result<Command, ()> {
+        let settings = CommandSettings::new("reconnect")
+            .description(Self::DESCRIPTION)
+            .add_argument("[<server-name>]")
+            .arguments_description(
+                "server-name: The server to reconnect to, defaults to the \
+                 server of the current buffer",
+            );
+
+        Command::new(
+            settings,
+            Self {
+                servers: servers.clone(),
+            },
+        )
+    }
+}
+
+impl CommandCallback for ReconnectCommand {
+    fn callback(&mut self, _: &Weechat, buffer: &Buffer, mut arguments: Args) {
+        let server = if let Some(name) = arguments.nth(1) {
+            if let Some(s) = self.servers.borrow().get(&name).cloned() {
+                s
+            } else {
+                Weechat::print(&format!(
+                    "{}{}: Server \"{}{}{}\" not found.",
+                    Weechat::prefix("error"),
+                    PLUGIN_NAME,
+                    Weechat::color("chat_server"),
+                    name,
+                    Weechat::color("reset")
+                ));
+                return;
+            }
+        } else if let Some(s) = self.servers.find_server(buffer) {
+            s
+        } else {
+            Weechat::print("Must be executed on Matrix buffer");
+            return;
+        };
+
+        if let Err(e) = server.reconnect() {
+            Weechat::print(&format!("{:?}", e));
+        }
+    }
+}
@@ -0,0 +1,45 @@
+use weechat::{
+    buffer::Buffer,
+    hooks::{Command, CommandCallback, CommandSettings},
+    Args, Weechat,
+};
+
+use crate::Servers;
+
+pub struct ResendCommand {
+    servers: Servers,
+}
+
+impl ResendCommand {
+    pub const DESCRIPTION: &'static str =
+        "Try to send the messages of the current room that failed to be sent \
+         again";
+
+    pub fn create(servers: &Servers) -> Result<Command, ()> {
+        let settings =
+            CommandSettings::new("resend").description(Self::DESCRIPTION);
+
+        Command::new(
+            settings,
+            Self {
+                servers: servers.clone(),
+            },
+        )
+    }
+}
+
+impl CommandCallback for ResendCommand {
+    fn callback(&mut self, _: &Weechat, buffer: &Buffer, _: Args) {
+        let room = if let Some(r) = self.servers.find_room(buffer) {
+            r
+        } else {
+            Weechat::print("Must be executed on a Matrix room buffer");
+            return;
+        };
+
+        let resend = || async move {
+            room.resend_failed_messages().await;
+        };
+        Weechat::spawn(resend()).detach();
+    }
+}
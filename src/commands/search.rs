@@ -0,0 +1,59 @@
+use weechat::{
+    buffer::Buffer,
+    hooks::{Command, CommandCallback, CommandSettings},
+    Args, Weechat,
+};
+
+use crate::Servers;
+
+pub struct SearchCommand {
+    servers: Servers,
+}
+
+impl SearchCommand {
+    pub const DESCRIPTION: &'static str =
+        "Search the messages of the current room on the server";
+
+    pub fn create(servers: &Servers) -> Result<Command, ()> {
+        let settings = CommandSettings::new("search")
+            .description(Self::DESCRIPTION)
+            .add_argument("<terms>")
+            .arguments_description(
+                "terms: The terms that should be searched for, the results \
+                 are shown in a new buffer",
+            );
+
+        Command::new(
+            settings,
+            Self {
+                servers: servers.clone(),
+            },
+        )
+    }
+}
+
+impl CommandCallback for SearchCommand {
+    fn callback(&mut self, _: &Weechat, buffer: &Buffer, arguments: Args) {
+        let room = if let Some(r) = self.servers.find_room(buffer) {
+            r
+        } else {
+            Weechat::print("Must be executed on a Matrix room buffer");
+            return;
+        };
+
+        let terms = arguments.skip(1).collect::<Vec<String>>().join(" ");
+
+        if terms.is_empty() {
+            Weechat::print(&format!(
+                "{}Usage: /search <terms>",
+                Weechat::prefix("error")
+            ));
+            return;
+        }
+
+        let search = || async move {
+            room.search(terms).await;
+        };
+        Weechat::spawn(search()).detach();
+    }
+}
@@ -0,0 +1,108 @@
+use weechat::{
+    buffer::Buffer,
+    hooks::{Command, CommandCallback, CommandSettings},
+    Args, Weechat,
+};
+
+use crate::Servers;
+
+pub struct SendRawCommand {
+    servers: Servers,
+}
+
+impl SendRawCommand {
+    pub const DESCRIPTION: &'static str =
+        "Send an event with a custom type and JSON content to the current room";
+
+    pub fn create(servers: &Servers) -> Result<Command, ()> {
+        let settings = CommandSettings::new("send-raw")
+            .description(Self::DESCRIPTION)
+            .add_argument("[-yes] [-state[=<state-key>]] <event-type> <json>")
+            .arguments_description(
+                "       -yes: Confirm that the event should be sent, without it \
+                 the event is only checked
+     -state: Send a state event instead of a message event, the state key \
+                 is empty unless one is given
+ event-type: The type of the event, e.g. org.example.custom
+       json: The content of the event as a JSON object",
+            )
+            .add_completion("-yes -state");
+
+        Command::new(
+            settings,
+            Self {
+                servers: servers.clone(),
+            },
+        )
+    }
+}
+
+impl CommandCallback for SendRawCommand {
+    fn callback(&mut self, _: &Weechat, buffer: &Buffer, arguments: Args) {
+        let room = if let Some(r) = self.servers.find_room(buffer) {
+            r
+        } else {
+            Weechat::print("Must be executed on a Matrix room buffer");
+            return;
+        };
+
+        let mut arguments = arguments.skip(1).peekable();
+        let mut confirmed = false;
+        let mut state_key = None;
+
+        while let Some(argument) = arguments.peek() {
+            if argument == "-yes" {
+                confirmed = true;
+            } else if argument == "-state" {
+                state_key = Some(String::new());
+            } else if let Some(key) = argument.strip_prefix("-state=") {
+                state_key = Some(key.to_owned());
+            } else {
+                break;
+            }
+
+            arguments.next();
+        }
+
+        let event_type = arguments.next();
+        let json = arguments.collect::<Vec<_>>().join(" ");
+
+        let event_type = match event_type {
+            Some(t) if !json.is_empty() => t,
+            _ => {
+                Weechat::print(&format!(
+                    "{}Usage: /send-raw [-yes] [-state[=<state-key>]] \
+                     <event-type> <json>",
+                    Weechat::prefix("error")
+                ));
+                return;
+            }
+        };
+
+        let content: serde_json::Value = match serde_json::from_str(&json) {
+            Ok(c) if c.is_object() => c,
+            Ok(_) => {
+                room.print_error("The event content needs to be a JSON object");
+                return;
+            }
+            Err(e) => {
+                room.print_error(&format!("Invalid event content: {}", e));
+                return;
+            }
+        };
+
+        if !confirmed {
+            room.print_error(&format!(
+                "The {} event won't be validated before it's sent, add -yes \
+                 to the command to send it",
+                event_type
+            ));
+            return;
+        }
+
+        let send = || async move {
+            room.send_raw_event(event_type, content, state_key).await;
+        };
+        Weechat::spawn(send()).detach();
+    }
+}
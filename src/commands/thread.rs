@@ -0,0 +1,72 @@
+use std::convert::TryFrom;
+
+use matrix_sdk::identifiers::EventId;
+
+use weechat::{
+    buffer::Buffer,
+    hooks::{Command, CommandCallback, CommandSettings},
+    Args, Weechat,
+};
+
+use crate::Servers;
+
+pub struct ThreadCommand {
+    servers: Servers,
+}
+
+impl ThreadCommand {
+    pub const DESCRIPTION: &'static str =
+        "Send a message to a thread in the current room";
+
+    pub fn create(servers: &Servers) -> Result<Command, ()> {
+        let settings = CommandSettings::new("thread")
+            .description(Self::DESCRIPTION)
+            .add_argument("<event-id> <message>")
+            .arguments_description(
+                "event-id: The id of the thread root or of a message in the \
+                 thread\n\
+                 message: The message that should be sent to the thread",
+            );
+
+        Command::new(
+            settings,
+            Self {
+                servers: servers.clone(),
+            },
+        )
+    }
+}
+
+impl CommandCallback for ThreadCommand {
+    fn callback(&mut self, _: &Weechat, buffer: &Buffer, arguments: Args) {
+        let room = if let Some(r) = self.servers.find_room(buffer) {
+            r
+        } else {
+            Weechat::print("Must be executed on a Matrix room buffer");
+            return;
+        };
+
+        let mut arguments = arguments.skip(1);
+
+        let event_id = arguments
+            .next()
+            .and_then(|a| EventId::try_from(a.as_str()).ok());
+        let message = arguments.collect::<Vec<String>>().join(" ");
+
+        let event_id = match event_id {
+            Some(e) if !message.is_empty() => e,
+            _ => {
+                Weechat::print(&format!(
+                    "{}Usage: /thread <event-id> <message>",
+                    Weechat::prefix("error")
+                ));
+                return;
+            }
+        };
+
+        let thread = || async move {
+            room.send_thread_message(event_id, message).await;
+        };
+        Weechat::spawn(thread()).detach();
+    }
+}
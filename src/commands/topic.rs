@@ -0,0 +1,56 @@
+use weechat::{
+    buffer::Buffer,
+    hooks::{Command, CommandCallback, CommandSettings},
+    Args, Weechat,
+};
+
+use crate::Servers;
+
+pub struct TopicCommand {
+    servers: Servers,
+}
+
+impl TopicCommand {
+    pub const DESCRIPTION: &'static str =
+        "Show or change the topic of the current room";
+
+    pub fn create(servers: &Servers) -> Result<Command, ()> {
+        let settings = CommandSettings::new("topic")
+            .description(Self::DESCRIPTION)
+            .add_argument("[<topic>]")
+            .arguments_description(
+                "topic: The new topic of the room, if omitted the current \
+                 topic is shown",
+            );
+
+        Command::new(
+            settings,
+            Self {
+                servers: servers.clone(),
+            },
+        )
+    }
+}
+
+impl CommandCallback for TopicCommand {
+    fn callback(&mut self, _: &Weechat, buffer: &Buffer, arguments: Args) {
+        let room = if let Some(r) = self.servers.find_room(buffer) {
+            r
+        } else {
+            Weechat::print("Must be executed on a Matrix room buffer");
+            return;
+        };
+
+        let topic = arguments.skip(1).collect::<Vec<String>>().join(" ");
+
+        if topic.is_empty() {
+            room.print_topic();
+            return;
+        }
+
+        let set_topic = || async move {
+            room.set_topic(topic).await;
+        };
+        Weechat::spawn(set_topic()).detach();
+    }
+}
@@ -0,0 +1,68 @@
+use std::path::PathBuf;
+
+use weechat::{
+    buffer::Buffer,
+    hooks::{Command, CommandCallback, CommandSettings},
+    Args, Weechat,
+};
+
+use crate::{room::UploadSource, Servers};
+
+pub struct UploadCommand {
+    servers: Servers,
+}
+
+impl UploadCommand {
+    pub const DESCRIPTION: &'static str =
+        "Upload a file or the image in the clipboard to the current room";
+
+    pub fn create(servers: &Servers) -> Result<Command, ()> {
+        let settings = CommandSettings::new("upload")
+            .description(Self::DESCRIPTION)
+            .add_argument("<path>")
+            .add_argument("--clipboard")
+            .arguments_description(
+                "     path: The path of the file that should be uploaded
+--clipboard: Upload the image that is in the clipboard",
+            )
+            .add_completion("%(filename)")
+            .add_completion("--clipboard");
+
+        Command::new(
+            settings,
+            Self {
+                servers: servers.clone(),
+            },
+        )
+    }
+}
+
+impl CommandCallback for UploadCommand {
+    fn callback(&mut self, _: &Weechat, buffer: &Buffer, arguments: Args) {
+        let room = if let Some(r) = self.servers.find_room(buffer) {
+            r
+        } else {
+            Weechat::print("Must be executed on a Matrix room buffer");
+            return;
+        };
+
+        let argument = arguments.skip(1).collect::<Vec<String>>().join(" ");
+
+        let source = match argument.as_str() {
+            "" => {
+                Weechat::print(&format!(
+                    "{}Usage: /upload <path>|--clipboard",
+                    Weechat::prefix("error")
+                ));
+                return;
+            }
+            "--clipboard" => UploadSource::Clipboard,
+            path => UploadSource::File(PathBuf::from(path)),
+        };
+
+        let upload = || async move {
+            room.upload(source).await;
+        };
+        Weechat::spawn(upload()).detach();
+    }
+}
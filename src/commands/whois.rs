@@ -0,0 +1,72 @@
+use std::convert::TryFrom;
+
+use matrix_sdk::identifiers::UserId;
+
+use weechat::{
+    buffer::Buffer,
+    hooks::{Command, CommandCallback, CommandSettings},
+    Args, Weechat,
+};
+
+use crate::Servers;
+
+pub struct WhoisCommand {
+    servers: Servers,
+}
+
+impl WhoisCommand {
+    pub const DESCRIPTION: &'static str =
+        "Show the profile, power level and devices of a user";
+
+    pub fn create(servers: &Servers) -> Result<Command, ()> {
+        let settings = CommandSettings::new("whois")
+            .description(Self::DESCRIPTION)
+            .add_argument("<user-id>")
+            .arguments_description("user-id: The id of the user")
+            .add_completion("%(matrix-nicks)");
+
+        Command::new(
+            settings,
+            Self {
+                servers: servers.clone(),
+            },
+        )
+    }
+}
+
+impl CommandCallback for WhoisCommand {
+    fn callback(&mut self, _: &Weechat, buffer: &Buffer, mut arguments: Args) {
+        let room = if let Some(r) = self.servers.find_room(buffer) {
+            r
+        } else {
+            Weechat::print("Must be executed on a Matrix room buffer");
+            return;
+        };
+
+        let user_id = if let Some(u) = arguments.nth(1) {
+            u
+        } else {
+            Weechat::print(&format!(
+                "{}Usage: /whois <user-id>",
+                Weechat::prefix("error")
+            ));
+            return;
+        };
+
+        let user_id = match UserId::try_from(user_id.as_str()) {
+            Ok(u) => u,
+            Err(e) => {
+                room.print_error(&format!(
+                    "Invalid user id {}: {}",
+                    user_id, e
+                ));
+                return;
+            }
+        };
+
+        let whois = || async move {
+            room.whois(user_id).await;
+        };
+        Weechat::spawn(whois()).detach();
+    }
+}
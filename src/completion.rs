@@ -0,0 +1,144 @@
+//! Completion module.
+//!
+//! Provides completions for the Matrix buffers, e.g. the members of a room for
+//! nick completion or the rooms and users we know about for the `/join` and
+//! `/msg` commands.
+
+use std::borrow::Cow;
+
+use weechat::{
+    buffer::Buffer,
+    hooks::{
+        Completion, CompletionCallback, CompletionHook, CompletionPosition,
+    },
+    Weechat,
+};
+
+use crate::Servers;
+
+pub struct Completions {
+    _nicks: CompletionHook,
+    _nick_override: CompletionHook,
+    _rooms: CompletionHook,
+    _users: CompletionHook,
+}
+
+impl Completions {
+    pub fn hook_all(servers: &Servers) -> Result<Completions, ()> {
+        Ok(Completions {
+            _nicks: CompletionHook::new(
+                "matrix-nicks",
+                "Members of the current Matrix room",
+                NickCompletion(servers.clone()),
+            )?,
+            // The `%(nicks)` item of the default completion template asks the
+            // "nick" completion of the plugin owning the buffer first, this
+            // makes input completion on room buffers use our member list.
+            _nick_override: CompletionHook::new(
+                "nick",
+                "Members of the current Matrix room, used by the nicks \
+                 completion on Matrix buffers",
+                NickCompletion(servers.clone()),
+            )?,
+            _rooms: CompletionHook::new(
+                "matrix-rooms",
+                "Aliases of the joined Matrix rooms and ids of invited rooms",
+                RoomCompletion(servers.clone()),
+            )?,
+            _users: CompletionHook::new(
+                "matrix-users",
+                "User ids of the users that share a Matrix room with us",
+                UserCompletion(servers.clone()),
+            )?,
+        })
+    }
+}
+
+/// Completes the nicks of the members of a room, or their user ids if the
+/// word that is being completed starts with an `@`.
+struct NickCompletion(Servers);
+
+impl CompletionCallback for NickCompletion {
+    fn callback(
+        &mut self,
+        _: &Weechat,
+        buffer: &Buffer,
+        _: Cow<str>,
+        completion: &Completion,
+    ) -> Result<(), ()> {
+        let room = if let Some(r) = self.0.find_room(buffer) {
+            r
+        } else {
+            return Ok(());
+        };
+
+        let user_ids =
+            completion.base_word().map_or(false, |w| w.starts_with('@'));
+
+        // The words are already sorted, recent speakers come first.
+        for word in room.member_completions(user_ids) {
+            completion.add_with_options(&word, true, CompletionPosition::End);
+        }
+
+        Ok(())
+    }
+}
+
+/// Completes the aliases of the rooms of a server, used for `/join`.
+struct RoomCompletion(Servers);
+
+impl CompletionCallback for RoomCompletion {
+    fn callback(
+        &mut self,
+        _: &Weechat,
+        buffer: &Buffer,
+        _: Cow<str>,
+        completion: &Completion,
+    ) -> Result<(), ()> {
+        let server = if let Some(s) = self.0.find_server(buffer) {
+            s
+        } else {
+            return Ok(());
+        };
+
+        for word in server.inner().room_completions() {
+            completion.add_with_options(
+                &word,
+                false,
+                CompletionPosition::Sorted,
+            );
+        }
+
+        Ok(())
+    }
+}
+
+/// Completes the user ids of the users we share a room with, used for
+/// `/msg`.
+struct UserCompletion(Servers);
+
+impl CompletionCallback for UserCompletion {
+    fn callback(
+        &mut self,
+        _: &Weechat,
+        buffer: &Buffer,
+        _: Cow<str>,
+        completion: &Completion,
+    ) -> Result<(), ()> {
+        let server = if let Some(s) = self.0.find_server(buffer) {
+            s
+        } else {
+            return Ok(());
+        };
+
+        for word in server.inner().user_completions() {
+            completion.add_with_options(
+                &word,
+                false,
+                CompletionPosition::Sorted,
+            );
+        }
+
+        Ok(())
+    }
+}
@@ -57,6 +57,29 @@ impl From<i32> for RedactionStyle {
     }
 }
 
+#[derive(Clone, Copy, EnumVariantNames)]
+#[strum(serialize_all = "kebab_case")]
+pub enum NickColorSource {
+    UserId,
+    DisplayName,
+}
+
+impl Default for NickColorSource {
+    fn default() -> Self {
+        NickColorSource::UserId
+    }
+}
+
+impl From<i32> for NickColorSource {
+    fn from(value: i32) -> Self {
+        match value {
+            0 => NickColorSource::UserId,
+            1 => NickColorSource::DisplayName,
+            _ => unreachable!(),
+        }
+    }
+}
+
 config!(
     "matrix-rust",
     Section look {
@@ -80,6 +103,14 @@ config!(
             "The style that should be used when a message needs to be redacted",
             RedactionStyle,
         },
+
+        nick_color_source: Enum {
+            // Description
+            "The source that should be used to calculate the color of a nick, \
+             the display name source will change the color of a user if they \
+             change their display name",
+            NickColorSource,
+        },
     },
     Section network {
         debug_buffer: bool {
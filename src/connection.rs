@@ -1,47 +1,382 @@
 use std::{
-    cell::RefCell,
-    collections::BTreeMap,
+    cell::{Cell, RefCell},
+    collections::{
+        hash_map::DefaultHasher, BTreeMap, HashMap, HashSet, VecDeque,
+    },
+    convert::TryFrom,
     future::Future,
-    path::PathBuf,
+    hash::{Hash, Hasher},
+    io::Cursor,
+    path::{Path, PathBuf},
     rc::{Rc, Weak},
-    sync::Arc,
-    time::Duration,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant, SystemTime},
 };
 
 use async_std::sync::{channel as async_channel, Receiver, Sender};
+use async_trait::async_trait;
+use futures::{
+    future::{abortable, join, select, AbortHandle, Aborted},
+    pin_mut,
+    stream::{self, StreamExt},
+};
+use mime::Mime;
 use serde_json::json;
-use tokio::runtime::Runtime;
-use tracing::error;
+use tokio::{
+    runtime::Runtime,
+    sync::Mutex as AsyncMutex,
+    task::{JoinError, JoinHandle},
+    time::{delay_for, timeout},
+};
+use tracing::{debug, error, warn};
+use url::Url;
 use uuid::Uuid;
 
 pub use matrix_sdk::{
     self,
+    api::error::ErrorKind,
     api::r0::{
+        account::whoami::Request as WhoamiRequest,
+        capabilities::get_capabilities::{
+            Capabilities, Request as CapabilitiesRequest,
+        },
+        config::{
+            get_global_account_data::Request as GetAccountDataRequest,
+            set_global_account_data::Request as SetAccountDataRequest,
+        },
         device::{
             delete_devices::Response as DeleteDevicesResponse,
             get_devices::Response as DevicesResponse,
+            update_device::{
+                Request as UpdateDeviceRequest,
+                Response as UpdateDeviceResponse,
+            },
+        },
+        filter::{
+            FilterDefinition, LazyLoadOptions, RoomEventFilter, RoomFilter,
+        },
+        media::{
+            get_content::Request as MediaRequest,
+            get_content_thumbnail::{
+                Method as ThumbnailMethod, Request as ThumbnailRequest,
+            },
+            get_media_preview::Request as MediaPreviewRequest,
+        },
+        membership::{
+            join_room_by_id::Response as JoinRoomResponse,
+            join_room_by_id_or_alias::Response as JoinRoomByAliasResponse,
+            leave_room::Response as LeaveRoomResponse,
+        },
+        message::{
+            get_message_events::Request as MessagesRequest,
+            send_message_event::Response as RoomSendResponse,
+        },
+        presence::set_presence::{
+            Request as PresenceRequest, Response as PresenceResponse,
+        },
+        profile::get_profile::{
+            Request as ProfileRequest, Response as ProfileResponse,
+        },
+        push::{
+            delete_pushrule::Request as DeletePushRuleRequest,
+            set_pushrule::Request as SetPushRuleRequest, RuleKind,
+        },
+        redact::redact_event::{
+            Request as RedactEventRequest, Response as RedactEventResponse,
+        },
+        room::{
+            create_room::{
+                Request as CreateRoomRequest, Response as CreateRoomResponse,
+                RoomPreset,
+            },
+            get_room_event::Request as RoomEventRequest,
+            Visibility as RoomVisibility,
+        },
+        search::search_events::{
+            Categories as SearchCategories, Criteria as SearchCriteria,
+            EventContext as SearchEventContext, Request as SearchRequest,
+            Response as SearchResponse,
         },
-        message::send_message_event::Response as RoomSendResponse,
         session::login::Response as LoginResponse,
+        state::{
+            get_state_events_for_key::Request as GetStateEventRequest,
+            send_state_event_for_key::{
+                Request as StateEventRequest, Response as StateEventResponse,
+            },
+        },
+        sync::sync_events::{Filter as SyncFilter, Response as SyncResponse},
+        tag::get_tags::Request as TagsRequest,
         typing::create_typing_event::{Response as TypingResponse, Typing},
         uiaa::AuthData,
     },
+    api::unversioned::get_supported_versions::Request as VersionsRequest,
     events::{
-        room::message::{MessageEventContent, TextMessageEventContent},
-        AnyMessageEventContent, AnySyncRoomEvent, AnySyncStateEvent,
+        custom::CustomEventContent,
+        ignored_user_list::{
+            IgnoredUserListEvent, IgnoredUserListEventContent,
+        },
+        presence::{PresenceEvent, PresenceState},
+        push_rules::{Action, PushCondition},
+        room::{
+            encrypted::EncryptedEventContent,
+            encryption::EncryptionEventContent,
+            message::{MessageEventContent, TextMessageEventContent},
+            pinned_events::PinnedEventsEventContent,
+        },
+        tag::Tags,
+        AnyBasicEvent, AnyInitialStateEvent, AnyMessageEventContent,
+        AnyStateEventContent, AnyStrippedStateEvent, AnySyncMessageEvent,
+        AnySyncRoomEvent, AnySyncStateEvent, AnyToDeviceEvent, EventType,
+        InitialStateEvent, SyncMessageEvent,
+    },
+    identifiers::{
+        DeviceIdBox, EventEncryptionAlgorithm, EventId, RoomId,
+        RoomIdOrAliasId, ServerNameBox, UserId,
     },
-    identifiers::{DeviceIdBox, RoomId, UserId},
     locks::RwLock,
-    Client, ClientConfig, LoopCtrl, Result as MatrixResult, Room, SyncSettings,
+    Client, ClientConfig, Error as MatrixError, FromHttpResponseError,
+    LoopCtrl, Raw, Result as MatrixResult, Room, ServerError, Session,
+    SyncSettings,
 };
 
 use weechat::{Task, Weechat};
 
-use crate::server::{InnerServer, MatrixServer};
+use crate::{
+    discovery::{self, DiscoveryError},
+    edit,
+    room::NotificationLevel,
+    server::{InnerServer, MatrixServer},
+    session::{self, StoredSession},
+    thread,
+};
 
 const DEFAULT_SYNC_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// The number of events before and after a search result that are shown as
+/// context.
+const SEARCH_CONTEXT_LINES: u32 = 1;
+
+/// The width and height of the avatar thumbnails we download.
+const AVATAR_SIZE: (u32, u32) = (64, 64);
+
+/// How long we wait for a sync response before we consider the connection to
+/// be broken, twice the sync timeout since the server answers a sync at the
+/// latest after the sync timeout.
+const SYNC_STALL_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// How often we check if the sync loop stalled.
+const SYNC_STALL_CHECK_INTERVAL: Duration = Duration::from_secs(10);
+
+/// How often we check that the server still answers our requests while the
+/// sync loop seems to be fine.
+const HEALTH_CHECK_INTERVAL: Duration = Duration::from_secs(300);
+
+/// How long the health check request may take before we consider the
+/// connection to be broken.
+const HEALTH_CHECK_TIMEOUT: Duration = Duration::from_secs(30);
 pub const TYPING_NOTICE_TIMEOUT: Duration = Duration::from_secs(4);
 
+/// How long to wait before retrying a rate limited request if the server
+/// doesn't tell us.
+const DEFAULT_RETRY_DELAY: Duration = Duration::from_secs(1);
+
+/// Shown if logging in fails because another client holds the lock of our
+/// crypto store.
+const STORE_LOCKED_ERROR: &str =
+    "The crypto store is locked, the account is probably in use by another \
+     WeeChat instance. Close the other instance or enable the unique_store \
+     option of the server";
+
+/// The number of messages the channel between the sync loop and the response
+/// receiver can hold.
+///
+/// The channel is bounded, once it's full the sync loop waits until the
+/// response receiver catches up, no events are dropped. A bigger channel lets
+/// the sync loop get further ahead of the Weechat mainloop at the cost of
+/// memory on large initial syncs.
+pub const CHANNEL_SIZE: usize = 1000;
+
+/// The number of queued messages at which we consider the channel congested.
+const CHANNEL_HIGH_WATERMARK: usize = CHANNEL_SIZE * 9 / 10;
+
+/// How long the channel needs to stay congested before we log a warning.
+const CHANNEL_CONGESTION_WARNING: Duration = Duration::from_secs(5);
+
+/// How many requests for the rooms restored from the store we send out at
+/// once, accounts with many rooms would otherwise flood the server.
+const MAX_CONCURRENT_RESTORE_REQUESTS: usize = 8;
+
+/// A device of another user, as known to our crypto store.
+#[derive(Debug, Clone)]
+pub struct UserDevice {
+    pub device_id: DeviceIdBox,
+    pub display_name: Option<String>,
+    pub verified: bool,
+}
+
+/// The spec versions, unstable features and capabilities of a homeserver.
+#[derive(Debug, Clone)]
+pub struct ServerInfo {
+    pub versions: Vec<String>,
+    pub unstable_features: BTreeMap<String, bool>,
+    pub capabilities: Capabilities,
+}
+
+impl ServerInfo {
+    /// Does the server support the given spec version.
+    pub fn supports_version(&self, version: &str) -> bool {
+        self.versions.iter().any(|v| v == version)
+    }
+
+    /// Is the given unstable feature enabled on the server.
+    pub fn supports_feature(&self, feature: &str) -> bool {
+        self.unstable_features
+            .get(feature)
+            .copied()
+            .unwrap_or(false)
+    }
+}
+
+/// The settings of a room that should be created.
+#[derive(Debug, Clone, Default)]
+pub struct RoomOptions {
+    /// Should the room be published in the room directory of the server.
+    pub public: bool,
+    /// The preset for the join rules, history visibility and guest access.
+    pub preset: Option<RoomPreset>,
+    /// The local part of the alias the room should get.
+    pub alias: Option<String>,
+    pub name: Option<String>,
+    pub topic: Option<String>,
+    /// Users that should be invited to the room.
+    pub invite: Vec<UserId>,
+    /// Should the room be encrypted from the start.
+    pub encrypted: bool,
+}
+
+#[derive(Debug)]
+pub enum ConnectionError {
+    /// The tokio runtime couldn't be created.
+    Runtime(std::io::Error),
+    /// A task that was spawned on the tokio runtime panicked or got cancelled.
+    Join(JoinError),
+    /// The homeserver request failed.
+    Matrix(MatrixError),
+    /// The given `mxc://` URI isn't valid.
+    InvalidMxcUri(String),
+    /// Reading or writing a file failed.
+    Io(std::io::Error),
+}
+
+impl std::fmt::Display for ConnectionError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ConnectionError::Runtime(e) => {
+                write!(f, "Error creating the tokio runtime: {}", e)
+            }
+            ConnectionError::Join(e) => write!(f, "Error running task: {}", e),
+            ConnectionError::Matrix(e) => write!(f, "{}", e),
+            ConnectionError::InvalidMxcUri(uri) => {
+                write!(f, "Invalid mxc URI {}", uri)
+            }
+            ConnectionError::Io(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl ConnectionError {
+    /// Did the server reject the request because we lack the permission to
+    /// do it.
+    pub fn is_forbidden(&self) -> bool {
+        self.error_kind() == Some(&ErrorKind::Forbidden)
+    }
+
+    /// Did the server reject the request because our access token isn't valid
+    /// anymore, e.g. because we got logged out.
+    pub fn is_unknown_token(&self) -> bool {
+        matches!(self.error_kind(), Some(ErrorKind::UnknownToken { .. }))
+    }
+
+    /// Did the server reject the request because it doesn't support it.
+    pub fn is_unrecognized(&self) -> bool {
+        self.error_kind() == Some(&ErrorKind::Unrecognized)
+    }
+
+    /// Did the request fail because we couldn't talk to the server, e.g.
+    /// because the network is down, retrying it later might succeed.
+    pub fn is_transient(&self) -> bool {
+        matches!(
+            self,
+            ConnectionError::Matrix(MatrixError::Reqwest(_))
+                | ConnectionError::Matrix(MatrixError::AuthenticationRequired)
+        )
+    }
+
+    /// How long to wait before retrying the request, if the server rejected it
+    /// because we're sending too many requests.
+    pub fn retry_after(&self) -> Option<Duration> {
+        if let Some(ErrorKind::LimitExceeded { retry_after_ms }) =
+            self.error_kind()
+        {
+            Some(retry_after_ms.unwrap_or(DEFAULT_RETRY_DELAY))
+        } else {
+            None
+        }
+    }
+
+    fn error_kind(&self) -> Option<&ErrorKind> {
+        if let ConnectionError::Matrix(e) = self {
+            matrix_error_kind(e)
+        } else {
+            None
+        }
+    }
+}
+
+/// Get the kind of the error the server responded with, if the request failed
+/// because of a known server error.
+fn matrix_error_kind(error: &MatrixError) -> Option<&ErrorKind> {
+    if let MatrixError::RumaResponse(FromHttpResponseError::Http(
+        ServerError::Known(e),
+    )) = error
+    {
+        Some(&e.kind)
+    } else {
+        None
+    }
+}
+
+/// Check if the error tells us that the crypto store is locked.
+///
+/// The sqlite database of the store can only be opened by a single client at
+/// a time, the error only shows up as a message from sqlite.
+fn is_store_locked(error: &MatrixError) -> bool {
+    let error = error.to_string();
+
+    error.contains("database is locked")
+        || error.contains("database table is locked")
+}
+
+/// Describe why logging in failed.
+fn login_error(error: &MatrixError) -> String {
+    if is_store_locked(error) {
+        STORE_LOCKED_ERROR.to_owned()
+    } else {
+        format!("Failed to log in: {}", error)
+    }
+}
+
+impl From<MatrixError> for ConnectionError {
+    fn from(error: MatrixError) -> Self {
+        ConnectionError::Matrix(error)
+    }
+}
+
+pub type ConnectionResult<T> = Result<T, ConnectionError>;
+
 pub struct InteractiveAuthInfo {
     pub user: String,
     pub password: String,
@@ -74,8 +409,392 @@ impl InteractiveAuthInfo {
 pub enum ClientMessage {
     LoginMessage(LoginResponse),
     SyncState(RoomId, AnySyncStateEvent),
-    SyncEvent(RoomId, AnySyncRoomEvent),
+    /// A timeline event, together with the root of the thread the event is
+    /// part of.
+    SyncEvent(RoomId, AnySyncRoomEvent, Option<EventId>),
+    /// An older event of a room, fetched so the room buffer doesn't start
+    /// out empty. Unlike sync events these are shown as history.
+    BacklogEvent(RoomId, AnySyncRoomEvent, Option<EventId>),
     RestoredRoom(Arc<RwLock<Room>>),
+    /// The tags of the rooms that were restored from the store, tags only
+    /// show up in a sync if they change.
+    RestoredTags(Vec<(RoomId, Tags)>),
+    Invite(RoomId, UserId, String),
+    LeftRoom(RoomId, Option<RoomRemoval>),
+    StateChange(ConnectionState),
+    SoftLogout,
+    OfflineLogin(UserId, DeviceIdBox),
+    Presence(PresenceEvent),
+    AccountData(AnyBasicEvent),
+    ToDevice(AnyToDeviceEvent),
+    RoomAccountData(RoomId, AnyBasicEvent),
+    /// The health check failed, the connection should be replaced by a new
+    /// one. Contains the reason why the check failed.
+    Reconnect(String),
+}
+
+/// The membership event that removed us from a room.
+#[derive(Debug)]
+pub struct RoomRemoval {
+    /// The user that removed us, this is our own user if we left the room.
+    pub sender: UserId,
+    pub banned: bool,
+    pub reason: Option<String>,
+}
+
+impl RoomRemoval {
+    /// Get the removal out of a timeline event, if the event is a membership
+    /// event that removed the given user.
+    fn from_event(
+        event: &Raw<AnySyncRoomEvent>,
+        user_id: &UserId,
+    ) -> Option<RoomRemoval> {
+        // The reason isn't part of the parsed member event content, so the
+        // event is looked at in its JSON form.
+        let event: serde_json::Value =
+            serde_json::from_str(event.json().get()).ok()?;
+
+        if event.get("type")?.as_str()? != "m.room.member"
+            || event.get("state_key")?.as_str()? != user_id.as_str()
+        {
+            return None;
+        }
+
+        let content = event.get("content")?;
+        let banned = match content.get("membership")?.as_str()? {
+            "ban" => true,
+            "leave" => false,
+            _ => return None,
+        };
+
+        Some(RoomRemoval {
+            sender: UserId::try_from(event.get("sender")?.as_str()?).ok()?,
+            banned,
+            reason: content
+                .get("reason")
+                .and_then(|r| r.as_str())
+                .filter(|r| !r.is_empty())
+                .map(|r| r.to_owned()),
+        })
+    }
+}
+
+/// The receiving side of the messages the sync loop sends out.
+///
+/// The response receiver hands every `ClientMessage` to the matching method,
+/// this lets the dispatch logic run without a real server.
+#[async_trait(?Send)]
+pub trait ClientMessageHandler {
+    fn print_error(&self, message: &str);
+    fn receive_login(&mut self, response: LoginResponse);
+    fn receive_offline_login(
+        &mut self,
+        user_id: UserId,
+        device_id: DeviceIdBox,
+    );
+    fn receive_soft_logout(&mut self);
+    fn set_connection_state(&mut self, state: ConnectionState);
+    fn receive_joined_state_event(
+        &mut self,
+        room_id: &RoomId,
+        event: AnySyncStateEvent,
+    );
+    async fn receive_joined_timeline_event(
+        &mut self,
+        room_id: &RoomId,
+        event: AnySyncRoomEvent,
+        thread_root: Option<EventId>,
+    );
+    async fn receive_backlog_event(
+        &mut self,
+        room_id: &RoomId,
+        event: AnySyncRoomEvent,
+        thread_root: Option<EventId>,
+    );
+    async fn restore_room(&mut self, room: Arc<RwLock<Room>>);
+    fn receive_restored_tags(&mut self, tags: Vec<(RoomId, Tags)>);
+    fn receive_invite(
+        &mut self,
+        room_id: RoomId,
+        inviter: UserId,
+        room_name: String,
+    );
+    fn receive_left_room(
+        &mut self,
+        room_id: &RoomId,
+        removal: Option<RoomRemoval>,
+    );
+    fn receive_to_device_event(&self, event: AnyToDeviceEvent);
+    fn receive_presence(&self, event: PresenceEvent);
+    fn receive_account_data(&mut self, event: AnyBasicEvent);
+    fn receive_room_account_data(
+        &mut self,
+        room_id: &RoomId,
+        event: AnyBasicEvent,
+    );
+}
+
+/// Hand a message coming from the sync loop to the right handler method.
+pub async fn dispatch(
+    handler: &mut impl ClientMessageHandler,
+    message: Result<ClientMessage, String>,
+) {
+    let message = match message {
+        Ok(m) => m,
+        Err(e) => {
+            handler.print_error(&format!("Ruma error {}", e));
+            return;
+        }
+    };
+
+    match message {
+        ClientMessage::LoginMessage(r) => handler.receive_login(r),
+        ClientMessage::SyncEvent(r, e, t) => {
+            handler.receive_joined_timeline_event(&r, e, t).await
+        }
+        ClientMessage::BacklogEvent(r, e, t) => {
+            handler.receive_backlog_event(&r, e, t).await
+        }
+        ClientMessage::SyncState(r, e) => {
+            handler.receive_joined_state_event(&r, e)
+        }
+        ClientMessage::RestoredRoom(room) => handler.restore_room(room).await,
+        ClientMessage::RestoredTags(tags) => {
+            handler.receive_restored_tags(tags)
+        }
+        ClientMessage::Invite(room_id, inviter, room_name) => {
+            handler.receive_invite(room_id, inviter, room_name)
+        }
+        ClientMessage::LeftRoom(room_id, removal) => {
+            handler.receive_left_room(&room_id, removal)
+        }
+        ClientMessage::StateChange(state) => {
+            if let ConnectionState::Error(e) = &state {
+                handler.print_error(e);
+            }
+
+            handler.set_connection_state(state)
+        }
+        ClientMessage::SoftLogout => handler.receive_soft_logout(),
+        ClientMessage::OfflineLogin(user_id, device_id) => {
+            handler.receive_offline_login(user_id, device_id)
+        }
+        ClientMessage::ToDevice(e) => handler.receive_to_device_event(e),
+        ClientMessage::Presence(e) => handler.receive_presence(e),
+        ClientMessage::AccountData(e) => handler.receive_account_data(e),
+        // The response receiver replaces the connection itself.
+        ClientMessage::Reconnect(_) => (),
+        ClientMessage::RoomAccountData(r, e) => {
+            handler.receive_room_account_data(&r, e)
+        }
+    }
+}
+
+/// The server settings the sync filter is built from.
+#[derive(Clone, Debug, Default)]
+pub struct FilterSettings {
+    /// The maximal number of timeline events per room in a sync response, 0
+    /// leaves the limit up to the server.
+    pub timeline_limit: u32,
+    /// Event types that shouldn't show up in the timeline.
+    pub excluded_types: Vec<String>,
+    /// Should the server only send us the members that are relevant for the
+    /// events we receive.
+    pub lazy_load_members: bool,
+    /// The maximal number of timeline events per room in the first sync
+    /// after logging in, 0 uses the regular filter for the first sync.
+    pub initial_timeline_limit: u32,
+}
+
+impl FilterSettings {
+    /// Get the settings for the filter of the first sync after logging in.
+    ///
+    /// Returns `None` if the first sync should use the regular filter.
+    pub fn initial(&self) -> Option<FilterSettings> {
+        if self.initial_timeline_limit == 0 {
+            None
+        } else {
+            Some(FilterSettings {
+                timeline_limit: self.initial_timeline_limit,
+                lazy_load_members: true,
+                ..self.clone()
+            })
+        }
+    }
+}
+
+/// Build the filter for our sync requests out of the given settings.
+pub fn sync_filter(settings: &FilterSettings) -> FilterDefinition<'_> {
+    let mut timeline = RoomEventFilter::default();
+    timeline.not_types = &settings.excluded_types;
+
+    if settings.timeline_limit > 0 {
+        timeline.limit = Some(settings.timeline_limit.into());
+    }
+
+    let mut state = RoomEventFilter::default();
+
+    if settings.lazy_load_members {
+        state.lazy_load_options = LazyLoadOptions::Enabled {
+            include_redundant_members: false,
+        };
+    }
+
+    let mut room = RoomFilter::default();
+    room.timeline = Some(timeline);
+    room.state = Some(state);
+
+    let mut filter = FilterDefinition::default();
+    filter.room = Some(room);
+
+    filter
+}
+
+/// The state of the connection to a server.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ConnectionState {
+    /// We aren't connected to the server.
+    Disconnected,
+    /// We're connecting to the server, or are logging in.
+    Connecting,
+    /// We're logged in and receive sync responses from the server.
+    Syncing,
+    /// Logging in failed or the server stopped answering our sync requests.
+    Error(String),
+    /// The rooms were restored from the local store, we don't talk to the
+    /// server.
+    Offline,
+}
+
+/// The sending side of the channel between the sync loop and the response
+/// receiver.
+///
+/// Sending waits if the channel is full, this applies backpressure to the sync
+/// loop. This can't deadlock as long as the response receiver never waits for
+/// the sync loop to make progress.
+///
+/// If the channel stays near full for a while a warning is logged, this usually
+/// means that processing and rendering events is too slow.
+#[derive(Clone)]
+pub struct ClientSender {
+    sender: Sender<Result<ClientMessage, String>>,
+    congested_since: Arc<Mutex<Option<Instant>>>,
+}
+
+impl ClientSender {
+    pub(crate) fn new(sender: Sender<Result<ClientMessage, String>>) -> Self {
+        Self {
+            sender,
+            congested_since: Arc::new(Mutex::new(None)),
+        }
+    }
+
+    /// Send a message to the response receiver.
+    pub async fn send(&self, message: Result<ClientMessage, String>) {
+        self.check_congestion();
+        self.sender.send(message).await
+    }
+
+    fn check_congestion(&self) {
+        let queued = self.sender.len();
+        let mut congested_since = self
+            .congested_since
+            .lock()
+            .expect("Channel congestion lock got poisoned");
+
+        if queued >= CHANNEL_HIGH_WATERMARK {
+            let since = *congested_since.get_or_insert_with(Instant::now);
+
+            if since.elapsed() >= CHANNEL_CONGESTION_WARNING {
+                warn!(
+                    "The response channel has been near full ({}/{}) for {:?}, \
+                     event processing can't keep up with the sync loop",
+                    queued,
+                    CHANNEL_SIZE,
+                    since.elapsed()
+                );
+                // Don't spam the log, warn again only if the congestion
+                // persists for another period.
+                *congested_since = Some(Instant::now());
+            }
+        } else if let Some(since) = congested_since.take() {
+            debug!(
+                "The response channel drained after being congested for {:?}",
+                since.elapsed()
+            );
+        }
+    }
+}
+
+thread_local! {
+    /// The tokio runtime that is shared between all the connections.
+    static RUNTIME: RefCell<Weak<Runtime>> = RefCell::new(Weak::new());
+}
+
+/// Get the shared tokio runtime.
+///
+/// The runtime is created lazily and is kept alive as long as a connection
+/// holds on to it.
+fn shared_runtime() -> ConnectionResult<Rc<Runtime>> {
+    RUNTIME.with(|runtime| {
+        let mut runtime = runtime.borrow_mut();
+
+        if let Some(r) = runtime.upgrade() {
+            Ok(r)
+        } else {
+            let r = Rc::new(Runtime::new().map_err(ConnectionError::Runtime)?);
+            *runtime = Rc::downgrade(&r);
+            Ok(r)
+        }
+    })
+}
+
+/// Get our user id out of the configured username.
+///
+/// A bare username is assumed to belong to the host of the homeserver.
+fn offline_user_id(username: &str, homeserver: &Url) -> Option<UserId> {
+    if username.starts_with('@') {
+        UserId::try_from(username).ok()
+    } else {
+        let server_name = match homeserver.port() {
+            Some(port) => format!("{}:{}", homeserver.host_str()?, port),
+            None => homeserver.host_str()?.to_owned(),
+        };
+
+        UserId::try_from(format!("@{}:{}", username, server_name)).ok()
+    }
+}
+
+/// Handle for a sync loop running on the shared runtime.
+///
+/// The sync loop gets aborted when the handle is dropped.
+#[derive(Debug)]
+struct SyncLoopHandle {
+    abort_handle: AbortHandle,
+    /// The task running the sync loop, taken once we wait for it to stop.
+    task: RefCell<Option<JoinHandle<Result<(), Aborted>>>>,
+}
+
+impl Drop for SyncLoopHandle {
+    fn drop(&mut self) {
+        self.abort_handle.abort();
+    }
+}
+
+/// The number of URL previews a connection keeps around, the cache is cleared
+/// once it's full.
+const MAX_CACHED_URL_PREVIEWS: usize = 256;
+
+/// The number of URL previews a connection fetches at most per
+/// `URL_PREVIEW_RATE_PERIOD`, links beyond that don't get a preview.
+const MAX_URL_PREVIEW_FETCHES: usize = 10;
+const URL_PREVIEW_RATE_PERIOD: Duration = Duration::from_secs(60);
+
+/// A preview of a web page, generated by the homeserver.
+#[derive(Debug, Clone)]
+pub struct UrlPreview {
+    pub title: Option<String>,
+    pub description: Option<String>,
 }
 
 /// Struc representing an active connection to the homeserver.
@@ -83,20 +802,39 @@ pub enum ClientMessage {
 /// Since the rust-sdk `Client` object uses reqwest for the HTTP client making
 /// requests requires the request to be made on a tokio runtime. The connection
 /// wraps the `Client` object and makes sure that requests are run on the
-/// runtime the `Connection` holds.
+/// runtime the `Connection` holds. The runtime is shared between all
+/// connections.
 ///
 /// While this struct is alive a sync loop will be going on. To cancel the sync
 /// loop drop the object.
 #[derive(Debug, Clone)]
 pub struct Connection {
+    /// The task handing the messages of the sync loop to the server, `None`
+    /// if something else receives them.
+    #[used]
+    receiver_task: Option<Rc<Task<()>>>,
     #[used]
-    receiver_task: Rc<Task<()>>,
+    sync_loop: Rc<SyncLoopHandle>,
     client: Client,
     pub runtime: Rc<Runtime>,
+    media_path: PathBuf,
+    server_path: PathBuf,
+    /// When we last received a sync response.
+    last_sync: Arc<Mutex<Option<SystemTime>>>,
+    /// URL previews we already fetched, `None` if the page has no preview or
+    /// fetching it failed.
+    url_previews: Rc<RefCell<HashMap<String, Option<UrlPreview>>>>,
+    /// Set once the homeserver told us that it doesn't support URL previews.
+    url_previews_unsupported: Rc<Cell<bool>>,
+    /// When we fetched the URL previews of the current rate limit period.
+    url_preview_fetches: Rc<RefCell<VecDeque<Instant>>>,
 }
 
 impl Connection {
-    pub async fn spawn<F>(&self, future: F) -> F::Output
+    /// Run the given future on the tokio runtime of the connection.
+    ///
+    /// Returns an error if the future panicked or got cancelled.
+    pub async fn spawn<F>(&self, future: F) -> ConnectionResult<F::Output>
     where
         F: Future + Send + 'static,
         F::Output: Send + 'static,
@@ -104,11 +842,52 @@ impl Connection {
         self.runtime
             .spawn(future)
             .await
-            .expect("Tokio error while sending a message")
+            .map_err(ConnectionError::Join)
+    }
+
+    /// Stop the sync loop and wait until it's gone.
+    ///
+    /// The aborted sync loop only gets dropped once the runtime polls it
+    /// again, until then it holds on to the client and the crypto store of
+    /// the client. Wait for this before opening the store again.
+    pub async fn shutdown(self) {
+        self.sync_loop.abort_handle.abort();
+
+        let task = self.sync_loop.task.borrow_mut().take();
+
+        if let Some(task) = task {
+            // The task was aborted, we only care that it's gone.
+            let _ = task.await;
+        }
+    }
+
+    /// Find the homeserver for the given server name, see the discovery
+    /// module for the details.
+    pub async fn discover_homeserver(
+        server_name: String,
+        proxy: Option<Url>,
+        verify: bool,
+    ) -> Result<Url, DiscoveryError> {
+        let runtime = shared_runtime()
+            .map_err(|e| DiscoveryError::Invalid(e.to_string()))?;
+
+        runtime
+            .spawn(discovery::discover_homeserver(server_name, proxy, verify))
+            .await
+            .map_err(|e| DiscoveryError::Invalid(e.to_string()))?
     }
 
-    pub fn new(server: &MatrixServer, client: &Client) -> Self {
-        let (tx, rx) = async_channel(1000);
+    /// Create a new connection and start syncing with the server.
+    ///
+    /// If `offline` is set we don't log in nor sync, the rooms are restored
+    /// from the local store instead.
+    pub fn new(
+        server: &MatrixServer,
+        client: &Client,
+        offline: bool,
+    ) -> ConnectionResult<Self> {
+        let runtime = shared_runtime()?;
+        let (tx, rx) = async_channel(CHANNEL_SIZE);
 
         let server_name = server.name();
 
@@ -119,26 +898,115 @@ impl Connection {
 
         let server = server.inner();
 
-        let runtime = Runtime::new().unwrap();
-
         let settings = server.settings();
 
-        runtime.spawn(Connection::sync_loop(
-            client.clone(),
-            tx,
-            settings.username.to_string(),
-            settings.password.to_string(),
-            server_name.to_string(),
+        let last_sync = Arc::new(Mutex::new(None));
+
+        let (abort_handle, task) = if offline {
+            let (load, abort_handle) = abortable(Connection::load_offline(
+                client.clone(),
+                ClientSender::new(tx),
+                settings.username.to_string(),
+                settings.homeserver.clone(),
+                server.get_store_path(),
+            ));
+
+            (abort_handle, runtime.spawn(load))
+        } else {
+            let (sync_loop, abort_handle) = abortable(Connection::sync_loop(
+                client.clone(),
+                ClientSender::new(tx),
+                settings.username.to_string(),
+                settings.password.to_string(),
+                settings.device_name.to_string(),
+                settings.autofetch_backlog,
+                server_name.to_string(),
+                server.get_store_path(),
+                last_sync.clone(),
+                settings.filter_settings(),
+            ));
+
+            (abort_handle, runtime.spawn(sync_loop))
+        };
+
+        Ok(Connection::with_sync_loop(
+            client,
+            runtime,
+            Some(receiver_task),
+            SyncLoopHandle {
+                abort_handle,
+                task: RefCell::new(Some(task)),
+            },
             server.get_server_path(),
-        ));
+            last_sync,
+        ))
+    }
+
+    /// Create a connection for a sync loop that was started elsewhere, the
+    /// messages of the sync loop aren't handed to a server.
+    ///
+    /// This lets the end-to-end tests send requests the same way the rooms
+    /// do, while they receive the messages of the sync loop themselves.
+    #[cfg(test)]
+    pub(crate) fn detached(
+        client: &Client,
+        abort_handle: AbortHandle,
+        task: JoinHandle<Result<(), Aborted>>,
+        server_path: PathBuf,
+    ) -> ConnectionResult<Self> {
+        Ok(Connection::with_sync_loop(
+            client,
+            shared_runtime()?,
+            None,
+            SyncLoopHandle {
+                abort_handle,
+                task: RefCell::new(Some(task)),
+            },
+            server_path,
+            Arc::new(Mutex::new(None)),
+        ))
+    }
+
+    fn with_sync_loop(
+        client: &Client,
+        runtime: Rc<Runtime>,
+        receiver_task: Option<Task<()>>,
+        sync_loop: SyncLoopHandle,
+        server_path: PathBuf,
+        last_sync: Arc<Mutex<Option<SystemTime>>>,
+    ) -> Self {
+        let media_path = server_path.join("media");
 
         Self {
             client: client.clone(),
-            runtime: Rc::new(runtime),
-            receiver_task: Rc::new(receiver_task),
+            runtime,
+            sync_loop: Rc::new(sync_loop),
+            media_path,
+            server_path,
+            receiver_task: receiver_task.map(Rc::new),
+            last_sync,
+            url_previews: Rc::new(RefCell::new(HashMap::new())),
+            url_previews_unsupported: Rc::new(Cell::new(false)),
+            url_preview_fetches: Rc::new(RefCell::new(VecDeque::new())),
         }
     }
 
+    /// Get the directory of the server this connection belongs to.
+    pub fn server_path(&self) -> &Path {
+        &self.server_path
+    }
+
+    /// Get the time we last received a sync response, if we received one at
+    /// all.
+    pub fn last_sync(&self) -> Option<SystemTime> {
+        *self.last_sync.lock().unwrap()
+    }
+
+    /// Wait for the given duration without blocking the Weechat main thread.
+    pub async fn sleep(&self, duration: Duration) {
+        self.spawn(delay_for(duration)).await.ok();
+    }
+
     /// Send a message to the given room.
     ///
     /// # Arguments
@@ -155,150 +1023,1321 @@ impl Connection {
         room_id: &RoomId,
         content: AnyMessageEventContent,
         transaction_id: Option<Uuid>,
-    ) -> MatrixResult<RoomSendResponse> {
+    ) -> ConnectionResult<RoomSendResponse> {
         let room_id = room_id.to_owned();
         let client = self.client.clone();
 
-        self.spawn(async move {
-            client
-                .room_send(
-                    &room_id,
-                    content,
-                    Some(transaction_id.unwrap_or_else(Uuid::new_v4)),
-                )
-                .await
-        })
-        .await
-    }
-
-    pub async fn delete_devices(
-        &self,
-        devices: Vec<DeviceIdBox>,
-        auth_info: Option<InteractiveAuthInfo>,
-    ) -> MatrixResult<DeleteDevicesResponse> {
-        let client = self.client.clone();
-        self.spawn(async move {
-            if let Some(info) = auth_info {
-                let auth = Some(info.as_auth_data());
-                client.delete_devices(&devices, auth).await
-            } else {
-                client.delete_devices(&devices, None).await
-            }
-        })
-        .await
-    }
-
-    /// Get the list of our own devices.
-    pub async fn devices(&self) -> MatrixResult<DevicesResponse> {
-        let client = self.client.clone();
-        self.spawn(async move { client.devices().await }).await
+        Ok(self
+            .spawn(async move {
+                client
+                    .room_send(
+                        &room_id,
+                        content,
+                        Some(transaction_id.unwrap_or_else(Uuid::new_v4)),
+                    )
+                    .await
+            })
+            .await??)
     }
 
-    /// Set or reset a typing notice.
+    /// Replace the content of a message we sent earlier.
     ///
     /// # Arguments
     ///
-    /// * `room_id` - The id of the room where the typing notice should be
-    /// active.
+    /// * `room_id` - The id of the room the message was sent to.
     ///
-    /// * `typing` - Should we set or unset the typing notice.
-    pub async fn send_typing_notice(
+    /// * `event_id` - The event id of the message that should be edited.
+    ///
+    /// * `body` - The new plain text body of the message.
+    ///
+    /// * `formatted` - The new HTML body of the message, if any.
+    ///
+    /// * `transaction_id` - Attach an unique id to the edit, see
+    /// `send_message()`.
+    pub async fn edit_message(
         &self,
         room_id: &RoomId,
-        typing: bool,
-    ) -> MatrixResult<TypingResponse> {
-        let room_id = room_id.to_owned();
-        let client = self.client.clone();
+        event_id: &EventId,
+        body: String,
+        formatted: Option<String>,
+        transaction_id: Uuid,
+    ) -> ConnectionResult<RoomSendResponse> {
+        let mut new_content = json!({
+            "msgtype": "m.text",
+            "body": body,
+        });
+
+        if let Some(formatted) = &formatted {
+            new_content["format"] = json!("org.matrix.custom.html");
+            new_content["formatted_body"] = json!(formatted);
+        }
+
+        // Clients that don't support edits show the fallback body, which is
+        // the new body prefixed with an asterisk.
+        let mut json = new_content.clone();
+        json["body"] = json!(format!("* {}", body));
+
+        if let Some(formatted) = &formatted {
+            json["formatted_body"] = json!(format!("* {}", formatted));
+        }
+
+        json["m.new_content"] = new_content;
+        json["m.relates_to"] = json!({
+            "rel_type": "m.replace",
+            "event_id": event_id,
+        });
+
+        let content = AnyMessageEventContent::Custom(CustomEventContent {
+            event_type: "m.room.message".to_owned(),
+            json,
+        });
+
+        self.send_message(room_id, content, Some(transaction_id))
+            .await
+    }
+
+    /// Redact an event in the given room.
+    ///
+    /// # Arguments
+    ///
+    /// * `room_id` - The id of the room the event was sent to.
+    ///
+    /// * `event_id` - The event id of the event that should be redacted.
+    ///
+    /// * `reason` - The reason for the redaction, shown to other room members.
+    pub async fn redact_event(
+        &self,
+        room_id: &RoomId,
+        event_id: &EventId,
+        reason: Option<String>,
+    ) -> ConnectionResult<RedactEventResponse> {
+        let room_id = room_id.to_owned();
+        let event_id = event_id.to_owned();
+        let client = self.client.clone();
+
+        Ok(self
+            .spawn(async move {
+                let txn_id = Uuid::new_v4().to_string();
+                let mut request =
+                    RedactEventRequest::new(&room_id, &event_id, &txn_id);
+                request.reason = reason.as_deref();
+
+                client.send(request).await
+            })
+            .await??)
+    }
+
+    /// Send a state event to the given room.
+    ///
+    /// # Arguments
+    ///
+    /// * `room_id` - The id of the room which the state event should be sent
+    /// to.
+    ///
+    /// * `content` - The content of the state event.
+    ///
+    /// * `state_key` - The state key of the event, most events use an empty
+    /// state key.
+    pub async fn send_state_event(
+        &self,
+        room_id: &RoomId,
+        content: AnyStateEventContent,
+        state_key: &str,
+    ) -> ConnectionResult<StateEventResponse> {
+        let room_id = room_id.to_owned();
+        let state_key = state_key.to_owned();
+        let client = self.client.clone();
+
+        Ok(self
+            .spawn(async move {
+                let request =
+                    StateEventRequest::new(&room_id, &state_key, &content);
+                client.send(request).await
+            })
+            .await??)
+    }
+
+    /// Fetch the ids of the pinned messages of the given room from the
+    /// server.
+    pub async fn get_pinned_events(
+        &self,
+        room_id: &RoomId,
+    ) -> ConnectionResult<Vec<EventId>> {
+        let room_id = room_id.to_owned();
+        let client = self.client.clone();
+
+        Ok(self
+            .spawn(async move {
+                let request = GetStateEventRequest::new(
+                    &room_id,
+                    EventType::RoomPinnedEvents,
+                    "",
+                );
+
+                match client.send(request).await {
+                    Ok(r) => {
+                        let content: PinnedEventsEventContent =
+                            serde_json::from_str(r.content.get())?;
+                        Ok(content.pinned)
+                    }
+                    // Nothing was ever pinned in the room.
+                    Err(e)
+                        if matrix_error_kind(&e)
+                            == Some(&ErrorKind::NotFound) =>
+                    {
+                        Ok(Vec::new())
+                    }
+                    Err(e) => Err(e),
+                }
+            })
+            .await??)
+    }
+
+    /// Send an event with an arbitrary type and content to the given room.
+    ///
+    /// This is meant for testing custom event types, the content isn't
+    /// validated in any way. Message events are encrypted if the room is
+    /// encrypted.
+    ///
+    /// # Arguments
+    ///
+    /// * `room_id` - The id of the room the event should be sent to.
+    ///
+    /// * `event_type` - The type of the event, e.g. `org.example.custom`.
+    ///
+    /// * `content` - The JSON content of the event.
+    ///
+    /// * `state_key` - The state key of the event, if set the event is sent as
+    /// a state event, otherwise as a message event.
+    pub async fn send_custom_event(
+        &self,
+        room_id: &RoomId,
+        event_type: String,
+        content: serde_json::Value,
+        state_key: Option<String>,
+    ) -> ConnectionResult<EventId> {
+        let room_id = room_id.to_owned();
+        let client = self.client.clone();
+
+        let content = CustomEventContent {
+            event_type,
+            json: content,
+        };
+
+        Ok(self
+            .spawn(async move {
+                if let Some(state_key) = state_key {
+                    let content = AnyStateEventContent::Custom(content);
+                    let request =
+                        StateEventRequest::new(&room_id, &state_key, &content);
+                    Ok(client.send(request).await?.event_id)
+                } else {
+                    let content = AnyMessageEventContent::Custom(content);
+                    client
+                        .room_send(&room_id, content, Some(Uuid::new_v4()))
+                        .await
+                        .map(|r| r.event_id)
+                }
+            })
+            .await??)
+    }
+
+    /// Change how much a room notifies us about new messages.
+    ///
+    /// Muting a room adds an override push rule for it, only getting notified
+    /// about mentions adds a room push rule. Both rules are named after the
+    /// room id, the rule that doesn't belong to the new level is removed.
+    pub async fn set_room_notification_mode(
+        &self,
+        room_id: &RoomId,
+        level: NotificationLevel,
+    ) -> ConnectionResult<()> {
+        let room_id = room_id.to_owned();
+        let client = self.client.clone();
+
+        Ok(self
+            .spawn(async move {
+                let rule_id = room_id.as_str();
+
+                let (add, remove) = match level {
+                    NotificationLevel::All => {
+                        (None, vec![RuleKind::Override, RuleKind::Room])
+                    }
+                    NotificationLevel::Mentions => {
+                        (Some(RuleKind::Room), vec![RuleKind::Override])
+                    }
+                    NotificationLevel::None => {
+                        (Some(RuleKind::Override), vec![RuleKind::Room])
+                    }
+                };
+
+                for kind in remove {
+                    let request =
+                        DeletePushRuleRequest::new("global", kind, rule_id);
+
+                    match client.send(request).await {
+                        Err(e)
+                            if matrix_error_kind(&e)
+                                != Some(&ErrorKind::NotFound) =>
+                        {
+                            return Err(e)
+                        }
+                        _ => (),
+                    }
+                }
+
+                if let Some(kind) = add {
+                    let conditions = if matches!(kind, RuleKind::Override) {
+                        vec![PushCondition::EventMatch {
+                            key: "room_id".to_owned(),
+                            pattern: rule_id.to_owned(),
+                        }]
+                    } else {
+                        vec![]
+                    };
+
+                    let mut request = SetPushRuleRequest::new(
+                        "global",
+                        kind,
+                        rule_id,
+                        vec![Action::DontNotify],
+                    );
+                    request.conditions = conditions;
+
+                    client.send(request).await?;
+                }
+
+                Ok::<_, MatrixError>(())
+            })
+            .await??)
+    }
+
+    /// Search the messages of a room on the server.
+    ///
+    /// # Arguments
+    ///
+    /// * `room_id` - The id of the room that should be searched.
+    ///
+    /// * `terms` - The terms that should be searched for.
+    ///
+    /// * `next_batch` - The token of the next page of results, returned by a
+    /// previous search with the same terms.
+    pub async fn search_messages(
+        &self,
+        room_id: &RoomId,
+        terms: &str,
+        next_batch: Option<String>,
+    ) -> ConnectionResult<SearchResponse> {
+        let room_id = room_id.to_owned();
+        let terms = terms.to_owned();
+        let client = self.client.clone();
+
+        Ok(self
+            .spawn(async move {
+                let rooms = [room_id];
+
+                let mut filter = RoomEventFilter::default();
+                filter.rooms = Some(&rooms);
+
+                let mut criteria = SearchCriteria::new(&terms);
+                criteria.filter = Some(filter);
+                criteria.event_context = Some(SearchEventContext {
+                    before_limit: SEARCH_CONTEXT_LINES.into(),
+                    after_limit: SEARCH_CONTEXT_LINES.into(),
+                    include_profile: false,
+                });
+
+                let mut categories = SearchCategories::new();
+                categories.room_events = Some(criteria);
+
+                let mut request = SearchRequest::new(categories);
+                request.next_batch = next_batch.as_deref();
+
+                client.send(request).await
+            })
+            .await??)
+    }
+
+    /// Decrypt an event that couldn't be decrypted when we received it, e.g.
+    /// because the room key for it only arrived later.
+    pub async fn decrypt_event(
+        &self,
+        room_id: &RoomId,
+        event: SyncMessageEvent<EncryptedEventContent>,
+    ) -> ConnectionResult<AnySyncRoomEvent> {
+        let room_id = room_id.to_owned();
+        let client = self.client.clone();
+
+        Ok(self
+            .spawn(async move {
+                let event = client.decrypt_room_event(&event, &room_id).await?;
+                Ok::<_, MatrixError>(event.deserialize()?)
+            })
+            .await??)
+    }
+
+    /// Ask our other devices to share the room key of an undecryptable event
+    /// with us.
+    ///
+    /// The SDK queues a room key request when it's missing the session of an
+    /// event it should decrypt. The request goes out with the next sync and
+    /// the SDK accepts the forwarded room key once one of our devices sends it
+    /// back. A session is only requested once.
+    ///
+    /// Returns the decrypted event if we already have the room key.
+    pub async fn request_room_key(
+        &self,
+        room_id: &RoomId,
+        event: SyncMessageEvent<EncryptedEventContent>,
+    ) -> ConnectionResult<Option<AnySyncRoomEvent>> {
+        let room_id = room_id.to_owned();
+        let client = self.client.clone();
+
+        Ok(self
+            .spawn(async move {
+                match client.decrypt_room_event(&event, &room_id).await {
+                    Ok(e) => Ok::<_, MatrixError>(Some(e.deserialize()?)),
+                    Err(e) => {
+                        debug!(
+                            "Requesting the room key for event {}: {}",
+                            event.event_id, e
+                        );
+                        Ok(None)
+                    }
+                }
+            })
+            .await??)
+    }
+
+    /// Fetch a single event of a room from the server.
+    ///
+    /// Encrypted events are decrypted if we have the room key for them.
+    pub async fn get_event(
+        &self,
+        room_id: &RoomId,
+        event_id: &EventId,
+    ) -> ConnectionResult<AnySyncRoomEvent> {
+        let room_id = room_id.to_owned();
+        let event_id = event_id.to_owned();
+        let client = self.client.clone();
+
+        Ok(self
+            .spawn(async move {
+                let request = RoomEventRequest::new(&room_id, &event_id);
+                let response = client.send(request).await?;
+                let event: AnySyncRoomEvent =
+                    response.event.deserialize()?.into();
+
+                if let AnySyncRoomEvent::Message(
+                    AnySyncMessageEvent::RoomEncrypted(e),
+                ) = &event
+                {
+                    let event = client.decrypt_room_event(e, &room_id).await?;
+                    Ok::<_, MatrixError>(event.deserialize()?)
+                } else {
+                    Ok(event)
+                }
+            })
+            .await??)
+    }
+
+    /// Enable encryption in the given room.
+    ///
+    /// Encryption can't be disabled once it's enabled.
+    pub async fn enable_encryption(
+        &self,
+        room_id: &RoomId,
+    ) -> ConnectionResult<StateEventResponse> {
+        let content =
+            AnyStateEventContent::RoomEncryption(EncryptionEventContent::new(
+                EventEncryptionAlgorithm::MegolmV1AesSha2,
+            ));
+
+        self.send_state_event(room_id, content, "").await
+    }
+
+    pub async fn delete_devices(
+        &self,
+        devices: Vec<DeviceIdBox>,
+        auth_info: Option<InteractiveAuthInfo>,
+    ) -> ConnectionResult<DeleteDevicesResponse> {
+        let client = self.client.clone();
+        Ok(self
+            .spawn(async move {
+                if let Some(info) = auth_info {
+                    let auth = Some(info.as_auth_data());
+                    client.delete_devices(&devices, auth).await
+                } else {
+                    client.delete_devices(&devices, None).await
+                }
+            })
+            .await??)
+    }
+
+    /// Join the room with the given room id.
+    ///
+    /// This is used to accept invites.
+    pub async fn join_room(
+        &self,
+        room_id: &RoomId,
+    ) -> ConnectionResult<JoinRoomResponse> {
+        let room_id = room_id.to_owned();
+        let client = self.client.clone();
+
+        Ok(self
+            .spawn(async move { client.join_room_by_id(&room_id).await })
+            .await??)
+    }
+
+    /// Join the room with the given room id or room alias.
+    pub async fn join_room_by_id_or_alias(
+        &self,
+        room: &RoomIdOrAliasId,
+    ) -> ConnectionResult<JoinRoomByAliasResponse> {
+        let room = room.to_owned();
+        let client = self.client.clone();
+
+        Ok(
+            self.spawn(async move {
+                client.join_room_by_id_or_alias(&room, &[]).await
+            })
+            .await??,
+        )
+    }
+
+    /// Leave the room with the given room id.
+    ///
+    /// This is used to reject invites as well.
+    pub async fn leave_room(
+        &self,
+        room_id: &RoomId,
+    ) -> ConnectionResult<LeaveRoomResponse> {
+        let room_id = room_id.to_owned();
+        let client = self.client.clone();
+
+        Ok(self
+            .spawn(async move { client.leave_room(&room_id).await })
+            .await??)
+    }
+
+    /// Get the list of our own devices.
+    pub async fn devices(&self) -> ConnectionResult<DevicesResponse> {
+        let client = self.client.clone();
+        Ok(self.spawn(async move { client.devices().await }).await??)
+    }
+
+    /// Set our own presence, optionally with a status message.
+    pub async fn set_presence(
+        &self,
+        presence: PresenceState,
+        status: Option<String>,
+    ) -> ConnectionResult<PresenceResponse> {
+        let client = self.client.clone();
+
+        Ok(self
+            .spawn(async move {
+                let user_id = client
+                    .user_id()
+                    .await
+                    .expect("Setting the presence while not logged in");
+
+                let mut request = PresenceRequest::new(&user_id, presence);
+                request.status_msg = status.as_deref();
+
+                client.send(request).await
+            })
+            .await??)
+    }
+
+    /// Set the human readable name of one of our own devices.
+    pub async fn set_device_display_name(
+        &self,
+        device_id: DeviceIdBox,
+        name: String,
+    ) -> ConnectionResult<UpdateDeviceResponse> {
+        let client = self.client.clone();
+
+        Ok(self
+            .spawn(async move {
+                let mut request = UpdateDeviceRequest::new(&device_id);
+                request.display_name = Some(name);
+
+                client.send(request).await
+            })
+            .await??)
+    }
+
+    /// Get the devices of the given user that are known to our crypto store.
+    ///
+    /// This doesn't contact the server, users that don't share an encrypted
+    /// room with us will have no known devices.
+    pub async fn user_devices(
+        &self,
+        user_id: &UserId,
+    ) -> ConnectionResult<Vec<UserDevice>> {
+        let user_id = user_id.to_owned();
+        let client = self.client.clone();
+
+        Ok(self
+            .spawn(async move {
+                let devices = client.get_user_devices(&user_id).await?;
+
+                Ok::<_, MatrixError>(
+                    devices
+                        .devices()
+                        .map(|d| UserDevice {
+                            device_id: d.device_id().to_owned(),
+                            display_name: d.display_name().clone(),
+                            verified: d.is_trusted(),
+                        })
+                        .collect(),
+                )
+            })
+            .await??)
+    }
+
+    /// Create a direct room with the given user.
+    ///
+    /// The user is invited to the room, the room is created with the trusted
+    /// private chat preset so both users have the same power level.
+    pub async fn create_dm(
+        &self,
+        user_id: &UserId,
+    ) -> ConnectionResult<CreateRoomResponse> {
+        let invite = [user_id.to_owned()];
+        let client = self.client.clone();
+
+        Ok(self
+            .spawn(async move {
+                let mut request = CreateRoomRequest::new();
+                request.invite = &invite;
+                request.is_direct = true;
+                request.preset = Some(RoomPreset::TrustedPrivateChat);
+
+                client.create_room(request).await
+            })
+            .await??)
+    }
+
+    /// Fetch the supported spec versions and the capabilities of the
+    /// homeserver.
+    pub async fn server_info(&self) -> ConnectionResult<ServerInfo> {
+        let client = self.client.clone();
+
+        Ok(self
+            .spawn(async move {
+                let versions = client.send(VersionsRequest::new()).await?;
+                let capabilities =
+                    client.send(CapabilitiesRequest::new()).await?;
+
+                Ok::<_, MatrixError>(ServerInfo {
+                    versions: versions.versions,
+                    unstable_features: versions.unstable_features,
+                    capabilities: capabilities.capabilities,
+                })
+            })
+            .await??)
+    }
+
+    /// Create a new room.
+    ///
+    /// Encryption is part of the initial state of the room, so no message can
+    /// ever be sent to the room unencrypted.
+    pub async fn create_room(
+        &self,
+        options: RoomOptions,
+    ) -> ConnectionResult<CreateRoomResponse> {
+        let client = self.client.clone();
+
+        Ok(self
+            .spawn(async move {
+                let initial_state = if options.encrypted {
+                    vec![AnyInitialStateEvent::RoomEncryption(
+                        InitialStateEvent {
+                            content: EncryptionEventContent::new(
+                                EventEncryptionAlgorithm::MegolmV1AesSha2,
+                            ),
+                            state_key: String::new(),
+                        },
+                    )]
+                } else {
+                    Vec::new()
+                };
+
+                let mut request = CreateRoomRequest::new();
+                request.initial_state = &initial_state;
+                request.invite = &options.invite;
+                request.preset = options.preset;
+                request.room_alias_name = options.alias.as_deref();
+                request.name = options.name.as_deref();
+                request.topic = options.topic.as_deref();
+                request.visibility = if options.public {
+                    RoomVisibility::Public
+                } else {
+                    RoomVisibility::Private
+                };
+
+                client.create_room(request).await
+            })
+            .await??)
+    }
+
+    /// Replace the `m.direct` account data of our user.
+    ///
+    /// # Arguments
+    ///
+    /// * `direct_rooms` - A map from user ids to the list of direct rooms we
+    /// have with the user.
+    pub async fn set_direct_rooms(
+        &self,
+        direct_rooms: BTreeMap<UserId, Vec<RoomId>>,
+    ) -> ConnectionResult<()> {
+        let client = self.client.clone();
+
+        Ok(self
+            .spawn(async move {
+                let user_id = client
+                    .user_id()
+                    .await
+                    .expect("Setting account data while not logged in");
+                let data = serde_json::value::to_raw_value(&direct_rooms)?;
+
+                client
+                    .send(SetAccountDataRequest::new(
+                        data, "m.direct", &user_id,
+                    ))
+                    .await?;
+
+                Ok::<_, MatrixError>(())
+            })
+            .await??)
+    }
+
+    /// Replace the list of users we ignore, stored in the
+    /// `m.ignored_user_list` account data of our user.
+    pub async fn set_ignored_users(
+        &self,
+        ignored_users: Vec<UserId>,
+    ) -> ConnectionResult<()> {
+        let client = self.client.clone();
+
+        Ok(self
+            .spawn(async move {
+                let user_id = client
+                    .user_id()
+                    .await
+                    .expect("Setting account data while not logged in");
+
+                let ignored_users: BTreeMap<UserId, serde_json::Value> =
+                    ignored_users.into_iter().map(|u| (u, json!({}))).collect();
+                let data = serde_json::value::to_raw_value(&json!({
+                    "ignored_users": ignored_users
+                }))?;
+
+                client
+                    .send(SetAccountDataRequest::new(
+                        data,
+                        "m.ignored_user_list",
+                        &user_id,
+                    ))
+                    .await?;
+
+                Ok::<_, MatrixError>(())
+            })
+            .await??)
+    }
+
+    /// Fetch the list of users we ignore from the account data of our user.
+    ///
+    /// Returns `None` if we never ignored anyone.
+    async fn fetch_ignored_users(
+        client: &Client,
+        user_id: &UserId,
+    ) -> MatrixResult<Option<Vec<UserId>>> {
+        let request =
+            GetAccountDataRequest::new(user_id, "m.ignored_user_list");
+
+        let response = match client.send(request).await {
+            Ok(r) => r,
+            Err(e) if matrix_error_kind(&e) == Some(&ErrorKind::NotFound) => {
+                return Ok(None)
+            }
+            Err(e) => return Err(e),
+        };
+
+        let data: serde_json::Value =
+            serde_json::from_str(response.account_data.json().get())?;
+
+        // Some servers wrap the content in an event, others don't.
+        let ignored_users = data
+            .get("content")
+            .unwrap_or(&data)
+            .get("ignored_users")
+            .and_then(|u| u.as_object())
+            .map(|u| {
+                u.keys()
+                    .filter_map(|u| UserId::try_from(u.as_str()).ok())
+                    .collect()
+            })
+            .unwrap_or_default();
+
+        Ok(Some(ignored_users))
+    }
+
+    /// Split a `mxc://` URI into its server name and media id.
+    fn parse_mxc_uri(
+        mxc_uri: &str,
+    ) -> ConnectionResult<(ServerNameBox, String)> {
+        let invalid = || ConnectionError::InvalidMxcUri(mxc_uri.to_owned());
+
+        let (server_name, media_id) = mxc_uri
+            .strip_prefix("mxc://")
+            .and_then(|s| {
+                let mut parts = s.splitn(2, '/');
+                Some((parts.next()?, parts.next()?))
+            })
+            .ok_or_else(invalid)?;
+
+        // The media id ends up as part of a file name, make sure it can't
+        // escape the media directory.
+        if media_id.is_empty()
+            || !media_id
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+        {
+            return Err(invalid());
+        }
+
+        let server_name =
+            ServerNameBox::try_from(server_name).map_err(|_| invalid())?;
+
+        Ok((server_name, media_id.to_owned()))
+    }
+
+    /// Download a file from the media repository of the homeserver.
+    ///
+    /// # Arguments
+    ///
+    /// * `mxc_uri` - The `mxc://` URI of the file.
+    ///
+    /// * `size` - The width and height of the thumbnail that should be
+    /// downloaded, if none is given the full file is downloaded.
+    pub async fn download_media(
+        &self,
+        mxc_uri: &str,
+        size: Option<(u32, u32)>,
+    ) -> ConnectionResult<Vec<u8>> {
+        let (server_name, media_id) = Connection::parse_mxc_uri(mxc_uri)?;
+        let client = self.client.clone();
+
+        Ok(self
+            .spawn(async move {
+                let file = if let Some((width, height)) = size {
+                    let mut request = ThumbnailRequest::new(
+                        &media_id,
+                        &server_name,
+                        width.into(),
+                        height.into(),
+                    );
+                    request.method = Some(ThumbnailMethod::Scale);
+
+                    client.send(request).await?.file
+                } else {
+                    let request = MediaRequest::new(&media_id, &server_name);
+                    client.send(request).await?.file
+                };
+
+                Ok::<_, MatrixError>(file)
+            })
+            .await??)
+    }
+
+    /// Get the preview of a web page from the homeserver.
+    ///
+    /// Every URL is only fetched once, the previews are cached. Returns `None`
+    /// if the page has no preview, if the homeserver doesn't support previews
+    /// or if we fetched too many previews recently.
+    pub async fn get_url_preview(
+        &self,
+        url: &str,
+    ) -> ConnectionResult<Option<UrlPreview>> {
+        if self.url_previews_unsupported.get() {
+            return Ok(None);
+        }
+
+        if let Some(preview) = self.url_previews.borrow().get(url) {
+            return Ok(preview.clone());
+        }
+
+        {
+            let mut fetches = self.url_preview_fetches.borrow_mut();
+
+            while fetches
+                .front()
+                .map_or(false, |t| t.elapsed() >= URL_PREVIEW_RATE_PERIOD)
+            {
+                fetches.pop_front();
+            }
+
+            // The URL isn't cached, the link gets a preview if it's posted
+            // again later on.
+            if fetches.len() >= MAX_URL_PREVIEW_FETCHES {
+                debug!("Too many URL previews fetched, skipping {}", url);
+                return Ok(None);
+            }
+
+            fetches.push_back(Instant::now());
+        }
+
+        {
+            let mut previews = self.url_previews.borrow_mut();
+
+            if previews.len() >= MAX_CACHED_URL_PREVIEWS {
+                previews.clear();
+            }
+
+            // Remember the URL right away, so a link that is posted again
+            // while we're fetching it isn't fetched twice.
+            previews.insert(url.to_owned(), None);
+        }
+
+        let client = self.client.clone();
+        let request_url = url.to_owned();
+
+        let result = self
+            .spawn(async move {
+                let request =
+                    MediaPreviewRequest::new(&request_url, SystemTime::now());
+                let data = match client.send(request).await?.data {
+                    Some(d) => d,
+                    None => return Ok(None),
+                };
+
+                let data: serde_json::Value = serde_json::from_str(data.get())?;
+
+                let field = |name: &str| {
+                    data.get(name)
+                        .and_then(|v| v.as_str())
+                        .map(|v| v.trim().to_owned())
+                        .filter(|v| !v.is_empty())
+                };
+
+                let preview = UrlPreview {
+                    title: field("og:title"),
+                    description: field("og:description"),
+                };
+
+                if preview.title.is_none() && preview.description.is_none() {
+                    Ok::<_, MatrixError>(None)
+                } else {
+                    Ok(Some(preview))
+                }
+            })
+            .await?
+            .map_err(ConnectionError::from);
+
+        match result {
+            Ok(preview) => {
+                self.url_previews
+                    .borrow_mut()
+                    .insert(url.to_owned(), preview.clone());
+                Ok(preview)
+            }
+            Err(e) => {
+                if e.is_unrecognized() {
+                    self.url_previews_unsupported.set(true);
+                }
+
+                Err(e)
+            }
+        }
+    }
+
+    /// Upload the given data to the media repository of the homeserver.
+    ///
+    /// Returns the `mxc://` URI of the uploaded file.
+    pub async fn upload_media(
+        &self,
+        data: Vec<u8>,
+        content_type: Mime,
+    ) -> ConnectionResult<String> {
+        let client = self.client.clone();
+
+        Ok(self
+            .spawn(async move {
+                let mut reader = Cursor::new(data);
+
+                client
+                    .upload(&content_type, &mut reader)
+                    .await
+                    .map(|r| r.content_uri)
+            })
+            .await??)
+    }
+
+    /// Get the path of the cached avatar with the given `mxc://` URI.
+    ///
+    /// The avatar is downloaded and stored in the media directory of the
+    /// server if it isn't cached yet.
+    pub async fn avatar(&self, mxc_uri: &str) -> ConnectionResult<PathBuf> {
+        let (server_name, media_id) = Connection::parse_mxc_uri(mxc_uri)?;
+
+        let mut path = self.media_path.clone();
+        path.push("avatars");
+        path.push(format!("{}_{}", server_name, media_id));
+
+        if path.exists() {
+            return Ok(path);
+        }
+
+        let data = self.download_media(mxc_uri, Some(AVATAR_SIZE)).await?;
+        let file_path = path.clone();
 
         self.spawn(async move {
-            let typing = if typing {
-                Typing::Yes(TYPING_NOTICE_TIMEOUT)
-            } else {
-                Typing::No
-            };
+            if let Some(dir) = file_path.parent() {
+                std::fs::create_dir_all(dir)?;
+            }
 
-            client.typing_notice(&room_id, typing).await
+            std::fs::write(&file_path, data)
         })
-        .await
+        .await?
+        .map_err(ConnectionError::Io)?;
+
+        Ok(path)
+    }
+
+    /// Fetch the global profile of the given user from the server.
+    pub async fn get_profile(
+        &self,
+        user_id: &UserId,
+    ) -> ConnectionResult<ProfileResponse> {
+        let user_id = user_id.to_owned();
+        let client = self.client.clone();
+
+        Ok(self
+            .spawn(
+                async move { client.send(ProfileRequest::new(&user_id)).await },
+            )
+            .await??)
+    }
+
+    /// Set or reset a typing notice.
+    ///
+    /// # Arguments
+    ///
+    /// * `room_id` - The id of the room where the typing notice should be
+    /// active.
+    ///
+    /// * `typing` - Should we set or unset the typing notice.
+    pub async fn send_typing_notice(
+        &self,
+        room_id: &RoomId,
+        typing: bool,
+    ) -> ConnectionResult<TypingResponse> {
+        let room_id = room_id.to_owned();
+        let client = self.client.clone();
+
+        Ok(self
+            .spawn(async move {
+                let typing = if typing {
+                    Typing::Yes(TYPING_NOTICE_TIMEOUT)
+                } else {
+                    Typing::No
+                };
+
+                client.typing_notice(&room_id, typing).await
+            })
+            .await??)
     }
 
     fn save_device_id(
         user_name: &str,
-        mut server_path: PathBuf,
+        homeserver: &Url,
+        server_path: PathBuf,
         response: &LoginResponse,
     ) -> std::io::Result<()> {
-        server_path.push(user_name);
-        server_path.set_extension("device_id");
-        std::fs::write(&server_path, &response.device_id.to_string())
+        let session = StoredSession {
+            user_id: response.user_id.clone(),
+            device_id: response.device_id.to_string(),
+            homeserver: homeserver.clone(),
+        };
+
+        session.save(&server_path, user_name)
+    }
+
+    /// Log in again after the server invalidated our access token.
+    ///
+    /// The stored device id is reused so our end-to-end encryption keys stay
+    /// valid.
+    async fn login_again(
+        client: &Client,
+        username: &str,
+        password: &str,
+        device_name: &str,
+        server_path: PathBuf,
+    ) -> Result<LoginResponse, String> {
+        let device_id = Connection::load_device_id(
+            username,
+            client.homeserver(),
+            server_path,
+        )
+        .map_err(|e| format!("Error reading the device id: {}", e))?;
+
+        client
+            .login(username, password, device_id.as_deref(), Some(device_name))
+            .await
+            .map_err(|e| login_error(&e))
     }
 
+    /// Load the device id we got the last time the user logged in on the
+    /// homeserver.
+    ///
+    /// Device ids stored by older versions are used if there is no stored
+    /// session, they get migrated when the login response is saved.
     fn load_device_id(
         user_name: &str,
-        mut server_path: PathBuf,
+        homeserver: &Url,
+        server_path: PathBuf,
     ) -> std::io::Result<Option<String>> {
-        server_path.push(user_name);
-        server_path.set_extension("device_id");
+        match StoredSession::find(&server_path, user_name, Some(homeserver))? {
+            Some(session) => Ok(Some(session.device_id)),
+            None => session::load_legacy_device_id(&server_path, user_name),
+        }
+    }
+
+    /// Response receiver loop.
+    /// This runs on the main Weechat thread and listens for responses coming
+    /// from the client running in the tokio executor.
+    pub async fn response_receiver(
+        receiver: Receiver<Result<ClientMessage, String>>,
+        server: Weak<RefCell<InnerServer>>,
+    ) {
+        loop {
+            let ret = receiver.recv().await;
+
+            let server_cell = if let Some(s) = server.upgrade() {
+                s
+            } else {
+                debug!(
+                    "The server got deleted, stopping the response receiver"
+                );
+                return;
+            };
+            let mut server = server_cell.borrow_mut();
+
+            let message = match ret {
+                Ok(m) => m,
+                // All the senders are gone. Disconnecting drops this task
+                // together with the sync loop, so if we get here the sync
+                // loop stopped on its own.
+                Err(_) => {
+                    // A loop that was syncing just fine didn't mean to stop,
+                    // a new connection brings a new sync loop together with
+                    // a new channel and receiver.
+                    if server.connection_state() == &ConnectionState::Syncing {
+                        debug!("The sync loop stopped unexpectedly");
+                        drop(server);
+                        Connection::spawn_reconnect(server_cell);
+                        return;
+                    }
+
+                    debug!(
+                        "The sync loop stopped, stopping the response receiver"
+                    );
+                    let connection = server.sync_loop_stopped();
+
+                    drop(server);
+                    drop(connection);
+                    return;
+                }
+            };
+
+            if let Ok(ClientMessage::Reconnect(reason)) = &message {
+                server.print_error(&format!("{}, reconnecting", reason));
+                drop(server);
+                Connection::spawn_reconnect(server_cell);
+                return;
+            }
+
+            dispatch(&mut *server, message).await;
+        }
+    }
+
+    /// Replace the connection of the given server with a new one.
+    ///
+    /// Reconnecting drops the response receiver together with the connection
+    /// it belongs to, so a separate task needs to do it.
+    fn spawn_reconnect(server: Rc<RefCell<InnerServer>>) {
+        let server = MatrixServer::from_inner(server);
+
+        Weechat::spawn(async move {
+            if let Err(e) = server.reconnect() {
+                server.print_error(&format!("Error reconnecting: {:?}", e));
+            }
+        })
+        .detach();
+    }
+
+    /// Restore our session and the joined rooms from the local store.
+    ///
+    /// This doesn't contact the server, the session needs to have been used
+    /// online before so the store and the device id exist.
+    pub async fn load_offline(
+        client: Client,
+        channel: ClientSender,
+        username: String,
+        homeserver: Option<Url>,
+        server_path: PathBuf,
+    ) {
+        let channel = &channel;
+        let fail = |message: String| async move {
+            channel
+                .send(Ok(ClientMessage::StateChange(ConnectionState::Error(
+                    message,
+                ))))
+                .await
+        };
+
+        let stored =
+            StoredSession::find(&server_path, &username, homeserver.as_ref())
+                .and_then(|s| match s {
+                    Some(s) => Ok(Some((Some(s.user_id), s.device_id))),
+                    None => {
+                        session::load_legacy_device_id(&server_path, &username)
+                            .map(|d| d.map(|d| (None, d)))
+                    }
+                });
+
+        let (user_id, device_id) = match stored {
+            Ok(Some(s)) => s,
+            Ok(None) => {
+                fail(
+                    "There is no stored session, connect once before using \
+                     the offline mode"
+                        .to_owned(),
+                )
+                .await;
+                return;
+            }
+            Err(e) => {
+                fail(format!("Error reading the device id: {}", e)).await;
+                return;
+            }
+        };
+
+        // Devices stored by older versions don't know our user id, guess it.
+        let user_id = if let Some(u) = user_id
+            .or_else(|| homeserver.and_then(|h| offline_user_id(&username, &h)))
+        {
+            u
+        } else {
+            fail(format!("Can't build a user id out of {}", username)).await;
+            return;
+        };
+
+        // The access token isn't stored, we don't need one as long as we
+        // don't send any requests.
+        let session = Session {
+            access_token: String::new(),
+            user_id: user_id.clone(),
+            device_id: device_id.into(),
+        };
+        let device_id = session.device_id.clone();
+
+        if let Err(e) = client.restore_login(session).await {
+            if is_store_locked(&e) {
+                fail(STORE_LOCKED_ERROR.to_owned()).await;
+            } else {
+                fail(format!("Error restoring the session: {}", e)).await;
+            }
+            return;
+        }
+
+        channel
+            .send(Ok(ClientMessage::OfflineLogin(user_id, device_id)))
+            .await;
+
+        for room in client.joined_rooms().read().await.values() {
+            channel
+                .send(Ok(ClientMessage::RestoredRoom(room.clone())))
+                .await
+        }
+    }
+
+    /// Fetch up to `limit` events of the room that were sent before the given
+    /// pagination token and send them out, oldest first.
+    async fn fetch_backlog(
+        client: &Client,
+        channel: &ClientSender,
+        room_id: &RoomId,
+        from: &str,
+        limit: u32,
+    ) {
+        if limit == 0 {
+            return;
+        }
 
-        let device_id = std::fs::read_to_string(server_path);
+        let mut request = MessagesRequest::backward(room_id, from);
+        request.limit = limit.into();
 
-        if let Err(e) = device_id {
-            // A file not found error is ok, report the rest.
-            if e.kind() != std::io::ErrorKind::NotFound {
-                return Err(e);
+        let response = match client.room_messages(request).await {
+            Ok(r) => r,
+            Err(e) => {
+                warn!("Failed fetching the backlog of room {}: {}", room_id, e);
+                return;
             }
-            return Ok(None);
         };
 
-        let device_id = device_id.unwrap_or_default();
+        // The events come newest first.
+        for event in response.chunk.iter().rev() {
+            // A room event is a sync room event with an additional room id.
+            let event: Raw<AnySyncRoomEvent> =
+                match serde_json::from_str(event.json().get()) {
+                    Ok(e) => e,
+                    Err(e) => {
+                        error!("Failed converting backlog event: {}", e);
+                        continue;
+                    }
+                };
 
-        if device_id.is_empty() {
-            Ok(None)
-        } else {
-            Ok(Some(device_id))
+            let event = edit::with_new_content(event);
+
+            if let Ok(e) = event.deserialize() {
+                channel
+                    .send(Ok(ClientMessage::BacklogEvent(
+                        room_id.clone(),
+                        e,
+                        thread::thread_root(&event),
+                    )))
+                    .await;
+            } else {
+                error!("Failed deserializing backlog event: {:#?}", event);
+            }
         }
     }
 
-    /// Response receiver loop.
-    /// This runs on the main Weechat thread and listens for responses coming
-    /// from the client running in the tokio executor.
-    pub async fn response_receiver(
-        receiver: Receiver<Result<ClientMessage, String>>,
-        server: Weak<RefCell<InnerServer>>,
+    /// Fetch the backlog of a room that was restored from the store, unless
+    /// it was already fetched.
+    async fn fetch_restored_backlog(
+        client: &Client,
+        channel: &ClientSender,
+        fetched: &HashMap<RoomId, AsyncMutex<bool>>,
+        room_id: &RoomId,
+        from: &str,
+        limit: u32,
     ) {
-        loop {
-            let ret = receiver.recv().await;
-
-            let server_cell = server
-                .upgrade()
-                .expect("Can't upgrade server in sync receiver");
-            let mut server = server_cell.borrow_mut();
+        let fetched = if let Some(f) = fetched.get(room_id) {
+            f
+        } else {
+            return;
+        };
 
-            let message = match ret {
-                Ok(m) => m,
-                Err(e) => {
-                    server.print_error(&format!(
-                        "Error receiving message: {:?}",
-                        e
-                    ));
-                    return;
-                }
-            };
+        let mut fetched = fetched.lock().await;
 
-            match message {
-                Ok(message) => match message {
-                    ClientMessage::LoginMessage(r) => server.receive_login(r),
-                    ClientMessage::SyncEvent(r, e) => {
-                        server.receive_joined_timeline_event(&r, e).await
-                    }
-                    ClientMessage::SyncState(r, e) => {
-                        server.receive_joined_state_event(&r, e)
-                    }
-                    ClientMessage::RestoredRoom(room) => {
-                        server.restore_room(room).await
-                    }
-                },
-                Err(e) => server.print_error(&format!("Ruma error {}", e)),
-            };
+        if !*fetched {
+            Connection::fetch_backlog(client, channel, room_id, from, limit)
+                .await;
+            *fetched = true;
         }
     }
 
@@ -307,15 +2346,27 @@ impl Connection {
     /// It communicates with the main Weechat thread using a async channel.
     pub async fn sync_loop(
         client: Client,
-        channel: Sender<Result<ClientMessage, String>>,
+        channel: ClientSender,
         username: String,
         password: String,
+        device_name: String,
+        backlog: u32,
         server_name: String,
         server_path: PathBuf,
+        last_sync: Arc<Mutex<Option<SystemTime>>>,
+        filter_settings: FilterSettings,
     ) {
+        // The rooms that were restored from the store, their tags and backlog
+        // get fetched once the sync is running.
+        let mut restored_rooms: Vec<RoomId> = Vec::new();
+        let mut restore_token = None;
+
         if !client.logged_in().await {
-            let device_id =
-                Connection::load_device_id(&username, server_path.clone());
+            let device_id = Connection::load_device_id(
+                &username,
+                client.homeserver(),
+                server_path.clone(),
+            );
 
             let device_id = match device_id {
                 Err(e) => {
@@ -337,7 +2388,7 @@ impl Connection {
                     &username,
                     &password,
                     device_id.as_deref(),
-                    Some("Weechat-Matrix-rs"),
+                    Some(&device_name),
                 )
                 .await;
 
@@ -345,6 +2396,7 @@ impl Connection {
                 Ok(response) => {
                     if let Err(e) = Connection::save_device_id(
                         &username,
+                        client.homeserver(),
                         server_path.clone(),
                         &response,
                     ) {
@@ -362,7 +2414,9 @@ impl Connection {
                 }
                 Err(e) => {
                     channel
-                        .send(Err(format!("Failed to log in: {:?}", e)))
+                        .send(Ok(ClientMessage::StateChange(
+                            ConnectionState::Error(login_error(&e)),
+                        )))
                         .await;
                     return;
                 }
@@ -375,59 +2429,864 @@ impl Connection {
                         .send(Ok(ClientMessage::RestoredRoom(room.clone())))
                         .await
                 }
+
+                restored_rooms =
+                    joined_rooms.read().await.keys().cloned().collect();
+
+                if let Some(user_id) = client.user_id().await {
+                    // The ignored users only show up in a sync if they
+                    // change.
+                    match Connection::fetch_ignored_users(&client, &user_id)
+                        .await
+                    {
+                        Ok(Some(ignored_users)) => {
+                            let event = AnyBasicEvent::IgnoredUserList(
+                                IgnoredUserListEvent {
+                                    content: IgnoredUserListEventContent {
+                                        ignored_users,
+                                    },
+                                },
+                            );
+
+                            channel
+                                .send(Ok(ClientMessage::AccountData(event)))
+                                .await
+                        }
+                        Ok(None) => (),
+                        Err(e) => {
+                            warn!("Failed fetching the ignored users: {}", e)
+                        }
+                    }
+                }
+
+                // The restored buffers are empty, they get the messages that
+                // were sent before our last sync.
+                restore_token = client.sync_token().await;
             }
         }
 
-        let sync_token = client.sync_token().await;
-        let sync_settings = SyncSettings::new().timeout(DEFAULT_SYNC_TIMEOUT);
+        let filter_id =
+            Connection::upload_filter(&client, &filter_settings).await;
 
-        let sync_settings = if let Some(t) = sync_token {
-            sync_settings.token(t)
-        } else {
-            sync_settings
+        // Without a sync token the server sends us the whole state and a
+        // slice of the timeline of every room, a smaller timeline gets us
+        // going faster on big accounts.
+        let initial_filter_id = match filter_settings.initial() {
+            Some(s) if client.sync_token().await.is_none() => {
+                Connection::upload_filter(&client, &s).await
+            }
+            _ => None,
         };
 
+        // The initial sync already brings a slice of the timeline of every
+        // room, fetching backlog for all of them one by one would only slow
+        // it down.
+        let initial_sync = AtomicBool::new(client.sync_token().await.is_none());
+        let sync_initial = &initial_sync;
+
+        // Rooms that already have a buffer, new rooms get some backlog.
+        let known_rooms: HashSet<RoomId> =
+            client.joined_rooms().read().await.keys().cloned().collect();
+        let known_rooms = Mutex::new(known_rooms);
+        let sync_known_rooms = &known_rooms;
+
+        // The backlog of a restored room needs to be shown before the new
+        // events of the room, whoever gets to the room first fetches it.
+        let backlog_fetched: HashMap<RoomId, AsyncMutex<bool>> =
+            if restore_token.is_some() {
+                restored_rooms
+                    .iter()
+                    .map(|r| (r.clone(), AsyncMutex::new(false)))
+                    .collect()
+            } else {
+                HashMap::new()
+            };
+        let backlog_fetched = &backlog_fetched;
+        let restore_token = &restore_token;
+
         let sync_channel = &channel;
+        let sync_client = &client;
+        let own_user_id = client.user_id().await;
+        let own_user_id = &own_user_id;
 
-        client
-            .sync_with_callback(sync_settings, |response| async move {
-                let channel = sync_channel;
+        // The SDK retries failed syncs on its own without telling us about the
+        // failures, so we notice a broken connection by the lack of sync
+        // responses instead.
+        let last_response = Mutex::new(Instant::now());
+        let stalled = AtomicBool::new(true);
+        let sync_last_response = &last_response;
+        let last_sync = &last_sync;
+        let sync_stalled = &stalled;
 
-                for (room_id, room) in response.rooms.join {
-                    for event in room.state.events {
-                        if let Ok(e) = event.deserialize() {
-                            channel
-                                .send(Ok(ClientMessage::SyncState(
-                                    room_id.clone(),
-                                    e,
-                                )))
-                                .await;
-                        } else {
-                            error!(
-                                "Failed deserializing state event: {:#?}",
-                                event
-                            );
+        let watchdog = async {
+            // Set if logging in again failed, the user needs to reconnect
+            // manually in that case.
+            let mut logged_out = false;
+            let mut last_health_check = Instant::now();
+
+            loop {
+                delay_for(SYNC_STALL_CHECK_INTERVAL).await;
+
+                let elapsed = sync_last_response.lock().unwrap().elapsed();
+
+                if elapsed > SYNC_STALL_TIMEOUT
+                    && !stalled.swap(true, Ordering::SeqCst)
+                {
+                    channel
+                        .send(Ok(ClientMessage::StateChange(
+                            ConnectionState::Error(format!(
+                                "No response from the server for {} seconds",
+                                elapsed.as_secs()
+                            )),
+                        )))
+                        .await;
+                }
+
+                let health_check_due = elapsed > SYNC_STALL_TIMEOUT
+                    || last_health_check.elapsed() > HEALTH_CHECK_INTERVAL;
+
+                if !health_check_due || logged_out {
+                    continue;
+                }
+
+                last_health_check = Instant::now();
+
+                // Check that the server still answers with a cheap request,
+                // the sync might also be failing because the server
+                // invalidated our access token.
+                let response = timeout(
+                    HEALTH_CHECK_TIMEOUT,
+                    client.send(WhoamiRequest::new()),
+                )
+                .await;
+
+                let reason = match response {
+                    Ok(Ok(_)) => continue,
+                    Ok(Err(e)) => match ConnectionError::from(e) {
+                        e if e.is_unknown_token() => None,
+                        // The server answered, it just didn't like the
+                        // request.
+                        ConnectionError::Matrix(MatrixError::RumaResponse(
+                            _,
+                        )) => continue,
+                        e => Some(e.to_string()),
+                    },
+                    Err(_) => Some(format!(
+                        "no answer from the server within {} seconds",
+                        HEALTH_CHECK_TIMEOUT.as_secs()
+                    )),
+                };
+
+                // A new connection takes over, there's nothing left to watch
+                // for this one.
+                if let Some(reason) = reason {
+                    channel
+                        .send(Ok(ClientMessage::Reconnect(format!(
+                            "The health check failed: {}",
+                            reason
+                        ))))
+                        .await;
+                    return;
+                }
+
+                channel.send(Ok(ClientMessage::SoftLogout)).await;
+
+                match Connection::login_again(
+                    &client,
+                    &username,
+                    &password,
+                    &device_name,
+                    server_path.clone(),
+                )
+                .await
+                {
+                    Ok(response) => {
+                        channel
+                            .send(Ok(ClientMessage::LoginMessage(response)))
+                            .await
+                    }
+                    Err(e) => {
+                        logged_out = true;
+
+                        channel
+                            .send(Ok(ClientMessage::StateChange(
+                                ConnectionState::Error(format!(
+                                    "{}, use /matrix disconnect and /matrix \
+                                     connect to log in",
+                                    e
+                                )),
+                            )))
+                            .await;
+                    }
+                }
+            }
+        };
+
+        let handle_response = |response: SyncResponse| async move {
+            let channel = sync_channel;
+            let client = sync_client;
+
+            *sync_last_response.lock().unwrap() = Instant::now();
+            *last_sync.lock().unwrap() = Some(SystemTime::now());
+
+            let initial = sync_initial.swap(false, Ordering::SeqCst);
+
+            if sync_stalled.swap(false, Ordering::SeqCst) {
+                channel
+                    .send(Ok(ClientMessage::StateChange(
+                        ConnectionState::Syncing,
+                    )))
+                    .await;
+            }
+
+            // Servers that disable presence simply don't send any.
+            for event in response.presence.events {
+                if let Ok(e) = event.deserialize() {
+                    channel.send(Ok(ClientMessage::Presence(e))).await;
+                } else {
+                    error!("Failed deserializing presence event: {:#?}", event);
+                }
+            }
+
+            for (room_id, room) in response.rooms.join {
+                for event in room.account_data.events {
+                    if let Ok(e) = event.deserialize() {
+                        channel
+                            .send(Ok(ClientMessage::RoomAccountData(
+                                room_id.clone(),
+                                e,
+                            )))
+                            .await;
+                    } else {
+                        error!(
+                            "Failed deserializing room account data \
+                                 event: {:#?}",
+                            event
+                        );
+                    }
+                }
+                for event in room.state.events {
+                    if let Ok(e) = event.deserialize() {
+                        channel
+                            .send(Ok(ClientMessage::SyncState(
+                                room_id.clone(),
+                                e,
+                            )))
+                            .await;
+                    } else {
+                        error!(
+                            "Failed deserializing state event: {:#?}",
+                            event
+                        );
+                    }
+                }
+
+                let new_room =
+                    sync_known_rooms.lock().unwrap().insert(room_id.clone());
+                let missing =
+                    backlog.saturating_sub(room.timeline.events.len() as u32);
+
+                if new_room && !initial && room.timeline.limited {
+                    if let Some(from) = &room.timeline.prev_batch {
+                        Connection::fetch_backlog(
+                            client, channel, &room_id, from, missing,
+                        )
+                        .await;
+                    }
+                }
+
+                if let Some(from) = restore_token {
+                    Connection::fetch_restored_backlog(
+                        client,
+                        channel,
+                        backlog_fetched,
+                        &room_id,
+                        from,
+                        backlog,
+                    )
+                    .await;
+                }
+                for event in room.timeline.events {
+                    let event = edit::with_new_content(event);
+
+                    if let Ok(e) = event.deserialize() {
+                        channel
+                            .send(Ok(ClientMessage::SyncEvent(
+                                room_id.clone(),
+                                e,
+                                thread::thread_root(&event),
+                            )))
+                            .await;
+                    } else {
+                        error!(
+                            "Failed deserializing timeline event: {:#?}",
+                            event
+                        );
+                    }
+                }
+            }
+
+            for event in response.to_device.events {
+                if let Ok(e) = event.deserialize() {
+                    channel.send(Ok(ClientMessage::ToDevice(e))).await;
+                } else {
+                    error!(
+                        "Failed deserializing to-device event: {:#?}",
+                        event
+                    );
+                }
+            }
+
+            // Send the global account data after the joined rooms, the
+            // rooms it refers to need to exist.
+            for event in response.account_data.events {
+                if let Ok(e) = event.deserialize() {
+                    channel.send(Ok(ClientMessage::AccountData(e))).await;
+                } else {
+                    error!(
+                        "Failed deserializing account data event: {:#?}",
+                        event
+                    );
+                }
+            }
+
+            for (room_id, room) in response.rooms.invite {
+                // The server includes our own member event in the stripped
+                // state, its sender is the user that invited us.
+                let inviter = room
+                    .invite_state
+                    .events
+                    .iter()
+                    .filter_map(|e| e.deserialize().ok())
+                    .find_map(|e| match e {
+                        AnyStrippedStateEvent::RoomMember(m)
+                            if own_user_id.as_ref().map_or(false, |u| {
+                                u.as_str() == m.state_key
+                            }) =>
+                        {
+                            Some(m.sender)
                         }
+                        _ => None,
+                    });
+
+                let inviter = if let Some(i) = inviter {
+                    i
+                } else {
+                    error!(
+                        "Received an invite for {} without an inviter",
+                        room_id
+                    );
+                    continue;
+                };
+
+                let room_name =
+                    if let Some(r) = client.get_invited_room(&room_id).await {
+                        r.read().await.display_name()
+                    } else {
+                        room_id.to_string()
+                    };
+
+                channel
+                    .send(Ok(ClientMessage::Invite(
+                        room_id, inviter, room_name,
+                    )))
+                    .await;
+            }
+
+            for (room_id, room) in response.rooms.leave {
+                let removal = own_user_id.as_ref().and_then(|u| {
+                    room.timeline
+                        .events
+                        .iter()
+                        .rev()
+                        .find_map(|e| RoomRemoval::from_event(e, u))
+                });
+
+                channel
+                    .send(Ok(ClientMessage::LeftRoom(room_id, removal)))
+                    .await;
+            }
+
+            LoopCtrl::Continue
+        };
+
+        let sync = async {
+            if let Some(id) = &initial_filter_id {
+                let initial_settings = SyncSettings::new()
+                    .full_state(true)
+                    .filter(SyncFilter::FilterId(id));
+
+                match client.sync(initial_settings).await {
+                    Ok(response) => {
+                        handle_response(response).await;
                     }
-                    for event in room.timeline.events {
-                        if let Ok(e) = event.deserialize() {
-                            channel
-                                .send(Ok(ClientMessage::SyncEvent(
-                                    room_id.clone(),
-                                    e,
-                                )))
-                                .await;
-                        } else {
-                            error!(
-                                "Failed deserializing timeline event: {:#?}",
-                                event
+                    // The regular sync starts from scratch in that case.
+                    Err(e) => warn!("The initial sync failed: {}", e),
+                }
+            }
+
+            let sync_settings =
+                SyncSettings::new().timeout(DEFAULT_SYNC_TIMEOUT);
+
+            // The sync token is from the initial sync or from the store.
+            let sync_settings = if let Some(t) = client.sync_token().await {
+                sync_settings.token(t)
+            } else {
+                sync_settings
+            };
+
+            let sync_settings = if let Some(id) = &filter_id {
+                sync_settings.filter(SyncFilter::FilterId(id))
+            } else {
+                sync_settings
+            };
+
+            client
+                .sync_with_callback(sync_settings, handle_response)
+                .await;
+        };
+
+        // Room tags only show up in a sync if they change, fetch the tags of
+        // the restored rooms next to the sync so the buffers can be sorted.
+        let restore_tags = async {
+            let user_id = match own_user_id {
+                Some(u) => u,
+                None => return,
+            };
+
+            if restored_rooms.is_empty() {
+                return;
+            }
+
+            let tags: Vec<_> = stream::iter(&restored_rooms)
+                .map(|room_id| async move {
+                    match sync_client
+                        .send(TagsRequest::new(user_id, room_id))
+                        .await
+                    {
+                        Ok(r) => Some((room_id.clone(), r.tags)),
+                        Err(e) => {
+                            warn!(
+                                "Failed fetching the tags of room {}: {}",
+                                room_id, e
                             );
+                            None
                         }
                     }
-                }
+                })
+                .buffer_unordered(MAX_CONCURRENT_RESTORE_REQUESTS)
+                .filter_map(|t| async move { t })
+                .collect()
+                .await;
 
-                LoopCtrl::Continue
-            })
-            .await;
+            sync_channel
+                .send(Ok(ClientMessage::RestoredTags(tags)))
+                .await;
+        };
+
+        let restore = async {
+            restore_tags.await;
+
+            if let Some(from) = restore_token {
+                stream::iter(&restored_rooms)
+                    .for_each_concurrent(
+                        MAX_CONCURRENT_RESTORE_REQUESTS,
+                        |room_id| {
+                            Connection::fetch_restored_backlog(
+                                sync_client,
+                                sync_channel,
+                                backlog_fetched,
+                                room_id,
+                                from,
+                                backlog,
+                            )
+                        },
+                    )
+                    .await;
+            }
+        };
+
+        // The watchdog only returns once it gave up on the connection, stop
+        // whichever of the two is still running once the other one returns.
+        let watchdog = join(watchdog, restore);
+        pin_mut!(sync, watchdog);
+        select(sync, watchdog).await;
+    }
+
+    /// Upload the sync filter built from the given settings, returns the id
+    /// of the filter.
+    ///
+    /// The SDK remembers uploaded filters by name, a name derived from the
+    /// filter makes sure that a changed filter gets uploaded again.
+    async fn upload_filter(
+        client: &Client,
+        settings: &FilterSettings,
+    ) -> Option<String> {
+        let filter = sync_filter(settings);
+        let filter_name = {
+            let mut hasher = DefaultHasher::new();
+            serde_json::to_string(&filter)
+                .unwrap_or_default()
+                .hash(&mut hasher);
+            format!("weechat-matrix-{:x}", hasher.finish())
+        };
+
+        match client.get_or_upload_filter(&filter_name, filter).await {
+            Ok(id) => Some(id),
+            Err(e) => {
+                warn!("Failed uploading the sync filter: {}", e);
+                None
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::executor::block_on;
+
+    use super::*;
+
+    /// A handler that records which of its methods got called.
+    #[derive(Default)]
+    struct MockHandler {
+        calls: RefCell<Vec<String>>,
+    }
+
+    impl MockHandler {
+        fn record(&self, call: impl Into<String>) {
+            self.calls.borrow_mut().push(call.into());
+        }
+
+        fn calls(&self) -> Vec<String> {
+            self.calls.borrow().clone()
+        }
+    }
+
+    #[async_trait(?Send)]
+    impl ClientMessageHandler for MockHandler {
+        fn print_error(&self, message: &str) {
+            self.record(format!("print_error {}", message));
+        }
+
+        fn receive_login(&mut self, response: LoginResponse) {
+            self.record(format!("receive_login {}", response.user_id));
+        }
+
+        fn receive_offline_login(
+            &mut self,
+            user_id: UserId,
+            device_id: DeviceIdBox,
+        ) {
+            self.record(format!(
+                "receive_offline_login {} {}",
+                user_id, device_id
+            ));
+        }
+
+        fn receive_soft_logout(&mut self) {
+            self.record("receive_soft_logout");
+        }
+
+        fn set_connection_state(&mut self, state: ConnectionState) {
+            self.record(format!("set_connection_state {:?}", state));
+        }
+
+        fn receive_joined_state_event(
+            &mut self,
+            room_id: &RoomId,
+            _: AnySyncStateEvent,
+        ) {
+            self.record(format!("receive_joined_state_event {}", room_id));
+        }
+
+        async fn receive_joined_timeline_event(
+            &mut self,
+            room_id: &RoomId,
+            _: AnySyncRoomEvent,
+            thread_root: Option<EventId>,
+        ) {
+            self.record(format!(
+                "receive_joined_timeline_event {} {:?}",
+                room_id,
+                thread_root.as_ref().map(|e| e.as_str())
+            ));
+        }
+
+        async fn receive_backlog_event(
+            &mut self,
+            room_id: &RoomId,
+            _: AnySyncRoomEvent,
+            _: Option<EventId>,
+        ) {
+            self.record(format!("receive_backlog_event {}", room_id));
+        }
+
+        async fn restore_room(&mut self, _: Arc<RwLock<Room>>) {
+            self.record("restore_room");
+        }
+
+        fn receive_restored_tags(&mut self, tags: Vec<(RoomId, Tags)>) {
+            self.record(format!("receive_restored_tags {}", tags.len()));
+        }
+
+        fn receive_invite(
+            &mut self,
+            room_id: RoomId,
+            inviter: UserId,
+            room_name: String,
+        ) {
+            self.record(format!(
+                "receive_invite {} {} {}",
+                room_id, inviter, room_name
+            ));
+        }
+
+        fn receive_left_room(
+            &mut self,
+            room_id: &RoomId,
+            removal: Option<RoomRemoval>,
+        ) {
+            self.record(format!(
+                "receive_left_room {} {}",
+                room_id,
+                removal.is_some()
+            ));
+        }
+
+        fn receive_to_device_event(&self, _: AnyToDeviceEvent) {
+            self.record("receive_to_device_event");
+        }
+
+        fn receive_presence(&self, event: PresenceEvent) {
+            self.record(format!("receive_presence {}", event.sender));
+        }
+
+        fn receive_account_data(&mut self, _: AnyBasicEvent) {
+            self.record("receive_account_data");
+        }
+
+        fn receive_room_account_data(
+            &mut self,
+            room_id: &RoomId,
+            _: AnyBasicEvent,
+        ) {
+            self.record(format!("receive_room_account_data {}", room_id));
+        }
+    }
+
+    fn dispatch_one(message: Result<ClientMessage, String>) -> Vec<String> {
+        let mut handler = MockHandler::default();
+        block_on(dispatch(&mut handler, message));
+        handler.calls()
+    }
+
+    fn room_id() -> RoomId {
+        RoomId::try_from("!room:example.org").unwrap()
+    }
+
+    fn user_id() -> UserId {
+        UserId::try_from("@alice:example.org").unwrap()
+    }
+
+    fn basic_event() -> AnyBasicEvent {
+        serde_json::from_value(json!({
+            "type": "m.tag",
+            "content": { "tags": {} }
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn errors_get_printed() {
+        assert_eq!(
+            dispatch_one(Err("timed out".to_owned())),
+            vec!["print_error Ruma error timed out"]
+        );
+    }
+
+    #[test]
+    fn error_states_get_printed_and_set() {
+        let state = ConnectionState::Error("Sync failed".to_owned());
+
+        assert_eq!(
+            dispatch_one(Ok(ClientMessage::StateChange(state))),
+            vec![
+                "print_error Sync failed",
+                "set_connection_state Error(\"Sync failed\")",
+            ]
+        );
+    }
+
+    #[test]
+    fn state_changes_only_get_set() {
+        assert_eq!(
+            dispatch_one(Ok(ClientMessage::StateChange(
+                ConnectionState::Syncing
+            ))),
+            vec!["set_connection_state Syncing"]
+        );
+    }
+
+    #[test]
+    fn login() {
+        let response =
+            LoginResponse::new(user_id(), "token".to_owned(), "DEVICE".into());
+
+        assert_eq!(
+            dispatch_one(Ok(ClientMessage::LoginMessage(response))),
+            vec!["receive_login @alice:example.org"]
+        );
+        assert_eq!(
+            dispatch_one(Ok(ClientMessage::OfflineLogin(
+                user_id(),
+                "DEVICE".into()
+            ))),
+            vec!["receive_offline_login @alice:example.org DEVICE"]
+        );
+        assert_eq!(
+            dispatch_one(Ok(ClientMessage::SoftLogout)),
+            vec!["receive_soft_logout"]
+        );
+    }
+
+    #[test]
+    fn room_events() {
+        let state: AnySyncStateEvent = serde_json::from_value(json!({
+            "type": "m.room.name",
+            "event_id": "$name:example.org",
+            "sender": "@alice:example.org",
+            "origin_server_ts": 1,
+            "state_key": "",
+            "content": { "name": "Room" }
+        }))
+        .unwrap();
+        let event: AnySyncRoomEvent = serde_json::from_value(json!({
+            "type": "m.room.message",
+            "event_id": "$message:example.org",
+            "sender": "@alice:example.org",
+            "origin_server_ts": 1,
+            "content": { "msgtype": "m.text", "body": "Hello" }
+        }))
+        .unwrap();
+        let root = EventId::try_from("$root:example.org").unwrap();
+
+        assert_eq!(
+            dispatch_one(Ok(ClientMessage::SyncState(room_id(), state))),
+            vec!["receive_joined_state_event !room:example.org"]
+        );
+        assert_eq!(
+            dispatch_one(Ok(ClientMessage::SyncEvent(
+                room_id(),
+                event.clone(),
+                Some(root)
+            ))),
+            vec![
+                "receive_joined_timeline_event !room:example.org \
+                 Some(\"$root:example.org\")"
+            ]
+        );
+        assert_eq!(
+            dispatch_one(Ok(ClientMessage::BacklogEvent(
+                room_id(),
+                event,
+                None
+            ))),
+            vec!["receive_backlog_event !room:example.org"]
+        );
+    }
+
+    #[test]
+    fn restored_rooms() {
+        let room = Room::new(&room_id(), &user_id());
+
+        assert_eq!(
+            dispatch_one(Ok(ClientMessage::RestoredRoom(Arc::new(
+                RwLock::new(room)
+            )))),
+            vec!["restore_room"]
+        );
+    }
+
+    #[test]
+    fn restored_tags() {
+        assert_eq!(
+            dispatch_one(Ok(ClientMessage::RestoredTags(vec![(
+                room_id(),
+                Tags::new()
+            )]))),
+            vec!["receive_restored_tags 1"]
+        );
+    }
+
+    #[test]
+    fn membership_changes() {
+        assert_eq!(
+            dispatch_one(Ok(ClientMessage::Invite(
+                room_id(),
+                user_id(),
+                "Room".to_owned()
+            ))),
+            vec!["receive_invite !room:example.org @alice:example.org Room"]
+        );
+
+        let removal = RoomRemoval {
+            sender: user_id(),
+            banned: true,
+            reason: None,
+        };
+
+        assert_eq!(
+            dispatch_one(Ok(ClientMessage::LeftRoom(room_id(), Some(removal)))),
+            vec!["receive_left_room !room:example.org true"]
+        );
+    }
+
+    #[test]
+    fn account_data_and_presence() {
+        let presence: PresenceEvent = serde_json::from_value(json!({
+            "type": "m.presence",
+            "sender": "@alice:example.org",
+            "content": { "presence": "online" }
+        }))
+        .unwrap();
+        let to_device: AnyToDeviceEvent = serde_json::from_value(json!({
+            "type": "m.room_key_request",
+            "sender": "@alice:example.org",
+            "content": {
+                "action": "request_cancellation",
+                "requesting_device_id": "DEVICE",
+                "request_id": "1"
+            }
+        }))
+        .unwrap();
+
+        assert_eq!(
+            dispatch_one(Ok(ClientMessage::Presence(presence))),
+            vec!["receive_presence @alice:example.org"]
+        );
+        assert_eq!(
+            dispatch_one(Ok(ClientMessage::ToDevice(to_device))),
+            vec!["receive_to_device_event"]
+        );
+        assert_eq!(
+            dispatch_one(Ok(ClientMessage::AccountData(basic_event()))),
+            vec!["receive_account_data"]
+        );
+        assert_eq!(
+            dispatch_one(Ok(ClientMessage::RoomAccountData(
+                room_id(),
+                basic_event()
+            ))),
+            vec!["receive_room_account_data !room:example.org"]
+        );
+    }
+
+    #[test]
+    fn reconnects_are_left_to_the_receiver() {
+        assert!(dispatch_one(Ok(ClientMessage::Reconnect(
+            "Sync timed out".to_owned()
+        )))
+        .is_empty());
     }
 }
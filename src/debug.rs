@@ -1,4 +1,12 @@
-use std::{cell::RefMut, io};
+use std::{
+    cell::RefMut,
+    fs::{File, OpenOptions},
+    io::{self, Write},
+    path::Path,
+    sync::{Arc, Mutex},
+};
+
+use tracing_subscriber::{reload, EnvFilter};
 
 use weechat::{
     buffer::{BufferBuilder, BufferHandle},
@@ -7,8 +15,11 @@ use weechat::{
 
 use crate::Matrix;
 
+/// The file the log messages are additionally written to, if any.
+type LogFile = Arc<Mutex<Option<File>>>;
+
 #[derive(Clone)]
-pub struct Debug();
+pub struct Debug(LogFile);
 
 impl Debug {
     fn create_debug_buffer(debug_buffer: &mut RefMut<Option<BufferHandle>>) {
@@ -50,11 +61,95 @@ impl Debug {
 
 impl io::Write for Debug {
     fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if let Some(file) = self.0.lock().unwrap().as_mut() {
+            file.write_all(&strip_ansi(buf))?;
+        }
+
         Weechat::spawn_from_thread(Debug::write_helper(buf.to_owned()));
         Ok(buf.len())
     }
 
     fn flush(&mut self) -> io::Result<()> {
+        if let Some(file) = self.0.lock().unwrap().as_mut() {
+            file.flush()?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Runtime controls for the log output.
+pub struct Logging {
+    set_filter: Box<dyn Fn(EnvFilter) -> Result<(), reload::Error>>,
+    file: LogFile,
+}
+
+impl Logging {
+    /// Install the global tracing subscriber.
+    ///
+    /// The filter is taken from the `RUST_LOG` environment variable and can be
+    /// changed later on.
+    pub fn init() -> Self {
+        let file = LogFile::default();
+        let writer_file = file.clone();
+
+        let builder = tracing_subscriber::fmt()
+            .with_writer(move || Debug(writer_file.clone()))
+            .with_env_filter(EnvFilter::from_default_env())
+            .with_filter_reloading();
+
+        let handle = builder.reload_handle();
+        builder.init();
+
+        Self {
+            set_filter: Box::new(move |filter| handle.reload(filter)),
+            file,
+        }
+    }
+
+    /// Replace the log filter, the directives use the same syntax as the
+    /// `RUST_LOG` environment variable, e.g. `matrix_sdk=debug,info`.
+    pub fn set_filter(&self, directives: &str) -> Result<(), String> {
+        let filter =
+            EnvFilter::try_new(directives).map_err(|e| e.to_string())?;
+        (self.set_filter)(filter).map_err(|e| e.to_string())
+    }
+
+    /// Start appending the log messages to the given file.
+    pub fn log_to_file(&self, path: &Path) -> io::Result<()> {
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        *self.file.lock().unwrap() = Some(file);
+
         Ok(())
     }
+
+    /// Stop writing the log messages to a file.
+    pub fn stop_logging_to_file(&self) {
+        self.file.lock().unwrap().take();
+    }
+}
+
+/// Remove the ANSI escape sequences the formatter uses for colors.
+fn strip_ansi(buf: &[u8]) -> Vec<u8> {
+    let mut stripped = Vec::with_capacity(buf.len());
+    let mut bytes = buf.iter();
+
+    while let Some(&b) = bytes.next() {
+        if b == 0x1b {
+            // Skip until the final byte of the escape sequence.
+            for &b in bytes.by_ref() {
+                if b.is_ascii_alphabetic() {
+                    break;
+                }
+            }
+        } else {
+            stripped.push(b);
+        }
+    }
+
+    stripped
 }
@@ -0,0 +1,159 @@
+//! Homeserver discovery.
+//!
+//! A Matrix user id only contains the server name, which doesn't need to be
+//! the host the homeserver runs on. The server name can point to the actual
+//! homeserver using a `/.well-known/matrix/client` file. If the server name
+//! doesn't provide such a file we assume that the homeserver runs on the
+//! server name itself.
+//!
+//! The discovered URL is checked by fetching the supported spec versions of
+//! the homeserver.
+
+use serde_json::Value;
+use url::Url;
+
+/// Why we couldn't find a homeserver for a server name.
+#[derive(Debug)]
+pub enum DiscoveryError {
+    /// The server name provided a well-known file but it's unusable.
+    Invalid(String),
+    /// The homeserver was found but it doesn't answer like a homeserver.
+    Unreachable(Url, String),
+}
+
+impl std::fmt::Display for DiscoveryError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DiscoveryError::Invalid(e) => {
+                write!(f, "Discovering the homeserver failed: {}", e)
+            }
+            DiscoveryError::Unreachable(url, e) => {
+                write!(f, "The homeserver {} isn't reachable: {}", url, e)
+            }
+        }
+    }
+}
+
+/// Find the homeserver for the given server name.
+///
+/// This needs to run on a tokio runtime.
+///
+/// # Arguments
+///
+/// * `server_name` - The server name part of a Matrix user id.
+///
+/// * `proxy` - The URL of the proxy all requests should go through.
+///
+/// * `verify` - Should the certificate of the server be verified.
+pub async fn discover_homeserver(
+    server_name: String,
+    proxy: Option<Url>,
+    verify: bool,
+) -> Result<Url, DiscoveryError> {
+    let mut builder =
+        reqwest::Client::builder().danger_accept_invalid_certs(!verify);
+
+    if let Some(proxy) = proxy {
+        let proxy = reqwest::Proxy::all(proxy.as_str())
+            .map_err(|e| DiscoveryError::Invalid(e.to_string()))?;
+        builder = builder.proxy(proxy);
+    }
+
+    let client = builder
+        .build()
+        .map_err(|e| DiscoveryError::Invalid(e.to_string()))?;
+
+    let fallback = Url::parse(&format!("https://{}", server_name))
+        .map_err(|e| DiscoveryError::Invalid(e.to_string()))?;
+
+    let homeserver = match well_known(&client, &fallback).await? {
+        Some(url) => url,
+        None => fallback,
+    };
+
+    // The homeserver might be served under a path prefix, keep it.
+    let versions = format!(
+        "{}/_matrix/client/versions",
+        homeserver.as_str().trim_end_matches('/')
+    );
+
+    let response = client
+        .get(&versions)
+        .send()
+        .await
+        .and_then(|r| r.error_for_status())
+        .map_err(|e| {
+            DiscoveryError::Unreachable(homeserver.clone(), e.to_string())
+        })?;
+
+    let versions: Value = response
+        .bytes()
+        .await
+        .map_err(|e| e.to_string())
+        .and_then(|b| serde_json::from_slice(&b).map_err(|e| e.to_string()))
+        .map_err(|e| DiscoveryError::Unreachable(homeserver.clone(), e))?;
+
+    if versions.get("versions").map_or(false, Value::is_array) {
+        Ok(homeserver)
+    } else {
+        Err(DiscoveryError::Unreachable(
+            homeserver,
+            "the response doesn't list any spec versions".to_owned(),
+        ))
+    }
+}
+
+/// Fetch the homeserver URL out of the well-known file of the server name.
+///
+/// Returns `None` if the server name doesn't provide a well-known file.
+async fn well_known(
+    client: &reqwest::Client,
+    server_name: &Url,
+) -> Result<Option<Url>, DiscoveryError> {
+    let url = server_name
+        .join("/.well-known/matrix/client")
+        .expect("Can't build the well-known URL");
+
+    let response = match client.get(url).send().await {
+        Ok(r) => r,
+        // Nothing that could serve a well-known file runs there, the
+        // homeserver might still run on a different port.
+        Err(_) => return Ok(None),
+    };
+
+    if response.status() == reqwest::StatusCode::NOT_FOUND {
+        return Ok(None);
+    }
+
+    let response = response
+        .error_for_status()
+        .map_err(|e| DiscoveryError::Invalid(e.to_string()))?;
+
+    let well_known: Value = response
+        .bytes()
+        .await
+        .map_err(|e| e.to_string())
+        .and_then(|b| serde_json::from_slice(&b).map_err(|e| e.to_string()))
+        .map_err(|e| {
+            DiscoveryError::Invalid(format!("invalid well-known file: {}", e))
+        })?;
+
+    let base_url = well_known
+        .get("m.homeserver")
+        .and_then(|h| h.get("base_url"))
+        .and_then(Value::as_str)
+        .ok_or_else(|| {
+            DiscoveryError::Invalid(
+                "the well-known file doesn't contain a homeserver".to_owned(),
+            )
+        })?;
+
+    let base_url = Url::parse(base_url).map_err(|e| {
+        DiscoveryError::Invalid(format!(
+            "invalid homeserver URL {}: {}",
+            base_url, e
+        ))
+    })?;
+
+    Ok(Some(base_url))
+}
@@ -0,0 +1,78 @@
+//! Persistent message drafts.
+//!
+//! Whatever is left in the input bar of a room buffer is saved when the buffer
+//! gets closed or the plugin gets unloaded. The draft is put back into the
+//! input bar once the room buffer is created again.
+//!
+//! Drafts are stored as a JSON object mapping room ids to the input, one file
+//! per server.
+
+use std::{cell::RefCell, collections::HashMap, io, path::PathBuf, rc::Rc};
+
+use tracing::error;
+
+use matrix_sdk::identifiers::RoomId;
+
+#[derive(Clone, Debug)]
+pub struct Drafts {
+    path: Rc<PathBuf>,
+    inner: Rc<RefCell<HashMap<RoomId, String>>>,
+}
+
+impl Drafts {
+    /// Load the drafts from the given file.
+    ///
+    /// A missing or unreadable file is treated as if there were no drafts.
+    pub fn load(path: PathBuf) -> Self {
+        let drafts = match std::fs::read(&path) {
+            Ok(data) => serde_json::from_slice(&data).unwrap_or_else(|e| {
+                error!("Error parsing drafts file {}: {}", path.display(), e);
+                HashMap::new()
+            }),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => HashMap::new(),
+            Err(e) => {
+                error!("Error reading drafts file {}: {}", path.display(), e);
+                HashMap::new()
+            }
+        };
+
+        Self {
+            path: Rc::new(path),
+            inner: Rc::new(RefCell::new(drafts)),
+        }
+    }
+
+    /// Take out the draft of the given room, if there is one.
+    pub fn take(&self, room_id: &RoomId) -> Option<String> {
+        self.inner.borrow_mut().remove(room_id)
+    }
+
+    /// Remember the input of the given room, an empty input removes the
+    /// draft.
+    pub fn set(&self, room_id: &RoomId, input: &str) {
+        let mut drafts = self.inner.borrow_mut();
+
+        if input.is_empty() {
+            drafts.remove(room_id);
+        } else {
+            drafts.insert(room_id.clone(), input.to_owned());
+        }
+    }
+
+    /// Write the drafts out to the drafts file.
+    pub fn save(&self) {
+        let write = || -> io::Result<()> {
+            let data = serde_json::to_vec(&*self.inner.borrow())?;
+
+            if let Some(dir) = self.path.parent() {
+                std::fs::create_dir_all(dir)?;
+            }
+
+            std::fs::write(&*self.path, data)
+        };
+
+        if let Err(e) = write() {
+            error!("Error saving drafts to {}: {}", self.path.display(), e);
+        }
+    }
+}
@@ -0,0 +1,174 @@
+//! End-to-end tests that run the sync loop against a real homeserver.
+//!
+//! The tests are ignored by default since they need a running homeserver and
+//! an account on it, e.g. a Conduit or Synapse instance started for testing:
+//!
+//! ```text
+//! MATRIX_TEST_HOMESERVER=http://localhost:8008 \
+//! MATRIX_TEST_USER=test \
+//! MATRIX_TEST_PASSWORD=test \
+//!     cargo test e2e_tests -- --ignored
+//! ```
+//!
+//! The tests talk to the sync loop the same way the response receiver does,
+//! through the channel of client messages, so they don't need Weechat. Requests
+//! go through a `Connection`, like the ones of the room buffers.
+
+use std::{
+    env,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use async_std::sync::{channel, Receiver};
+use futures::future::abortable;
+use tokio::{runtime::Runtime, time::timeout};
+use url::Url;
+use uuid::Uuid;
+
+use crate::connection::{
+    AnyMessageEventContent, AnySyncRoomEvent, Client, ClientConfig,
+    ClientMessage, ClientSender, Connection, ConnectionState,
+    CreateRoomRequest, FilterSettings, MessageEventContent, CHANNEL_SIZE,
+};
+
+/// How long we wait for the homeserver before a test fails.
+const TIMEOUT: Duration = Duration::from_secs(60);
+
+type ClientReceiver = Receiver<Result<ClientMessage, String>>;
+
+/// The account on the test homeserver the tests log in with.
+struct TestAccount {
+    homeserver: Url,
+    username: String,
+    password: String,
+}
+
+impl TestAccount {
+    fn from_env() -> Self {
+        let var = |name: &str| {
+            env::var(name).unwrap_or_else(|_| {
+                panic!("{} needs to be set to run the e2e tests", name)
+            })
+        };
+
+        Self {
+            homeserver: Url::parse(&var("MATRIX_TEST_HOMESERVER"))
+                .expect("MATRIX_TEST_HOMESERVER isn't a valid URL"),
+            username: var("MATRIX_TEST_USER"),
+            password: var("MATRIX_TEST_PASSWORD"),
+        }
+    }
+}
+
+/// Wait until the sync loop sends a message the given closure picks.
+async fn wait_for<T>(
+    receiver: &ClientReceiver,
+    mut pick: impl FnMut(ClientMessage) -> Option<T>,
+) -> T {
+    let wait = async {
+        loop {
+            match receiver.recv().await {
+                Ok(Ok(message)) => {
+                    if let Some(picked) = pick(message) {
+                        return picked;
+                    }
+                }
+                Ok(Err(e)) => panic!("The sync loop sent an error: {}", e),
+                Err(_) => panic!("The sync loop stopped"),
+            }
+        }
+    };
+
+    timeout(TIMEOUT, wait)
+        .await
+        .expect("Timed out waiting for the sync loop")
+}
+
+#[test]
+#[ignore]
+fn message_round_trip() {
+    let account = TestAccount::from_env();
+    let store_path =
+        env::temp_dir().join(format!("weechat-matrix-e2e-{}", Uuid::new_v4()));
+
+    let client = Client::new_with_config(
+        account.homeserver.clone(),
+        ClientConfig::new().store_path(&store_path),
+    )
+    .expect("Error creating the client");
+
+    let (sender, receiver) = channel(CHANNEL_SIZE);
+    let (sync_loop, abort_handle) = abortable(Connection::sync_loop(
+        client.clone(),
+        ClientSender::new(sender),
+        account.username,
+        account.password,
+        "weechat-matrix e2e test".to_owned(),
+        0,
+        "e2e".to_owned(),
+        store_path.clone(),
+        Arc::new(Mutex::new(None)),
+        FilterSettings::default(),
+    ));
+
+    let mut runtime = Runtime::new().expect("Error creating the runtime");
+    let task = runtime.spawn(sync_loop);
+    let connection =
+        Connection::detached(&client, abort_handle, task, store_path.clone())
+            .expect("Error creating the connection");
+
+    runtime.block_on(async {
+        wait_for(&receiver, |m| match m {
+            ClientMessage::StateChange(ConnectionState::Error(e)) => {
+                panic!("Error connecting to the homeserver: {}", e)
+            }
+            ClientMessage::StateChange(ConnectionState::Syncing) => Some(()),
+            _ => None,
+        })
+        .await;
+
+        let room_id = client
+            .create_room(CreateRoomRequest::new())
+            .await
+            .expect("Error creating a room")
+            .room_id;
+
+        let transaction_id = Uuid::new_v4();
+        let content = AnyMessageEventContent::RoomMessage(
+            MessageEventContent::text_plain("Hello from the e2e tests"),
+        );
+
+        connection
+            .send_message(&room_id, content, Some(transaction_id))
+            .await
+            .expect("Error sending the message");
+
+        // Our own message comes back through the sync with the transaction id
+        // we sent it out with.
+        let body = wait_for(&receiver, |m| match m {
+            ClientMessage::SyncEvent(r, AnySyncRoomEvent::Message(e), _)
+                if r == room_id
+                    && e.unsigned().transaction_id.as_deref()
+                        == Some(transaction_id.to_string().as_str()) =>
+            {
+                Some(e.content())
+            }
+            _ => None,
+        })
+        .await;
+
+        match body {
+            AnyMessageEventContent::RoomMessage(MessageEventContent::Text(
+                t,
+            )) => assert_eq!(t.body, "Hello from the e2e tests"),
+            c => panic!("Received an unexpected message {:?}", c),
+        }
+    });
+
+    // Dropping the connection stops the sync loop, the runtimes need to be
+    // dropped outside of an async context.
+    drop(connection);
+    drop(runtime);
+    std::fs::remove_dir_all(store_path).ok();
+}
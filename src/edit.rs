@@ -0,0 +1,152 @@
+//! Edit module.
+//!
+//! Edits are messages with an `m.replace` relation pointing to the message
+//! they replace, the new content of the message is in `m.new_content`. The new
+//! content isn't part of the typed event content, so edits get their content
+//! swapped for the new content while they are still raw events.
+
+use std::convert::TryFrom;
+
+use serde_json::Value;
+
+use matrix_sdk::{
+    events::{
+        room::message::{MessageEventContent, Relation},
+        AnySyncMessageEvent, AnySyncRoomEvent, SyncMessageEvent,
+    },
+    identifiers::EventId,
+    Raw,
+};
+
+/// The relation type of edits.
+const REPLACE_REL_TYPE: &str = "m.replace";
+
+/// Replace the content of an edit with the new content of the edited message.
+///
+/// The relation is kept so the edit can still be told apart from a new
+/// message, events that aren't edits are returned unchanged.
+pub fn with_new_content(event: Raw<AnySyncRoomEvent>) -> Raw<AnySyncRoomEvent> {
+    new_content_event(&event).unwrap_or(event)
+}
+
+fn new_content_event(
+    event: &Raw<AnySyncRoomEvent>,
+) -> Option<Raw<AnySyncRoomEvent>> {
+    let mut event: Value = serde_json::from_str(event.json().get()).ok()?;
+    let content = event.get_mut("content")?;
+
+    if content.get("m.relates_to")?.get("rel_type")?.as_str()?
+        != REPLACE_REL_TYPE
+    {
+        return None;
+    }
+
+    let relates_to = content["m.relates_to"].take();
+    let mut new_content = content.get_mut("m.new_content")?.take();
+    new_content
+        .as_object_mut()?
+        .insert("m.relates_to".to_owned(), relates_to);
+    *content = new_content;
+
+    serde_json::from_str(&event.to_string()).ok()
+}
+
+/// Get the event id of the message the event replaces if the event is an
+/// edit.
+pub fn replaced_event(event: &AnySyncMessageEvent) -> Option<EventId> {
+    let relates_to = match event {
+        AnySyncMessageEvent::RoomMessage(SyncMessageEvent {
+            content, ..
+        }) => match content {
+            MessageEventContent::Text(c) => c.relates_to.as_ref(),
+            MessageEventContent::Notice(c) => c.relates_to.as_ref(),
+            _ => None,
+        },
+        _ => None,
+    };
+
+    match relates_to? {
+        Relation::Custom(r)
+            if r.get("rel_type")?.as_str()? == REPLACE_REL_TYPE =>
+        {
+            EventId::try_from(r.get("event_id")?.as_str()?).ok()
+        }
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+    use crate::render::Render;
+
+    fn raw_event(json: Value) -> Raw<AnySyncRoomEvent> {
+        serde_json::from_str(&json.to_string()).unwrap()
+    }
+
+    fn message(event: Raw<AnySyncRoomEvent>) -> AnySyncMessageEvent {
+        match event.deserialize().unwrap() {
+            AnySyncRoomEvent::Message(m) => m,
+            e => panic!("Expected a message, got {:?}", e),
+        }
+    }
+
+    #[test]
+    fn incoming_edit_renders_the_new_content() {
+        let event = raw_event(json!({
+            "type": "m.room.message",
+            "event_id": "$edit:example.org",
+            "sender": "@alice:example.org",
+            "origin_server_ts": 1,
+            "content": {
+                "msgtype": "m.text",
+                "body": "* Hello world",
+                "m.new_content": {
+                    "msgtype": "m.text",
+                    "body": "Hello world",
+                },
+                "m.relates_to": {
+                    "rel_type": "m.replace",
+                    "event_id": "$original:example.org",
+                },
+            },
+        }));
+
+        let event = message(with_new_content(event));
+
+        assert_eq!(
+            replaced_event(&event),
+            Some(EventId::try_from("$original:example.org").unwrap())
+        );
+
+        let lines = match &event {
+            AnySyncMessageEvent::RoomMessage(SyncMessageEvent {
+                content: MessageEventContent::Text(c),
+                ..
+            }) => c.render(&()).lines,
+            e => panic!("Expected a text message, got {:?}", e),
+        };
+        let lines: Vec<&str> =
+            lines.iter().map(|l| l.message.as_str()).collect();
+
+        assert_eq!(lines, vec!["Hello world"]);
+    }
+
+    #[test]
+    fn regular_messages_are_not_edits() {
+        let event = raw_event(json!({
+            "type": "m.room.message",
+            "event_id": "$message:example.org",
+            "sender": "@alice:example.org",
+            "origin_server_ts": 1,
+            "content": {
+                "msgtype": "m.text",
+                "body": "Hello world",
+            },
+        }));
+
+        assert_eq!(replaced_event(&message(with_new_content(event))), None);
+    }
+}
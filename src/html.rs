@@ -0,0 +1,676 @@
+//! HTML module.
+//!
+//! Renders the HTML subset that Matrix allows in the `formatted_body` of a
+//! message into a string containing Weechat color and attribute codes.
+//!
+//! Tags we don't know how to render degrade to their text content. Since every
+//! line of a message is printed out separately, all the active attributes are
+//! re-applied at the start of every line.
+
+#[cfg(test)]
+use tests::Weechat;
+#[cfg(not(test))]
+use weechat::Weechat;
+
+/// Tags that never have a closing tag.
+const VOID_TAGS: &[&str] = &["br", "hr", "img"];
+
+/// Tags that start a new line when they are opened and closed.
+const BLOCK_TAGS: &[&str] = &[
+    "p",
+    "div",
+    "pre",
+    "blockquote",
+    "ul",
+    "ol",
+    "li",
+    "h1",
+    "h2",
+    "h3",
+    "h4",
+    "h5",
+    "h6",
+    "table",
+    "tr",
+];
+
+/// The intensity levels of the 6x6x6 color cube of 256 color terminals.
+const CUBE_LEVELS: [u8; 6] = [0, 95, 135, 175, 215, 255];
+
+/// Prefix for links that point to a Matrix entity, e.g. mentions. For those we
+/// only display the link text.
+const MATRIX_TO_PREFIX: &str = "https://matrix.to/";
+
+/// An open HTML element.
+struct Element {
+    /// The lowercase name of the tag.
+    name: String,
+    /// The Weechat color or attribute this element applies to its content.
+    style: Option<String>,
+    /// The target of a link element.
+    href: Option<String>,
+    /// The position in the output where the content of the element starts.
+    start: usize,
+}
+
+#[derive(Default)]
+struct Renderer {
+    output: String,
+    stack: Vec<Element>,
+    /// Counters of the currently open lists, `None` for unordered ones.
+    lists: Vec<Option<usize>>,
+    /// Are we at the start of a new line, the line prefix and active
+    /// attributes weren't written yet.
+    line_start: bool,
+    /// Are we inside an element that should be skipped, e.g. a reply fallback.
+    skip_depth: usize,
+}
+
+/// Parse a tag, returning the tag name, if it's a closing tag, and the list of
+/// attributes.
+fn parse_tag(tag: &str) -> (String, bool, Vec<(String, String)>) {
+    let tag = tag.trim().trim_end_matches('/');
+    let (closing, tag) = if let Some(t) = tag.strip_prefix('/') {
+        (true, t)
+    } else {
+        (false, tag)
+    };
+
+    let name_end = tag
+        .find(|c: char| c.is_whitespace())
+        .unwrap_or_else(|| tag.len());
+    let name = tag[..name_end].to_lowercase();
+
+    let mut attributes = Vec::new();
+    let mut rest = tag[name_end..].trim_start();
+
+    while !rest.is_empty() {
+        let key_end = rest
+            .find(|c: char| c == '=' || c.is_whitespace())
+            .unwrap_or_else(|| rest.len());
+        let key = rest[..key_end].to_lowercase();
+        rest = rest[key_end..].trim_start();
+
+        let value = if let Some(r) = rest.strip_prefix('=') {
+            let r = r.trim_start();
+
+            let (value, remaining) = match r.chars().next() {
+                Some(quote) if quote == '"' || quote == '\'' => {
+                    let r = &r[1..];
+                    let end = r.find(quote).unwrap_or_else(|| r.len());
+                    (&r[..end], r.get(end + 1..).unwrap_or(""))
+                }
+                _ => {
+                    let end = r
+                        .find(|c: char| c.is_whitespace())
+                        .unwrap_or_else(|| r.len());
+                    (&r[..end], &r[end..])
+                }
+            };
+
+            rest = remaining.trim_start();
+            decode_entities(value)
+        } else {
+            String::new()
+        };
+
+        if !key.is_empty() {
+            attributes.push((key, value));
+        }
+    }
+
+    (name, closing, attributes)
+}
+
+/// Decode the HTML character references in the given string.
+fn decode_entities(text: &str) -> String {
+    let mut decoded = String::with_capacity(text.len());
+    let mut rest = text;
+
+    while let Some(start) = rest.find('&') {
+        decoded.push_str(&rest[..start]);
+        rest = &rest[start..];
+
+        let end = match rest.find(';') {
+            // Character references are short, don't treat a random ampersand
+            // followed by a semicolon much later on as a reference.
+            Some(end) if end <= 10 => end,
+            _ => {
+                decoded.push('&');
+                rest = &rest[1..];
+                continue;
+            }
+        };
+
+        let entity = &rest[1..end];
+
+        let character = match entity {
+            "amp" => Some('&'),
+            "lt" => Some('<'),
+            "gt" => Some('>'),
+            "quot" => Some('"'),
+            "apos" => Some('\''),
+            "nbsp" => Some(' '),
+            _ => {
+                let number = if let Some(hex) = entity
+                    .strip_prefix("#x")
+                    .or_else(|| entity.strip_prefix("#X"))
+                {
+                    u32::from_str_radix(hex, 16).ok()
+                } else if let Some(dec) = entity.strip_prefix('#') {
+                    dec.parse().ok()
+                } else {
+                    None
+                };
+
+                number.and_then(std::char::from_u32)
+            }
+        };
+
+        if let Some(c) = character {
+            decoded.push(c);
+            rest = &rest[end + 1..];
+        } else {
+            decoded.push('&');
+            rest = &rest[1..];
+        }
+    }
+
+    decoded.push_str(rest);
+    decoded
+}
+
+/// Find the xterm 256 color that is the closest to the given RGB color.
+fn closest_xterm_color(red: u8, green: u8, blue: u8) -> u8 {
+    fn closest_level(value: u8) -> usize {
+        CUBE_LEVELS
+            .iter()
+            .enumerate()
+            .min_by_key(|(_, l)| (**l as i32 - value as i32).abs())
+            .map(|(i, _)| i)
+            .unwrap_or(0)
+    }
+
+    fn distance(a: (u8, u8, u8), b: (u8, u8, u8)) -> i32 {
+        let d = |x: u8, y: u8| (x as i32 - y as i32).pow(2);
+        d(a.0, b.0) + d(a.1, b.1) + d(a.2, b.2)
+    }
+
+    let color = (red, green, blue);
+
+    let (r, g, b) = (
+        closest_level(red),
+        closest_level(green),
+        closest_level(blue),
+    );
+    let cube_color = (CUBE_LEVELS[r], CUBE_LEVELS[g], CUBE_LEVELS[b]);
+    let cube_index = 16 + 36 * r + 6 * g + b;
+
+    // The grayscale ramp goes from 8 to 238 in steps of 10.
+    let average = (red as i32 + green as i32 + blue as i32) / 3;
+    let gray_step = ((average - 8).max(0) / 10).min(23);
+    let gray_level = (8 + gray_step * 10) as u8;
+    let gray_color = (gray_level, gray_level, gray_level);
+    let gray_index = 232 + gray_step as usize;
+
+    if distance(color, gray_color) < distance(color, cube_color) {
+        gray_index as u8
+    } else {
+        cube_index as u8
+    }
+}
+
+/// Convert a HTML color into a color name Weechat understands.
+fn weechat_color_name(color: &str) -> Option<String> {
+    let color = color.trim();
+
+    if let Some(hex) = color.strip_prefix('#') {
+        let hex = if hex.len() == 3 {
+            hex.chars().flat_map(|c| vec![c, c]).collect()
+        } else {
+            hex.to_owned()
+        };
+
+        if hex.len() != 6 || !hex.chars().all(|c| c.is_ascii_hexdigit()) {
+            return None;
+        }
+
+        let component =
+            |i: usize| u8::from_str_radix(&hex[i..i + 2], 16).unwrap_or(0);
+
+        Some(
+            closest_xterm_color(component(0), component(2), component(4))
+                .to_string(),
+        )
+    } else if !color.is_empty()
+        && color.chars().all(|c| c.is_ascii_alphabetic())
+    {
+        Some(color.to_lowercase())
+    } else {
+        None
+    }
+}
+
+/// Get the color attribute of a font or span element.
+fn color_style(attributes: &[(String, String)]) -> Option<String> {
+    let attribute = |names: &[&str]| {
+        attributes
+            .iter()
+            .find(|(k, _)| names.contains(&k.as_str()))
+            .and_then(|(_, v)| weechat_color_name(v))
+    };
+
+    let foreground = attribute(&["data-mx-color", "color"]);
+    let background = attribute(&["data-mx-bg-color"]);
+
+    let color = match (foreground, background) {
+        (Some(f), Some(b)) => format!("{},{}", f, b),
+        (Some(f), None) => f,
+        (None, Some(b)) => format!("default,{}", b),
+        (None, None) => return None,
+    };
+
+    Some(Weechat::color(&color).to_owned())
+}
+
+impl Renderer {
+    fn new() -> Self {
+        Self {
+            line_start: true,
+            ..Default::default()
+        }
+    }
+
+    fn in_pre(&self) -> bool {
+        self.stack.iter().any(|e| e.name == "pre")
+    }
+
+    /// Write the line prefix and the active attributes if we're at the start
+    /// of a line.
+    fn start_line(&mut self) {
+        if !self.line_start {
+            return;
+        }
+
+        self.line_start = false;
+
+        let quote_depth =
+            self.stack.iter().filter(|e| e.name == "blockquote").count();
+
+        if quote_depth > 0 {
+            self.output.push_str(Weechat::color("chat_delimiters"));
+            self.output.push_str(&"> ".repeat(quote_depth));
+            self.output.push_str(Weechat::color("reset"));
+        }
+
+        for style in self.stack.iter().filter_map(|e| e.style.as_ref()) {
+            self.output.push_str(style);
+        }
+    }
+
+    fn newline(&mut self) {
+        if self.skip_depth > 0 {
+            return;
+        }
+
+        let trimmed_len = self.output.trim_end_matches(' ').len();
+        self.output.truncate(trimmed_len);
+        self.output.push('\n');
+        self.line_start = true;
+
+        // Open elements might have started inside the trimmed spaces.
+        for element in &mut self.stack {
+            element.start = element.start.min(trimmed_len);
+        }
+    }
+
+    /// Start a new line unless we're already at the start of one.
+    fn ensure_newline(&mut self) {
+        if !self.line_start && !self.output.is_empty() {
+            self.newline();
+        }
+    }
+
+    fn space(&mut self) {
+        if !self.line_start && !self.output.ends_with(' ') {
+            self.output.push(' ');
+        }
+    }
+
+    fn write(&mut self, text: &str) {
+        if self.skip_depth > 0 {
+            return;
+        }
+
+        self.start_line();
+        self.output.push_str(text);
+    }
+
+    fn text(&mut self, text: &str) {
+        if self.skip_depth > 0 || text.is_empty() {
+            return;
+        }
+
+        let text = decode_entities(text);
+
+        if self.in_pre() {
+            let mut lines = text.split('\n').peekable();
+
+            while let Some(line) = lines.next() {
+                if !line.is_empty() {
+                    self.write(line);
+                }
+
+                if lines.peek().is_some() {
+                    self.newline();
+                }
+            }
+        } else {
+            // Whitespace outside of preformatted text collapses into a single
+            // space and is ignored at the start of a line.
+            for (i, word) in text.split_whitespace().enumerate() {
+                if i > 0 || text.starts_with(char::is_whitespace) {
+                    self.space();
+                }
+
+                self.write(word);
+            }
+
+            if text.ends_with(char::is_whitespace) {
+                self.space();
+            }
+        }
+    }
+
+    /// Re-apply all the active attributes after one of them got removed.
+    fn restore_styles(&mut self) {
+        if self.line_start || self.skip_depth > 0 {
+            return;
+        }
+
+        self.output.push_str(Weechat::color("reset"));
+
+        for style in self.stack.iter().filter_map(|e| e.style.as_ref()) {
+            self.output.push_str(style);
+        }
+    }
+
+    fn open_tag(&mut self, name: String, attributes: Vec<(String, String)>) {
+        if BLOCK_TAGS.contains(&name.as_str()) {
+            self.ensure_newline();
+        }
+
+        match name.as_str() {
+            "br" => {
+                self.newline();
+                return;
+            }
+            "hr" => {
+                self.ensure_newline();
+                self.write("---");
+                self.newline();
+                return;
+            }
+            "img" => {
+                let alt = attributes
+                    .iter()
+                    .find(|(k, _)| k == "alt")
+                    .map(|(_, v)| v.clone());
+
+                if let Some(alt) = alt {
+                    self.text(&alt);
+                }
+                return;
+            }
+            "li" => {
+                let depth = self.lists.len().max(1);
+                let marker = match self.lists.last_mut() {
+                    Some(Some(counter)) => {
+                        *counter += 1;
+                        format!("{}. ", counter)
+                    }
+                    _ => "• ".to_owned(),
+                };
+
+                self.write(&"  ".repeat(depth - 1));
+                self.write(&marker);
+            }
+            "ul" => self.lists.push(None),
+            "ol" => {
+                let start = attributes
+                    .iter()
+                    .find(|(k, _)| k == "start")
+                    .and_then(|(_, v)| v.parse::<usize>().ok())
+                    .unwrap_or(1);
+                self.lists.push(Some(start.saturating_sub(1)));
+            }
+            "mx-reply" => self.skip_depth += 1,
+            _ => (),
+        }
+
+        let style = match name.as_str() {
+            "b" | "strong" | "h1" | "h2" | "h3" | "h4" | "h5" | "h6" => {
+                Some(Weechat::color("bold").to_owned())
+            }
+            "i" | "em" => Some(Weechat::color("italic").to_owned()),
+            "u" | "ins" => Some(Weechat::color("underline").to_owned()),
+            "code" => Some(Weechat::color("blue").to_owned()),
+            "font" | "span" => color_style(&attributes),
+            _ => None,
+        };
+
+        if let Some(style) = &style {
+            if !self.line_start && self.skip_depth == 0 {
+                self.output.push_str(style);
+            }
+        }
+
+        let href = if name == "a" {
+            attributes
+                .into_iter()
+                .find(|(k, _)| k == "href")
+                .map(|(_, v)| v)
+        } else {
+            None
+        };
+
+        self.stack.push(Element {
+            name,
+            style,
+            href,
+            start: self.output.len(),
+        });
+    }
+
+    fn close_element(&mut self, element: Element) {
+        match element.name.as_str() {
+            "ul" | "ol" => {
+                self.lists.pop();
+            }
+            "mx-reply" => self.skip_depth = self.skip_depth.saturating_sub(1),
+            "a" => {
+                if let Some(href) = element.href {
+                    let content =
+                        self.output.get(element.start..).unwrap_or_default();
+                    let text = Weechat::remove_color(content).trim().to_owned();
+
+                    if !href.is_empty()
+                        && text != href
+                        && !href.starts_with(MATRIX_TO_PREFIX)
+                    {
+                        if text.is_empty() {
+                            self.write(&href);
+                        } else {
+                            self.write(&format!(" ({})", href));
+                        }
+                    }
+                }
+            }
+            _ => (),
+        }
+
+        if element.style.is_some() {
+            self.restore_styles();
+        }
+
+        if BLOCK_TAGS.contains(&element.name.as_str()) {
+            self.ensure_newline();
+        }
+    }
+
+    fn close_tag(&mut self, name: &str) {
+        if !self.stack.iter().any(|e| e.name == name) {
+            return;
+        }
+
+        while let Some(element) = self.stack.pop() {
+            let found = element.name == name;
+            self.close_element(element);
+
+            if found {
+                break;
+            }
+        }
+    }
+
+    fn tag(&mut self, tag: &str) {
+        let (name, closing, attributes) = parse_tag(tag);
+
+        if name.is_empty() {
+            return;
+        }
+
+        if closing {
+            self.close_tag(&name);
+        } else {
+            let self_closing = tag.trim_end().ends_with('/')
+                && !VOID_TAGS.contains(&name.as_str());
+
+            self.open_tag(name.clone(), attributes);
+
+            if self_closing {
+                self.close_tag(&name);
+            }
+        }
+    }
+
+    fn finish(mut self) -> String {
+        while let Some(element) = self.stack.pop() {
+            self.close_element(element);
+        }
+
+        self.output.trim_end().to_owned()
+    }
+}
+
+/// Find the end of a tag, ignoring any `>` characters in quoted attribute
+/// values.
+fn find_tag_end(tag: &str) -> Option<usize> {
+    let mut quote = None;
+
+    for (i, c) in tag.char_indices() {
+        match (quote, c) {
+            (None, '"') | (None, '\'') => quote = Some(c),
+            (Some(q), c) if q == c => quote = None,
+            (None, '>') => return Some(i),
+            _ => (),
+        }
+    }
+
+    None
+}
+
+/// Render the given HTML into a string containing Weechat color codes.
+pub fn render(html: &str) -> String {
+    let mut renderer = Renderer::new();
+    let mut rest = html;
+
+    while let Some(start) = rest.find('<') {
+        renderer.text(&rest[..start]);
+        rest = &rest[start..];
+
+        if let Some(comment) = rest.strip_prefix("<!--") {
+            rest = comment.find("-->").map_or("", |end| &comment[end + 3..]);
+            continue;
+        }
+
+        if let Some(end) = find_tag_end(&rest[1..]) {
+            renderer.tag(&rest[1..end + 1]);
+            rest = &rest[end + 2..];
+        } else {
+            // Not a tag, treat the rest as text.
+            renderer.text(rest);
+            rest = "";
+        }
+    }
+
+    renderer.text(rest);
+    renderer.finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Stand-in for the Weechat color functions, which need a running
+    /// Weechat. Colors render as nothing so the output is plain text.
+    pub struct Weechat;
+
+    impl Weechat {
+        pub fn color(_: &str) -> &'static str {
+            ""
+        }
+
+        pub fn remove_color(string: &str) -> String {
+            string.to_owned()
+        }
+    }
+
+    #[test]
+    fn link_text_and_target() {
+        assert_eq!(
+            render("<a href=\"https://example.org\">example</a>"),
+            "example (https://example.org)"
+        );
+        assert_eq!(
+            render("<a href=\"https://example.org\">https://example.org</a>"),
+            "https://example.org"
+        );
+    }
+
+    #[test]
+    fn empty_link_shows_target() {
+        assert_eq!(
+            render("<a href=\"https://example.org\"></a>"),
+            "https://example.org"
+        );
+    }
+
+    #[test]
+    fn link_starting_after_trimmed_spaces() {
+        assert_eq!(
+            render("<pre>x  <a href=\"https://e\">\n</a></pre>"),
+            "x\nhttps://e"
+        );
+    }
+
+    #[test]
+    fn link_crossing_a_line_break() {
+        assert_eq!(
+            render("foo  <a href=\"https://e\"><br>bar</a>"),
+            "foo\nbar (https://e)"
+        );
+        assert_eq!(
+            render("<pre>a <a href=\"https://e\">b  \nc</a></pre>"),
+            "a b\nc (https://e)"
+        );
+    }
+
+    #[test]
+    fn matrix_link_crossing_a_line_break() {
+        let html = "hi  <a href=\"https://matrix.to/#/@alice:example.org\">\
+                    <br>Alice</a>";
+
+        assert_eq!(render(html), "hi\nAlice");
+    }
+}
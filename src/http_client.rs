@@ -0,0 +1,100 @@
+//! HTTP client module.
+//!
+//! The SDK creates its own HTTP client which only allows a proxy to be
+//! configured and certificate verification to be disabled. Servers that use a
+//! certificate signed by a custom root certificate need a client that trusts
+//! that certificate, this module provides such a client.
+//!
+//! The client is created per server, so the TLS configuration of one server
+//! never affects the other servers.
+
+use std::path::Path;
+
+use async_trait::async_trait;
+use url::Url;
+
+use matrix_sdk::{HttpSend, Result as MatrixResult};
+
+/// A HTTP client that can be configured with a custom root certificate.
+#[derive(Clone, Debug)]
+pub struct HttpClient {
+    client: reqwest::Client,
+}
+
+impl HttpClient {
+    /// Create a new HTTP client.
+    ///
+    /// # Arguments
+    ///
+    /// * `ca_file` - Path to a PEM encoded root certificate that should be
+    /// trusted in addition to the system root certificates.
+    ///
+    /// * `proxy` - The URL of the proxy all requests should go through.
+    ///
+    /// * `verify` - Should the certificate of the server be verified.
+    pub fn new(
+        ca_file: &Path,
+        proxy: Option<&Url>,
+        verify: bool,
+    ) -> Result<Self, String> {
+        let pem = std::fs::read(ca_file).map_err(|e| {
+            format!("Can't read certificate {}: {}", ca_file.display(), e)
+        })?;
+
+        let certificate =
+            reqwest::Certificate::from_pem(&pem).map_err(|e| {
+                format!("Invalid certificate {}: {}", ca_file.display(), e)
+            })?;
+
+        let mut builder = reqwest::Client::builder()
+            .add_root_certificate(certificate)
+            .danger_accept_invalid_certs(!verify);
+
+        if let Some(proxy) = proxy {
+            let proxy = reqwest::Proxy::all(proxy.as_str())
+                .map_err(|e| format!("Invalid proxy {}: {}", proxy, e))?;
+            builder = builder.proxy(proxy);
+        }
+
+        let client = builder
+            .build()
+            .map_err(|e| format!("Can't create the HTTP client: {}", e))?;
+
+        Ok(Self { client })
+    }
+}
+
+#[async_trait]
+impl HttpSend for HttpClient {
+    async fn send_request(
+        &self,
+        request: http::Request<Vec<u8>>,
+    ) -> MatrixResult<http::Response<Vec<u8>>> {
+        let (parts, body) = request.into_parts();
+
+        let response = self
+            .client
+            .request(parts.method, &parts.uri.to_string())
+            .headers(parts.headers)
+            .body(body)
+            .send()
+            .await?;
+
+        let mut builder = http::Response::builder().status(response.status());
+
+        if let Some(headers) = builder.headers_mut() {
+            headers.extend(
+                response
+                    .headers()
+                    .iter()
+                    .map(|(k, v)| (k.clone(), v.clone())),
+            );
+        }
+
+        let body = response.bytes().await?.to_vec();
+
+        Ok(builder
+            .body(body)
+            .expect("Can't construct a response from a valid response"))
+    }
+}
@@ -1,12 +1,26 @@
 #![feature(get_mut_unchecked)]
 
 mod commands;
+mod completion;
 mod config;
 mod connection;
 mod debug;
+mod discovery;
+mod drafts;
+#[cfg(test)]
+mod e2e_tests;
+mod edit;
+mod html;
+mod http_client;
+mod links;
+mod markdown;
+mod redaction_log;
 mod render;
+mod reply;
 mod room;
 mod server;
+mod session;
+mod thread;
 
 use std::{
     cell::{Ref, RefCell, RefMut},
@@ -21,12 +35,19 @@ use weechat::{
 };
 
 use crate::{
-    commands::Commands, config::ConfigHandle, room::RoomHandle,
+    commands::Commands, completion::Completions, config::ConfigHandle,
+    connection::ConnectionState, debug::Logging, room::RoomHandle,
     server::MatrixServer,
 };
 
 const PLUGIN_NAME: &str = "matrix";
 
+/// The name of the bar item showing the connection state of a server.
+const CONNECTION_BAR_ITEM: &str = "matrix_connection";
+
+/// The name of the bar item showing if the current room is encrypted.
+const MODES_BAR_ITEM: &str = "matrix_modes";
+
 #[derive(Clone, Debug)]
 pub struct Servers(Rc<RefCell<HashMap<String, MatrixServer>>>);
 
@@ -115,12 +136,17 @@ struct Matrix {
     #[used]
     commands: Commands,
     #[used]
+    completions: Completions,
+    #[used]
     config: ConfigHandle,
     #[used]
     status_bar: BarItem,
     #[used]
+    connection_bar: BarItem,
+    #[used]
     typing_notice_signal: SignalHook,
     debug_buffer: RefCell<Option<BufferHandle>>,
+    logging: Logging,
 }
 
 impl std::fmt::Debug for Matrix {
@@ -179,19 +205,52 @@ impl BarItemCallback for Servers {
     }
 }
 
+/// Bar item callback showing the connection state of the server the current
+/// buffer belongs to.
+struct ConnectionBarItem(Servers);
+
+impl BarItemCallback for ConnectionBarItem {
+    fn callback(&mut self, _: &Weechat, buffer: &Buffer) -> String {
+        let server = if let Some(s) = self.0.find_server(buffer) {
+            s
+        } else {
+            return "".to_owned();
+        };
+
+        let server = server.inner();
+
+        let (color, state) = match server.connection_state() {
+            ConnectionState::Disconnected => ("default", "disconnected"),
+            ConnectionState::Connecting => ("yellow", "connecting"),
+            ConnectionState::Syncing => ("green", "syncing"),
+            ConnectionState::Offline => ("cyan", "offline"),
+            ConnectionState::Error(_) => ("red", "error"),
+        };
+
+        format!(
+            "{}{}{}",
+            Weechat::color(color),
+            state,
+            Weechat::color("reset")
+        )
+    }
+}
+
 impl Plugin for Matrix {
     fn init(_: &Weechat, _args: Args) -> Result<Self, ()> {
         let servers = Servers::new();
         let config = ConfigHandle::new(&servers);
         let commands = Commands::hook_all(&servers, &config)?;
+        let completions = Completions::hook_all(&servers)?;
 
         // TODO move the bar creation into a separate file.
-        let status_bar = BarItem::new("matrix_modes", servers.clone())?;
+        let status_bar = BarItem::new(MODES_BAR_ITEM, servers.clone())?;
+        let connection_bar = BarItem::new(
+            CONNECTION_BAR_ITEM,
+            ConnectionBarItem(servers.clone()),
+        )?;
 
-        tracing_subscriber::fmt()
-            .with_writer(debug::Debug)
-            .with_env_filter(tracing_subscriber::EnvFilter::from_default_env())
-            .init();
+        let logging = Logging::init();
 
         {
             let config_borrow = config.borrow();
@@ -213,9 +272,12 @@ impl Plugin for Matrix {
         let plugin = Matrix {
             servers: servers.clone(),
             commands,
+            completions,
             config,
             status_bar,
+            connection_bar,
             debug_buffer: RefCell::new(None),
+            logging,
             typing_notice_signal: typing,
         };
 
@@ -240,6 +302,7 @@ impl Drop for Matrix {
         // the rooms, once leaving the rooms is implemented when the buffer gets
         // closed.
         for server in servers.values_mut() {
+            server.save_drafts();
             server.disconnect();
         }
     }
@@ -0,0 +1,68 @@
+//! Finding web links in rendered messages.
+
+/// The URL schemes that are recognized as links.
+const URL_SCHEMES: &[&str] = &["https://", "http://"];
+
+/// Characters that are usually punctuation following a link instead of being
+/// part of it.
+const TRAILING_PUNCTUATION: &[char] =
+    &['.', ',', ':', ';', '!', '?', '\'', '"', ')', ']', '>'];
+
+/// Get the length of the URL at the start of the given string, if it starts
+/// with one.
+fn url_length(text: &str) -> Option<usize> {
+    let scheme = URL_SCHEMES.iter().find(|s| text.starts_with(*s))?;
+
+    // A URL ends at whitespace or at the start of a Weechat color code.
+    let end = text
+        .find(|c: char| c.is_whitespace() || c.is_control())
+        .unwrap_or_else(|| text.len());
+    let url = text[..end].trim_end_matches(TRAILING_PUNCTUATION);
+
+    if url.len() > scheme.len() {
+        Some(url.len())
+    } else {
+        None
+    }
+}
+
+/// Find the first web link in the given text.
+pub fn find_url(text: &str) -> Option<&str> {
+    text.char_indices()
+        .find_map(|(i, _)| url_length(&text[i..]).map(|l| &text[i..i + l]))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn finds_the_first_url() {
+        assert_eq!(
+            find_url("see https://example.org and http://example.com"),
+            Some("https://example.org")
+        );
+        assert_eq!(find_url("no links here"), None);
+    }
+
+    #[test]
+    fn trailing_punctuation_is_not_part_of_the_url() {
+        assert_eq!(
+            find_url("(look at https://example.org/a?b=c)."),
+            Some("https://example.org/a?b=c")
+        );
+    }
+
+    #[test]
+    fn url_ends_at_a_color_code() {
+        assert_eq!(
+            find_url("https://example.org\x1928bold"),
+            Some("https://example.org")
+        );
+    }
+
+    #[test]
+    fn bare_scheme_is_not_a_url() {
+        assert_eq!(find_url("https:// and http://."), None);
+    }
+}
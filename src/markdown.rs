@@ -0,0 +1,102 @@
+//! Markdown module.
+//!
+//! Converts the input of a room buffer into a plain text body and an optional
+//! HTML formatted body.
+//!
+//! The formatted body is only produced if the input actually contains some
+//! Markdown, plain text input is sent out unmodified so we don't bloat the
+//! message with a needless `formatted_body`.
+
+use pulldown_cmark::{html, Event, Options, Parser, Tag};
+
+/// The result of parsing the user input as Markdown.
+pub struct ParsedMarkdown {
+    /// The plain text body of the message.
+    pub body: String,
+    /// The HTML formatted body, if the input contained any formatting.
+    pub formatted: Option<String>,
+}
+
+/// Does the event only carry text or does it format the text in some way.
+fn is_plain(event: &Event) -> bool {
+    matches!(
+        event,
+        Event::Start(Tag::Paragraph)
+            | Event::End(Tag::Paragraph)
+            | Event::Text(_)
+            | Event::SoftBreak
+            | Event::HardBreak
+    )
+}
+
+/// Collect the text of plain Markdown events.
+///
+/// Paragraphs are separated by an empty line while line breaks are preserved,
+/// this reverses the parsing step for input that contains no formatting.
+fn plain_text(events: &[Event]) -> String {
+    let mut text = String::new();
+
+    for event in events {
+        match event {
+            Event::Start(Tag::Paragraph) if !text.is_empty() => {
+                text.push_str("\n\n")
+            }
+            Event::Text(t) => text.push_str(t),
+            Event::SoftBreak | Event::HardBreak => text.push('\n'),
+            _ => (),
+        }
+    }
+
+    text
+}
+
+/// Strip the paragraph tags if the whole message is a single paragraph.
+fn strip_single_paragraph(formatted: String) -> String {
+    let trimmed = formatted.trim_end();
+
+    if trimmed.starts_with("<p>")
+        && trimmed.ends_with("</p>")
+        && trimmed.matches("<p>").count() == 1
+    {
+        trimmed["<p>".len()..trimmed.len() - "</p>".len()].to_owned()
+    } else {
+        formatted
+    }
+}
+
+/// Parse the given input as Markdown.
+///
+/// If the input contains Markdown formatting the body will be left as is and
+/// the HTML formatted body will be set.
+///
+/// If the input contains no formatting no formatted body will be produced. The
+/// body will have any Markdown escapes resolved, e.g. `\*foo\*` will become
+/// `*foo*`.
+pub fn parse(input: &str) -> ParsedMarkdown {
+    let options = Options::ENABLE_STRIKETHROUGH | Options::ENABLE_TABLES;
+
+    let events: Vec<Event> = Parser::new_ext(input, options)
+        .map(|event| match event {
+            // Matrix clients expect newlines to be preserved.
+            Event::SoftBreak => Event::HardBreak,
+            // Don't let raw HTML through, escape it instead.
+            Event::Html(html) => Event::Text(html),
+            e => e,
+        })
+        .collect();
+
+    if events.iter().all(is_plain) {
+        return ParsedMarkdown {
+            body: plain_text(&events),
+            formatted: None,
+        };
+    }
+
+    let mut formatted = String::new();
+    html::push_html(&mut formatted, events.into_iter());
+
+    ParsedMarkdown {
+        body: input.to_owned(),
+        formatted: Some(strip_single_paragraph(formatted)),
+    }
+}
@@ -0,0 +1,51 @@
+//! Redaction log.
+//!
+//! The messages we delete can be recorded in a log file in the directory of
+//! the server, giving moderators a record of what they removed. Every
+//! redaction is appended to the file as a single tab separated line.
+
+use std::{
+    fs::OpenOptions,
+    io::{self, Write},
+    path::Path,
+};
+
+use chrono::Local;
+
+use matrix_sdk::identifiers::{EventId, RoomId};
+
+const LOG_FILE: &str = "redactions.log";
+
+/// A message that we deleted.
+pub struct RedactionLogEntry<'a> {
+    pub room_id: &'a RoomId,
+    pub event_id: &'a EventId,
+    pub reason: Option<&'a str>,
+    /// A single line preview of the message, if the message was known
+    /// locally.
+    pub message: Option<&'a str>,
+}
+
+/// Append the given entry to the redaction log in the given server directory.
+pub fn append(server_path: &Path, entry: &RedactionLogEntry) -> io::Result<()> {
+    // Tabs separate the fields, they can't be part of one.
+    let field =
+        |f: Option<&str>| f.map_or("-".to_owned(), |f| f.replace('\t', " "));
+
+    std::fs::create_dir_all(server_path)?;
+
+    let mut file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(server_path.join(LOG_FILE))?;
+
+    writeln!(
+        file,
+        "{}\t{}\t{}\treason: {}\tmessage: {}",
+        Local::now().to_rfc3339(),
+        entry.room_id,
+        entry.event_id,
+        field(entry.reason),
+        field(entry.message),
+    )
+}
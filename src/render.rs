@@ -9,9 +9,10 @@ use matrix_sdk::{
             message::{
                 AudioMessageEventContent, EmoteMessageEventContent,
                 FileMessageEventContent, ImageMessageEventContent,
-                LocationMessageEventContent, NoticeMessageEventContent,
-                RedactedMessageEventContent, ServerNoticeMessageEventContent,
-                TextMessageEventContent, VideoMessageEventContent,
+                LocationMessageEventContent, MessageFormat,
+                NoticeMessageEventContent, RedactedMessageEventContent,
+                ServerNoticeMessageEventContent, TextMessageEventContent,
+                VideoMessageEventContent,
             },
         },
         RedactedSyncMessageEvent, SyncStateEvent,
@@ -22,7 +23,12 @@ use matrix_sdk::{
 
 use weechat::Weechat;
 
-use crate::room::WeechatRoomMember;
+use crate::{html, reply, room::WeechatRoomMember, PLUGIN_NAME};
+
+/// Build the tag that marks every line of the event with the given id.
+pub fn event_id_tag(event_id: &EventId) -> String {
+    format!("{}_id_{}", PLUGIN_NAME, event_id.as_str())
+}
 
 /// The rendered version of an event.
 #[allow(dead_code)]
@@ -64,7 +70,7 @@ pub trait Render {
 
     fn event_tags(&self, event_id: &EventId, sender: &UserId) -> Vec<String> {
         let mut tags = self.tags();
-        let event_tag = format!("matrix_id_{}", event_id.as_str());
+        let event_tag = event_id_tag(event_id);
         let sender_tag = format!("matrix_sender_{}", sender.as_str());
         tags.push(event_tag);
         tags.push(sender_tag);
@@ -75,7 +81,7 @@ pub trait Render {
     fn prefix(&self, sender: &WeechatRoomMember) -> String {
         format!(
             "{}{}{}",
-            Weechat::color(&sender.color),
+            Weechat::color(&sender.color.borrow()),
             sender.nick.borrow(),
             Weechat::color("reset")
         )
@@ -155,15 +161,23 @@ impl Render for TextMessageEventContent {
     type RenderContext = ();
 
     fn render(&self, _: &Self::RenderContext) -> RenderedContent {
-        let lines = self
-            .body
+        // The formatted reply fallback is skipped by the HTML renderer, the
+        // plain one needs to be stripped manually.
+        let body = if self.formatted_body().is_none()
+            && reply::in_reply_to(self).is_some()
+        {
+            reply::strip_plain_fallback(&self.body).to_owned()
+        } else {
+            self.render_body()
+        };
+
+        let lines = body
             .lines()
             .map(|l| RenderedLine {
                 message: l.to_owned(),
                 tags: self.tags(),
             })
             .collect();
-        // TODO parse and render using the formattted body.
         RenderedContent { lines }
     }
 }
@@ -177,16 +191,28 @@ impl Render for EmoteMessageEventContent {
     }
 
     fn render(&self, sender: &Self::RenderContext) -> RenderedContent {
-        // TODO parse and render using the formattted body.
-        // TODO handle multiple lines in the body.
-        let message = format!("{} {}", sender.nick.borrow(), self.body);
+        let body = self.render_body();
+        let mut body_lines = body.lines();
+
+        // Only the first line gets the nick of the sender, the rest are
+        // continuation lines.
+        let first_line = format!(
+            "{}{}{} {}",
+            Weechat::color(&sender.color.borrow()),
+            sender.nick.borrow(),
+            Weechat::color("reset"),
+            body_lines.next().unwrap_or_default()
+        );
 
-        let line = RenderedLine {
-            message,
-            tags: self.tags(),
-        };
+        let lines = std::iter::once(first_line)
+            .chain(body_lines.map(|l| l.to_owned()))
+            .map(|message| RenderedLine {
+                message,
+                tags: self.tags(),
+            })
+            .collect();
 
-        RenderedContent { lines: vec![line] }
+        RenderedContent { lines }
     }
 }
 
@@ -227,12 +253,27 @@ impl Render for NoticeMessageEventContent {
     }
 
     fn render(&self, sender: &Self::RenderContext) -> RenderedContent {
-        // TODO parse and render using the formattted body.
+        // Notices are shown in italics so they stand out from the regular
+        // conversation.
+        let body = self
+            .render_body()
+            .lines()
+            .map(|l| {
+                format!(
+                    "{}{}{}",
+                    Weechat::color("italic"),
+                    l,
+                    Weechat::color("-italic")
+                )
+            })
+            .collect::<Vec<_>>()
+            .join("\n");
+
         let message = format!(
             "{prefix}{color_notice}Notice\
             {color_delim}({color_reset}{}{color_delim}){color_reset}: {}",
             sender.nick.borrow(),
-            self.body,
+            body,
             prefix = Weechat::prefix("network"),
             color_notice = Weechat::color("irc.color.notice"),
             color_delim = Weechat::color("chat_delimiters"),
@@ -306,9 +347,22 @@ impl Render for EncryptedEventContent {
     const TAGS: &'static [&'static str] = &["matrix_encrypted"];
     type RenderContext = ();
 
+    /// The lines of undecryptable events are tagged with the session id, so
+    /// they can be found once the room key for the session arrives.
+    fn tags(&self) -> Vec<String> {
+        let mut tags: Vec<String> =
+            Self::TAGS.iter().map(|t| t.to_string()).collect();
+
+        if let EncryptedEventContent::MegolmV1AesSha2(c) = self {
+            tags.push(format!("matrix_session_{}", c.session_id));
+        }
+
+        tags
+    }
+
     fn render(&self, _: &Self::RenderContext) -> RenderedContent {
         let message = format!(
-            "{}<{}Unable to decrypt message{}>{}",
+            "{}<{}🔒 Unable to decrypt message, waiting for keys{}>{}",
             Weechat::color("chat_delimiters"),
             Weechat::color("logger.color.backlog_line"),
             Weechat::color("chat_delimiters"),
@@ -317,8 +371,6 @@ impl Render for EncryptedEventContent {
 
         let line = RenderedLine {
             message,
-            // TODO add tags that allow us decrypt the event at a later point in
-            // time, sender key, algorithm, session id.
             tags: self.tags(),
         };
 
@@ -353,6 +405,9 @@ impl Render for RedactedSyncMessageEvent<RedactedMessageEventContent> {
 /// Trait for message event types that contain an optional formatted body.
 /// `resolve_body` will return the formatted body if present, else fallback to
 /// the regular body.
+///
+/// `render_body` will return the formatted body converted to Weechat colors if
+/// present, else fallback to the regular body.
 trait HasFormattedBody {
     fn body(&self) -> &str;
     fn formatted_body(&self) -> Option<&str>;
@@ -360,6 +415,11 @@ trait HasFormattedBody {
     fn resolve_body(&self) -> &str {
         self.formatted_body().unwrap_or_else(|| self.body())
     }
+
+    fn render_body(&self) -> String {
+        self.formatted_body()
+            .map_or_else(|| self.body().to_owned(), html::render)
+    }
 }
 
 // Repeating this for each event type would get boring fast so lets use a simple
@@ -375,7 +435,10 @@ macro_rules! has_formatted_body {
 
             #[inline]
             fn formatted_body(&self) -> Option<&str> {
-                self.formatted.as_ref().map(|f| f.body.as_ref())
+                self.formatted
+                    .as_ref()
+                    .filter(|f| matches!(f.format, MessageFormat::Html))
+                    .map(|f| f.body.as_ref())
             }
         }
     };
@@ -582,3 +645,32 @@ pub fn render_membership(
         ),
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::convert::TryFrom;
+
+    use super::*;
+
+    #[test]
+    fn event_tags_contain_the_event_id_tag() {
+        let event_id = EventId::try_from("$abc:example.org").unwrap();
+        let sender = UserId::try_from("@alice:example.org").unwrap();
+
+        let tags = TextMessageEventContent::plain("Hello")
+            .event_tags(&event_id, &sender);
+
+        assert!(tags.contains(&event_id_tag(&event_id)));
+        assert!(tags.contains(&"matrix_text".to_owned()));
+        assert!(tags.contains(&"matrix_sender_@alice:example.org".to_owned()));
+    }
+
+    #[test]
+    fn event_id_tags_are_unique() {
+        let first = EventId::try_from("$abc:example.org").unwrap();
+        let second = EventId::try_from("$abcd:example.org").unwrap();
+
+        assert_eq!(event_id_tag(&first), "matrix_id_$abc:example.org");
+        assert_ne!(event_id_tag(&first), event_id_tag(&second));
+    }
+}
@@ -0,0 +1,217 @@
+//! Reply module.
+//!
+//! Builds and strips the reply fallbacks Matrix clients put into the body and
+//! formatted body of a reply, so clients without reply support can still show
+//! which message is being replied to.
+
+use unicode_segmentation::UnicodeSegmentation;
+
+use matrix_sdk::{
+    events::room::message::{
+        FormattedBody, InReplyTo, MessageEventContent, MessageFormat, Relation,
+        TextMessageEventContent,
+    },
+    identifiers::{EventId, RoomId, UserId},
+};
+
+/// The maximum number of characters of the parent message that a reply preview
+/// shows.
+const PREVIEW_LENGTH: usize = 50;
+
+/// The message a reply is replying to.
+pub struct ReplyParent<'a> {
+    pub room_id: &'a RoomId,
+    pub event_id: &'a EventId,
+    pub sender: &'a UserId,
+    pub content: &'a MessageEventContent,
+}
+
+/// Get the plain text body of a message.
+pub fn content_body(content: &MessageEventContent) -> &str {
+    use MessageEventContent::*;
+
+    match content {
+        Audio(c) => &c.body,
+        Emote(c) => &c.body,
+        File(c) => &c.body,
+        Image(c) => &c.body,
+        Location(c) => &c.body,
+        Notice(c) => &c.body,
+        ServerNotice(c) => &c.body,
+        Text(c) => &c.body,
+        Video(c) => &c.body,
+    }
+}
+
+/// Get the HTML formatted body of a message, if it has one.
+fn content_formatted_body(content: &MessageEventContent) -> Option<&str> {
+    use MessageEventContent::*;
+
+    let formatted = match content {
+        Emote(c) => c.formatted.as_ref(),
+        Notice(c) => c.formatted.as_ref(),
+        Text(c) => c.formatted.as_ref(),
+        _ => None,
+    };
+
+    formatted
+        .filter(|f| f.format == MessageFormat::Html)
+        .map(|f| f.body.as_str())
+}
+
+/// Get the event id of the message a text message is replying to.
+pub fn in_reply_to(content: &TextMessageEventContent) -> Option<&EventId> {
+    if let Some(Relation::Reply { in_reply_to }) = &content.relates_to {
+        Some(&in_reply_to.event_id)
+    } else {
+        None
+    }
+}
+
+/// Remove the reply fallback from the plain text body of a reply.
+///
+/// The fallback consists of the quoted lines at the start of the body followed
+/// by an empty line.
+pub fn strip_plain_fallback(body: &str) -> &str {
+    if !body.starts_with("> ") {
+        return body;
+    }
+
+    match body.find("\n\n") {
+        Some(end) if body[..end].lines().all(|l| l.starts_with('>')) => {
+            &body[end + 2..]
+        }
+        _ => body,
+    }
+}
+
+/// Remove the reply fallback from the formatted body of a reply.
+pub fn strip_html_fallback(html: &str) -> &str {
+    const END_TAG: &str = "</mx-reply>";
+
+    if html.starts_with("<mx-reply>") {
+        if let Some(end) = html.find(END_TAG) {
+            return &html[end + END_TAG.len()..];
+        }
+    }
+
+    html
+}
+
+fn escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\n' => escaped.push_str("<br />"),
+            c => escaped.push(c),
+        }
+    }
+
+    escaped
+}
+
+/// Create the plain text reply fallback, the parent message quoted and
+/// attributed to its sender.
+fn plain_fallback(parent: &ReplyParent) -> String {
+    let body = strip_plain_fallback(content_body(parent.content));
+    let emote = if let MessageEventContent::Emote(_) = parent.content {
+        "* "
+    } else {
+        ""
+    };
+
+    let mut lines = body.lines();
+    let first_line = format!(
+        "> {}<{}> {}",
+        emote,
+        parent.sender,
+        lines.next().unwrap_or_default()
+    );
+
+    std::iter::once(first_line)
+        .chain(lines.map(|l| format!("> {}", l)))
+        .collect::<Vec<String>>()
+        .join("\n")
+}
+
+/// Create the HTML reply fallback.
+fn html_fallback(parent: &ReplyParent) -> String {
+    let parent_html = content_formatted_body(parent.content)
+        .map(|f| strip_html_fallback(f).to_owned())
+        .unwrap_or_else(|| {
+            escape(strip_plain_fallback(content_body(parent.content)))
+        });
+
+    format!(
+        "<mx-reply><blockquote>\
+         <a href=\"https://matrix.to/#/{room_id}/{event_id}\">In reply to</a> \
+         <a href=\"https://matrix.to/#/{sender}\">{sender}</a><br />\
+         {parent}</blockquote></mx-reply>",
+        room_id = parent.room_id,
+        event_id = parent.event_id,
+        sender = parent.sender,
+        parent = parent_html,
+    )
+}
+
+/// Create the content of a reply.
+///
+/// If the parent message is known the reply fallbacks are prepended to the
+/// body and formatted body of the reply, otherwise only the relation to the
+/// parent is added.
+pub fn reply_content(
+    event_id: &EventId,
+    parent: Option<ReplyParent>,
+    body: String,
+    formatted: Option<FormattedBody>,
+) -> MessageEventContent {
+    let relates_to = Some(Relation::Reply {
+        in_reply_to: InReplyTo {
+            event_id: event_id.clone(),
+        },
+    });
+
+    let (body, formatted) = if let Some(parent) = parent {
+        let formatted_body =
+            formatted.map(|f| f.body).unwrap_or_else(|| escape(&body));
+
+        let formatted = FormattedBody {
+            format: MessageFormat::Html,
+            body: format!("{}{}", html_fallback(&parent), formatted_body),
+        };
+
+        (
+            format!("{}\n\n{}", plain_fallback(&parent), body),
+            Some(formatted),
+        )
+    } else {
+        (body, formatted)
+    };
+
+    MessageEventContent::Text(TextMessageEventContent {
+        body,
+        formatted,
+        relates_to,
+    })
+}
+
+/// Create a single line preview of the message that is being replied to.
+pub fn preview(content: &MessageEventContent) -> String {
+    let body = strip_plain_fallback(content_body(content));
+    let first_line = body.lines().next().unwrap_or_default();
+
+    if first_line.graphemes(true).count() > PREVIEW_LENGTH
+        || body.lines().nth(1).is_some()
+    {
+        let truncated: String =
+            first_line.graphemes(true).take(PREVIEW_LENGTH).collect();
+        format!("{}…", truncated)
+    } else {
+        first_line.to_owned()
+    }
+}
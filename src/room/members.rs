@@ -1,5 +1,9 @@
 use std::{
-    cell::RefCell, collections::HashMap, convert::TryFrom, rc::Rc, sync::Arc,
+    cell::{Cell, RefCell},
+    collections::HashMap,
+    convert::TryFrom,
+    rc::Rc,
+    sync::Arc,
 };
 
 use futures::executor::block_on;
@@ -7,6 +11,7 @@ use tracing::{debug, error, trace};
 
 use matrix_sdk::{
     events::{
+        presence::PresenceState,
         room::member::{
             MemberEventContent,
             MembershipChange::{
@@ -15,7 +20,8 @@ use matrix_sdk::{
             },
             MembershipState,
         },
-        SyncStateEvent,
+        room::power_levels::PowerLevelsEventContent,
+        EventType, SyncStateEvent,
     },
     identifiers::UserId,
     locks::{RwLock, RwLockReadGuard},
@@ -28,34 +34,157 @@ use weechat::{
 };
 
 use super::BUFFER_CLOSED_ERROR;
-use crate::render::render_membership;
+use crate::{
+    config::{Config, NickColorSource},
+    render::render_membership,
+};
 
 #[derive(Clone)]
 pub struct Members {
     room: Arc<RwLock<Room>>,
+    config: Rc<RefCell<Config>>,
     inner: Rc<RefCell<HashMap<UserId, WeechatRoomMember>>>,
+    power_levels: Rc<RefCell<Option<PowerLevels>>>,
     pub(super) buffer: Rc<Option<BufferHandle>>,
 }
 
+/// The power levels of a room, as far as we need them.
+#[derive(Clone, Debug, Default)]
+struct PowerLevels {
+    users: HashMap<UserId, i64>,
+    users_default: i64,
+    events: HashMap<EventType, i64>,
+    events_default: i64,
+    redact: i64,
+}
+
+impl PowerLevels {
+    /// Get the power level of the user with the given user id.
+    fn user_level(&self, user_id: &UserId) -> i64 {
+        self.users
+            .get(user_id)
+            .copied()
+            .unwrap_or(self.users_default)
+    }
+
+    /// Get the power level that is required to send an event of the given
+    /// type.
+    fn event_level(&self, event_type: &EventType) -> i64 {
+        self.events
+            .get(event_type)
+            .copied()
+            .unwrap_or(self.events_default)
+    }
+}
+
+impl From<&PowerLevelsEventContent> for PowerLevels {
+    fn from(content: &PowerLevelsEventContent) -> Self {
+        Self {
+            users: content
+                .users
+                .iter()
+                .map(|(u, l)| (u.clone(), i64::from(*l)))
+                .collect(),
+            users_default: i64::from(content.users_default),
+            events: content
+                .events
+                .iter()
+                .map(|(e, l)| (e.clone(), i64::from(*l)))
+                .collect(),
+            events_default: i64::from(content.events_default),
+            redact: i64::from(content.redact),
+        }
+    }
+}
+
 enum RoomError {
     NonExistentMember(UserId),
 }
 
+/// The nicklist groups members get sorted into, based on their power level.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum MemberGroup {
+    Admin,
+    Moderator,
+    Member,
+}
+
+impl MemberGroup {
+    pub fn from_power_level(power_level: i64) -> Self {
+        if power_level >= 100 {
+            MemberGroup::Admin
+        } else if power_level >= 50 {
+            MemberGroup::Moderator
+        } else {
+            MemberGroup::Member
+        }
+    }
+
+    /// The name of the nicklist group.
+    ///
+    /// Weechat sorts groups by their name and hides everything up to the `|`
+    /// character.
+    fn nicklist_name(self) -> &'static str {
+        match self {
+            MemberGroup::Admin => "000|admins",
+            MemberGroup::Moderator => "001|moderators",
+            MemberGroup::Member => "999|members",
+        }
+    }
+
+    /// The nick prefix of the group, modeled after the IRC channel modes.
+    pub fn prefix(self) -> &'static str {
+        match self {
+            MemberGroup::Admin => "@",
+            MemberGroup::Moderator => "%",
+            MemberGroup::Member => "",
+        }
+    }
+
+    fn prefix_color(self) -> &'static str {
+        match self {
+            MemberGroup::Admin => "lightgreen",
+            MemberGroup::Moderator => "lightmagenta",
+            MemberGroup::Member => "default",
+        }
+    }
+}
+
+/// The kind of a membership change, used to summarize a batch of changes.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MembershipKind {
+    Joined,
+    Left,
+    ProfileChanged,
+    Other,
+}
+
+/// A rendered membership change that still needs to be printed.
+#[derive(Clone, Debug)]
+pub struct MembershipLine {
+    pub kind: MembershipKind,
+    pub timestamp: i64,
+    pub message: String,
+}
+
 #[derive(Clone, Debug)]
 pub struct WeechatRoomMember {
     pub user_id: Rc<UserId>,
     pub nick: Rc<RefCell<String>>,
     pub display_name: Rc<RefCell<Option<String>>>,
     pub prefix: Rc<RefCell<Option<String>>>,
-    #[allow(clippy::rc_buffer)]
-    pub color: Rc<String>,
+    pub color: Rc<RefCell<String>>,
+    /// Is the member away or offline, as far as their presence tells us.
+    pub away: Rc<Cell<bool>>,
 }
 
 impl Members {
-    pub fn new(room: Arc<RwLock<Room>>) -> Self {
+    pub fn new(room: Arc<RwLock<Room>>, config: Rc<RefCell<Config>>) -> Self {
         Self {
             room,
+            config,
             inner: Rc::new(RefCell::new(HashMap::new())),
+            power_levels: Rc::new(RefCell::new(None)),
             buffer: Rc::new(None),
         }
     }
@@ -69,14 +198,182 @@ impl Members {
             .expect(BUFFER_CLOSED_ERROR)
     }
 
+    /// Get the power level of the member with the given user id.
+    ///
+    /// The power levels of the room are used if we received them, otherwise
+    /// the power level the SDK knows about is used. Returns 0, the default
+    /// power level, if no such member exists.
+    pub fn power_level(&self, user_id: &UserId) -> i64 {
+        if let Some(power_levels) = &*self.power_levels.borrow() {
+            return power_levels.user_level(user_id);
+        }
+
+        self.room()
+            .get_member(user_id)
+            .and_then(|m| m.power_level)
+            .map(i64::from)
+            .unwrap_or(0)
+    }
+
+    /// Can the user with the given user id send messages to the room.
+    ///
+    /// If we don't know the power levels of the room yet, we assume that the
+    /// user can send messages and let the server decide.
+    pub fn can_send_message(&self, user_id: &UserId) -> bool {
+        if let Some(power_levels) = &*self.power_levels.borrow() {
+            power_levels.user_level(user_id)
+                >= power_levels.event_level(&EventType::RoomMessage)
+        } else {
+            true
+        }
+    }
+
+    /// Can the user with the given user id redact events of other users.
+    ///
+    /// Like for `can_send_message()`, unknown power levels leave the decision
+    /// to the server.
+    pub fn can_redact(&self, user_id: &UserId) -> bool {
+        if let Some(power_levels) = &*self.power_levels.borrow() {
+            power_levels.user_level(user_id) >= power_levels.redact
+        } else {
+            true
+        }
+    }
+
+    /// Store the power levels of the room that we restored from the store.
+    ///
+    /// This needs to be called before the members get restored, otherwise
+    /// they might end up in the wrong nicklist group.
+    pub fn restore_power_levels(&self) {
+        let room = self.room();
+
+        let power_levels = if let Some(p) = &room.power_levels {
+            p
+        } else {
+            return;
+        };
+
+        let users = room
+            .joined_members
+            .values()
+            .chain(room.invited_members.values())
+            .filter_map(|m| {
+                m.power_level.map(|l| (m.user_id.clone(), i64::from(l)))
+            })
+            .collect();
+
+        let events = power_levels
+            .events
+            .iter()
+            .map(|(e, l)| (e.clone(), i64::from(*l)))
+            .collect();
+
+        self.power_levels.replace(Some(PowerLevels {
+            users,
+            users_default: i64::from(power_levels.users_default),
+            events,
+            events_default: i64::from(power_levels.events_default),
+            redact: i64::from(power_levels.redact),
+        }));
+    }
+
+    /// Update the power levels of the room.
+    ///
+    /// Every member is moved to the nicklist group that matches their new
+    /// power level.
+    pub fn update_power_levels(&self, content: &PowerLevelsEventContent) {
+        self.power_levels.replace(Some(PowerLevels::from(content)));
+
+        let buffer = self.buffer();
+
+        for member in self.inner.borrow().values() {
+            let nick = member.nick.borrow();
+            buffer.remove_nick(&nick);
+
+            if self.add_nick(&buffer, member, &nick).is_err() {
+                error!(
+                    "{}: Couldn't regroup nick {} after a power level change",
+                    buffer.name(),
+                    nick
+                );
+            }
+        }
+    }
+
+    /// Add the given nick of a member to the nicklist group that matches the
+    /// power level of the member.
+    fn add_nick(
+        &self,
+        buffer: &Buffer,
+        member: &WeechatRoomMember,
+        nick: &str,
+    ) -> Result<(), ()> {
+        let group =
+            MemberGroup::from_power_level(self.power_level(&member.user_id));
+
+        let prefix = group.prefix();
+        member.prefix.replace(if prefix.is_empty() {
+            None
+        } else {
+            Some(prefix.to_owned())
+        });
+
+        let nicklist_group =
+            match buffer.search_nicklist_group(group.nicklist_name()) {
+                Some(g) => g,
+                None => buffer.add_nicklist_group(
+                    group.nicklist_name(),
+                    "weechat.color.nicklist_group",
+                    true,
+                    None,
+                )?,
+            };
+
+        let color = if member.away.get() {
+            "weechat.color.nicklist_away".to_owned()
+        } else {
+            member.color.borrow().clone()
+        };
+
+        let nick_settings = NickSettings::new(nick)
+            .set_color(&color)
+            .set_prefix(prefix)
+            .set_prefix_color(group.prefix_color());
+
+        nicklist_group.add_nick(nick_settings).map(|_| ())
+    }
+
+    /// Update the presence of a member, away and offline members are shown
+    /// with the away color in the nicklist.
+    pub fn update_presence(&self, user_id: &UserId, presence: &PresenceState) {
+        let away = !matches!(presence, PresenceState::Online);
+
+        if let Some(member) = self.inner.borrow().get(user_id) {
+            if member.away.replace(away) == away {
+                return;
+            }
+
+            let buffer = self.buffer();
+            let nick = member.nick.borrow();
+            buffer.remove_nick(&nick);
+
+            if self.add_nick(&buffer, member, &nick).is_err() {
+                error!(
+                    "{}: Couldn't update nick {} after a presence change",
+                    buffer.name(),
+                    nick
+                );
+            }
+        }
+    }
+
     /// Add a new Weechat room member.
     pub fn add(&self, member: WeechatRoomMember) {
         {
             let buffer = self.buffer();
             let nick = member.nick.borrow();
-            let nick_settings = NickSettings::new(&nick);
 
-            buffer.add_nick(nick_settings).unwrap_or_else(|_| {
+            self.add_nick(&buffer, &member, &nick).unwrap_or_else(|_| {
                 panic!("Error adding nick for {:#?}, already added?", member)
             });
         }
@@ -112,6 +409,36 @@ impl Members {
         self.inner.borrow().get(user_id).cloned()
     }
 
+    /// Get all the members of the room.
+    pub fn members(&self) -> Vec<WeechatRoomMember> {
+        self.inner.borrow().values().cloned().collect()
+    }
+
+    /// Get the nicks of all the members together with their nicklist group.
+    ///
+    /// The nicks are sorted by their group first and by the nick second.
+    pub fn names(&self) -> Vec<(MemberGroup, String)> {
+        let mut names: Vec<(MemberGroup, String)> = self
+            .inner
+            .borrow()
+            .values()
+            .map(|m| {
+                (
+                    MemberGroup::from_power_level(self.power_level(&m.user_id)),
+                    m.nick.borrow().clone(),
+                )
+            })
+            .collect();
+
+        names.sort_by(|(group_a, nick_a), (group_b, nick_b)| {
+            group_a
+                .cmp(group_b)
+                .then_with(|| nick_a.to_lowercase().cmp(&nick_b.to_lowercase()))
+        });
+
+        names
+    }
+
     /// Change nick of member.
     ///
     /// Returns either the old nick of the member, or an error if the member
@@ -132,9 +459,7 @@ impl Members {
 
             buffer.remove_nick(&member.nick.borrow());
 
-            let nick_settings = NickSettings::new(&new_nick);
-            buffer
-                .add_nick(nick_settings)
+            self.add_nick(&buffer, member, &new_nick)
                 .expect("Can't add nick to nicklist");
 
             let old_nick = member.nick.replace(new_nick);
@@ -149,24 +474,52 @@ impl Members {
         block_on(self.room.read())
     }
 
+    /// Create a new Weechat room member, the nick color is picked using the
+    /// configured nick color source.
+    pub fn create_member(
+        &self,
+        user_id: &UserId,
+        nick: String,
+        display_name: Option<String>,
+    ) -> WeechatRoomMember {
+        let color_source = self.config.borrow().look().nick_color_source();
+        WeechatRoomMember::new(user_id, nick, display_name, color_source)
+    }
+
     pub fn calculate_buffer_name(&self) -> String {
         let room = self.room();
         let room_name = room.display_name();
 
-        if room_name == "#" {
+        if room.direct_target.is_some() {
+            // Direct chats are named after the other user, like IRC queries.
+            room_name
+        } else if room_name == "#" {
             "##".to_owned()
         } else if room_name.starts_with('#') {
             room_name
         } else {
-            // TODO: only do this for non-direct chats
             format!("#{}", room_name)
         }
     }
 
+    /// Calculate the short name of the room buffer.
+    ///
+    /// The canonical alias of the room is used if the room has one, since it's
+    /// stable and unique, otherwise this falls back to the buffer name.
+    pub fn calculate_short_name(&self) -> String {
+        if let Some(alias) = &self.room().canonical_alias {
+            alias.to_string()
+        } else {
+            self.calculate_buffer_name()
+        }
+    }
+
     fn update_buffer_name(&self) {
         let name = self.calculate_buffer_name();
+        let short_name = self.calculate_short_name();
         let buffer = self.buffer();
-        buffer.set_name(&name)
+        buffer.set_name(&name);
+        buffer.set_short_name(&short_name);
     }
 
     /// Helper method to calculate the display name of a room member from their
@@ -234,13 +587,15 @@ impl Members {
         }
     }
 
+    /// Update the member list with the given membership event.
+    ///
+    /// Returns the line describing the change for events that are part of the
+    /// timeline, the caller decides when to print it.
     pub fn handle_membership_event(
         &self,
         event: &SyncStateEvent<MemberEventContent>,
         state_event: bool,
-    ) {
-        let buffer = self.buffer();
-
+    ) -> Option<MembershipLine> {
         let sender_id = event.sender.clone();
         let target_id;
 
@@ -251,7 +606,7 @@ impl Members {
                 "Invalid state key given by the server: {}",
                 event.state_key
             );
-            return;
+            return None;
         }
 
         let new_nick = self.calculate_user_name(&target_id);
@@ -277,7 +632,7 @@ impl Members {
                         .display_name
                         .clone();
 
-                    self.add(WeechatRoomMember::new(
+                    self.add(self.create_member(
                         &target_id,
                         new_nick,
                         display_name,
@@ -296,11 +651,22 @@ impl Members {
             // Names of rooms without display names can get affected by the
             // member list so we need to update them.
             self.update_buffer_name();
+
+            None
         } else {
             let change_op = event.membership_change();
             let sender;
             let target;
 
+            let kind = match change_op {
+                Joined => MembershipKind::Joined,
+                Left | Banned | Kicked | KickedAndBanned => {
+                    MembershipKind::Left
+                }
+                ProfileChanged { .. } => MembershipKind::ProfileChanged,
+                _ => MembershipKind::Other,
+            };
+
             match change_op {
                 Joined | Invited => {
                     debug!(
@@ -318,11 +684,8 @@ impl Members {
                         .display_name
                         .clone();
 
-                    let member = WeechatRoomMember::new(
-                        &target_id,
-                        new_nick,
-                        display_name,
-                    );
+                    let member =
+                        self.create_member(&target_id, new_nick, display_name);
                     self.add(member.clone());
 
                     sender = self.get(&sender_id);
@@ -362,6 +725,16 @@ impl Members {
                     target = self.get(&target_id);
 
                     if displayname_changed {
+                        if let Some(member) = self.get(&target_id) {
+                            member
+                                .display_name
+                                .replace(event.content.displayname.clone());
+
+                            let color_source =
+                                self.config.borrow().look().nick_color_source();
+                            member.update_color(color_source);
+                        }
+
                         match self.rename_member(&target_id, new_nick.clone()) {
                             Ok(old_nick) => debug!(
                                 "{}: Profile changed for {}, renaming {} -> {}",
@@ -377,12 +750,6 @@ impl Members {
                                 user_id
                             ),
                         }
-
-                        // TODO remove this unwrap
-                        self.get(&target_id)
-                            .unwrap()
-                            .display_name
-                            .replace(event.content.displayname.clone());
                     }
 
                     if avatar_url_changed {
@@ -436,7 +803,12 @@ impl Members {
                 .duration_since(std::time::SystemTime::UNIX_EPOCH)
                 .unwrap_or_default()
                 .as_secs();
-            buffer.print_date_tags(timestamp as i64, &[], &message);
+
+            Some(MembershipLine {
+                kind,
+                timestamp: timestamp as i64,
+                message,
+            })
         }
     }
 }
@@ -446,16 +818,54 @@ impl WeechatRoomMember {
         user_id: &UserId,
         nick: String,
         display_name: Option<String>,
+        color_source: NickColorSource,
     ) -> Self {
-        let color = Weechat::info_get("nick_color_name", user_id.as_str())
-            .expect("Couldn't get the nick color name");
+        let color = Self::calculate_color(
+            user_id,
+            display_name.as_deref(),
+            color_source,
+        );
 
         WeechatRoomMember {
             user_id: Rc::new(user_id.clone()),
             nick: Rc::new(RefCell::new(nick)),
             display_name: Rc::new(RefCell::new(display_name)),
             prefix: Rc::new(RefCell::new(None)),
-            color: Rc::new(color),
+            color: Rc::new(RefCell::new(color)),
+            away: Rc::new(Cell::new(false)),
         }
     }
+
+    /// Calculate the nick color name of a member.
+    ///
+    /// Weechat hashes the given string, so the same user will always get the
+    /// same color, as long as the string stays the same. If the display name
+    /// should be used as the source but the member doesn't have one, the user
+    /// id is used instead.
+    fn calculate_color(
+        user_id: &UserId,
+        display_name: Option<&str>,
+        color_source: NickColorSource,
+    ) -> String {
+        let source = match color_source {
+            NickColorSource::UserId => user_id.as_str(),
+            NickColorSource::DisplayName => {
+                display_name.unwrap_or_else(|| user_id.as_str())
+            }
+        };
+
+        Weechat::info_get("nick_color_name", source)
+            .expect("Couldn't get the nick color name")
+    }
+
+    /// Recalculate the nick color, e.g. after the display name changed.
+    pub fn update_color(&self, color_source: NickColorSource) {
+        let color = Self::calculate_color(
+            &self.user_id,
+            self.display_name.borrow().as_deref(),
+            color_source,
+        );
+
+        self.color.replace(color);
+    }
 }
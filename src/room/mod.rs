@@ -23,24 +23,53 @@
 //! decrypt a previously undecryptable event.
 
 mod members;
+mod pins;
+mod search;
+mod upload;
 
-use members::Members;
 pub use members::WeechatRoomMember;
+use members::{MemberGroup, Members, MembershipKind, MembershipLine};
+pub use upload::UploadSource;
 
 pub const BUFFER_CLOSED_ERROR: &str =
     "Buffer got closed but Room is still lingering around";
 
+/// How many of the members that spoke last are remembered for nick
+/// completion.
+const RECENT_SPEAKERS: usize = 20;
+
+/// How many undecryptable events are kept around to be decrypted once their
+/// room key arrives, the oldest ones are forgotten first.
+const MAX_UNDECRYPTED_EVENTS: usize = 500;
+
+/// How many threaded messages we remember the thread root of, the oldest ones
+/// are forgotten first.
+const MAX_THREAD_MESSAGES: usize = 1000;
+
+/// How many times sending a rate limited message is retried.
+const SEND_RETRIES: u32 = 3;
+
+/// How long we wait for more membership changes before the collected ones are
+/// printed.
+const MEMBERSHIP_BATCH_WINDOW: Duration = Duration::from_secs(2);
+
+/// The maximum number of characters of the title and the description of a
+/// link preview.
+const MAX_PREVIEW_TITLE: usize = 80;
+const MAX_PREVIEW_DESCRIPTION: usize = 120;
+
 use std::{
     borrow::Cow,
-    cell::RefCell,
-    collections::HashMap,
+    cell::{Cell, RefCell},
+    collections::{BTreeMap, VecDeque},
     ops::Deref,
     rc::Rc,
     sync::{Arc, Mutex},
-    time::Instant,
+    time::{Duration, Instant},
 };
 
 use async_trait::async_trait;
+use chrono::{Local, TimeZone, Utc};
 use futures::executor::block_on;
 use tracing::{debug, trace};
 use unicode_segmentation::UnicodeSegmentation;
@@ -48,13 +77,22 @@ use url::Url;
 
 use matrix_sdk::{
     events::{
+        presence::PresenceState,
         room::{
-            message::{MessageEventContent, TextMessageEventContent},
-            redaction::SyncRedactionEvent,
+            encrypted::EncryptedEventContent,
+            message::{
+                EmoteMessageEventContent, FormattedBody, MessageEventContent,
+                MessageFormat, NoticeMessageEventContent,
+                TextMessageEventContent,
+            },
+            name::NameEventContent,
+            redaction::{RedactionEventContent, SyncRedactionEvent},
+            topic::TopicEventContent,
         },
+        tag::TagInfo,
         AnyMessageEventContent, AnyPossiblyRedactedSyncMessageEvent,
-        AnyRedactedSyncMessageEvent, AnySyncMessageEvent, AnySyncRoomEvent,
-        AnySyncStateEvent, SyncMessageEvent,
+        AnyRedactedSyncMessageEvent, AnyStateEventContent, AnySyncMessageEvent,
+        AnySyncRoomEvent, AnySyncStateEvent, SyncMessageEvent,
     },
     identifiers::{EventId, RoomId, UserId},
     locks::{RwLock, RwLockReadGuard},
@@ -72,11 +110,28 @@ use weechat::{
 
 use crate::{
     config::{Config, RedactionStyle},
-    connection::{Connection, TYPING_NOTICE_TIMEOUT},
-    render::{Render, RenderedEvent},
-    PLUGIN_NAME,
+    connection::{
+        Connection, ConnectionError, ConnectionResult, RoomRemoval, UrlPreview,
+        UserDevice, TYPING_NOTICE_TIMEOUT,
+    },
+    drafts::Drafts,
+    edit, links, markdown,
+    redaction_log::{self, RedactionLogEntry},
+    render::{event_id_tag, Render, RenderedEvent, RenderedLine},
+    reply::{self, ReplyParent},
+    server::ServerSettings,
+    thread, MODES_BAR_ITEM, PLUGIN_NAME,
 };
 
+/// The groups room buffers are sorted into, favourites come first while low
+/// priority rooms come last.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum RoomGroup {
+    Favourite,
+    Regular,
+    LowPriority,
+}
+
 #[derive(Clone)]
 pub struct RoomHandle {
     inner: MatrixRoom,
@@ -100,38 +155,193 @@ pub struct MatrixRoom {
     buffer: Rc<Option<BufferHandle>>,
 
     config: Rc<RefCell<Config>>,
+    settings: Rc<RefCell<ServerSettings>>,
+    highlight_patterns: Rc<RefCell<Vec<String>>>,
     connection: Rc<RefCell<Option<Connection>>>,
 
+    tags: Rc<RefCell<BTreeMap<String, TagInfo>>>,
+
+    /// The members that sent a message most recently, the last speaker
+    /// comes first.
+    recent_speakers: Rc<RefCell<VecDeque<UserId>>>,
+    /// The messages that are part of a thread and their thread roots, the
+    /// oldest come first.
+    thread_roots: Rc<RefCell<VecDeque<(EventId, EventId)>>>,
+    /// Did we warn about sending a message to a room that was upgraded.
+    tombstone_warned: Rc<Cell<bool>>,
+    /// Did we print the banner pointing to the room that replaced this room.
+    tombstone_shown: Rc<Cell<bool>>,
+    /// Membership changes that weren't printed yet, they are collapsed into a
+    /// summary if there are enough of them.
+    pending_memberships: Rc<RefCell<Vec<MembershipLine>>>,
+
+    /// Events we couldn't decrypt, the oldest come first.
+    undecrypted_events:
+        Rc<RefCell<VecDeque<SyncMessageEvent<EncryptedEventContent>>>>,
+
     typing_notice_time: Rc<RefCell<Option<Instant>>>,
     typing_in_flight: Rc<Mutex<()>>,
 
     outgoing_messages: MessageQueue,
+    pinned_events: Rc<RefCell<Vec<EventId>>>,
+    /// Set if we got kicked or banned from the room, or left it using another
+    /// client.
+    removed: Rc<Cell<bool>>,
+    /// The message that was quoted using `/quote`, the next message we send
+    /// is a reply to it.
+    pending_reply: Rc<RefCell<Option<EventId>>>,
+    notification_level: Rc<Cell<NotificationLevel>>,
+    /// The message and reason of a deletion that waits for a confirmation.
+    pending_redaction: Rc<RefCell<Option<(EventId, Option<String>)>>>,
 
     members: Members,
 }
 
+/// A message the user should be notified about, e.g. using a desktop
+/// notification.
+pub struct Notification {
+    pub room_name: String,
+    pub sender: String,
+    /// A single line preview of the message.
+    pub body: String,
+}
+
+/// How much a room notifies us about new messages.
+///
+/// The level is stored in the push rules of our account, so other clients
+/// respect it as well.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum NotificationLevel {
+    /// Every message notifies us.
+    All,
+    /// Only messages that highlight us notify us.
+    Mentions,
+    /// The room is muted.
+    None,
+}
+
+impl NotificationLevel {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            NotificationLevel::All => "all",
+            NotificationLevel::Mentions => "mentions",
+            NotificationLevel::None => "none",
+        }
+    }
+}
+
+impl Default for NotificationLevel {
+    fn default() -> Self {
+        NotificationLevel::All
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum OutgoingState {
+    /// The message is waiting to be sent out.
+    Queued,
+    /// The message is being sent out right now.
+    Sending,
+    /// Sending the message failed permanently, it's only sent again if the
+    /// user asks for it.
+    Failed,
+}
+
+#[derive(Debug, Clone)]
+struct OutgoingMessage {
+    uuid: Uuid,
+    echo: bool,
+    state: OutgoingState,
+    content: MessageEventContent,
+}
+
+/// The messages of a room that weren't yet sent out, in the order the user
+/// sent them.
+///
+/// The queue outlives the connection, messages that are sent while we're
+/// disconnected are sent once the connection comes back.
 #[derive(Debug, Clone, Default)]
 pub struct MessageQueue {
-    queue: Rc<RefCell<HashMap<Uuid, (bool, MessageEventContent)>>>,
+    queue: Rc<RefCell<VecDeque<OutgoingMessage>>>,
+    draining: Rc<Cell<bool>>,
 }
 
 impl MessageQueue {
     fn new() -> Self {
         Self {
-            queue: Rc::new(RefCell::new(HashMap::new())),
+            queue: Rc::new(RefCell::new(VecDeque::new())),
+            draining: Rc::new(Cell::new(false)),
         }
     }
 
+    fn push(
+        &self,
+        uuid: Uuid,
+        echo: bool,
+        state: OutgoingState,
+        content: MessageEventContent,
+    ) {
+        self.queue.borrow_mut().push_back(OutgoingMessage {
+            uuid,
+            echo,
+            state,
+            content,
+        });
+    }
+
     fn add(&self, uuid: Uuid, content: MessageEventContent) {
-        self.queue.borrow_mut().insert(uuid, (false, content));
+        self.push(uuid, false, OutgoingState::Queued, content)
     }
 
     fn add_with_echo(&self, uuid: Uuid, content: MessageEventContent) {
-        self.queue.borrow_mut().insert(uuid, (true, content));
+        self.push(uuid, true, OutgoingState::Queued, content)
+    }
+
+    /// Add a message that is sent out right away by the caller instead of
+    /// waiting for its turn in the queue.
+    fn add_in_flight(&self, uuid: Uuid, content: MessageEventContent) {
+        self.push(uuid, true, OutgoingState::Sending, content)
     }
 
     fn remove(&self, uuid: Uuid) -> Option<(bool, MessageEventContent)> {
-        self.queue.borrow_mut().remove(&uuid)
+        let mut queue = self.queue.borrow_mut();
+        let position = queue.iter().position(|m| m.uuid == uuid)?;
+
+        queue.remove(position).map(|m| (m.echo, m.content))
+    }
+
+    /// Take the oldest message that is waiting to be sent out, the message
+    /// stays in the queue marked as being sent.
+    fn next_queued(&self) -> Option<(Uuid, MessageEventContent)> {
+        self.queue
+            .borrow_mut()
+            .iter_mut()
+            .find(|m| m.state == OutgoingState::Queued)
+            .map(|m| {
+                m.state = OutgoingState::Sending;
+                (m.uuid, m.content.clone())
+            })
+    }
+
+    fn set_state(&self, uuid: Uuid, state: OutgoingState) {
+        if let Some(m) =
+            self.queue.borrow_mut().iter_mut().find(|m| m.uuid == uuid)
+        {
+            m.state = state;
+        }
+    }
+
+    /// Put the failed messages back into the queue, returns their ids.
+    fn requeue_failed(&self) -> Vec<Uuid> {
+        self.queue
+            .borrow_mut()
+            .iter_mut()
+            .filter(|m| m.state == OutgoingState::Failed)
+            .map(|m| {
+                m.state = OutgoingState::Queued;
+                m.uuid
+            })
+            .collect()
     }
 }
 
@@ -139,12 +349,15 @@ impl RoomHandle {
     pub fn new(
         connection: &Rc<RefCell<Option<Connection>>>,
         config: Rc<RefCell<Config>>,
+        settings: Rc<RefCell<ServerSettings>>,
+        highlight_patterns: Rc<RefCell<Vec<String>>>,
+        drafts: Drafts,
         room: Arc<RwLock<Room>>,
         homeserver: &Url,
         room_id: RoomId,
         own_user_id: &UserId,
     ) -> Self {
-        let members = Members::new(room.clone());
+        let members = Members::new(room.clone(), config.clone());
 
         let mut room = MatrixRoom {
             homeserver: Rc::new(homeserver.clone()),
@@ -153,16 +366,35 @@ impl RoomHandle {
             typing_notice_time: Rc::new(RefCell::new(None)),
             typing_in_flight: Rc::new(Mutex::new(())),
             config,
+            settings,
+            highlight_patterns,
+            tags: Rc::new(RefCell::new(BTreeMap::new())),
+            recent_speakers: Rc::new(RefCell::new(VecDeque::new())),
+            thread_roots: Rc::new(RefCell::new(VecDeque::new())),
+            tombstone_warned: Rc::new(Cell::new(false)),
+            tombstone_shown: Rc::new(Cell::new(false)),
+            pending_memberships: Rc::new(RefCell::new(Vec::new())),
+            undecrypted_events: Rc::new(RefCell::new(VecDeque::new())),
             room,
             own_user_id: Rc::new(own_user_id.to_owned()),
             members: members.clone(),
             buffer: members.buffer,
             outgoing_messages: MessageQueue::new(),
+            pinned_events: Rc::new(RefCell::new(Vec::new())),
+            removed: Rc::new(Cell::new(false)),
+            notification_level: Rc::new(Cell::new(NotificationLevel::All)),
+            pending_redaction: Rc::new(RefCell::new(None)),
+            pending_reply: Rc::new(RefCell::new(None)),
         };
 
+        let draft = drafts.take(&room_id);
+        let draft_room_id = room_id.clone();
+
         let buffer_handle = BufferBuilderAsync::new(&room_id.to_string())
             .input_callback(room.clone())
-            .close_callback(|_weechat: &Weechat, _buffer: &Buffer| {
+            .close_callback(move |_weechat: &Weechat, buffer: &Buffer| {
+                drafts.set(&draft_room_id, &buffer.input());
+                drafts.save();
                 // TODO remove the roombuffer from the server here.
                 // TODO leave the room if the plugin isn't unloading.
                 Ok(())
@@ -176,6 +408,10 @@ impl RoomHandle {
 
         buffer.enable_nicklist();
 
+        if let Some(draft) = draft {
+            buffer.set_input(&draft);
+        }
+
         // This is fine since we're only given the room to the buffer input and
         // the callback can only run once we yield controll back to Weechat.
         unsafe {
@@ -183,6 +419,8 @@ impl RoomHandle {
                 Some(buffer_handle.clone());
         }
 
+        room.update_time_display();
+
         Self {
             inner: room,
             buffer_handle,
@@ -193,6 +431,9 @@ impl RoomHandle {
         room: Arc<RwLock<Room>>,
         connection: &Rc<RefCell<Option<Connection>>>,
         config: Rc<RefCell<Config>>,
+        settings: Rc<RefCell<ServerSettings>>,
+        highlight_patterns: Rc<RefCell<Vec<String>>>,
+        drafts: Drafts,
         homeserver: &Url,
     ) -> Self {
         let room_clone = room.clone();
@@ -203,6 +444,9 @@ impl RoomHandle {
         let room_buffer = Self::new(
             connection,
             config,
+            settings,
+            highlight_patterns,
+            drafts,
             room_clone,
             homeserver,
             room_id,
@@ -211,6 +455,8 @@ impl RoomHandle {
 
         debug!("Restoring room {}", room_lock.room_id);
 
+        room_buffer.members.restore_power_levels();
+
         let matrix_members = room_lock
             .joined_members
             .values()
@@ -224,7 +470,7 @@ impl RoomHandle {
                 .clone();
 
             trace!("Restoring member {}", member.user_id);
-            let member = WeechatRoomMember::new(
+            let member = room_buffer.members.create_member(
                 &member.user_id,
                 member.disambiguated_name(),
                 display_name,
@@ -234,7 +480,9 @@ impl RoomHandle {
         }
 
         room_buffer.update_buffer_name();
+        room_buffer.update_buffer_title();
         room_buffer.restore_messages().await;
+        room_buffer.print_tombstone();
 
         room_buffer
     }
@@ -248,7 +496,9 @@ impl RoomHandle {
             if buffer.num_lines() == 0 {
                 for event in room.messages.iter() {
                     match event {
-                        Regular(e) => self.handle_room_message(e).await,
+                        Regular(e) => {
+                            self.handle_room_message(e, false).await;
+                        }
                         Redacted(e) => self.handle_redacted_events(e),
                     }
                 }
@@ -259,15 +509,38 @@ impl RoomHandle {
 
 #[async_trait(?Send)]
 impl BufferInputCallbackAsync for MatrixRoom {
-    async fn callback(&mut self, _: BufferHandle, input: String) {
-        // TODO parse the input here and produce a formatted body.
-        let content = MessageEventContent::Text(TextMessageEventContent {
-            body: input,
-            formatted: None,
-            relates_to: None,
-        });
+    async fn callback(&mut self, buffer: BufferHandle, input: String) {
+        // Nobody will read messages in a room that was replaced, make sure the
+        // user really wants to post here.
+        if let Some(replacement) = self.replacement_room() {
+            if !self.tombstone_warned.replace(true) {
+                self.print_error(&format!(
+                    "This room has been replaced by {}, send the message \
+                     again to post it here anyway",
+                    replacement
+                ));
+
+                if let Ok(buffer) = buffer.upgrade() {
+                    buffer.set_input(&input);
+                }
 
-        self.send_message(content).await;
+                return;
+            }
+        }
+
+        let pending_reply = self.pending_reply.borrow_mut().take();
+
+        // Images dragged into the terminal end up as their path in the input,
+        // upload them instead of sending the path.
+        if let Some(path) = upload::image_path(&input) {
+            self.upload(UploadSource::File(path)).await;
+        } else if let Some(event_id) =
+            pending_reply.filter(|_| input.starts_with('>'))
+        {
+            self.send_reply(Some(event_id), input).await;
+        } else {
+            self.send_text(input).await;
+        }
     }
 }
 
@@ -284,6 +557,217 @@ impl MatrixRoom {
         &self.room_id
     }
 
+    /// Get the room that replaced this room, if the room was upgraded.
+    pub fn replacement_room(&self) -> Option<RoomId> {
+        self.room()
+            .tombstone
+            .as_ref()
+            .map(|t| t.replacement.clone())
+    }
+
+    /// Print a banner pointing to the room that replaced this room.
+    fn print_tombstone(&self) {
+        let tombstone = if let Some(t) = self.room().tombstone.clone() {
+            t
+        } else {
+            return;
+        };
+
+        self.tombstone_shown.set(true);
+
+        self.print_network(&format!(
+            "{}This room has been upgraded{}: {}, join the new room with \
+             /join {}",
+            Weechat::color("bold"),
+            Weechat::color("-bold"),
+            tombstone.body,
+            tombstone.replacement
+        ));
+    }
+
+    /// Print the banner of a tombstone that is part of the room state, unless
+    /// the banner was already printed.
+    ///
+    /// The upgrade happened in the past, e.g. the state is part of the initial
+    /// sync, so the new room isn't joined.
+    fn show_tombstone(&self) {
+        if !self.tombstone_shown.get() {
+            self.print_tombstone();
+        }
+    }
+
+    /// Handle the upgrade of the room, the new room gets joined if the server
+    /// is configured to do so.
+    ///
+    /// This should only be called for tombstones that are part of the
+    /// timeline.
+    fn handle_tombstone(&self) {
+        self.print_tombstone();
+
+        if !self.settings.borrow().autojoin_upgraded_rooms {
+            return;
+        }
+
+        let replacement = if let Some(r) = self.replacement_room() {
+            r
+        } else {
+            return;
+        };
+
+        let connection = if let Some(c) = self.connection.borrow().clone() {
+            c
+        } else {
+            return;
+        };
+
+        let room = self.clone();
+
+        Weechat::spawn(async move {
+            match connection.join_room(&replacement).await {
+                Ok(_) => room.print_network(&format!(
+                    "Joined the upgraded room {}",
+                    replacement
+                )),
+                Err(e) => room.print_error(&format!(
+                    "Error joining the upgraded room {}: {}",
+                    replacement, e
+                )),
+            }
+        })
+        .detach();
+    }
+
+    /// Get the user this room is a direct chat with, if it's a direct chat.
+    pub fn direct_target(&self) -> Option<UserId> {
+        self.room().direct_target.clone()
+    }
+
+    /// Mark the room as a direct chat with the given user, or as a regular room
+    /// if no user is given.
+    pub fn set_direct_target(&self, user_id: Option<UserId>) {
+        if self.direct_target() == user_id {
+            return;
+        }
+
+        block_on(self.room.write()).direct_target = user_id;
+        self.update_buffer_name();
+    }
+
+    /// Update the presence of the given member of the room.
+    pub fn update_presence(&self, user_id: &UserId, presence: &PresenceState) {
+        self.members.update_presence(user_id, presence)
+    }
+
+    /// Update the tags of the room, e.g. `m.favourite` or `m.lowpriority`.
+    ///
+    /// The tag names are exposed in the `matrix_tags` local variable of the
+    /// buffer, without their namespace. Returns true if the group of the room
+    /// or its order inside of the group changed.
+    pub fn update_tags(&self, tags: BTreeMap<String, TagInfo>) -> bool {
+        let names: Vec<&str> = tags
+            .keys()
+            .map(|t| {
+                t.strip_prefix("m.")
+                    .or_else(|| t.strip_prefix("u."))
+                    .unwrap_or(t)
+            })
+            .collect();
+
+        if let Ok(buffer) = self.buffer_handle().upgrade() {
+            buffer.set_localvar("matrix_tags", &names.join(","));
+        }
+
+        let group = self.group();
+        *self.tags.borrow_mut() = tags;
+
+        self.group() != group
+    }
+
+    /// Get the group the room buffer should be sorted into and the order of
+    /// the room inside of the group, if the tag of the group has one.
+    pub fn group(&self) -> (RoomGroup, Option<f64>) {
+        let tags = self.tags.borrow();
+
+        if let Some(tag) = tags.get("m.favourite") {
+            (RoomGroup::Favourite, tag.order)
+        } else if let Some(tag) = tags.get("m.lowpriority") {
+            (RoomGroup::LowPriority, tag.order)
+        } else {
+            (RoomGroup::Regular, None)
+        }
+    }
+
+    /// Make the buffer of the room the current buffer.
+    pub fn switch_to_buffer(&self) {
+        if let Ok(buffer) = self.buffer_handle().upgrade() {
+            buffer.switch_to();
+        }
+    }
+
+    /// Convert the given user input into a plain and a formatted body.
+    ///
+    /// The input is parsed as Markdown if the server has Markdown enabled.
+    fn parse_input(&self, input: String) -> (String, Option<FormattedBody>) {
+        if self.settings.borrow().markdown {
+            let parsed = markdown::parse(&input);
+            let formatted = parsed.formatted.map(|body| FormattedBody {
+                format: MessageFormat::Html,
+                body,
+            });
+
+            (parsed.body, formatted)
+        } else {
+            (input, None)
+        }
+    }
+
+    /// Create the content of a text message from the given user input.
+    fn text_content(&self, input: String) -> MessageEventContent {
+        let (body, formatted) = self.parse_input(input);
+
+        MessageEventContent::Text(TextMessageEventContent {
+            body,
+            formatted,
+            relates_to: None,
+        })
+    }
+
+    /// Create the content of an emote message from the given user input.
+    fn emote_content(&self, input: String) -> MessageEventContent {
+        let (body, formatted) = self.parse_input(input);
+
+        MessageEventContent::Emote(EmoteMessageEventContent { body, formatted })
+    }
+
+    /// Create the content of a notice from the given user input.
+    fn notice_content(&self, input: String) -> MessageEventContent {
+        let (body, formatted) = self.parse_input(input);
+
+        MessageEventContent::Notice(NoticeMessageEventContent {
+            body,
+            formatted,
+            relates_to: None,
+        })
+    }
+
+    /// Send the given user input as a text message.
+    pub async fn send_text(&self, input: String) {
+        let content = self.text_content(input);
+        self.send_message(content).await;
+    }
+
+    /// Send the given user input as an emote, e.g. `/me waves`.
+    pub async fn send_emote(&self, input: String) {
+        let content = self.emote_content(input);
+        self.send_message(content).await;
+    }
+
+    /// Send the given user input as a notice, the message type bots use.
+    pub async fn send_notice(&self, input: String) {
+        let content = self.notice_content(input);
+        self.send_message(content).await;
+    }
+
     pub fn buffer_handle(&self) -> BufferHandle {
         (&*self.buffer)
             .as_ref()
@@ -294,17 +778,60 @@ impl MatrixRoom {
     fn print_rendered_event(&self, rendered: RenderedEvent) {
         let buffer = self.buffer_handle();
 
+        // Events carry the time the server received them, backfilled events
+        // are printed with that time. A server clock that runs ahead of ours
+        // shouldn't make events look like they're from the future though.
+        let now = Utc::now().timestamp();
+        let timestamp = if rendered.message_timestamp as i64 > now {
+            now
+        } else {
+            rendered.message_timestamp as i64
+        };
+
+        let prefix =
+            if let Some(format) = &self.settings.borrow().timestamp_format {
+                let time = if timestamp == 0 {
+                    Local::now()
+                } else {
+                    Local.timestamp(timestamp, 0)
+                };
+
+                format!(
+                    "{}{}{} {}",
+                    Weechat::color("chat_time"),
+                    time.format(format),
+                    Weechat::color("reset"),
+                    rendered.prefix
+                )
+            } else {
+                rendered.prefix
+            };
+
         if let Ok(buffer) = buffer.upgrade() {
             for line in rendered.content.lines {
-                let message =
-                    format!("{}\t{}", &rendered.prefix, &line.message);
+                let message = format!("{}\t{}", &prefix, &line.message);
                 let tags: Vec<&str> =
                     line.tags.iter().map(|t| t.as_str()).collect();
-                buffer.print_date_tags(0, &tags, &message)
+                buffer.print_date_tags(timestamp, &tags, &message)
             }
         }
     }
 
+    /// Hide the WeeChat time column if the server has its own timestamp
+    /// format configured, the time is part of the prefix in that case.
+    pub fn update_time_display(&self) {
+        let show_time = self.settings.borrow().timestamp_format.is_none();
+
+        if let Ok(buffer) = self.buffer_handle().upgrade() {
+            buffer
+                .run_command(&format!(
+                    "/buffer set time_for_each_line {}",
+                    if show_time { 1 } else { 0 }
+                ))
+                .ok();
+        }
+    }
+
     fn redact_event(&self, event: &SyncRedactionEvent) {
         let buffer_handle = self.buffer_handle();
 
@@ -317,8 +844,6 @@ impl MatrixRoom {
         // TODO remove this unwrap.
         let redacter = self.members.get(&event.sender).unwrap();
 
-        let event_id_tag =
-            Cow::from(format!("{}_id_{}", PLUGIN_NAME, event.redacts));
         let tag = Cow::from("matrix_redacted");
 
         let reason = if let Some(r) = &event.content.reason {
@@ -338,12 +863,6 @@ impl MatrixRoom {
 
         let redaction_style = self.config.borrow().look().redaction_style();
 
-        let predicate = |l: &BufferLine| {
-            let tags = l.tags();
-            tags.contains(&event_id_tag)
-                && !tags.contains(&Cow::from("matrix_redacted"))
-        };
-
         let strike_through = |string: Cow<str>| {
             Weechat::remove_color(&string)
                 .graphemes(true)
@@ -385,16 +904,18 @@ impl MatrixRoom {
             line.set_tags(&tags);
         };
 
-        let mut lines = buffer.lines();
-        let first_line = lines.rfind(predicate);
+        let mut lines = find_event_lines(&buffer, &event.redacts)
+            .into_iter()
+            .filter(|l| !l.tags().contains(&tag))
+            .rev();
 
-        if let Some(line) = first_line {
+        if let Some(line) = lines.next() {
             modify_line(line, tag.clone(), redact_first_line);
         } else {
             return;
         }
 
-        while let Some(line) = lines.next_back().filter(predicate) {
+        for line in lines {
             modify_line(line, tag.clone(), redact_string);
         }
     }
@@ -414,7 +935,7 @@ impl MatrixRoom {
 
         let send_time = event.origin_server_ts();
 
-        let rendered = match event.content() {
+        let mut rendered = match event.content() {
             RoomEncrypted(c) => {
                 c.render_with_prefix(send_time, event.event_id(), &sender, &())
             }
@@ -477,109 +998,855 @@ impl MatrixRoom {
             _ => return None,
         };
 
+        let thread_root = self.thread_root(event.event_id());
+
+        // Messages in a thread reply to the thread root as a fallback for
+        // clients without thread support, show the thread instead.
+        let preview = if let Some(root) = thread_root {
+            Some(self.thread_indicator(&root))
+        } else if let RoomMessage(Text(c)) = event.content() {
+            reply::in_reply_to(&c).and_then(|e| self.reply_preview(e))
+        } else {
+            None
+        };
+
+        if let Some(preview) = preview {
+            let tags = rendered
+                .content
+                .lines
+                .first()
+                .map(|l| l.tags.clone())
+                .unwrap_or_default();
+
+            rendered.content.lines.insert(
+                0,
+                RenderedLine {
+                    message: preview,
+                    tags,
+                },
+            );
+        }
+
         Some(rendered)
     }
 
-    // Add the content of the message to our outgoing messag queue and print out
-    // a local echo line if local echo is enabled.
-    fn queue_outgoing_message(
+    /// Find a message in the locally known message history of the room.
+    fn find_message(
         &self,
-        uuid: Uuid,
-        content: &MessageEventContent,
-    ) {
-        if self.config.borrow().look().local_echo() {
-            if let MessageEventContent::Text(c) = content {
-                let sender =
-                    self.members.get(&self.own_user_id).unwrap_or_else(|| {
-                        panic!("No own member {}", self.own_user_id)
-                    });
-
-                let local_echo =
-                    c.render_with_prefix_for_echo(&sender, uuid, &());
-                self.print_rendered_event(local_echo);
+        event_id: &EventId,
+    ) -> Option<SyncMessageEvent<MessageEventContent>> {
+        self.room().messages.iter().find_map(|e| match e {
+            AnyPossiblyRedactedSyncMessageEvent::Regular(
+                AnySyncMessageEvent::RoomMessage(m),
+            ) if &m.event_id == event_id => Some(m.clone()),
+            _ => None,
+        })
+    }
 
-                self.outgoing_messages.add_with_echo(uuid, content.clone());
-            } else {
-                self.outgoing_messages.add(uuid, content.clone());
-            }
-        } else {
-            self.outgoing_messages.add(uuid, content.clone());
-        }
+    /// Find the last message in the locally known message history that wasn't
+    /// sent by us.
+    fn last_message(&self) -> Option<SyncMessageEvent<MessageEventContent>> {
+        self.room()
+            .messages
+            .iter()
+            .filter_map(|e| match e {
+                AnyPossiblyRedactedSyncMessageEvent::Regular(
+                    AnySyncMessageEvent::RoomMessage(m),
+                ) if m.sender != *self.own_user_id => Some(m.clone()),
+                _ => None,
+            })
+            .last()
     }
 
-    /// Send the given content to the server.
+    /// Find the last message in the locally known message history that was
+    /// sent by us.
+    fn last_own_message(
+        &self,
+    ) -> Option<SyncMessageEvent<MessageEventContent>> {
+        self.room()
+            .messages
+            .iter()
+            .filter_map(|e| match e {
+                AnyPossiblyRedactedSyncMessageEvent::Regular(
+                    AnySyncMessageEvent::RoomMessage(m),
+                ) if m.sender == *self.own_user_id => Some(m.clone()),
+                _ => None,
+            })
+            .last()
+    }
+
+    /// Quote the sender and a single line preview of the message with the
+    /// given event id.
     ///
-    /// # Arguments
+    /// Returns None if the message isn't locally known.
+    fn quote(&self, event_id: &EventId) -> Option<String> {
+        let message = self.find_message(event_id)?;
+        Some(self.quote_message(&message))
+    }
+
+    /// Quote the sender and a single line preview of the given message.
+    fn quote_message(
+        &self,
+        message: &SyncMessageEvent<MessageEventContent>,
+    ) -> String {
+        let nick = self
+            .members
+            .get(&message.sender)
+            .map(|m| m.nick.borrow().clone())
+            .unwrap_or_else(|| message.sender.to_string());
+
+        format!("<{}> {}", nick, reply::preview(&message.content))
+    }
+
+    /// Render a compact quote of the message with the given event id.
     ///
-    /// * `content` - The content that should be sent to the server.
+    /// Returns None if the message isn't locally known.
+    fn reply_preview(&self, event_id: &EventId) -> Option<String> {
+        Some(format!(
+            "{}> {}{}",
+            Weechat::color("darkgray"),
+            self.quote(event_id)?,
+            Weechat::color("reset")
+        ))
+    }
+
+    /// Render the line marking a message as part of the thread with the given
+    /// root.
+    fn thread_indicator(&self, root: &EventId) -> String {
+        format!(
+            "{}↳ in thread {}{}",
+            Weechat::color("darkgray"),
+            self.quote(root).unwrap_or_else(|| root.to_string()),
+            Weechat::color("reset")
+        )
+    }
+
+    /// Send the given user input as a reply.
     ///
-    /// # Examples
+    /// # Arguments
     ///
-    /// ```
-    /// let content = MessageEventContent::Text(TextMessageEventContent {
-    ///     body: "Hello world".to_owned(),
-    ///     formatted: None,
-    ///     relates_to: None,
-    /// });
-    /// let content = AnyMessageEventContent::RoomMessage(content);
+    /// * `event_id` - The event id of the message we're replying to, if none
+    /// is given the last message of another room member is used.
     ///
-    /// buffer.send_message(content).await
-    /// ```
-    pub async fn send_message(&self, content: MessageEventContent) {
-        let uuid = Uuid::new_v4();
+    /// * `input` - The user input that should be sent as the reply.
+    pub async fn send_reply(&self, event_id: Option<EventId>, input: String) {
+        let parent = match &event_id {
+            Some(e) => self.find_message(e),
+            None => self.last_message(),
+        };
 
-        if let Some(c) = &*self.connection.borrow() {
-            self.queue_outgoing_message(uuid, &content);
-            match c
-                .send_message(
-                    &self.room_id,
-                    AnyMessageEventContent::RoomMessage(content),
-                    Some(uuid),
-                )
-                .await
-            {
-                Ok(r) => {
-                    self.handle_outgoing_message(uuid, &r.event_id).await;
-                }
-                Err(_e) => {
-                    // TODO print out an error, remember to modify the local
-                    // echo line if there is one.
-                    self.outgoing_messages.remove(uuid);
-                }
+        let event_id = match (event_id, &parent) {
+            (Some(e), _) => e,
+            (None, Some(p)) => p.event_id.clone(),
+            (None, None) => {
+                self.print_error("There is no message to reply to");
+                return;
             }
-        } else if let Ok(buffer) = self.buffer_handle().upgrade() {
-            buffer.print("Error not connected");
-        }
+        };
+
+        let (body, formatted) = self.parse_input(input);
+
+        let parent = parent.as_ref().map(|p| ReplyParent {
+            room_id: &self.room_id,
+            event_id: &p.event_id,
+            sender: &p.sender,
+            content: &p.content,
+        });
+
+        let content = reply::reply_content(&event_id, parent, body, formatted);
+        self.send_message(content).await;
     }
 
-    /// Send out a typing notice.
+    /// Put a Markdown quote of a message into the input bar.
     ///
-    /// This will send out a typing notice or reset the one in progress, if
-    /// needed. It will make sure that only one typing notice request is in
-    /// flight at a time.
+    /// The message that gets sent next is sent as a reply to the quoted
+    /// message, unless the quote was removed from the input.
     ///
-    /// Typing notices are sent out only if we have more than 4 letters in the
-    /// input and the input isn't a command.
+    /// # Arguments
     ///
-    /// If the input is empty the typing notice is disabled.
-    pub fn update_typing_notice(&self) {
-        let typing_in_flight = self.typing_in_flight.clone();
-        let buffer_handle = self.buffer_handle();
+    /// * `event_id` - The event id of the message that should be quoted, if
+    /// none is given the last message of another room member is used.
+    pub fn quote_into_input(&self, event_id: Option<EventId>) {
+        let message = match &event_id {
+            Some(e) => self.find_message(e),
+            None => self.last_message(),
+        };
 
-        let buffer = if let Ok(b) = buffer_handle.upgrade() {
-            b
+        let message = if let Some(m) = message {
+            m
         } else {
+            self.print_error("There is no message to quote");
             return;
         };
 
-        let input = buffer.input();
+        let nick = self
+            .members
+            .get(&message.sender)
+            .map(|m| m.nick.borrow().clone())
+            .unwrap_or_else(|| message.sender.to_string());
 
-        if input.starts_with('/') && !input.starts_with("//") {
-            // Don't send typing notices for commands.
-            return;
+        let body =
+            reply::strip_plain_fallback(reply::content_body(&message.content));
+
+        let mut quote = format!("> {}:", nick);
+
+        for line in body.lines() {
+            quote.push_str("\n> ");
+            quote.push_str(line);
         }
 
-        let connection = self.connection.clone();
+        quote.push_str("\n\n");
+
+        if let Ok(buffer) = self.buffer_handle().upgrade() {
+            buffer.set_input(&quote);
+            *self.pending_reply.borrow_mut() = Some(message.event_id);
+        }
+    }
+
+    /// Replace the content of a message we sent earlier.
+    ///
+    /// # Arguments
+    ///
+    /// * `event_id` - The event id of the message that should be edited, if
+    /// none is given our last message is used.
+    ///
+    /// * `input` - The user input that should replace the message.
+    pub async fn edit_message(&self, event_id: Option<EventId>, input: String) {
+        let message = match &event_id {
+            Some(e) => self.find_message(e),
+            None => self.last_own_message(),
+        };
+
+        let message = match message {
+            Some(m) if m.sender != *self.own_user_id => {
+                self.print_error("You can only edit your own messages");
+                return;
+            }
+            Some(m) => m,
+            None => {
+                self.print_error("There is no message to edit");
+                return;
+            }
+        };
+
+        if !matches!(message.content, MessageEventContent::Text(_)) {
+            self.print_error("Only text messages can be edited");
+            return;
+        }
+
+        let connection = if let Some(c) = self.connection.borrow().clone() {
+            c
+        } else {
+            self.print_error("Error not connected");
+            return;
+        };
+
+        let (body, formatted) = self.parse_input(input);
+
+        // The edit doesn't go into the outgoing message queue, it's shown
+        // right away and once it comes back in a sync response it replaces
+        // the message again like any other edit.
+        if let Err(e) = connection
+            .edit_message(
+                &self.room_id,
+                &message.event_id,
+                body.clone(),
+                formatted.as_ref().map(|f| f.body.clone()),
+                Uuid::new_v4(),
+            )
+            .await
+        {
+            if e.is_forbidden() {
+                self.print_error(
+                    "You don't have permission to edit this message",
+                );
+            } else {
+                self.print_error(&format!("Error editing message: {}", e));
+            }
+            return;
+        }
+
+        self.print_edit(SyncMessageEvent {
+            content: MessageEventContent::Text(TextMessageEventContent {
+                body,
+                formatted,
+                relates_to: None,
+            }),
+            ..message
+        })
+        .await;
+    }
+
+    /// Handle an edit of one of the messages in the room.
+    ///
+    /// The edit carries the new content of the message, only the sender of a
+    /// message can edit it.
+    async fn handle_edit(
+        &self,
+        event_id: EventId,
+        edit: &SyncMessageEvent<MessageEventContent>,
+    ) {
+        let buffer = if let Ok(b) = self.buffer_handle().upgrade() {
+            b
+        } else {
+            return;
+        };
+
+        let sender_tag = Cow::from(format!("matrix_sender_{}", edit.sender));
+        let sent_by_editor = find_event_lines(&buffer, &event_id)
+            .first()
+            .map_or(false, |l| l.tags().contains(&sender_tag));
+
+        if !sent_by_editor {
+            return;
+        }
+
+        self.print_edit(SyncMessageEvent {
+            event_id,
+            ..edit.clone()
+        })
+        .await;
+    }
+
+    /// Replace the lines of a message with the lines of its edited version.
+    async fn print_edit(&self, message: SyncMessageEvent<MessageEventContent>) {
+        let event_id = message.event_id.clone();
+        let event = AnySyncMessageEvent::RoomMessage(message);
+
+        let mut rendered =
+            if let Some(r) = self.render_message_event(&event).await {
+                r
+            } else {
+                return;
+            };
+
+        for line in &mut rendered.content.lines {
+            line.tags.push("no_highlight".to_owned());
+        }
+
+        if let Some(line) = rendered.content.lines.last_mut() {
+            line.message.push_str(&format!(
+                " {}(edited){}",
+                Weechat::color("chat_delimiters"),
+                Weechat::color("reset")
+            ));
+        }
+
+        if let Ok(buffer) = self.buffer_handle().upgrade() {
+            replace_event_lines(&buffer, &event_id, rendered);
+        }
+    }
+
+    /// Redact a message.
+    ///
+    /// Our own messages can always be redacted, messages of other room
+    /// members only if our power level allows it.
+    ///
+    /// # Arguments
+    ///
+    /// * `event_id` - The event id of the message that should be redacted, if
+    /// none is given our last message is used.
+    ///
+    /// * `reason` - The reason for the redaction.
+    ///
+    /// * `confirmed` - Did the user confirm the redaction. If confirmations
+    /// are enabled, an unconfirmed redaction only shows the message and waits
+    /// for a confirmation. Confirming without an event id and reason redacts
+    /// the message that waits for a confirmation.
+    pub async fn redact(
+        &self,
+        event_id: Option<EventId>,
+        reason: Option<String>,
+        confirmed: bool,
+    ) {
+        let pending = self.pending_redaction.borrow_mut().take();
+
+        let (event_id, reason) = match (event_id, reason, pending) {
+            (None, None, Some(pending)) if confirmed => {
+                (Some(pending.0), pending.1)
+            }
+            (event_id, reason, _) => (event_id, reason),
+        };
+
+        let message = match &event_id {
+            Some(e) => self.find_message(e),
+            None => self.last_own_message(),
+        };
+
+        let event_id = match (event_id, &message) {
+            (Some(e), _) => e,
+            (None, Some(m)) => m.event_id.clone(),
+            (None, None) => {
+                self.print_error("There is no message to delete");
+                return;
+            }
+        };
+
+        let forbidden_message =
+            "You don't have permission to delete this message";
+
+        // If the message isn't locally known we let the server decide.
+        if let Some(message) = &message {
+            if message.sender != *self.own_user_id
+                && !self.members.can_redact(&self.own_user_id)
+            {
+                self.print_error(forbidden_message);
+                return;
+            }
+        }
+
+        let preview = message.as_ref().map(|m| self.quote_message(m));
+
+        if !confirmed && self.settings.borrow().confirm_redactions {
+            self.print_network(&format!(
+                "Delete the message {}? Repeat the command with -yes to \
+                 confirm",
+                preview.unwrap_or_else(|| event_id.to_string())
+            ));
+            *self.pending_redaction.borrow_mut() = Some((event_id, reason));
+            return;
+        }
+
+        let connection = if let Some(c) = self.connection.borrow().clone() {
+            c
+        } else {
+            self.print_error("Error not connected");
+            return;
+        };
+
+        let response = match connection
+            .redact_event(&self.room_id, &event_id, reason.clone())
+            .await
+        {
+            Ok(r) => r,
+            Err(e) if e.is_forbidden() => {
+                self.print_error(forbidden_message);
+                return;
+            }
+            Err(e) => {
+                self.print_error(&format!("Error deleting message: {}", e));
+                return;
+            }
+        };
+
+        if self.settings.borrow().redaction_log {
+            let entry = RedactionLogEntry {
+                room_id: &self.room_id,
+                event_id: &event_id,
+                reason: reason.as_deref(),
+                message: preview.as_deref(),
+            };
+
+            if let Err(e) =
+                redaction_log::append(connection.server_path(), &entry)
+            {
+                self.print_error(&format!(
+                    "Error writing to the redaction log: {}",
+                    e
+                ));
+            }
+        }
+
+        // Mark the lines as redacted right away, the redaction event that
+        // comes back in a sync response will skip the already redacted lines.
+        self.redact_event(&SyncRedactionEvent {
+            content: RedactionEventContent { reason },
+            redacts: event_id,
+            event_id: response.event_id,
+            sender: (&*self.own_user_id).clone(),
+            origin_server_ts: std::time::SystemTime::now(),
+            unsigned: Default::default(),
+        });
+    }
+
+    /// Send the given user input as a message in a thread.
+    ///
+    /// # Arguments
+    ///
+    /// * `root` - The event id of the thread root, if the message is itself
+    /// part of a thread the message is sent to that thread.
+    ///
+    /// * `input` - The user input that should be sent to the thread.
+    pub async fn send_thread_message(&self, root: EventId, input: String) {
+        if !self.check_can_send() {
+            return;
+        }
+
+        let connection = if let Some(c) = self.connection.borrow().clone() {
+            c
+        } else {
+            self.print_error("Error not connected");
+            return;
+        };
+
+        let root = self.thread_root(&root).unwrap_or(root);
+
+        let (body, formatted) = self.parse_input(input);
+        let content =
+            thread::thread_content(&root, body.clone(), formatted.clone());
+
+        // Like edits, threaded messages don't go into the outgoing message
+        // queue, they are printed once the server accepted them.
+        let event_id = match connection
+            .send_message(&self.room_id, content, Some(Uuid::new_v4()))
+            .await
+        {
+            Ok(r) => r.event_id,
+            Err(e) => {
+                self.print_error(&format!("Error sending message: {}", e));
+                return;
+            }
+        };
+
+        self.add_thread_root(event_id.clone(), root);
+
+        let event = AnySyncMessageEvent::RoomMessage(SyncMessageEvent {
+            sender: (&*self.own_user_id).clone(),
+            origin_server_ts: std::time::SystemTime::now(),
+            event_id,
+            content: MessageEventContent::Text(TextMessageEventContent {
+                body,
+                formatted,
+                relates_to: None,
+            }),
+            unsigned: Default::default(),
+        });
+
+        if let Some(mut rendered) = self.render_message_event(&event).await {
+            for line in &mut rendered.content.lines {
+                line.tags.push("no_highlight".to_owned());
+            }
+
+            self.print_rendered_event(rendered);
+        }
+    }
+
+    // Add the content of the message to our outgoing messag queue and print out
+    // a local echo line if local echo is enabled.
+    fn queue_outgoing_message(
+        &self,
+        uuid: Uuid,
+        content: &MessageEventContent,
+    ) {
+        if self.config.borrow().look().local_echo() {
+            let sender =
+                self.members.get(&self.own_user_id).unwrap_or_else(|| {
+                    panic!("No own member {}", self.own_user_id)
+                });
+
+            let local_echo = match content {
+                MessageEventContent::Text(c) => {
+                    Some(c.render_with_prefix_for_echo(&sender, uuid, &()))
+                }
+                MessageEventContent::Emote(c) => {
+                    Some(c.render_with_prefix_for_echo(&sender, uuid, &sender))
+                }
+                MessageEventContent::Notice(c) => {
+                    Some(c.render_with_prefix_for_echo(&sender, uuid, &sender))
+                }
+                _ => None,
+            };
+
+            if let Some(local_echo) = local_echo {
+                self.print_rendered_event(local_echo);
+                self.outgoing_messages.add_with_echo(uuid, content.clone());
+            } else {
+                self.outgoing_messages.add(uuid, content.clone());
+            }
+        } else {
+            self.outgoing_messages.add(uuid, content.clone());
+        }
+    }
+
+    /// Check if we're allowed to send messages to the room, prints out an
+    /// error if we aren't.
+    fn check_can_send(&self) -> bool {
+        if self.removed.get() {
+            self.print_error("You aren't a member of this room anymore");
+            false
+        } else if !self.members.can_send_message(&self.own_user_id) {
+            self.print_error("You don't have permission to post in this room");
+            false
+        } else {
+            true
+        }
+    }
+
+    /// Print out why we aren't a member of the room anymore and stop sending
+    /// messages to it.
+    ///
+    /// # Arguments
+    ///
+    /// * `removal` - The membership event that removed us, if the sync
+    /// response contained one.
+    pub fn handle_removal(&self, removal: Option<&RoomRemoval>) {
+        if self.removed.replace(true) {
+            return;
+        }
+
+        let message = match removal {
+            Some(r) if r.sender == *self.own_user_id => {
+                "You left the room".to_owned()
+            }
+            Some(r) => {
+                let sender = self
+                    .members
+                    .get(&r.sender)
+                    .map(|m| m.nick.borrow().clone())
+                    .unwrap_or_else(|| r.sender.to_string());
+
+                format!(
+                    "You were {} by {}{}{}{}",
+                    if r.banned { "banned" } else { "kicked" },
+                    Weechat::color("chat_nick"),
+                    sender,
+                    Weechat::color("reset"),
+                    r.reason
+                        .as_ref()
+                        .map_or_else(String::new, |r| format!(": {}", r)),
+                )
+            }
+            None => "You aren't a member of this room anymore".to_owned(),
+        };
+
+        self.print_network(&message);
+    }
+
+    /// Mark the room as joined again after we got removed from it.
+    pub fn handle_rejoin(&self) {
+        if self.removed.replace(false) {
+            self.print_network("You joined the room again");
+        }
+    }
+
+    /// Close the buffer of the room.
+    pub fn close_buffer(&self) {
+        if let Ok(buffer) = self.buffer_handle().upgrade() {
+            buffer.close();
+        }
+    }
+
+    /// Send the given content to the server.
+    ///
+    /// # Arguments
+    ///
+    /// * `content` - The content that should be sent to the server.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// let content = MessageEventContent::Text(TextMessageEventContent {
+    ///     body: "Hello world".to_owned(),
+    ///     formatted: None,
+    ///     relates_to: None,
+    /// });
+    /// let content = AnyMessageEventContent::RoomMessage(content);
+    ///
+    /// buffer.send_message(content).await
+    /// ```
+    pub async fn send_message(&self, content: MessageEventContent) {
+        let uuid = Uuid::new_v4();
+
+        if !self.check_can_send() {
+            return;
+        }
+
+        self.queue_outgoing_message(uuid, &content);
+        self.send_outgoing_messages().await;
+    }
+
+    /// Send out the messages of the outgoing message queue, oldest first.
+    ///
+    /// Messages stay in the queue if the connection to the server is down,
+    /// this should be called again once the connection comes back. Messages
+    /// that the server rejects are marked as failed and can be put back into
+    /// the queue using `resend_failed_messages()`.
+    pub async fn send_outgoing_messages(&self) {
+        // Someone else is already going through the queue, they will pick up
+        // any newly queued messages as well.
+        if self.outgoing_messages.draining.replace(true) {
+            return;
+        }
+
+        while let Some((uuid, content)) = self.outgoing_messages.next_queued() {
+            let connection = if let Some(c) = self.connection.borrow().clone() {
+                c
+            } else {
+                self.outgoing_messages
+                    .set_state(uuid, OutgoingState::Queued);
+                self.annotate_local_echo(uuid, "queued");
+                break;
+            };
+
+            match self.send_with_retries(&connection, uuid, content).await {
+                Ok(()) => (),
+                Err(e) if e.is_transient() => {
+                    // Messages need to go out in order, so the rest of the
+                    // queue has to wait as well.
+                    self.outgoing_messages
+                        .set_state(uuid, OutgoingState::Queued);
+                    self.annotate_local_echo(uuid, "queued");
+                    break;
+                }
+                Err(e) => {
+                    self.outgoing_messages
+                        .set_state(uuid, OutgoingState::Failed);
+                    self.annotate_local_echo(uuid, &format!("failed: {}", e));
+                    self.print_error(&format!(
+                        "Error sending message: {}, use /resend to try again",
+                        e
+                    ));
+                }
+            }
+        }
+
+        self.outgoing_messages.draining.set(false);
+    }
+
+    /// Put the messages that failed to be sent back into the outgoing queue
+    /// and try to send them again.
+    pub async fn resend_failed_messages(&self) {
+        let failed = self.outgoing_messages.requeue_failed();
+
+        if failed.is_empty() {
+            self.print_network("There are no failed messages in this room");
+            return;
+        }
+
+        for uuid in failed {
+            self.annotate_local_echo(uuid, "queued");
+        }
+
+        self.send_outgoing_messages().await;
+    }
+
+    /// Send a message that was already added to the outgoing message queue,
+    /// bypassing the rest of the queue.
+    ///
+    /// Returns false if the message couldn't be sent, the message is removed
+    /// from the queue in that case.
+    async fn send_queued_message(
+        &self,
+        uuid: Uuid,
+        content: MessageEventContent,
+    ) -> bool {
+        let connection = if let Some(c) = self.connection.borrow().clone() {
+            c
+        } else {
+            self.outgoing_messages.remove(uuid);
+            return false;
+        };
+
+        match self.send_with_retries(&connection, uuid, content).await {
+            Ok(()) => true,
+            Err(e) => {
+                self.outgoing_messages.remove(uuid);
+                self.annotate_local_echo(uuid, "failed");
+                self.print_error(&format!("Error sending message: {}", e));
+                false
+            }
+        }
+    }
+
+    /// Send a single message from the outgoing message queue.
+    ///
+    /// Rate limited messages are retried up to `SEND_RETRIES` times, the
+    /// transaction id stays the same so the server can deduplicate them.
+    async fn send_with_retries(
+        &self,
+        connection: &Connection,
+        uuid: Uuid,
+        content: MessageEventContent,
+    ) -> ConnectionResult<()> {
+        let mut retries = 0;
+
+        loop {
+            let error = match connection
+                .send_message(
+                    &self.room_id,
+                    AnyMessageEventContent::RoomMessage(content.clone()),
+                    Some(uuid),
+                )
+                .await
+            {
+                Ok(r) => {
+                    self.handle_outgoing_message(uuid, &r.event_id).await;
+                    return Ok(());
+                }
+                Err(e) => e,
+            };
+
+            match error.retry_after() {
+                Some(delay) if retries < SEND_RETRIES => {
+                    retries += 1;
+                    self.annotate_local_echo(uuid, "retrying…");
+                    connection.sleep(delay).await;
+                }
+                _ => return Err(error),
+            }
+        }
+    }
+
+    /// Append a note about the state of the message, e.g. that sending it is
+    /// being retried, to the last line of its local echo.
+    ///
+    /// A previous note on the line is replaced.
+    fn annotate_local_echo(&self, uuid: Uuid, note: &str) {
+        let uuid_tag = Cow::from(format!("matrix_echo_{}", uuid.to_string()));
+
+        let buffer = if let Ok(b) = self.buffer_handle().upgrade() {
+            b
+        } else {
+            return;
+        };
+
+        if let Some(line) =
+            buffer.lines().rfind(|l| l.tags().contains(&uuid_tag))
+        {
+            let message = line.message();
+            let note_start = format!(" {}(", Weechat::color("chat_delimiters"));
+            let note =
+                format!("{}{}){}", note_start, note, Weechat::color("reset"));
+
+            // The echo has its colors stripped, so the start of the note
+            // can't be part of the message itself.
+            let message = match message.find(&note_start) {
+                Some(index) => &message[..index],
+                None => &message[..],
+            };
+
+            line.set_message(&format!("{}{}", message, note));
+        }
+    }
+
+    /// Send out a typing notice.
+    ///
+    /// This will send out a typing notice or reset the one in progress, if
+    /// needed. It will make sure that only one typing notice request is in
+    /// flight at a time.
+    ///
+    /// Typing notices are sent out only if we have more than 4 letters in the
+    /// input and the input isn't a command.
+    ///
+    /// If the input is empty the typing notice is disabled.
+    pub fn update_typing_notice(&self) {
+        let typing_in_flight = self.typing_in_flight.clone();
+        let buffer_handle = self.buffer_handle();
+
+        let buffer = if let Ok(b) = buffer_handle.upgrade() {
+            b
+        } else {
+            return;
+        };
+
+        let input = buffer.input();
+
+        if input.starts_with('/') && !input.starts_with("//") {
+            // Don't send typing notices for commands.
+            return;
+        }
+
+        let connection = self.connection.clone();
         let room_id = self.room_id.clone();
         let typing_notice_time = self.typing_notice_time.clone();
 
@@ -640,83 +1907,1028 @@ impl MatrixRoom {
         }
     }
 
-    /// Replace the local echo of an event with a fully rendered one.
-    fn replace_local_echo(
+    /// Get the thread root of a message that is part of a thread.
+    fn thread_root(&self, event_id: &EventId) -> Option<EventId> {
+        self.thread_roots
+            .borrow()
+            .iter()
+            .rev()
+            .find(|(e, _)| e == event_id)
+            .map(|(_, root)| root.clone())
+    }
+
+    /// Remember the thread root of a message that is part of a thread.
+    ///
+    /// Only the latest messages are kept, the oldest one is forgotten once
+    /// there are too many of them.
+    fn add_thread_root(&self, event_id: EventId, root: EventId) {
+        let mut thread_roots = self.thread_roots.borrow_mut();
+        thread_roots.push_back((event_id, root));
+
+        if thread_roots.len() > MAX_THREAD_MESSAGES {
+            thread_roots.pop_front();
+        }
+    }
+
+    /// Remember an event we couldn't decrypt so it can be decrypted once its
+    /// room key arrives.
+    ///
+    /// Only the latest events are kept, the oldest one is forgotten once
+    /// there are too many of them.
+    fn add_undecrypted_event(
+        &self,
+        event: SyncMessageEvent<EncryptedEventContent>,
+    ) {
+        let mut events = self.undecrypted_events.borrow_mut();
+        events.push_back(event);
+
+        if events.len() > MAX_UNDECRYPTED_EVENTS {
+            events.pop_front();
+        }
+    }
+
+    /// Try to decrypt the events that are encrypted with the given session
+    /// again and replace their placeholder lines with the decrypted content.
+    ///
+    /// This should be called when a room key for the session arrives.
+    pub async fn retry_decryption(&self, session_id: &str) {
+        let events = {
+            let mut undecrypted = self.undecrypted_events.borrow_mut();
+            let (events, rest): (VecDeque<_>, VecDeque<_>) =
+                undecrypted.drain(..).partition(|e| {
+                    megolm_session_id(&e.content) == Some(session_id)
+                });
+            *undecrypted = rest;
+
+            events
+        };
+
+        if events.is_empty() {
+            return;
+        }
+
+        let connection = if let Some(c) = self.connection.borrow().clone() {
+            c
+        } else {
+            return;
+        };
+
+        for event in events {
+            let event_id = event.event_id.clone();
+
+            let decrypted = match connection
+                .decrypt_event(&self.room_id, event.clone())
+                .await
+            {
+                Ok(AnySyncRoomEvent::Message(e)) => e,
+                Ok(_) => continue,
+                Err(e) => {
+                    debug!("Failed to decrypt event {}: {}", event_id, e);
+
+                    // Keep the event around, the room key might be requested
+                    // again.
+                    self.add_undecrypted_event(event);
+                    continue;
+                }
+            };
+
+            if let Some(rendered) = self.render_message_event(&decrypted).await
+            {
+                if let Ok(buffer) = self.buffer_handle().upgrade() {
+                    replace_event_lines(&buffer, &event_id, rendered);
+                }
+            }
+        }
+    }
+
+    /// Request the room key for undecryptable messages from our other
+    /// devices.
+    ///
+    /// Once the key arrives the messages are decrypted and their placeholders
+    /// get replaced.
+    ///
+    /// # Arguments
+    ///
+    /// * `id` - The id of an undecryptable event or the id of the session
+    /// that should be requested.
+    pub async fn request_room_key(&self, id: &str) {
+        let event = self
+            .undecrypted_events
+            .borrow()
+            .iter()
+            .find(|e| {
+                e.event_id.as_str() == id
+                    || megolm_session_id(&e.content) == Some(id)
+            })
+            .cloned();
+
+        let (event, session_id) = match event {
+            Some(e) => {
+                let session_id = megolm_session_id(&e.content)
+                    .expect("Undecryptable event without a Megolm session")
+                    .to_owned();
+                (e, session_id)
+            }
+            None => {
+                self.print_error(&format!(
+                    "No undecryptable message with the event or session id {}",
+                    id
+                ));
+                return;
+            }
+        };
+
+        let connection = if let Some(c) = self.connection.borrow().clone() {
+            c
+        } else {
+            self.print_error("Can't request room keys, not connected");
+            return;
+        };
+
+        match connection.request_room_key(&self.room_id, event).await {
+            // The key arrived in the meantime, nothing to request.
+            Ok(Some(_)) => self.retry_decryption(&session_id).await,
+            Ok(None) => self.print_network(&format!(
+                "Requested the room key for session {} from your other \
+                 devices",
+                session_id
+            )),
+            Err(e) => self
+                .print_error(&format!("Error requesting the room key: {}", e)),
+        }
+    }
+
+    /// Replace the local echo of an event with a fully rendered one.
+    fn replace_local_echo(
+        &self,
+        uuid: Uuid,
+        buffer: &Buffer,
+        rendered: RenderedEvent,
+    ) {
+        let uuid_tag = Cow::from(format!("matrix_echo_{}", uuid.to_string()));
+        let line_contains_uuid = |l: &BufferLine| l.tags().contains(&uuid_tag);
+
+        let mut lines = buffer.lines();
+        let mut first_line = lines.rfind(line_contains_uuid);
+        let mut line_num = 0;
+
+        while let Some(line) = &first_line {
+            let rendered_line = &rendered.content.lines[line_num];
+            let tags: Vec<&str> =
+                rendered_line.tags.iter().map(|t| t.as_str()).collect();
+
+            // Replace the echo tag with the event tags so the line can be
+            // found using the event id from now on.
+            line.set_message(&rendered_line.message);
+            line.set_tags(&tags);
+
+            line_num += 1;
+            first_line = lines.next_back().filter(line_contains_uuid);
+        }
+    }
+
+    /// Print the event we sent out with the given transaction id.
+    ///
+    /// Returns false if the event isn't in our outgoing message queue, e.g.
+    /// because it was sent before a restart, it needs to be printed like any
+    /// other event in that case.
+    async fn handle_outgoing_message(
+        &self,
+        uuid: Uuid,
+        event_id: &EventId,
+    ) -> bool {
+        if let Some((echo, content)) = self.outgoing_messages.remove(uuid) {
+            let event = SyncMessageEvent {
+                sender: (&*self.own_user_id).clone(),
+                origin_server_ts: std::time::SystemTime::now(),
+                event_id: event_id.clone(),
+                content,
+                unsigned: Default::default(),
+            };
+
+            let event = AnySyncMessageEvent::RoomMessage(event);
+
+            let rendered = self
+                .render_message_event(&event)
+                .await
+                .expect("Sent out an event that we don't know how to render");
+
+            if let Ok(buffer) = self.buffer_handle().upgrade() {
+                if echo {
+                    self.replace_local_echo(uuid, &buffer, rendered);
+                } else {
+                    self.print_rendered_event(rendered);
+                }
+            }
+
+            true
+        } else {
+            false
+        }
+    }
+
+    fn add_recent_speaker(&self, user_id: &UserId) {
+        let mut speakers = self.recent_speakers.borrow_mut();
+
+        speakers.retain(|u| u != user_id);
+        speakers.push_front(user_id.clone());
+        speakers.truncate(RECENT_SPEAKERS);
+    }
+
+    /// Get the aliases of the room, the canonical alias comes first.
+    pub fn aliases(&self) -> Vec<String> {
+        let room = self.room();
+
+        room.canonical_alias
+            .iter()
+            .chain(room.aliases.iter().filter(|a| {
+                room.canonical_alias.as_ref().map_or(true, |c| c != *a)
+            }))
+            .map(|a| a.to_string())
+            .collect()
+    }
+
+    /// Get the words that complete to the members of the room.
+    ///
+    /// Members that spoke recently come first, the rest of the members follow
+    /// in alphabetical order. Our own user is left out.
+    ///
+    /// # Arguments
+    ///
+    /// * `user_ids` - Complete to the user ids of the members instead of their
+    /// nicks.
+    pub fn member_completions(&self, user_ids: bool) -> Vec<String> {
+        let word = |m: &WeechatRoomMember| {
+            if user_ids {
+                m.user_id.to_string()
+            } else {
+                m.nick.borrow().clone()
+            }
+        };
+
+        let recent: Vec<WeechatRoomMember> = self
+            .recent_speakers
+            .borrow()
+            .iter()
+            .filter_map(|u| self.members.get(u))
+            .collect();
+
+        let mut rest: Vec<WeechatRoomMember> = self
+            .members
+            .members()
+            .into_iter()
+            .filter(|m| {
+                *m.user_id != *self.own_user_id
+                    && !recent.iter().any(|r| r.user_id == m.user_id)
+            })
+            .collect();
+
+        rest.sort_by_key(|m| word(m).to_lowercase());
+
+        recent.iter().chain(rest.iter()).map(word).collect()
+    }
+
+    /// Print the number of members and their nicks to the room buffer.
+    pub fn print_names(&self) {
+        let buffer = if let Ok(b) = self.buffer_handle().upgrade() {
+            b
+        } else {
+            return;
+        };
+
+        let names = self.members.names();
+        let count = |group: MemberGroup| {
+            names.iter().filter(|(g, _)| *g == group).count()
+        };
+
+        let nicks = names
+            .iter()
+            .map(|(group, nick)| format!("{}{}", group.prefix(), nick))
+            .collect::<Vec<String>>()
+            .join(" ");
+
+        let room_name = self.members.calculate_buffer_name();
+
+        buffer.print(&format!(
+            "{}Nicks {}{}{}: {}[{}{}{}]{}",
+            Weechat::prefix("network"),
+            Weechat::color("chat_channel"),
+            room_name,
+            Weechat::color("reset"),
+            Weechat::color("chat_delimiters"),
+            Weechat::color("reset"),
+            nicks,
+            Weechat::color("chat_delimiters"),
+            Weechat::color("reset"),
+        ));
+
+        buffer.print(&format!(
+            "{}Room {}{}{}: {} members ({} admins, {} moderators, {} \
+             regular members)",
+            Weechat::prefix("network"),
+            Weechat::color("chat_channel"),
+            room_name,
+            Weechat::color("reset"),
+            names.len(),
+            count(MemberGroup::Admin),
+            count(MemberGroup::Moderator),
+            count(MemberGroup::Member),
+        ));
+    }
+
+    fn update_buffer_name(&self) {
+        let name = self.members.calculate_buffer_name();
+        let short_name = self.members.calculate_short_name();
+
+        if let Ok(b) = self.buffer_handle().upgrade() {
+            b.set_name(&name);
+            b.set_short_name(&short_name);
+        }
+    }
+
+    /// Set the title of the buffer to the topic of the room.
+    ///
+    /// Rooms that don't notify us about every message show their notification
+    /// level in front of the topic.
+    fn update_buffer_title(&self) {
+        let topic = self.room().topic.clone().unwrap_or_default();
+
+        let title = match self.notification_level.get() {
+            NotificationLevel::All => topic,
+            NotificationLevel::Mentions => {
+                format!("[mentions only] {}", topic).trim_end().to_owned()
+            }
+            NotificationLevel::None => {
+                format!("[muted] {}", topic).trim_end().to_owned()
+            }
+        };
+
+        if let Ok(b) = self.buffer_handle().upgrade() {
+            b.set_title(&title)
+        }
+    }
+
+    /// Update the cached notification level of the room, e.g. because our
+    /// push rules changed.
+    pub fn set_notification_level(&self, level: NotificationLevel) {
+        if self.notification_level.replace(level) != level {
+            self.update_buffer_title();
+        }
+    }
+
+    /// Print the notification level of the room.
+    pub fn print_notification_level(&self) {
+        self.print_network(&format!(
+            "The notification level of this room is {}",
+            self.notification_level.get().as_str()
+        ));
+    }
+
+    /// Change the notification level of the room by updating our push rules.
+    pub async fn change_notification_level(&self, level: NotificationLevel) {
+        let connection = if let Some(c) = self.connection.borrow().clone() {
+            c
+        } else {
+            self.print_error("Error not connected");
+            return;
+        };
+
+        match connection
+            .set_room_notification_mode(&self.room_id, level)
+            .await
+        {
+            Ok(()) => {
+                // Don't wait for the push rules to come back in a sync
+                // response, messages might arrive before that.
+                self.set_notification_level(level);
+                self.print_notification_level();
+            }
+            Err(e) => self.print_error(&format!(
+                "Error changing the notification level: {}",
+                e
+            )),
+        }
+    }
+
+    /// Print an error message to the room buffer.
+    pub fn print_error(&self, message: &str) {
+        if let Ok(buffer) = self.buffer_handle().upgrade() {
+            buffer.print(&format!(
+                "{}{}: {}",
+                Weechat::prefix("error"),
+                PLUGIN_NAME,
+                message
+            ));
+        }
+    }
+
+    /// Print a message with the network prefix to the room buffer.
+    fn print_network(&self, message: &str) {
+        if let Ok(buffer) = self.buffer_handle().upgrade() {
+            buffer.print(&format!("{}{}", Weechat::prefix("network"), message));
+        }
+    }
+
+    /// Print information about the user with the given user id.
+    ///
+    /// The profile is taken from the room if the user is a member of it,
+    /// otherwise it's fetched from the server.
+    pub async fn whois(&self, user_id: UserId) {
+        let connection = if let Some(c) = self.connection.borrow().clone() {
+            c
+        } else {
+            self.print_error("Error not connected");
+            return;
+        };
+
+        let (display_name, avatar_url, membership) = {
+            let room = self.room();
+
+            let membership = if room.joined_members.contains_key(&user_id) {
+                Some("joined")
+            } else if room.invited_members.contains_key(&user_id) {
+                Some("invited")
+            } else {
+                None
+            };
+
+            let member = room.get_member(&user_id);
+
+            (
+                member.and_then(|m| m.display_name.clone()),
+                member.and_then(|m| m.avatar_url.clone()),
+                membership,
+            )
+        };
+
+        let (display_name, avatar_url, membership) =
+            if let Some(membership) = membership {
+                (display_name, avatar_url, membership)
+            } else {
+                match connection.get_profile(&user_id).await {
+                    Ok(p) => (p.displayname, p.avatar_url, "not a member"),
+                    Err(e) => {
+                        self.print_error(&format!(
+                            "Error fetching the profile of {}: {}",
+                            user_id, e
+                        ));
+                        return;
+                    }
+                }
+            };
+
+        let devices = match connection.user_devices(&user_id).await {
+            Ok(d) => d,
+            Err(e) => {
+                self.print_error(&format!(
+                    "Error fetching the devices of {}: {}",
+                    user_id, e
+                ));
+                Vec::new()
+            }
+        };
+
+        // Avatars are downloaded lazily, only once somebody is interested in
+        // them. Scripts can pick the cached file up using the signal, e.g. to
+        // render it on terminals with image support.
+        let avatar_path = if let Some(url) = &avatar_url {
+            match connection.avatar(url).await {
+                Ok(path) => {
+                    Weechat::hook_signal_send(
+                        "matrix_avatar_downloaded",
+                        format!("{} {}", user_id, path.display()).as_str(),
+                    );
+                    Some(path)
+                }
+                Err(e) => {
+                    self.print_error(&format!(
+                        "Error downloading the avatar of {}: {}",
+                        user_id, e
+                    ));
+                    None
+                }
+            }
+        } else {
+            None
+        };
+
+        let color = Weechat::info_get("nick_color_name", user_id.as_str())
+            .unwrap_or_default();
+
+        self.print_network(&format!(
+            "{}[{}{}{}]",
+            Weechat::color("chat_delimiters"),
+            Weechat::color(&color),
+            user_id,
+            Weechat::color("chat_delimiters"),
+        ));
+
+        let format_device = |d: &UserDevice| {
+            format!(
+                "{} {}({}){}",
+                d.device_id.as_str(),
+                Weechat::color("chat_delimiters"),
+                if d.verified { "verified" } else { "unverified" },
+                Weechat::color("reset"),
+            )
+        };
+
+        let mut lines = vec![
+            format!("  display name: {}", display_name.unwrap_or_default()),
+            format!("  avatar: {}", avatar_url.unwrap_or_default()),
+            format!(
+                "  avatar file: {}",
+                avatar_path.map_or("".to_owned(), |p| p.display().to_string())
+            ),
+            format!("  membership: {}", membership),
+            format!("  power level: {}", self.members.power_level(&user_id)),
+            format!("  devices: {}", devices.len()),
+        ];
+
+        lines.extend(devices.iter().map(|d| {
+            format!(
+                "    {}{}",
+                format_device(d),
+                d.display_name
+                    .as_ref()
+                    .map(|n| format!(" {}", n))
+                    .unwrap_or_default()
+            )
+        }));
+
+        for line in lines {
+            self.print_network(&line);
+        }
+    }
+
+    /// Print the topic of the room to the room buffer.
+    pub fn print_topic(&self) {
+        let topic = self.room().topic.clone();
+
+        if let Ok(buffer) = self.buffer_handle().upgrade() {
+            let message = if let Some(topic) = topic {
+                format!(
+                    "{}Topic for {}{}{} is \"{}\"",
+                    Weechat::prefix("network"),
+                    Weechat::color("chat_channel"),
+                    self.members.calculate_buffer_name(),
+                    Weechat::color("reset"),
+                    topic
+                )
+            } else {
+                format!(
+                    "{}No topic set for {}{}{}",
+                    Weechat::prefix("network"),
+                    Weechat::color("chat_channel"),
+                    self.members.calculate_buffer_name(),
+                    Weechat::color("reset"),
+                )
+            };
+
+            buffer.print(&message);
+        }
+    }
+
+    /// Send a state event with an empty state key to the room.
+    ///
+    /// The state of the room buffer will be updated once the event comes back
+    /// from the server in a sync response.
+    async fn send_state_event(&self, content: AnyStateEventContent) {
+        let connection = self.connection.borrow().clone();
+
+        if let Some(c) = connection {
+            if let Err(e) = c.send_state_event(&self.room_id, content, "").await
+            {
+                self.print_state_event_error(e);
+            }
+        } else {
+            self.print_error("Error not connected");
+        }
+    }
+
+    /// Send an event with an arbitrary type and content to the room.
+    ///
+    /// # Arguments
+    ///
+    /// * `event_type` - The type of the event.
+    ///
+    /// * `content` - The JSON content of the event.
+    ///
+    /// * `state_key` - The state key of the event, if set the event is sent as
+    /// a state event.
+    pub async fn send_raw_event(
         &self,
-        uuid: Uuid,
-        buffer: &Buffer,
-        rendered: RenderedEvent,
+        event_type: String,
+        content: serde_json::Value,
+        state_key: Option<String>,
     ) {
-        let uuid_tag = Cow::from(format!("matrix_echo_{}", uuid.to_string()));
-        let line_contains_uuid = |l: &BufferLine| l.tags().contains(&uuid_tag);
-
-        let mut lines = buffer.lines();
-        let mut first_line = lines.rfind(line_contains_uuid);
-        let mut line_num = 0;
+        let connection = if let Some(c) = self.connection.borrow().clone() {
+            c
+        } else {
+            self.print_error("Error not connected");
+            return;
+        };
 
-        while let Some(line) = &first_line {
-            let rendered_line = &rendered.content.lines[line_num];
+        let kind = if state_key.is_some() {
+            "state"
+        } else {
+            "message"
+        };
 
-            line.set_message(&rendered_line.message);
+        match connection
+            .send_custom_event(
+                &self.room_id,
+                event_type.clone(),
+                content,
+                state_key,
+            )
+            .await
+        {
+            Ok(event_id) => self.print_network(&format!(
+                "Sent the {} {} event {}",
+                event_type, kind, event_id
+            )),
+            Err(e) if e.is_forbidden() => self.print_error(&format!(
+                "You don't have the permission to send {} events in this room",
+                event_type
+            )),
+            Err(e) => self.print_error(&format!(
+                "Error sending the {} event: {}",
+                event_type, e
+            )),
+        }
+    }
 
-            line_num += 1;
-            first_line = lines.next_back().filter(line_contains_uuid);
+    fn print_state_event_error(&self, error: ConnectionError) {
+        if error.is_forbidden() {
+            self.print_error(
+                "You don't have the permission to change this in this room",
+            );
+        } else {
+            self.print_error(&format!("Error sending state event: {}", error));
         }
     }
 
-    async fn handle_outgoing_message(&self, uuid: Uuid, event_id: &EventId) {
-        if let Some((echo, content)) = self.outgoing_messages.remove(uuid) {
-            let event = SyncMessageEvent {
-                sender: (&*self.own_user_id).clone(),
-                origin_server_ts: std::time::SystemTime::now(),
-                event_id: event_id.clone(),
-                content,
-                unsigned: Default::default(),
-            };
+    /// Print whether the room is encrypted and which algorithm it uses.
+    pub fn print_encryption_status(&self) {
+        let algorithm = self
+            .room()
+            .encrypted
+            .as_ref()
+            .map(|e| e.algorithm().to_string());
 
-            let event = AnySyncMessageEvent::RoomMessage(event);
+        let message = if let Some(algorithm) = algorithm {
+            format!("Encryption is enabled in this room ({})", algorithm)
+        } else {
+            "Encryption isn't enabled in this room".to_owned()
+        };
 
-            let rendered = self
-                .render_message_event(&event)
-                .await
-                .expect("Sent out an event that we don't know how to render");
+        self.print_network(&message);
+    }
 
-            if let Ok(buffer) = self.buffer_handle().upgrade() {
-                if echo {
-                    self.replace_local_echo(uuid, &buffer, rendered);
-                } else {
-                    self.print_rendered_event(rendered);
-                }
+    /// Enable encryption in the room.
+    ///
+    /// The room will be marked as encrypted once the event comes back from the
+    /// server in a sync response.
+    pub async fn enable_encryption(&self) {
+        if self.is_encrypted() {
+            self.print_error("Encryption is already enabled in this room");
+            return;
+        }
+
+        let connection = self.connection.borrow().clone();
+
+        if let Some(c) = connection {
+            if let Err(e) = c.enable_encryption(&self.room_id).await {
+                self.print_state_event_error(e);
             }
+        } else {
+            self.print_error("Error not connected");
         }
     }
 
-    fn update_buffer_name(&self) {
-        let name = self.members.calculate_buffer_name();
+    /// Change the topic of the room.
+    pub async fn set_topic(&self, topic: String) {
+        let content =
+            AnyStateEventContent::RoomTopic(TopicEventContent { topic });
+        self.send_state_event(content).await;
+    }
 
-        if let Ok(b) = self.buffer_handle().upgrade() {
-            b.set_name(&name)
+    /// Change the name of the room.
+    pub async fn set_name(&self, name: String) {
+        match NameEventContent::new(name) {
+            Ok(content) => {
+                self.send_state_event(AnyStateEventContent::RoomName(content))
+                    .await
+            }
+            Err(e) => self.print_error(&format!("Invalid room name: {}", e)),
+        }
+    }
+
+    /// Check if the message should highlight us.
+    ///
+    /// A message highlights us if it mentions our user id, our display name
+    /// or one of the configured highlight words. Our own messages never
+    /// highlight us.
+    fn is_highlight(&self, event: &AnySyncMessageEvent) -> bool {
+        if event.sender() == &*self.own_user_id {
+            return false;
+        }
+
+        let content =
+            if let AnyMessageEventContent::RoomMessage(c) = event.content() {
+                c
+            } else {
+                return false;
+            };
+
+        let body = reply::content_body(&content).to_lowercase();
+        let formatted = match &content {
+            MessageEventContent::Text(c) => c.formatted.as_ref(),
+            MessageEventContent::Emote(c) => c.formatted.as_ref(),
+            MessageEventContent::Notice(c) => c.formatted.as_ref(),
+            _ => None,
         }
+        .map(|f| f.body.to_lowercase());
+
+        let mut words = self.settings.borrow().highlight_words.clone();
+        words.push(self.own_user_id.to_string());
+
+        if let Some(display_name) = self
+            .members
+            .get(&self.own_user_id)
+            .and_then(|m| m.display_name.borrow().clone())
+        {
+            words.push(display_name);
+        }
+
+        let word_highlight = words.iter().map(|w| w.to_lowercase()).any(|w| {
+            contains_word(&body, &w)
+                || formatted.as_ref().map_or(false, |f| contains_word(f, &w))
+        });
+
+        // The content push rules of the user are matched against single
+        // words of the plain text body.
+        word_highlight
+            || self.highlight_patterns.borrow().iter().any(|p| {
+                let pattern: Vec<char> = p.chars().collect();
+
+                body.split_whitespace()
+                    .map(|w| w.trim_matches(|c: char| !c.is_alphanumeric()))
+                    .any(|w| {
+                        glob_match(&pattern, &w.chars().collect::<Vec<_>>())
+                    })
+            })
     }
 
-    async fn handle_room_message(&self, event: &AnySyncMessageEvent) {
+    /// Print out a message.
+    ///
+    /// Returns a notification if the message highlights us or if it was sent
+    /// to us in a direct chat.
+    async fn handle_room_message(
+        &self,
+        event: &AnySyncMessageEvent,
+        notify: bool,
+    ) -> Option<Notification> {
         // If the event has a transaction id it's an event that we sent out
         // ourselves, the content will be in the outgoing message queue and it
         // may have been printed out as a local echo.
         if let Some(id) = &event.unsigned().transaction_id {
             if let Ok(id) = Uuid::parse_str(id) {
-                self.handle_outgoing_message(id, event.event_id()).await;
-                return;
+                if self.handle_outgoing_message(id, event.event_id()).await {
+                    return None;
+                }
+            }
+        }
+
+        // Edits replace the message they edit instead of being printed.
+        if let Some(event_id) = edit::replaced_event(event) {
+            if let AnySyncMessageEvent::RoomMessage(m) = event {
+                self.handle_edit(event_id, m).await;
+            }
+
+            return None;
+        }
+
+        if let AnySyncMessageEvent::RoomEncrypted(e) = event {
+            if megolm_session_id(&e.content).is_some() {
+                self.add_undecrypted_event(e.clone());
             }
         }
 
         if let AnySyncMessageEvent::RoomRedaction(r) = event {
             self.redact_event(r);
-        } else if let Some(rendered) = self.render_message_event(event).await {
+            None
+        } else if let Some(mut rendered) =
+            self.render_message_event(event).await
+        {
+            if notify && event.sender() != &*self.own_user_id {
+                self.add_recent_speaker(event.sender());
+            }
+
+            // Notices are usually sent by bots, those can be kept from
+            // highlighting us.
+            let quiet_notice =
+                is_notice(event) && !self.settings.borrow().highlight_notices;
+
+            let level = self.notification_level.get();
+            let muted = level == NotificationLevel::None;
+
+            // Weechat would highlight our own messages if they contain our
+            // nick, messages from the history shouldn't trigger a new highlight
+            // either.
+            let tag = if event.sender() == &*self.own_user_id || !notify {
+                "no_highlight"
+            } else if muted {
+                "notify_none"
+            } else if !quiet_notice && self.is_highlight(event) {
+                "notify_highlight"
+            } else if level == NotificationLevel::Mentions {
+                "notify_low"
+            } else {
+                "notify_message"
+            };
+
+            for line in &mut rendered.content.lines {
+                line.tags.push(tag.to_owned());
+
+                if quiet_notice || muted {
+                    line.tags.push("no_highlight".to_owned());
+                }
+            }
+
             self.print_rendered_event(rendered);
+
+            if notify && self.settings.borrow().url_previews {
+                self.preview_url(event);
+            }
+
+            let direct = tag == "notify_message"
+                && !quiet_notice
+                && self.direct_target().is_some();
+
+            if tag == "notify_highlight" || direct {
+                self.notification(event)
+            } else {
+                None
+            }
+        } else {
+            None
         }
     }
 
+    /// Fetch the preview of the first link in a message and print it out
+    /// below the message.
+    fn preview_url(&self, event: &AnySyncMessageEvent) {
+        let body = match event.content() {
+            AnyMessageEventContent::RoomMessage(MessageEventContent::Text(
+                c,
+            )) if reply::in_reply_to(&c).is_some() => {
+                reply::strip_plain_fallback(&c.body).to_owned()
+            }
+            AnyMessageEventContent::RoomMessage(c) => {
+                reply::content_body(&c).to_owned()
+            }
+            _ => return,
+        };
+
+        let url = if let Some(url) = links::find_url(&body) {
+            url.to_owned()
+        } else {
+            return;
+        };
+
+        let connection = if let Some(c) = self.connection.borrow().clone() {
+            c
+        } else {
+            return;
+        };
+
+        let room = self.clone();
+        let event_id = event.event_id().clone();
+
+        let fetch = async move {
+            match connection.get_url_preview(&url).await {
+                Ok(Some(preview)) => room.print_url_preview(&event_id, preview),
+                Ok(None) => (),
+                // Previews are optional, don't bother the user with errors.
+                Err(e) => debug!("Error fetching a preview of {}: {}", url, e),
+            }
+        };
+
+        Weechat::spawn(fetch).detach();
+    }
+
+    fn print_url_preview(&self, event_id: &EventId, preview: UrlPreview) {
+        // The preview is squeezed onto a single line.
+        let shorten = |text: String, length: usize| {
+            let text = text.split_whitespace().collect::<Vec<_>>().join(" ");
+
+            if text.chars().count() > length {
+                let text: String = text.chars().take(length - 1).collect();
+                format!("{}…", text.trim_end())
+            } else {
+                text
+            }
+        };
+
+        let title = preview.title.map(|t| shorten(t, MAX_PREVIEW_TITLE));
+        let description = preview
+            .description
+            .map(|d| shorten(d, MAX_PREVIEW_DESCRIPTION));
+
+        let text = match (title, description) {
+            (Some(t), Some(d)) => format!(
+                "{}{}{} - {}",
+                Weechat::color("bold"),
+                t,
+                Weechat::color("-bold"),
+                d
+            ),
+            (Some(t), None) => format!(
+                "{}{}{}",
+                Weechat::color("bold"),
+                t,
+                Weechat::color("-bold")
+            ),
+            (None, Some(d)) => d,
+            (None, None) => return,
+        };
+
+        let tags = [
+            "matrix_url_preview",
+            &format!("matrix_preview_{}", event_id),
+            "notify_none",
+            "no_highlight",
+        ];
+
+        let buffer = if let Ok(b) = self.buffer_handle().upgrade() {
+            b
+        } else {
+            return;
+        };
+
+        // Weechat can't insert lines in the middle of a buffer. If other
+        // lines were printed while the preview was fetched it can't go right
+        // below its message, quote the message so it's clear which one the
+        // preview belongs to.
+        let event_id_tag = Cow::from(event_id_tag(event_id));
+        let below_message = buffer
+            .lines()
+            .next_back()
+            .map_or(false, |l| l.tags().contains(&event_id_tag));
+
+        let text = if below_message {
+            text
+        } else {
+            let quote =
+                self.quote(event_id).unwrap_or_else(|| event_id.to_string());
+
+            format!(
+                "{}{}:{} {}",
+                quote,
+                Weechat::color("chat_delimiters"),
+                Weechat::color("reset"),
+                text
+            )
+        };
+
+        buffer.print_date_tags(
+            0,
+            &tags,
+            &format!(
+                "\t{}│{} {}",
+                Weechat::color("chat_delimiters"),
+                Weechat::color("reset"),
+                text
+            ),
+        );
+    }
+
+    fn notification(
+        &self,
+        event: &AnySyncMessageEvent,
+    ) -> Option<Notification> {
+        let content =
+            if let AnyMessageEventContent::RoomMessage(c) = event.content() {
+                c
+            } else {
+                return None;
+            };
+
+        let sender = self
+            .members
+            .get(event.sender())
+            .map(|m| m.nick.borrow().clone())
+            .unwrap_or_else(|| event.sender().to_string());
+
+        Some(Notification {
+            room_name: self.room().display_name(),
+            sender,
+            body: reply::preview(&content),
+        })
+    }
+
     fn handle_redacted_events(&self, event: &AnyRedactedSyncMessageEvent) {
         use AnyRedactedSyncMessageEvent::*;
 
@@ -741,10 +2953,119 @@ impl MatrixRoom {
         }
     }
 
-    pub async fn handle_sync_room_event(&self, event: AnySyncRoomEvent) {
+    /// Print the given membership change, or hold it back so it can be
+    /// collapsed with the changes that follow it.
+    fn queue_membership(&self, line: MembershipLine) {
+        let threshold = self.settings.borrow().membership_collapse_threshold;
+        let connection = self.connection.borrow().clone();
+
+        let connection = match connection {
+            Some(c) if threshold > 0 => c,
+            _ => {
+                self.print_membership(&line);
+                return;
+            }
+        };
+
+        let first = {
+            let mut pending = self.pending_memberships.borrow_mut();
+            pending.push(line);
+            pending.len() == 1
+        };
+
+        if first {
+            let room = self.clone();
+
+            Weechat::spawn(async move {
+                connection.sleep(MEMBERSHIP_BATCH_WINDOW).await;
+                room.flush_memberships();
+            })
+            .detach();
+        }
+    }
+
+    /// Print the membership changes we held back, collapsed into a single
+    /// summary line if there are at least as many as the configured threshold.
+    fn flush_memberships(&self) {
+        let pending: Vec<MembershipLine> =
+            self.pending_memberships.borrow_mut().drain(..).collect();
+
+        let last = if let Some(l) = pending.last() {
+            l
+        } else {
+            return;
+        };
+
+        let threshold = self.settings.borrow().membership_collapse_threshold;
+
+        if threshold == 0 || (pending.len() as u32) < threshold {
+            for line in &pending {
+                self.print_membership(line);
+            }
+            return;
+        }
+
+        let count = |kind| pending.iter().filter(|l| l.kind == kind).count();
+
+        let parts: Vec<String> = [
+            (MembershipKind::Joined, "joined"),
+            (MembershipKind::Left, "left"),
+            (MembershipKind::ProfileChanged, "changed their profile"),
+            (MembershipKind::Other, "other membership changes"),
+        ]
+        .iter()
+        .filter_map(|(kind, description)| match count(*kind) {
+            0 => None,
+            n => Some(format!("{} {}", n, description)),
+        })
+        .collect();
+
+        let summary = MembershipLine {
+            kind: MembershipKind::Other,
+            timestamp: last.timestamp,
+            message: format!(
+                "{}Membership changes: {}",
+                Weechat::prefix("network"),
+                parts.join(", ")
+            ),
+        };
+
+        self.print_membership(&summary);
+    }
+
+    fn print_membership(&self, line: &MembershipLine) {
+        if let Ok(buffer) = self.buffer_handle().upgrade() {
+            buffer.print_date_tags(line.timestamp, &[], &line.message);
+        }
+    }
+
+    /// Handle a timeline event of a sync response.
+    ///
+    /// Returns a notification if the event is a message the user should be
+    /// notified about.
+    pub async fn handle_sync_room_event(
+        &self,
+        event: AnySyncRoomEvent,
+        thread_root: Option<EventId>,
+    ) -> Option<Notification> {
+        if let (Some(root), AnySyncRoomEvent::Message(m)) =
+            (thread_root, &event)
+        {
+            self.add_thread_root(m.event_id().clone(), root);
+        }
+
+        // Membership changes are batched, print the collected ones before
+        // anything else so the buffer stays in order.
+        if !matches!(
+            event,
+            AnySyncRoomEvent::State(AnySyncStateEvent::RoomMember(_))
+        ) {
+            self.flush_memberships();
+        }
+
         match &event {
             AnySyncRoomEvent::Message(message) => {
-                self.handle_room_message(message).await
+                return self.handle_room_message(message, true).await;
             }
 
             AnySyncRoomEvent::RedactedMessage(e) => {
@@ -755,21 +3076,188 @@ impl MatrixRoom {
 
             AnySyncRoomEvent::State(event) => match event {
                 AnySyncStateEvent::RoomMember(e) => {
-                    self.members.handle_membership_event(e, false)
+                    if let Some(line) =
+                        self.members.handle_membership_event(e, false)
+                    {
+                        self.queue_membership(line);
+                    }
+                }
+                AnySyncStateEvent::RoomName(_)
+                | AnySyncStateEvent::RoomCanonicalAlias(_) => {
+                    self.update_buffer_name()
+                }
+                AnySyncStateEvent::RoomTopic(_) => self.update_buffer_title(),
+                AnySyncStateEvent::RoomPowerLevels(e) => {
+                    self.members.update_power_levels(&e.content)
+                }
+                AnySyncStateEvent::RoomEncryption(_) => {
+                    self.print_encryption_status();
+                    Weechat::bar_item_update(MODES_BAR_ITEM);
+                }
+                AnySyncStateEvent::RoomTombstone(_) => self.handle_tombstone(),
+                AnySyncStateEvent::RoomPinnedEvents(e) => {
+                    self.handle_pinned_events(e, false)
                 }
-                AnySyncStateEvent::RoomName(_) => self.update_buffer_name(),
                 _ => (),
             },
         }
+
+        None
+    }
+
+    /// Handle an older event of the room that was fetched as backlog.
+    ///
+    /// Like the messages restored from the store these are history, they
+    /// don't highlight us and state changes in them aren't acted upon.
+    pub async fn handle_backlog_event(
+        &self,
+        event: AnySyncRoomEvent,
+        thread_root: Option<EventId>,
+    ) {
+        match &event {
+            AnySyncRoomEvent::Message(message) => {
+                if let Some(root) = thread_root {
+                    self.add_thread_root(message.event_id().clone(), root);
+                }
+
+                self.handle_room_message(message, false).await;
+            }
+            AnySyncRoomEvent::RedactedMessage(e) => {
+                self.handle_redacted_events(e)
+            }
+            _ => (),
+        }
     }
 
     pub fn handle_sync_state_event(&self, event: AnySyncStateEvent) {
         match &event {
             AnySyncStateEvent::RoomMember(e) => {
-                self.members.handle_membership_event(e, true)
+                self.members.handle_membership_event(e, true);
+            }
+            AnySyncStateEvent::RoomName(_)
+            | AnySyncStateEvent::RoomCanonicalAlias(_) => {
+                self.update_buffer_name()
+            }
+            AnySyncStateEvent::RoomTopic(_) => self.update_buffer_title(),
+            AnySyncStateEvent::RoomPowerLevels(e) => {
+                self.members.update_power_levels(&e.content)
+            }
+            AnySyncStateEvent::RoomEncryption(_) => {
+                Weechat::bar_item_update(MODES_BAR_ITEM)
+            }
+            AnySyncStateEvent::RoomTombstone(_) => self.show_tombstone(),
+            AnySyncStateEvent::RoomPinnedEvents(e) => {
+                self.handle_pinned_events(e, true)
             }
-            AnySyncStateEvent::RoomName(_) => self.update_buffer_name(),
             _ => (),
         }
     }
 }
+
+/// Find the lines of the buffer that belong to the event with the given id.
+///
+/// Every line of a rendered event is tagged with the event id, the lines are
+/// returned in the order they appear in the buffer.
+pub fn find_event_lines<'a>(
+    buffer: &'a Buffer,
+    event_id: &EventId,
+) -> Vec<BufferLine<'a>> {
+    let event_id_tag = Cow::from(event_id_tag(event_id));
+
+    buffer
+        .lines()
+        .filter(|l| l.tags().contains(&event_id_tag))
+        .collect()
+}
+
+/// Replace the lines of an already printed event with a new rendering of it.
+///
+/// Lines can't be inserted into the buffer after the fact, if the new rendering
+/// has more lines than the old one the surplus lines are joined to the last
+/// line.
+fn replace_event_lines(
+    buffer: &Buffer,
+    event_id: &EventId,
+    rendered: RenderedEvent,
+) {
+    let lines = find_event_lines(buffer, event_id);
+    let line_count = lines.len();
+    let mut rendered_lines = rendered.content.lines.into_iter();
+
+    for (i, line) in lines.into_iter().enumerate() {
+        let rendered_line = if let Some(l) = rendered_lines.next() {
+            l
+        } else {
+            line.set_message("");
+            continue;
+        };
+
+        let mut message = rendered_line.message;
+
+        if i + 1 == line_count {
+            for l in rendered_lines.by_ref() {
+                message.push(' ');
+                message.push_str(&l.message);
+            }
+        }
+
+        let tags: Vec<&str> =
+            rendered_line.tags.iter().map(|t| t.as_str()).collect();
+
+        line.set_message(&message);
+        line.set_tags(&tags);
+    }
+}
+
+/// Get the id of the Megolm session the content is encrypted with.
+fn megolm_session_id(content: &EncryptedEventContent) -> Option<&str> {
+    if let EncryptedEventContent::MegolmV1AesSha2(c) = content {
+        Some(&c.session_id)
+    } else {
+        None
+    }
+}
+
+/// Check if the event is a notice.
+fn is_notice(event: &AnySyncMessageEvent) -> bool {
+    matches!(
+        event,
+        AnySyncMessageEvent::RoomMessage(SyncMessageEvent {
+            content: MessageEventContent::Notice(_),
+            ..
+        })
+    )
+}
+
+/// Check if the haystack contains the given word, the word needs to be
+/// surrounded by non-alphanumeric characters or the ends of the haystack.
+fn contains_word(haystack: &str, word: &str) -> bool {
+    if word.is_empty() {
+        return false;
+    }
+
+    haystack.match_indices(word).any(|(start, _)| {
+        let end = start + word.len();
+
+        let before = haystack[..start].chars().next_back();
+        let after = haystack[end..].chars().next();
+
+        !before.map_or(false, char::is_alphanumeric)
+            && !after.map_or(false, char::is_alphanumeric)
+    })
+}
+
+/// Match a text against a glob pattern, `*` matches any number of characters
+/// and `?` matches a single character.
+fn glob_match(pattern: &[char], text: &[char]) -> bool {
+    match pattern.split_first() {
+        None => text.is_empty(),
+        Some(('*', rest)) => {
+            (0..=text.len()).any(|i| glob_match(rest, &text[i..]))
+        }
+        Some(('?', rest)) => !text.is_empty() && glob_match(rest, &text[1..]),
+        Some((c, rest)) => {
+            text.first() == Some(c) && glob_match(rest, &text[1..])
+        }
+    }
+}
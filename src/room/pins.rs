@@ -0,0 +1,196 @@
+//! Pinned messages of room buffers.
+//!
+//! The ids of the pinned messages are kept in the `m.room.pinned_events` state
+//! event. Pinned messages that aren't part of the locally known history are
+//! fetched from the server when the user lists them.
+
+use matrix_sdk::{
+    events::{
+        room::pinned_events::PinnedEventsEventContent, AnyStateEventContent,
+        AnySyncMessageEvent, AnySyncRoomEvent, SyncStateEvent,
+    },
+    identifiers::EventId,
+};
+
+use weechat::Weechat;
+
+use super::MatrixRoom;
+
+impl MatrixRoom {
+    /// Get the ids of the pinned messages of the room.
+    pub fn pinned_events(&self) -> Vec<EventId> {
+        self.pinned_events.borrow().clone()
+    }
+
+    /// Remember the new set of pinned messages.
+    ///
+    /// # Arguments
+    ///
+    /// * `event` - The pinned events state event.
+    ///
+    /// * `state` - Is the event part of the room state, if not it's part of
+    /// the timeline and changes to the pins are printed out.
+    pub(super) fn handle_pinned_events(
+        &self,
+        event: &SyncStateEvent<PinnedEventsEventContent>,
+        state: bool,
+    ) {
+        let pinned = event.content.pinned.clone();
+        let old = self.pinned_events.replace(pinned.clone());
+
+        if state {
+            return;
+        }
+
+        let nick = self
+            .members
+            .get(&event.sender)
+            .map(|m| m.nick.borrow().clone())
+            .unwrap_or_else(|| event.sender.to_string());
+
+        for event_id in pinned.iter().filter(|e| !old.contains(e)) {
+            self.print_pin_change(&nick, "pinned", event_id);
+        }
+
+        for event_id in old.iter().filter(|e| !pinned.contains(e)) {
+            self.print_pin_change(&nick, "unpinned", event_id);
+        }
+    }
+
+    fn print_pin_change(&self, nick: &str, action: &str, event_id: &EventId) {
+        let message =
+            self.quote(event_id).unwrap_or_else(|| event_id.to_string());
+
+        self.print_network(&format!(
+            "{}{} {} a message: {}{}",
+            Weechat::color("darkgray"),
+            nick,
+            action,
+            message,
+            Weechat::color("reset")
+        ));
+    }
+
+    /// Print the pinned messages of the room.
+    ///
+    /// Messages that aren't in the locally known history are fetched from the
+    /// server.
+    pub async fn print_pinned_events(&self) {
+        let connection = self.connection.borrow().clone();
+
+        // Only changes to the pins reach us through the sync, prefer the
+        // current state of the server over the pins we know about.
+        let pinned = if let Some(c) = connection {
+            match c.get_pinned_events(&self.room_id).await {
+                Ok(p) => {
+                    self.pinned_events.replace(p.clone());
+                    p
+                }
+                Err(_) => self.pinned_events(),
+            }
+        } else {
+            self.pinned_events()
+        };
+
+        if pinned.is_empty() {
+            self.print_network("There are no pinned messages in this room");
+            return;
+        }
+
+        self.print_network(&format!("Pinned messages ({}):", pinned.len()));
+
+        for event_id in pinned {
+            let quote = match self.quote(&event_id) {
+                Some(q) => q,
+                None => self.fetch_quote(&event_id).await,
+            };
+
+            self.print_network(&format!(
+                "    {} {}({}){}",
+                quote,
+                Weechat::color("chat_delimiters"),
+                event_id,
+                Weechat::color("reset")
+            ));
+        }
+    }
+
+    /// Fetch a message from the server and quote it.
+    async fn fetch_quote(&self, event_id: &EventId) -> String {
+        let connection = if let Some(c) = self.connection.borrow().clone() {
+            c
+        } else {
+            return "<not available while disconnected>".to_owned();
+        };
+
+        match connection.get_event(&self.room_id, event_id).await {
+            Ok(AnySyncRoomEvent::Message(
+                AnySyncMessageEvent::RoomMessage(m),
+            )) => self.quote_message(&m),
+            Ok(AnySyncRoomEvent::Message(
+                AnySyncMessageEvent::RoomEncrypted(_),
+            )) => "<unable to decrypt the message>".to_owned(),
+            Ok(AnySyncRoomEvent::RedactedMessage(_)) => {
+                "<the message was deleted>".to_owned()
+            }
+            Ok(_) => "<not a message>".to_owned(),
+            Err(e) => format!("<error fetching the message: {}>", e),
+        }
+    }
+
+    /// Pin or unpin a message in the room.
+    ///
+    /// # Arguments
+    ///
+    /// * `event_id` - The id of the message that should be pinned or
+    /// unpinned.
+    ///
+    /// * `pin` - Should the message be pinned, otherwise it gets unpinned.
+    pub async fn set_pinned(&self, event_id: EventId, pin: bool) {
+        let connection = if let Some(c) = self.connection.borrow().clone() {
+            c
+        } else {
+            self.print_error("Error not connected");
+            return;
+        };
+
+        // The pins we know about only get updated when they change, e.g. the
+        // rooms restored after a restart don't know about them. Start from
+        // the current state so we don't throw away the pins of others.
+        let mut pinned = match connection.get_pinned_events(&self.room_id).await
+        {
+            Ok(p) => p,
+            Err(e) => {
+                self.print_error(&format!(
+                    "Error fetching the pinned messages: {}",
+                    e
+                ));
+                return;
+            }
+        };
+        self.pinned_events.replace(pinned.clone());
+
+        if pin {
+            if pinned.contains(&event_id) {
+                self.print_error("The message is already pinned");
+                return;
+            }
+
+            pinned.push(event_id);
+        } else {
+            if !pinned.contains(&event_id) {
+                self.print_error("The message isn't pinned");
+                return;
+            }
+
+            pinned.retain(|e| e != &event_id);
+        }
+
+        let content =
+            AnyStateEventContent::RoomPinnedEvents(PinnedEventsEventContent {
+                pinned,
+            });
+
+        self.send_state_event(content).await;
+    }
+}
@@ -0,0 +1,307 @@
+//! Search buffers.
+//!
+//! The results of a server side search in a room are printed into a separate
+//! buffer. Every result is numbered, typing the number into the search buffer
+//! jumps to the result in the room buffer while typing `next` fetches the next
+//! page of results.
+
+use std::{borrow::Cow, cell::RefCell, rc::Rc, time::SystemTime};
+
+use async_trait::async_trait;
+
+use matrix_sdk::{
+    events::{AnyMessageEvent, AnyRoomEvent},
+    identifiers::EventId,
+    Raw,
+};
+
+use weechat::{
+    buffer::{
+        Buffer, BufferBuilderAsync, BufferHandle, BufferInputCallbackAsync,
+    },
+    Weechat,
+};
+
+use super::MatrixRoom;
+use crate::{render::event_id_tag, reply, PLUGIN_NAME};
+
+#[derive(Clone)]
+struct SearchBuffer {
+    room: MatrixRoom,
+    terms: Rc<String>,
+    next_batch: Rc<RefCell<Option<String>>>,
+    results: Rc<RefCell<Vec<EventId>>>,
+}
+
+impl SearchBuffer {
+    fn print(&self, buffer: &BufferHandle, message: &str) {
+        if let Ok(buffer) = buffer.upgrade() {
+            buffer.print(message);
+        }
+    }
+
+    fn print_error(&self, buffer: &BufferHandle, message: &str) {
+        self.print(buffer, &format!("{}{}", Weechat::prefix("error"), message));
+    }
+
+    /// Print a single event of the search results.
+    ///
+    /// Results are numbered, the events around them are printed as context in
+    /// a dimmed color.
+    fn print_event(
+        &self,
+        buffer: &BufferHandle,
+        event: &Raw<AnyRoomEvent>,
+        number: Option<usize>,
+    ) {
+        let event = match event.deserialize() {
+            Ok(AnyRoomEvent::Message(AnyMessageEvent::RoomMessage(e))) => e,
+            _ => return,
+        };
+
+        let nick = self
+            .room
+            .members
+            .get(&event.sender)
+            .map(|m| {
+                format!(
+                    "{}{}{}",
+                    Weechat::color(&m.color.borrow()),
+                    m.nick.borrow(),
+                    Weechat::color("reset")
+                )
+            })
+            .unwrap_or_else(|| event.sender.to_string());
+
+        let (marker, color) = if let Some(number) = number {
+            (format!("[{}] ", number), "reset")
+        } else {
+            ("    ".to_owned(), "logger.color.backlog_line")
+        };
+
+        let timestamp = event
+            .origin_server_ts
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+
+        let body =
+            reply::strip_plain_fallback(reply::content_body(&event.content));
+
+        if let Ok(buffer) = buffer.upgrade() {
+            for line in body.lines() {
+                buffer.print_date_tags(
+                    timestamp as i64,
+                    &[],
+                    &format!(
+                        "{}\t{}{}{}",
+                        nick,
+                        Weechat::color(color),
+                        marker,
+                        line,
+                    ),
+                );
+            }
+        }
+    }
+
+    /// Fetch the next page of search results and print them.
+    async fn fetch(&self, buffer: &BufferHandle) {
+        let connection = if let Some(c) = self.room.connection.borrow().clone()
+        {
+            c
+        } else {
+            self.print_error(buffer, "Error not connected");
+            return;
+        };
+
+        let next_batch = self.next_batch.borrow().clone();
+        let first_page = next_batch.is_none();
+
+        let response = match connection
+            .search_messages(&self.room.room_id, &self.terms, next_batch)
+            .await
+        {
+            Ok(r) => r,
+            Err(e) if e.is_unrecognized() => {
+                self.print_error(
+                    buffer,
+                    "The homeserver doesn't support searching messages",
+                );
+                return;
+            }
+            Err(e) => {
+                self.print_error(buffer, &format!("Error searching: {}", e));
+                return;
+            }
+        };
+
+        let results = response.search_categories.room_events;
+
+        if first_page {
+            self.print(
+                buffer,
+                &format!(
+                    "{}Found {} results for \"{}\"",
+                    Weechat::prefix("network"),
+                    results
+                        .count
+                        .map_or_else(|| "no".to_owned(), |c| c.to_string()),
+                    self.terms
+                ),
+            );
+        }
+
+        for result in &results.results {
+            let event_id = match result.result.as_ref().map(|e| e.deserialize())
+            {
+                Some(Ok(e)) => e.event_id().clone(),
+                _ => continue,
+            };
+
+            for event in &result.context.events_before {
+                self.print_event(buffer, event, None);
+            }
+
+            let number = {
+                let mut event_ids = self.results.borrow_mut();
+                event_ids.push(event_id);
+                event_ids.len()
+            };
+
+            if let Some(event) = &result.result {
+                self.print_event(buffer, event, Some(number));
+            }
+
+            for event in &result.context.events_after {
+                self.print_event(buffer, event, None);
+            }
+        }
+
+        *self.next_batch.borrow_mut() = results.next_batch.clone();
+
+        let hint = if results.next_batch.is_some() {
+            "Type the number of a result to jump to it or next for more results"
+        } else {
+            "Type the number of a result to jump to it"
+        };
+
+        self.print(buffer, &format!("{}{}", Weechat::prefix("network"), hint));
+    }
+}
+
+#[async_trait(?Send)]
+impl BufferInputCallbackAsync for SearchBuffer {
+    async fn callback(&mut self, buffer: BufferHandle, input: String) {
+        let input = input.trim();
+
+        if input == "next" {
+            if self.next_batch.borrow().is_some() {
+                self.fetch(&buffer).await;
+            } else {
+                self.print_error(&buffer, "There are no more results");
+            }
+        } else if let Ok(number) = input.parse::<usize>() {
+            let event_id = number
+                .checked_sub(1)
+                .and_then(|n| self.results.borrow().get(n).cloned());
+
+            if let Some(event_id) = event_id {
+                if !self.room.jump_to_event(&event_id) {
+                    self.print_error(
+                        &buffer,
+                        "The message isn't loaded in the room buffer",
+                    );
+                }
+            } else {
+                self.print_error(&buffer, &format!("No result {}", number));
+            }
+        } else {
+            self.print_error(
+                &buffer,
+                "Type the number of a result or next for more results",
+            );
+        }
+    }
+}
+
+impl MatrixRoom {
+    /// Search the messages of the room on the server and show the results in
+    /// a search buffer.
+    ///
+    /// A previous search buffer of the room is closed.
+    pub async fn search(&self, terms: String) {
+        let buffer_name = format!("{}.search", self.room_id);
+
+        if let Some(buffer) = Weechat::buffer_search(PLUGIN_NAME, &buffer_name)
+        {
+            buffer.close();
+        }
+
+        let search = SearchBuffer {
+            room: self.clone(),
+            terms: Rc::new(terms),
+            next_batch: Rc::new(RefCell::new(None)),
+            results: Rc::new(RefCell::new(Vec::new())),
+        };
+
+        let buffer_handle = match BufferBuilderAsync::new(&buffer_name)
+            .input_callback(search.clone())
+            .close_callback(|_weechat: &Weechat, _buffer: &Buffer| Ok(()))
+            .build()
+        {
+            Ok(b) => b,
+            Err(_) => {
+                self.print_error("Can't create the search buffer");
+                return;
+            }
+        };
+
+        if let Ok(buffer) = buffer_handle.upgrade() {
+            buffer.set_short_name(&format!(
+                "search: {}",
+                self.members.calculate_short_name()
+            ));
+            buffer.set_title(&format!(
+                "Search results for \"{}\" in {}",
+                search.terms,
+                self.members.calculate_buffer_name()
+            ));
+            buffer.switch_to();
+        }
+
+        search.fetch(&buffer_handle).await;
+    }
+
+    /// Switch to the room buffer and scroll to the lines of the given event.
+    ///
+    /// Returns false if the event isn't printed in the room buffer.
+    pub fn jump_to_event(&self, event_id: &EventId) -> bool {
+        let buffer = if let Ok(b) = self.buffer_handle().upgrade() {
+            b
+        } else {
+            return false;
+        };
+
+        let tag = Cow::from(event_id_tag(event_id));
+
+        let lines_from_bottom = if let Some(p) =
+            buffer.lines().rev().position(|l| l.tags().contains(&tag))
+        {
+            p
+        } else {
+            return false;
+        };
+
+        buffer.switch_to();
+        buffer.run_command("/window scroll_bottom").ok();
+
+        if lines_from_bottom > 0 {
+            buffer
+                .run_command(&format!("/window scroll -{}", lines_from_bottom))
+                .ok();
+        }
+
+        true
+    }
+}
@@ -0,0 +1,259 @@
+//! File uploads for room buffers.
+//!
+//! Uploads show up as a placeholder line tagged like a local echo while the
+//! file is being uploaded. Once the message containing the file is sent out
+//! the placeholder gets replaced by the rendered message, the same way local
+//! echoes are replaced.
+
+use std::{
+    borrow::Cow,
+    io,
+    path::{Path, PathBuf},
+    process::Command,
+};
+
+use mime::Mime;
+use url::Url;
+
+use matrix_sdk::{
+    events::room::message::{
+        FileMessageEventContent, ImageMessageEventContent, MessageEventContent,
+    },
+    uuid::Uuid,
+};
+
+use weechat::Weechat;
+
+use super::MatrixRoom;
+
+/// Where the file that should be uploaded comes from.
+pub enum UploadSource {
+    /// A file on the disk.
+    File(PathBuf),
+    /// An image in the clipboard.
+    Clipboard,
+}
+
+impl UploadSource {
+    /// A human readable name of the file.
+    fn name(&self) -> String {
+        match self {
+            UploadSource::File(path) => path
+                .file_name()
+                .map(|n| n.to_string_lossy().to_string())
+                .unwrap_or_else(|| path.display().to_string()),
+            UploadSource::Clipboard => "clipboard.png".to_owned(),
+        }
+    }
+
+    fn content_type(&self) -> Mime {
+        match self {
+            UploadSource::File(path) => {
+                mime_guess::from_path(path).first_or_octet_stream()
+            }
+            UploadSource::Clipboard => mime::IMAGE_PNG,
+        }
+    }
+}
+
+/// Check if the input is the path of an image, e.g. an image that was dragged
+/// into the terminal.
+///
+/// Absolute paths, paths relative to the home directory and `file://` URIs
+/// are recognized, the file needs to exist.
+pub fn image_path(input: &str) -> Option<PathBuf> {
+    let input = input.trim().trim_matches(|c| c == '\'' || c == '"');
+
+    let path = if input.starts_with("file://") {
+        Url::parse(input).ok()?.to_file_path().ok()?
+    } else if let Some(path) = input.strip_prefix("~/") {
+        let mut home = PathBuf::from(std::env::var_os("HOME")?);
+        home.push(path);
+        home
+    } else if input.starts_with('/') {
+        PathBuf::from(input)
+    } else {
+        return None;
+    };
+
+    let is_image = mime_guess::from_path(&path)
+        .first()
+        .map_or(false, |m| m.type_() == mime::IMAGE);
+
+    if is_image && path.is_file() {
+        Some(path)
+    } else {
+        None
+    }
+}
+
+/// Get the image in the clipboard as a PNG.
+///
+/// This uses `wl-paste` on Wayland and `xclip` otherwise.
+fn read_clipboard() -> io::Result<Vec<u8>> {
+    let output = if std::env::var_os("WAYLAND_DISPLAY").is_some() {
+        Command::new("wl-paste")
+            .args(&["--no-newline", "--type", "image/png"])
+            .output()?
+    } else {
+        Command::new("xclip")
+            .args(&["-selection", "clipboard", "-target", "image/png", "-out"])
+            .output()?
+    };
+
+    if output.status.success() && !output.stdout.is_empty() {
+        Ok(output.stdout)
+    } else {
+        Err(io::Error::new(
+            io::ErrorKind::Other,
+            "The clipboard doesn't contain an image",
+        ))
+    }
+}
+
+fn read_file(path: &Path) -> io::Result<Vec<u8>> {
+    std::fs::read(path)
+}
+
+impl MatrixRoom {
+    /// Print the placeholder line of an upload.
+    fn print_upload_line(&self, uuid: Uuid, message: &str) {
+        let prefix = self
+            .members
+            .get(&self.own_user_id)
+            .map(|m| {
+                format!(
+                    "{}{}{}",
+                    Weechat::color(&m.color.borrow()),
+                    m.nick.borrow(),
+                    Weechat::color("reset")
+                )
+            })
+            .unwrap_or_default();
+
+        let tag = format!("matrix_echo_{}", uuid.to_string());
+
+        if let Ok(buffer) = self.buffer_handle().upgrade() {
+            buffer.print_date_tags(
+                0,
+                &[&tag],
+                &format!(
+                    "{}\t{}{}{}",
+                    prefix,
+                    Weechat::color_pair("darkgray", "default"),
+                    message,
+                    Weechat::color("reset")
+                ),
+            );
+        }
+    }
+
+    /// Replace the placeholder line of a failed upload with an error message.
+    fn fail_upload(&self, uuid: Uuid, message: &str) {
+        let tag = Cow::from(format!("matrix_echo_{}", uuid.to_string()));
+
+        if let Ok(buffer) = self.buffer_handle().upgrade() {
+            for line in buffer.lines().filter(|l| l.tags().contains(&tag)) {
+                line.set_message(&format!(
+                    "{}{}{}",
+                    Weechat::color("red"),
+                    message,
+                    Weechat::color("reset")
+                ));
+                line.set_tags(&["matrix_upload_failed"]);
+            }
+        }
+    }
+
+    /// Upload a file to the homeserver and send it to the room.
+    pub async fn upload(&self, source: UploadSource) {
+        let connection = if let Some(c) = self.connection.borrow().clone() {
+            c
+        } else {
+            self.print_error("Error not connected");
+            return;
+        };
+
+        if self.is_encrypted() {
+            self.print_error(
+                "Uploading files to encrypted rooms isn't supported yet",
+            );
+            return;
+        }
+
+        if !self.check_can_send() {
+            return;
+        }
+
+        let uuid = Uuid::new_v4();
+        let name = source.name();
+        let content_type = source.content_type();
+
+        self.print_upload_line(uuid, &format!("Uploading {}...", name));
+
+        let data = match &source {
+            UploadSource::File(path) => {
+                let path = path.clone();
+                connection.spawn(async move { read_file(&path) }).await
+            }
+            UploadSource::Clipboard => {
+                connection.spawn(async { read_clipboard() }).await
+            }
+        };
+
+        let data = match data {
+            Ok(Ok(d)) => d,
+            Ok(Err(e)) => {
+                self.fail_upload(
+                    uuid,
+                    &format!("Error reading {}: {}", name, e),
+                );
+                return;
+            }
+            Err(e) => {
+                self.fail_upload(
+                    uuid,
+                    &format!("Error reading {}: {}", name, e),
+                );
+                return;
+            }
+        };
+
+        let url =
+            match connection.upload_media(data, content_type.clone()).await {
+                Ok(u) => u,
+                Err(e) => {
+                    self.fail_upload(
+                        uuid,
+                        &format!("Error uploading {}: {}", name, e),
+                    );
+                    return;
+                }
+            };
+
+        let content = if content_type.type_() == mime::IMAGE {
+            MessageEventContent::Image(ImageMessageEventContent {
+                body: name.clone(),
+                info: None,
+                url: Some(url),
+                file: None,
+            })
+        } else {
+            MessageEventContent::File(FileMessageEventContent {
+                body: name.clone(),
+                filename: Some(name.clone()),
+                info: None,
+                url: Some(url),
+                file: None,
+            })
+        };
+
+        // The placeholder line acts as the local echo of the message, it will
+        // be replaced with the rendered message once the message is sent.
+        self.outgoing_messages.add_in_flight(uuid, content.clone());
+
+        if !self.send_queued_message(uuid, content).await {
+            self.fail_upload(uuid, &format!("Error sending {}", name));
+        }
+    }
+}
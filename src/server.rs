@@ -6,8 +6,8 @@
 //! server they need to be removed from the server section when the server is
 //! dropped.
 //!
-//! The server will create a tokio runtime which will spawn a task for the sync
-//! loop.
+//! The server will spawn a task for the sync loop on a tokio runtime. The
+//! runtime is shared between all the connected servers.
 //!
 //! It will also spawn a task on the Weechat mainloop, this one waits for
 //! responses from the sync loop.
@@ -43,7 +43,7 @@
 //!   +--------------------------------------------------------------------+
 //!
 //!
-//! The tokio runtime and response receiver task will be alive only if the user
+//! The sync loop and response receiver task will be alive only if the user
 //! connects to the server while the room buffer input callback will print an
 //! error if the server is disconnected.
 //!
@@ -54,63 +54,181 @@
 //! receiver fetches events individually from a mpsc channel. This makes sure
 //! that processing events will not block the Weechat mainloop for too long.
 
-use chrono::{offset::Utc, DateTime};
+use async_trait::async_trait;
+use chrono::{
+    offset::{Local, Utc},
+    DateTime,
+};
 use futures::executor::block_on;
 use indoc::indoc;
 use std::{
     cell::{Ref, RefCell, RefMut},
-    collections::HashMap,
+    cmp::Ordering,
+    collections::{BTreeMap, BTreeSet, HashMap},
+    convert::TryFrom,
     path::PathBuf,
+    process::{Command, Stdio},
     rc::{Rc, Weak},
     sync::Arc,
+    time::{Duration, Instant},
 };
+use tracing::debug;
 use url::Url;
 
 use matrix_sdk::{
     self,
     api::r0::session::login::Response as LoginResponse,
-    events::{AnySyncRoomEvent, AnySyncStateEvent},
-    identifiers::{DeviceIdBox, RoomId, UserId},
+    events::{
+        direct::DirectEventContent,
+        presence::{PresenceEvent, PresenceState},
+        push_rules::{Action, Ruleset, Tweak},
+        room_key_request::Action as KeyRequestAction,
+        tag::Tags,
+        AnyBasicEvent, AnySyncRoomEvent, AnySyncStateEvent, AnyToDeviceEvent,
+    },
+    identifiers::{DeviceIdBox, EventId, RoomId, RoomIdOrAliasId, UserId},
     locks::RwLock,
     Client, ClientConfig, Room,
 };
 
 use weechat::{
     buffer::{BufferBuilder, BufferHandle},
-    config::{BooleanOptionSettings, ConfigSection, StringOptionSettings},
+    config::{
+        BooleanOptionSettings, ConfigSection, IntegerOptionSettings,
+        StringOptionSettings,
+    },
     Weechat,
 };
 
 use crate::{
     config::Config,
-    connection::{Connection, InteractiveAuthInfo},
-    room::RoomHandle,
-    ConfigHandle, PLUGIN_NAME,
+    connection::{
+        ClientMessageHandler, Connection, ConnectionError, ConnectionState,
+        FilterSettings, InteractiveAuthInfo, RoomOptions, RoomRemoval,
+        ServerInfo,
+    },
+    drafts::Drafts,
+    http_client::HttpClient,
+    room::{Notification, NotificationLevel, RoomHandle},
+    session::UniqueStore,
+    ConfigHandle, CONNECTION_BAR_ITEM, PLUGIN_NAME,
 };
 
+/// The URL schemes the HTTP client supports for proxies.
+const PROXY_SCHEMES: &[&str] = &["http", "https", "socks5", "socks5h"];
+
+/// The number of messages we fetch for a new room buffer by default.
+const DEFAULT_AUTOFETCH_BACKLOG: u32 = 20;
+
+/// The number of membership changes that get collapsed into a summary line by
+/// default.
+const DEFAULT_MEMBERSHIP_COLLAPSE_THRESHOLD: u32 = 5;
+
+/// The display name our device gets if none is configured.
+const DEFAULT_DEVICE_NAME: &str = "Weechat-Matrix-rs";
+
+/// The number of timeline events per room the first sync after logging in
+/// fetches by default.
+const DEFAULT_INITIAL_TIMELINE_LIMIT: u32 = 10;
+
+/// The default minimal number of seconds between two runs of the notify
+/// command.
+const DEFAULT_NOTIFY_INTERVAL: u32 = 10;
+
 #[derive(Debug)]
 pub enum ServerError {
     StartError(String),
     IoError(String),
 }
 
-#[derive(Default)]
 pub struct ServerSettings {
     pub homeserver: Option<Url>,
     pub proxy: Option<Url>,
     pub autoconnect: bool,
     pub username: String,
     pub password: String,
+    pub markdown: bool,
+    pub highlight_words: Vec<String>,
+    pub ssl_ca_file: Option<PathBuf>,
+    pub ssl_verify: bool,
+    pub timestamp_format: Option<String>,
+    pub autojoin_upgraded_rooms: bool,
+    pub device_name: String,
+    pub autofetch_backlog: u32,
+    pub highlight_notices: bool,
+    pub membership_collapse_threshold: u32,
+    pub timeline_limit: u32,
+    pub excluded_event_types: Vec<String>,
+    pub lazy_load_members: bool,
+    pub initial_timeline_limit: u32,
+    pub notify_command: Option<String>,
+    pub notify_interval: u32,
+    pub close_removed_rooms: bool,
+    pub url_previews: bool,
+    pub confirm_redactions: bool,
+    pub redaction_log: bool,
+    pub unique_store: bool,
+}
+
+impl Default for ServerSettings {
+    fn default() -> Self {
+        Self {
+            homeserver: None,
+            proxy: None,
+            autoconnect: false,
+            username: String::new(),
+            password: String::new(),
+            markdown: true,
+            highlight_words: Vec::new(),
+            ssl_ca_file: None,
+            ssl_verify: true,
+            timestamp_format: None,
+            autojoin_upgraded_rooms: false,
+            device_name: DEFAULT_DEVICE_NAME.to_owned(),
+            autofetch_backlog: DEFAULT_AUTOFETCH_BACKLOG,
+            highlight_notices: true,
+            membership_collapse_threshold:
+                DEFAULT_MEMBERSHIP_COLLAPSE_THRESHOLD,
+            timeline_limit: 0,
+            excluded_event_types: Vec::new(),
+            lazy_load_members: false,
+            initial_timeline_limit: DEFAULT_INITIAL_TIMELINE_LIMIT,
+            notify_command: None,
+            notify_interval: DEFAULT_NOTIFY_INTERVAL,
+            close_removed_rooms: false,
+            url_previews: false,
+            confirm_redactions: true,
+            redaction_log: false,
+            unique_store: false,
+        }
+    }
 }
 
 impl ServerSettings {
     pub fn new() -> Self {
         Default::default()
     }
+
+    /// Get the settings the sync filter gets built from.
+    pub fn filter_settings(&self) -> FilterSettings {
+        FilterSettings {
+            timeline_limit: self.timeline_limit,
+            excluded_types: self.excluded_event_types.clone(),
+            lazy_load_members: self.lazy_load_members,
+            initial_timeline_limit: self.initial_timeline_limit,
+        }
+    }
 }
 
 pub struct LoginInfo {
     user_id: UserId,
+    device_id: DeviceIdBox,
+}
+
+/// A pending invite to a room.
+pub struct Invite {
+    pub inviter: UserId,
+    pub room_name: String,
 }
 
 #[derive(Clone)]
@@ -131,12 +249,33 @@ pub struct InnerServer {
     #[allow(clippy::rc_buffer)]
     server_name: Rc<String>,
     rooms: HashMap<RoomId, RoomHandle>,
-    settings: ServerSettings,
+    invites: HashMap<RoomId, Invite>,
+    /// Rooms we created that didn't show up in a sync yet, together with the
+    /// message that should be sent once they do.
+    pending_rooms: HashMap<RoomId, Option<String>>,
+    connection_state: ConnectionState,
+    settings: Rc<RefCell<ServerSettings>>,
+    highlight_patterns: Rc<RefCell<Vec<String>>>,
+    drafts: Drafts,
+    /// The capabilities of the homeserver, fetched once per connection.
+    server_info: Option<ServerInfo>,
     config: ConfigHandle,
     client: Option<Client>,
     login_state: Option<LoginInfo>,
     connection: Rc<RefCell<Option<Connection>>>,
     server_buffer: Rc<RefCell<Option<BufferHandle>>>,
+    /// When the notify command was last run.
+    last_notification: Option<Instant>,
+    /// Users whose messages we don't show, kept in sync with the
+    /// `m.ignored_user_list` account data.
+    ignored_users: BTreeSet<UserId>,
+    /// The notification levels of rooms that don't notify us about every
+    /// message, taken from our push rules.
+    notification_levels: HashMap<RoomId, NotificationLevel>,
+    /// The direct rooms of the last `m.direct` account data we received.
+    direct_rooms: BTreeMap<UserId, Vec<RoomId>>,
+    /// The crypto store of this session, if the server uses a unique store.
+    unique_store: Option<UniqueStore>,
 }
 
 impl MatrixServer {
@@ -150,12 +289,23 @@ impl MatrixServer {
         let server = InnerServer {
             server_name: server_name.clone(),
             rooms: HashMap::new(),
-            settings: ServerSettings::new(),
+            invites: HashMap::new(),
+            pending_rooms: HashMap::new(),
+            connection_state: ConnectionState::Disconnected,
+            settings: Rc::new(RefCell::new(ServerSettings::new())),
+            highlight_patterns: Rc::new(RefCell::new(Vec::new())),
+            drafts: Drafts::load(server_path(name).join("drafts.json")),
+            server_info: None,
             config: config.clone(),
             client: None,
             login_state: None,
             connection: Rc::new(RefCell::new(None)),
             server_buffer: Rc::new(RefCell::new(None)),
+            last_notification: None,
+            ignored_users: BTreeSet::new(),
+            notification_levels: HashMap::new(),
+            direct_rooms: BTreeMap::new(),
+            unique_store: None,
         };
 
         let server = Rc::new(RefCell::new(server));
@@ -167,6 +317,13 @@ impl MatrixServer {
         }
     }
 
+    /// Get a handle to the server that owns the given inner server.
+    pub(crate) fn from_inner(inner: Rc<RefCell<InnerServer>>) -> Self {
+        let server_name = inner.borrow().server_name.clone();
+
+        MatrixServer { server_name, inner }
+    }
+
     pub fn name(&self) -> &str {
         &self.server_name
     }
@@ -208,7 +365,13 @@ impl MatrixServer {
             match c.delete_devices(devices.clone(), None).await {
                 Ok(_) => print_success(),
                 Err(e) => {
-                    if let Some(info) = e.uiaa_response() {
+                    let uiaa_info = if let ConnectionError::Matrix(e) = &e {
+                        e.uiaa_response()
+                    } else {
+                        None
+                    };
+
+                    if let Some(info) = uiaa_info {
                         let auth_info = InteractiveAuthInfo {
                             user: self.inner().settings().username.clone(),
                             password: self.inner().settings().password.clone(),
@@ -231,6 +394,208 @@ impl MatrixServer {
         };
     }
 
+    /// Accept the invite to the room with the given room id.
+    pub async fn accept_invite(&self, room_id: RoomId) {
+        if let Some(c) = self.connection() {
+            match c.join_room(&room_id).await {
+                Ok(_) => {
+                    self.inner.borrow_mut().invites.remove(&room_id);
+                    self.print_network(&format!(
+                        "Accepted the invite to {}",
+                        room_id
+                    ));
+                }
+                Err(e) => self.print_error(&format!(
+                    "Error accepting the invite to {}: {:?}",
+                    room_id, e
+                )),
+            }
+        } else {
+            self.print_error("Can't accept invites, not connected");
+        }
+    }
+
+    /// Reject the invite to the room with the given room id.
+    pub async fn reject_invite(&self, room_id: RoomId) {
+        if let Some(c) = self.connection() {
+            match c.leave_room(&room_id).await {
+                Ok(_) => {
+                    self.inner.borrow_mut().invites.remove(&room_id);
+                    self.print_network(&format!(
+                        "Rejected the invite to {}",
+                        room_id
+                    ));
+                }
+                Err(e) => self.print_error(&format!(
+                    "Error rejecting the invite to {}: {:?}",
+                    room_id, e
+                )),
+            }
+        } else {
+            self.print_error("Can't reject invites, not connected");
+        }
+    }
+
+    /// Get the direct rooms we have with other users.
+    ///
+    /// This starts out with the `m.direct` account data we received last, so
+    /// direct rooms that aren't loaded or that other clients recorded are
+    /// kept.
+    fn direct_rooms(&self) -> BTreeMap<UserId, Vec<RoomId>> {
+        let server = self.inner();
+        let mut direct_rooms = server.direct_rooms.clone();
+
+        for room in server.rooms.values() {
+            if let Some(user_id) = room.direct_target() {
+                let rooms = direct_rooms.entry(user_id).or_default();
+
+                if !rooms.contains(room.room_id()) {
+                    rooms.push(room.room_id().to_owned());
+                }
+            }
+        }
+
+        direct_rooms
+    }
+
+    /// Open a direct room with the given user, creating one if we don't have
+    /// one yet.
+    ///
+    /// # Arguments
+    ///
+    /// * `user_id` - The user we want to talk to.
+    ///
+    /// * `message` - A message that should be sent to the room once it's
+    /// open.
+    pub async fn open_dm(&self, user_id: UserId, message: Option<String>) {
+        let existing_room = self
+            .inner()
+            .rooms
+            .values()
+            .find(|r| r.direct_target().as_ref() == Some(&user_id))
+            .cloned();
+
+        if let Some(room) = existing_room {
+            room.switch_to_buffer();
+
+            if let Some(message) = message {
+                room.send_text(message).await;
+            }
+
+            return;
+        }
+
+        let connection = if let Some(c) = self.connection() {
+            c
+        } else {
+            self.print_error("Can't open a direct room, not connected");
+            return;
+        };
+
+        let room_id = match connection.create_dm(&user_id).await {
+            Ok(r) => r.room_id,
+            Err(e) => {
+                self.print_error(&format!(
+                    "Error creating a direct room with {}: {}",
+                    user_id, e
+                ));
+                return;
+            }
+        };
+
+        let mut direct_rooms = self.direct_rooms();
+        let rooms = direct_rooms.entry(user_id.clone()).or_default();
+
+        if !rooms.contains(&room_id) {
+            rooms.push(room_id.clone());
+        }
+
+        if let Err(e) = connection.set_direct_rooms(direct_rooms).await {
+            self.print_error(&format!(
+                "Error marking the room {} as direct: {}",
+                room_id, e
+            ));
+        }
+
+        // The room might have shown up in a sync while we were waiting for
+        // the responses, otherwise we'll open it once it does.
+        let room = self.inner().rooms.get(&room_id).cloned();
+
+        if let Some(room) = room {
+            room.switch_to_buffer();
+
+            if let Some(message) = message {
+                room.send_text(message).await;
+            }
+        } else {
+            self.inner
+                .borrow_mut()
+                .pending_rooms
+                .insert(room_id, message);
+        }
+    }
+
+    /// Join the room with the given room id or alias and open its buffer.
+    pub async fn join_room(&self, room: RoomIdOrAliasId) {
+        let connection = if let Some(c) = self.connection() {
+            c
+        } else {
+            self.print_error("Can't join rooms, not connected");
+            return;
+        };
+
+        let room_id = match connection.join_room_by_id_or_alias(&room).await {
+            Ok(r) => r.room_id,
+            Err(e) => {
+                self.print_error(&format!("Error joining {}: {}", room, e));
+                return;
+            }
+        };
+
+        self.inner.borrow_mut().invites.remove(&room_id);
+
+        let room = self.inner().rooms.get(&room_id).cloned();
+
+        if let Some(room) = room {
+            room.switch_to_buffer();
+        } else {
+            self.inner.borrow_mut().pending_rooms.insert(room_id, None);
+        }
+    }
+
+    /// Create a new room and open its buffer.
+    ///
+    /// # Arguments
+    ///
+    /// * `options` - The settings of the new room.
+    pub async fn create_room(&self, options: RoomOptions) {
+        let connection = if let Some(c) = self.connection() {
+            c
+        } else {
+            self.print_error("Can't create a room, not connected");
+            return;
+        };
+
+        let room_id = match connection.create_room(options).await {
+            Ok(r) => r.room_id,
+            Err(e) => {
+                self.print_error(&format!("Error creating the room: {}", e));
+                return;
+            }
+        };
+
+        self.print_network(&format!("Created the room {}", room_id));
+
+        // Same as for direct rooms, the room might already be there.
+        let room = self.inner().rooms.get(&room_id).cloned();
+
+        if let Some(room) = room {
+            room.switch_to_buffer();
+        } else {
+            self.inner.borrow_mut().pending_rooms.insert(room_id, None);
+        }
+    }
+
     pub async fn export_keys(&self, file: PathBuf, passphrase: String) {
         let client = self.inner().get_client().unwrap();
 
@@ -239,7 +604,12 @@ impl MatrixServer {
         };
 
         if let Some(c) = self.connection() {
-            if let Err(e) = c.spawn(export).await {
+            let result = c
+                .spawn(export)
+                .await
+                .and_then(|r| r.map_err(ConnectionError::from));
+
+            if let Err(e) = result {
                 self.print_error(&format!(
                     "Error exporting E2EE keys {:#?}",
                     e
@@ -261,7 +631,12 @@ impl MatrixServer {
             let import =
                 async move { client.import_keys(file, &passphrase).await };
 
-            match c.spawn(import).await {
+            let result = c
+                .spawn(import)
+                .await
+                .and_then(|r| r.map_err(ConnectionError::from));
+
+            match result {
                 Ok(num) => {
                     if num > 0 {
                         self.print_network(&format!(
@@ -286,6 +661,133 @@ impl MatrixServer {
         };
     }
 
+    /// Print the list of users we ignore.
+    pub fn print_ignored_users(&self) {
+        let ignored_users: Vec<String> = self
+            .inner()
+            .ignored_users
+            .iter()
+            .map(|u| u.to_string())
+            .collect();
+
+        if ignored_users.is_empty() {
+            self.print_network("You aren't ignoring anyone");
+        } else {
+            self.print_network(&format!(
+                "Ignored users: {}",
+                ignored_users.join(", ")
+            ));
+        }
+    }
+
+    /// Start or stop ignoring the given user.
+    ///
+    /// # Arguments
+    ///
+    /// * `user_id` - The user that should be ignored or unignored.
+    ///
+    /// * `ignore` - Should the user be ignored, otherwise we stop ignoring
+    /// them.
+    pub async fn set_ignored(&self, user_id: UserId, ignore: bool) {
+        let connection = if let Some(c) = self.connection() {
+            c
+        } else {
+            self.print_error("Can't change the ignored users, not connected");
+            return;
+        };
+
+        let mut ignored_users = self.inner().ignored_users.clone();
+
+        let changed = if ignore {
+            ignored_users.insert(user_id.clone())
+        } else {
+            ignored_users.remove(&user_id)
+        };
+
+        if !changed {
+            self.print_error(&format!(
+                "You are {} ignoring {}",
+                if ignore { "already" } else { "not" },
+                user_id
+            ));
+            return;
+        }
+
+        match connection
+            .set_ignored_users(ignored_users.iter().cloned().collect())
+            .await
+        {
+            Ok(()) => {
+                // Don't wait for the sync to update the list, messages from
+                // the user might come in before that.
+                self.inner.borrow_mut().ignored_users = ignored_users;
+                self.print_network(&if ignore {
+                    format!("Ignoring {}", user_id)
+                } else {
+                    format!("No longer ignoring {}", user_id)
+                });
+            }
+            Err(e) => self.print_error(&format!(
+                "Error changing the ignored users: {}",
+                e
+            )),
+        }
+    }
+
+    /// Mark us as away with the given message, or as being back if there's no
+    /// message.
+    pub async fn set_away(&self, message: Option<String>) {
+        let connection = if let Some(c) = self.connection() {
+            c
+        } else {
+            self.print_error("Can't change the presence, not connected");
+            return;
+        };
+
+        let presence = if message.is_some() {
+            PresenceState::Unavailable
+        } else {
+            PresenceState::Online
+        };
+        let away = message.is_some();
+
+        match connection.set_presence(presence, message).await {
+            Ok(_) => self.print_network(if away {
+                "You have been marked as being away"
+            } else {
+                "You are no longer marked as being away"
+            }),
+            // Lots of servers disable presence, there's nothing the user can
+            // do about it.
+            Err(e) if e.is_unrecognized() || e.is_forbidden() => {
+                debug!("The server doesn't support presence: {}", e)
+            }
+            Err(e) => {
+                self.print_error(&format!("Error setting the presence: {}", e))
+            }
+        }
+    }
+
+    pub async fn set_device_name(&self, device_id: DeviceIdBox, name: String) {
+        if let Some(c) = self.connection() {
+            match c
+                .set_device_display_name(device_id.clone(), name.clone())
+                .await
+            {
+                Ok(_) => self.print_network(&format!(
+                    "Successfully renamed device {} to {}",
+                    device_id, name
+                )),
+                Err(e) => self.print_error(&format!(
+                    "Error renaming device {} {:?}",
+                    device_id, e
+                )),
+            }
+        } else {
+            self.print_error("Can't rename devices, not connected")
+        }
+    }
+
     pub async fn devices(&self) {
         if let Some(c) = self.connection() {
             let response = match c.devices().await {
@@ -354,6 +856,94 @@ impl MatrixServer {
         };
     }
 
+    /// Fetch the spec versions and capabilities of the homeserver, the result
+    /// is cached until we disconnect.
+    async fn server_info(&self) -> Option<ServerInfo> {
+        if let Some(info) = self.inner().server_info.clone() {
+            return Some(info);
+        }
+
+        let connection = if let Some(c) = self.connection() {
+            c
+        } else {
+            self.print_error("Can't fetch the server info, not connected");
+            return None;
+        };
+
+        match connection.server_info().await {
+            Ok(info) => {
+                self.inner.borrow_mut().server_info = Some(info.clone());
+                Some(info)
+            }
+            Err(e) => {
+                self.print_error(&format!(
+                    "Error fetching the server info: {}",
+                    e
+                ));
+                None
+            }
+        }
+    }
+
+    /// Print the spec versions, unstable features and capabilities of the
+    /// homeserver.
+    pub async fn print_server_info(&self) {
+        let info = if let Some(i) = self.server_info().await {
+            i
+        } else {
+            return;
+        };
+
+        let yes_no = |supported| if supported { "yes" } else { "no" };
+
+        let threads = info.supports_version("v1.3")
+            || info.supports_feature("org.matrix.msc3440")
+            || info.supports_feature("org.matrix.msc3440.stable");
+
+        let mut lines = vec![
+            format!(
+                "Server info for {}{}{}:",
+                Weechat::color("chat_server"),
+                self.name(),
+                Weechat::color("reset")
+            ),
+            format!("  Spec versions: {}", info.versions.join(", ")),
+        ];
+
+        let features: Vec<&str> = info
+            .unstable_features
+            .iter()
+            .filter(|(_, enabled)| **enabled)
+            .map(|(feature, _)| feature.as_str())
+            .collect();
+
+        if !features.is_empty() {
+            lines.push(format!("  Unstable features: {}", features.join(", ")));
+        }
+
+        if let Some(room_versions) = &info.capabilities.room_versions {
+            let available: Vec<&str> =
+                room_versions.available.keys().map(|v| v.as_str()).collect();
+
+            lines.push(format!(
+                "  Default room version: {} (available: {})",
+                room_versions.default,
+                available.join(", ")
+            ));
+        }
+
+        if let Some(change_password) = &info.capabilities.change_password {
+            lines.push(format!(
+                "  Password changes: {}",
+                yes_no(change_password.enabled)
+            ));
+        }
+
+        lines.push(format!("  Threads: {}", yes_no(threads)));
+
+        self.print_network(&lines.join("\n"));
+    }
+
     /// Parse an URL returning a None if the string is empty.
     ///
     /// # Panics
@@ -386,6 +976,56 @@ impl MatrixServer {
         }
     }
 
+    /// Check that the value is either a homeserver URL or a full user id
+    /// which the homeserver can be discovered from.
+    pub fn parse_homeserver_or_user_id(value: String) -> Result<(), String> {
+        if value.starts_with('@') {
+            UserId::try_from(value.as_str())
+                .map(|_| ())
+                .map_err(|e| e.to_string())
+        } else {
+            MatrixServer::parse_url(value)
+        }
+    }
+
+    /// Parse a proxy URL returning an error if the URL isn't valid or if the
+    /// scheme isn't one of the supported proxy schemes.
+    pub fn parse_proxy_url(value: &str) -> Result<Url, String> {
+        let url = Url::parse(value).map_err(|e| e.to_string())?;
+
+        if PROXY_SCHEMES.contains(&url.scheme()) {
+            Ok(url)
+        } else {
+            Err(format!(
+                "Unsupported proxy scheme {}, supported schemes are: {}",
+                url.scheme(),
+                PROXY_SCHEMES.join(", ")
+            ))
+        }
+    }
+
+    /// Check if the provided value is a valid proxy URL, printing out the
+    /// reason if it isn't.
+    fn is_proxy_valid(value: &str) -> bool {
+        if value.is_empty() {
+            return true;
+        }
+
+        match MatrixServer::parse_proxy_url(value) {
+            Ok(_) => true,
+            Err(e) => {
+                Weechat::print(&format!(
+                    "{}{}: Invalid proxy URL {}: {}",
+                    Weechat::prefix("error"),
+                    PLUGIN_NAME,
+                    value,
+                    e
+                ));
+                false
+            }
+        }
+    }
+
     /// Check if the provided value is a valid URL.
     fn is_url_valid(value: &str) -> bool {
         if value.is_empty() {
@@ -412,8 +1052,8 @@ impl MatrixServer {
                         "Server got deleted while server config is alive",
                     );
 
-                    let mut server = server_ref.borrow_mut();
-                    server.settings.autoconnect = value;
+                    let server = server_ref.borrow();
+                    server.settings.borrow_mut().autoconnect = value;
                 });
 
         server_section
@@ -434,9 +1074,9 @@ impl MatrixServer {
                         "Server got deleted while server config is alive",
                     );
 
-                    let mut server = server_ref.borrow_mut();
+                    let server = server_ref.borrow();
 
-                    server.settings.homeserver =
+                    server.settings.borrow_mut().homeserver =
                         MatrixServer::parse_url_unchecked(&option.value());
                 });
 
@@ -448,8 +1088,13 @@ impl MatrixServer {
         let server_copy = server.clone();
 
         let proxy = StringOptionSettings::new(format!("{}.proxy", server_name))
+            .description(
+                "Proxy that should be used to connect to the homeserver, \
+                 supported schemes are http, https and socks5 \
+                 (e.g. socks5://127.0.0.1:9050 for Tor)",
+            )
             .set_check_callback(|_, _, value| {
-                MatrixServer::is_url_valid(&value)
+                MatrixServer::is_proxy_valid(&value)
             })
             .set_change_callback(move |_, option| {
                 let server = server.clone();
@@ -457,8 +1102,8 @@ impl MatrixServer {
                     .upgrade()
                     .expect("Server got deleted while server config is alive");
 
-                let mut server = server_ref.borrow_mut();
-                server.settings.proxy =
+                let server = server_ref.borrow();
+                server.settings.borrow_mut().proxy =
                     MatrixServer::parse_url_unchecked(&option.value());
             });
 
@@ -478,8 +1123,9 @@ impl MatrixServer {
                         "Server got deleted while server config is alive",
                     );
 
-                    let mut server = server_ref.borrow_mut();
-                    server.settings.username = option.value().to_string();
+                    let server = server_ref.borrow();
+                    server.settings.borrow_mut().username =
+                        option.value().to_string();
                 });
 
         server_section
@@ -487,6 +1133,7 @@ impl MatrixServer {
             .expect("Can't create username option");
 
         let server = server_copy;
+        let server_copy = server.clone();
 
         let password =
             StringOptionSettings::new(format!("{}.password", server_name))
@@ -497,49 +1144,775 @@ impl MatrixServer {
                         "Server got deleted while server config is alive",
                     );
 
-                    let mut server = server_ref.borrow_mut();
-                    server.settings.password = option.value().to_string();
+                    let server = server_ref.borrow();
+                    server.settings.borrow_mut().password =
+                        option.value().to_string();
                 });
 
         server_section
             .new_string_option(password)
             .expect("Can't create password option");
-    }
 
-    pub fn connected(&self) -> bool {
-        self.inner.borrow().connected()
-    }
+        let server = server_copy;
+        let server_copy = server.clone();
 
-    pub fn autoconnect(&self) -> bool {
-        self.inner.borrow().settings.autoconnect
-    }
+        let markdown =
+            BooleanOptionSettings::new(format!("{}.markdown", server_name))
+                .description(
+                    "Parse outgoing messages as Markdown and send a HTML \
+                     formatted body along with the plain text one",
+                )
+                .default_value(true)
+                .set_change_callback(move |_, option| {
+                    let server = server.clone();
+                    let value = option.value();
 
-    pub fn connect(&self) -> Result<(), ServerError> {
-        if self.connected() {
-            self.print_error(&format!(
-                "Already connected to {}{}{}",
-                Weechat::color("chat_server"),
-                self.name(),
-                Weechat::color("reset")
-            ));
+                    let server_ref = server.upgrade().expect(
+                        "Server got deleted while server config is alive",
+                    );
 
-            return Ok(());
-        }
+                    let server = server_ref.borrow();
+                    server.settings.borrow_mut().markdown = value;
+                });
 
-        let client = self.inner.borrow_mut().get_or_create_client()?;
-        let connection = Connection::new(&self, &client);
+        server_section
+            .new_boolean_option(markdown)
+            .expect("Can't create markdown option");
 
-        *self.inner.borrow_mut().connection.borrow_mut() = Some(connection);
+        let server = server_copy;
+        let server_copy = server.clone();
 
-        self.print_network(&format!(
-            "Connected to {}{}{}",
-            Weechat::color("chat_server"),
-            self.name(),
-            Weechat::color("reset")
-        ));
+        let highlight_words = StringOptionSettings::new(format!(
+            "{}.highlight_words",
+            server_name
+        ))
+        .description(
+            "Comma separated list of words that highlight a message, in \
+             addition to our user id and display name",
+        )
+        .set_change_callback(move |_, option| {
+            let server = server.clone();
+
+            let server_ref = server
+                .upgrade()
+                .expect("Server got deleted while server config is alive");
+
+            let server = server_ref.borrow();
+            server.settings.borrow_mut().highlight_words = option
+                .value()
+                .split(',')
+                .map(|w| w.trim().to_owned())
+                .filter(|w| !w.is_empty())
+                .collect();
+        });
 
-        Ok(())
-    }
+        server_section
+            .new_string_option(highlight_words)
+            .expect("Can't create highlight_words option");
+
+        let server = server_copy;
+        let server_copy = server.clone();
+
+        let ssl_ca_file =
+            StringOptionSettings::new(format!("{}.ssl_ca_file", server_name))
+                .description(
+                    "Path to a PEM encoded root certificate that should be \
+                     trusted when connecting to the homeserver, useful for \
+                     servers using an internal CA",
+                )
+                .set_change_callback(move |_, option| {
+                    let server = server.clone();
+                    let value = option.value();
+
+                    let server_ref = server.upgrade().expect(
+                        "Server got deleted while server config is alive",
+                    );
+
+                    let server = server_ref.borrow();
+                    server.settings.borrow_mut().ssl_ca_file =
+                        if value.is_empty() {
+                            None
+                        } else {
+                            Some(PathBuf::from(value.as_ref()))
+                        };
+                });
+
+        server_section
+            .new_string_option(ssl_ca_file)
+            .expect("Can't create ssl_ca_file option");
+
+        let server = server_copy;
+        let server_copy = server.clone();
+
+        let ssl_verify =
+            BooleanOptionSettings::new(format!("{}.ssl_verify", server_name))
+                .description(
+                    "Verify the TLS certificate of the homeserver, DANGER: \
+                     disabling this allows anyone on the network path to \
+                     read and modify the traffic",
+                )
+                .default_value(true)
+                .set_change_callback(move |_, option| {
+                    let server = server.clone();
+                    let value = option.value();
+
+                    let server_ref = server.upgrade().expect(
+                        "Server got deleted while server config is alive",
+                    );
+
+                    let server = server_ref.borrow();
+                    server.settings.borrow_mut().ssl_verify = value;
+                });
+
+        server_section
+            .new_boolean_option(ssl_verify)
+            .expect("Can't create ssl_verify option");
+
+        let server = server_copy;
+        let server_copy = server.clone();
+
+        let timestamp_format = StringOptionSettings::new(format!(
+            "{}.timestamp_format",
+            server_name
+        ))
+        .description(
+            "Format of the timestamp that is shown for messages, see man \
+             strftime for the specifiers, e.g. %H:%M or %I:%M %p for a 12 \
+             hour clock (empty value uses the WeeChat time format)",
+        )
+        .set_change_callback(move |_, option| {
+            let server = server.clone();
+            let value = option.value();
+
+            let server_ref = server
+                .upgrade()
+                .expect("Server got deleted while server config is alive");
+
+            let server = server_ref.borrow();
+            server.settings.borrow_mut().timestamp_format = if value.is_empty()
+            {
+                None
+            } else {
+                Some(value.to_string())
+            };
+
+            for room in server.rooms.values() {
+                room.update_time_display();
+            }
+        });
+
+        server_section
+            .new_string_option(timestamp_format)
+            .expect("Can't create timestamp_format option");
+
+        let server = server_copy;
+        let server_copy = server.clone();
+
+        let autojoin_upgraded_rooms = BooleanOptionSettings::new(format!(
+            "{}.autojoin_upgraded_rooms",
+            server_name
+        ))
+        .description(
+            "Automatically join the new room when a room gets upgraded",
+        )
+        .set_change_callback(move |_, option| {
+            let server = server.clone();
+            let value = option.value();
+
+            let server_ref = server
+                .upgrade()
+                .expect("Server got deleted while server config is alive");
+
+            let server = server_ref.borrow();
+            server.settings.borrow_mut().autojoin_upgraded_rooms = value;
+        });
+
+        server_section
+            .new_boolean_option(autojoin_upgraded_rooms)
+            .expect("Can't create autojoin_upgraded_rooms option");
+
+        let server = server_copy;
+        let server_copy = server.clone();
+
+        let device_name =
+            StringOptionSettings::new(format!("{}.device_name", server_name))
+                .description(
+                    "The display name our device gets when we log in for the \
+                     first time",
+                )
+                .default_value(DEFAULT_DEVICE_NAME)
+                .set_change_callback(move |_, option| {
+                    let server = server.clone();
+                    let value = option.value();
+
+                    let server_ref = server.upgrade().expect(
+                        "Server got deleted while server config is alive",
+                    );
+
+                    let server = server_ref.borrow();
+                    server.settings.borrow_mut().device_name =
+                        if value.is_empty() {
+                            DEFAULT_DEVICE_NAME.to_owned()
+                        } else {
+                            value.to_string()
+                        };
+                });
+
+        server_section
+            .new_string_option(device_name)
+            .expect("Can't create device_name option");
+
+        let server = server_copy;
+        let server_copy = server.clone();
+
+        let autofetch_backlog = IntegerOptionSettings::new(format!(
+            "{}.autofetch_backlog",
+            server_name
+        ))
+        .description(
+            "The number of messages that are fetched when a room buffer gets \
+             created, 0 disables fetching messages",
+        )
+        .min(0)
+        .max(500)
+        .default_value(DEFAULT_AUTOFETCH_BACKLOG as i32)
+        .set_change_callback(move |_, option| {
+            let server = server.clone();
+
+            let server_ref = server
+                .upgrade()
+                .expect("Server got deleted while server config is alive");
+
+            let server = server_ref.borrow();
+            server.settings.borrow_mut().autofetch_backlog =
+                option.value() as u32;
+        });
+
+        server_section
+            .new_integer_option(autofetch_backlog)
+            .expect("Can't create autofetch_backlog option");
+
+        let server = server_copy;
+        let server_copy = server.clone();
+
+        let highlight_notices = BooleanOptionSettings::new(format!(
+            "{}.highlight_notices",
+            server_name
+        ))
+        .description(
+            "Should notices, which are usually sent by bots, be able to \
+             highlight us",
+        )
+        .default_value(true)
+        .set_change_callback(move |_, option| {
+            let server = server.clone();
+            let value = option.value();
+
+            let server_ref = server
+                .upgrade()
+                .expect("Server got deleted while server config is alive");
+
+            let server = server_ref.borrow();
+            server.settings.borrow_mut().highlight_notices = value;
+        });
+
+        server_section
+            .new_boolean_option(highlight_notices)
+            .expect("Can't create highlight_notices option");
+
+        let server = server_copy;
+        let server_copy = server.clone();
+
+        let membership_collapse_threshold = IntegerOptionSettings::new(
+            format!("{}.membership_collapse_threshold", server_name),
+        )
+        .description(
+            "The number of membership changes arriving in quick succession \
+             that are shown as a single summary line, 0 disables collapsing",
+        )
+        .min(0)
+        .max(1000)
+        .default_value(DEFAULT_MEMBERSHIP_COLLAPSE_THRESHOLD as i32)
+        .set_change_callback(move |_, option| {
+            let server = server.clone();
+
+            let server_ref = server
+                .upgrade()
+                .expect("Server got deleted while server config is alive");
+
+            let server = server_ref.borrow();
+            server.settings.borrow_mut().membership_collapse_threshold =
+                option.value() as u32;
+        });
+
+        server_section
+            .new_integer_option(membership_collapse_threshold)
+            .expect("Can't create membership_collapse_threshold option");
+
+        let server = server_copy;
+        let server_copy = server.clone();
+
+        let timeline_limit = IntegerOptionSettings::new(format!(
+            "{}.timeline_limit",
+            server_name
+        ))
+        .description(
+            "The maximal number of events per room in a sync response, 0 \
+             leaves the limit up to the server, takes effect on the next \
+             connect",
+        )
+        .min(0)
+        .max(1000)
+        .set_change_callback(move |_, option| {
+            let server = server.clone();
+
+            let server_ref = server
+                .upgrade()
+                .expect("Server got deleted while server config is alive");
+
+            let server = server_ref.borrow();
+            server.settings.borrow_mut().timeline_limit = option.value() as u32;
+        });
+
+        server_section
+            .new_integer_option(timeline_limit)
+            .expect("Can't create timeline_limit option");
+
+        let server = server_copy;
+        let server_copy = server.clone();
+
+        let excluded_event_types = StringOptionSettings::new(format!(
+            "{}.excluded_event_types",
+            server_name
+        ))
+        .description(
+            "A comma separated list of event types that shouldn't be \
+             received as part of the timeline, e.g. m.room.member, takes \
+             effect on the next connect",
+        )
+        .set_change_callback(move |_, option| {
+            let server = server.clone();
+
+            let server_ref = server
+                .upgrade()
+                .expect("Server got deleted while server config is alive");
+
+            let server = server_ref.borrow();
+            server.settings.borrow_mut().excluded_event_types = option
+                .value()
+                .split(',')
+                .map(|t| t.trim().to_owned())
+                .filter(|t| !t.is_empty())
+                .collect();
+        });
+
+        server_section
+            .new_string_option(excluded_event_types)
+            .expect("Can't create excluded_event_types option");
+
+        let server = server_copy;
+        let server_copy = server.clone();
+
+        let lazy_load_members = BooleanOptionSettings::new(format!(
+            "{}.lazy_load_members",
+            server_name
+        ))
+        .description(
+            "Only receive the members of a room that sent the events we \
+             see, this speeds up syncing in big rooms, takes effect on the \
+             next connect",
+        )
+        .set_change_callback(move |_, option| {
+            let server = server.clone();
+            let value = option.value();
+
+            let server_ref = server
+                .upgrade()
+                .expect("Server got deleted while server config is alive");
+
+            let server = server_ref.borrow();
+            server.settings.borrow_mut().lazy_load_members = value;
+        });
+
+        server_section
+            .new_boolean_option(lazy_load_members)
+            .expect("Can't create lazy_load_members option");
+
+        let server = server_copy;
+        let server_copy = server.clone();
+
+        let initial_timeline_limit = IntegerOptionSettings::new(format!(
+            "{}.initial_timeline_limit",
+            server_name
+        ))
+        .description(
+            "The maximal number of events per room in the first sync after \
+             logging in, members are lazy loaded for it as well, this speeds \
+             up the first login on big accounts, 0 uses the regular sync \
+             filter",
+        )
+        .min(0)
+        .max(1000)
+        .default_value(DEFAULT_INITIAL_TIMELINE_LIMIT as i32)
+        .set_change_callback(move |_, option| {
+            let server = server.clone();
+
+            let server_ref = server
+                .upgrade()
+                .expect("Server got deleted while server config is alive");
+
+            let server = server_ref.borrow();
+            server.settings.borrow_mut().initial_timeline_limit =
+                option.value() as u32;
+        });
+
+        server_section
+            .new_integer_option(initial_timeline_limit)
+            .expect("Can't create initial_timeline_limit option");
+
+        let server = server_copy;
+        let server_copy = server.clone();
+
+        let notify_command = StringOptionSettings::new(format!(
+            "{}.notify_command",
+            server_name
+        ))
+        .description(
+            "A shell command that is run when a message highlights us or \
+             arrives in a direct chat, the room name, sender and message are \
+             passed to it as the arguments $1, $2 and $3 (e.g. notify-send \
+             \"$1\" \"$2: $3\"), an empty value disables it",
+        )
+        .set_change_callback(move |_, option| {
+            let server = server.clone();
+            let value = option.value();
+
+            let server_ref = server
+                .upgrade()
+                .expect("Server got deleted while server config is alive");
+
+            let server = server_ref.borrow();
+            server.settings.borrow_mut().notify_command = if value.is_empty() {
+                None
+            } else {
+                Some(value.to_string())
+            };
+        });
+
+        server_section
+            .new_string_option(notify_command)
+            .expect("Can't create notify_command option");
+
+        let server = server_copy;
+        let server_copy = server.clone();
+
+        let notify_interval = IntegerOptionSettings::new(format!(
+            "{}.notify_interval",
+            server_name
+        ))
+        .description(
+            "The minimal number of seconds between two runs of the notify \
+             command, notifications arriving in between are dropped",
+        )
+        .min(0)
+        .max(3600)
+        .default_value(DEFAULT_NOTIFY_INTERVAL as i32)
+        .set_change_callback(move |_, option| {
+            let server = server.clone();
+
+            let server_ref = server
+                .upgrade()
+                .expect("Server got deleted while server config is alive");
+
+            let server = server_ref.borrow();
+            server.settings.borrow_mut().notify_interval =
+                option.value() as u32;
+        });
+
+        server_section
+            .new_integer_option(notify_interval)
+            .expect("Can't create notify_interval option");
+
+        let server = server_copy;
+        let server_copy = server.clone();
+
+        let close_removed_rooms = BooleanOptionSettings::new(format!(
+            "{}.close_removed_rooms",
+            server_name
+        ))
+        .description(
+            "Close the buffer of a room once we got kicked or banned from it, \
+             or left it using another client",
+        )
+        .set_change_callback(move |_, option| {
+            let server = server.clone();
+            let value = option.value();
+
+            let server_ref = server
+                .upgrade()
+                .expect("Server got deleted while server config is alive");
+
+            let server = server_ref.borrow();
+            server.settings.borrow_mut().close_removed_rooms = value;
+        });
+
+        server_section
+            .new_boolean_option(close_removed_rooms)
+            .expect("Can't create close_removed_rooms option");
+
+        let server = server_copy;
+        let server_copy = server.clone();
+
+        let url_previews = BooleanOptionSettings::new(format!(
+            "{}.url_previews",
+            server_name
+        ))
+        .description(
+            "Show a preview of the first link of a message, the links are sent \
+             to the homeserver which fetches the previews",
+        )
+        .set_change_callback(move |_, option| {
+            let server = server.clone();
+            let value = option.value();
+
+            let server_ref = server
+                .upgrade()
+                .expect("Server got deleted while server config is alive");
+
+            let server = server_ref.borrow();
+            server.settings.borrow_mut().url_previews = value;
+        });
+
+        server_section
+            .new_boolean_option(url_previews)
+            .expect("Can't create url_previews option");
+
+        let server = server_copy;
+        let server_copy = server.clone();
+
+        let confirm_redactions = BooleanOptionSettings::new(format!(
+            "{}.confirm_redactions",
+            server_name
+        ))
+        .description(
+            "Show the message that should be deleted and ask for a \
+             confirmation before deleting it",
+        )
+        .default_value(true)
+        .set_change_callback(move |_, option| {
+            let server = server.clone();
+            let value = option.value();
+
+            let server_ref = server
+                .upgrade()
+                .expect("Server got deleted while server config is alive");
+
+            let server = server_ref.borrow();
+            server.settings.borrow_mut().confirm_redactions = value;
+        });
+
+        server_section
+            .new_boolean_option(confirm_redactions)
+            .expect("Can't create confirm_redactions option");
+
+        let server = server_copy;
+        let server_copy = server.clone();
+
+        let redaction_log = BooleanOptionSettings::new(format!(
+            "{}.redaction_log",
+            server_name
+        ))
+        .description(
+            "Record the messages we delete in the redactions.log file in the \
+             directory of the server",
+        )
+        .set_change_callback(move |_, option| {
+            let server = server.clone();
+            let value = option.value();
+
+            let server_ref = server
+                .upgrade()
+                .expect("Server got deleted while server config is alive");
+
+            let server = server_ref.borrow();
+            server.settings.borrow_mut().redaction_log = value;
+        });
+
+        server_section
+            .new_boolean_option(redaction_log)
+            .expect("Can't create redaction_log option");
+
+        let server = server_copy;
+
+        let unique_store = BooleanOptionSettings::new(format!(
+            "{}.unique_store",
+            server_name
+        ))
+        .description(
+            "Use a separate crypto store for every session, this allows the \
+             account to be used by multiple WeeChat instances at the same \
+             time. Every running session logs in as its own device, the \
+             stores of sessions that ended are reused. Only affects servers \
+             that didn't connect yet",
+        )
+        .set_change_callback(move |_, option| {
+            let server = server.clone();
+            let value = option.value();
+
+            let server_ref = server
+                .upgrade()
+                .expect("Server got deleted while server config is alive");
+
+            let server = server_ref.borrow();
+            server.settings.borrow_mut().unique_store = value;
+        });
+
+        server_section
+            .new_boolean_option(unique_store)
+            .expect("Can't create unique_store option");
+    }
+
+    pub fn connected(&self) -> bool {
+        self.inner.borrow().connected()
+    }
+
+    pub fn autoconnect(&self) -> bool {
+        self.inner.borrow().settings().autoconnect
+    }
+
+    pub fn connect(&self) -> Result<(), ServerError> {
+        self.connect_with(false)
+    }
+
+    /// Restore the rooms of the server from the local store without
+    /// connecting to the server.
+    ///
+    /// This requires that we logged in to the server before.
+    pub fn connect_offline(&self) -> Result<(), ServerError> {
+        self.connect_with(true)
+    }
+
+    fn connect_with(&self, offline: bool) -> Result<(), ServerError> {
+        if self.connected() {
+            self.print_error(&format!(
+                "Already connected to {}{}{}",
+                Weechat::color("chat_server"),
+                self.name(),
+                Weechat::color("reset")
+            ));
+
+            return Ok(());
+        }
+
+        let user_id = {
+            let server = self.inner.borrow();
+            let settings = server.settings();
+
+            if settings.homeserver.is_none() {
+                UserId::try_from(settings.username.as_str()).ok()
+            } else {
+                None
+            }
+        };
+
+        // Only a full user id is configured, look up the homeserver first.
+        if let Some(user_id) = user_id {
+            let server = self.clone();
+            let discover = || async move {
+                server.discover_homeserver(user_id, offline).await;
+            };
+            Weechat::spawn(discover()).detach();
+
+            return Ok(());
+        }
+
+        let client = self.inner.borrow_mut().get_or_create_client()?;
+
+        self.inner
+            .borrow_mut()
+            .set_connection_state(ConnectionState::Connecting);
+
+        let connection = match Connection::new(&self, &client, offline) {
+            Ok(c) => c,
+            Err(e) => {
+                self.inner.borrow_mut().set_connection_state(
+                    ConnectionState::Error(e.to_string()),
+                );
+
+                self.print_error(&format!(
+                    "Error connecting to {}{}{}: {}",
+                    Weechat::color("chat_server"),
+                    self.name(),
+                    Weechat::color("reset"),
+                    e
+                ));
+
+                return Ok(());
+            }
+        };
+
+        *self.inner.borrow_mut().connection.borrow_mut() = Some(connection);
+
+        self.print_network(&format!(
+            "Connected to {}{}{}{}",
+            Weechat::color("chat_server"),
+            self.name(),
+            Weechat::color("reset"),
+            if offline { " in offline mode" } else { "" }
+        ));
+
+        Ok(())
+    }
+
+    /// Find the homeserver of the given user, store it in the homeserver
+    /// option and connect to it.
+    async fn discover_homeserver(&self, user_id: UserId, offline: bool) {
+        self.print_network(&format!(
+            "Looking up the homeserver of {}",
+            user_id
+        ));
+
+        let (proxy, verify) = {
+            let server = self.inner.borrow();
+            let settings = server.settings();
+            (settings.proxy.clone(), settings.ssl_verify)
+        };
+
+        let homeserver = match Connection::discover_homeserver(
+            user_id.server_name().to_string(),
+            proxy,
+            verify,
+        )
+        .await
+        {
+            Ok(h) => h,
+            Err(e) => {
+                self.print_error(&e.to_string());
+                return;
+            }
+        };
+
+        self.print_network(&format!(
+            "Found the homeserver {} for {}",
+            homeserver, user_id
+        ));
+
+        {
+            let config = self.inner.borrow().config.clone();
+            let mut config = config.borrow_mut();
+            let section = config
+                .search_section_mut("server")
+                .expect("Can't get server section");
+            let option = section
+                .search_option(&format!("{}.homeserver", self.name()))
+                .expect("Homeserver option wasn't created");
+            option.set(homeserver.as_str(), true);
+        }
+
+        if let Err(e) = self.connect_with(offline) {
+            self.print_error(&format!("{:?}", e));
+        }
+    }
 
     pub fn print(&self, message: &str) {
         self.inner().print(message)
@@ -557,6 +1930,10 @@ impl MatrixServer {
         self.inner().print_error(message)
     }
 
+    pub fn save_drafts(&self) {
+        self.inner().save_drafts()
+    }
+
     pub fn disconnect(&self) {
         if !self.connected() {
             self.print_error(&format!(
@@ -575,6 +1952,11 @@ impl MatrixServer {
             connection.take();
         }
 
+        self.inner.borrow_mut().server_info = None;
+        self.inner
+            .borrow_mut()
+            .set_connection_state(ConnectionState::Disconnected);
+
         self.print_network(&format!(
             "Disconnected from {}{}{}",
             Weechat::color("chat_server"),
@@ -583,38 +1965,170 @@ impl MatrixServer {
         ));
     }
 
+    /// Replace the current connection with a fresh one, e.g. after the
+    /// network changed.
+    ///
+    /// Connects if we aren't connected yet.
+    pub fn reconnect(&self) -> Result<(), ServerError> {
+        if !self.connected() {
+            return self.connect();
+        }
+
+        self.print_network(&format!(
+            "Reconnecting to {}{}{}",
+            Weechat::color("chat_server"),
+            self.name(),
+            Weechat::color("reset")
+        ));
+
+        let connection = self.inner().connection.borrow_mut().take();
+
+        let mut server = self.inner.borrow_mut();
+        server.server_info = None;
+        server.set_connection_state(ConnectionState::Disconnected);
+        drop(server);
+
+        // The old sync loop holds on to the crypto store, wait until it's
+        // gone so the new connection doesn't find the store locked.
+        let server = self.clone();
+        let reconnect = || async move {
+            if let Some(connection) = connection {
+                connection.shutdown().await;
+            }
+
+            if let Err(e) = server.connect() {
+                server.print_error(&format!("Error reconnecting: {:?}", e));
+            }
+        };
+        Weechat::spawn(reconnect()).detach();
+
+        Ok(())
+    }
+
     pub fn get_info_str(&self, details: bool) -> String {
+        let server = self.inner.borrow();
+
+        let state = match server.connection_state() {
+            ConnectionState::Disconnected => "not connected".to_owned(),
+            ConnectionState::Connecting => "connecting".to_owned(),
+            ConnectionState::Syncing => "syncing".to_owned(),
+            ConnectionState::Offline => "offline".to_owned(),
+            ConnectionState::Error(e) => format!("error: {}", e),
+        };
+
         let mut s = String::from(&format!(
             "{}{}{} [{}]",
             Weechat::color("chat_server"),
             self.server_name.as_ref().to_owned(),
             Weechat::color("reset"),
-            if self.connected() {
-                "connected"
-            } else {
-                "not connected"
-            }
+            state,
         ));
 
+        if let (Some(login), Some(connection)) =
+            (&server.login_state, &*server.connection.borrow())
+        {
+            let last_sync = connection.last_sync().map_or_else(
+                || "never".to_owned(),
+                |t| {
+                    let time: DateTime<Local> = t.into();
+                    time.format("%Y/%m/%d %H:%M:%S").to_string()
+                },
+            );
+
+            s.push_str(&format!(
+                " {} ({}), {} rooms, last sync: {}",
+                login.user_id,
+                login.device_id,
+                server.rooms.len(),
+                last_sync
+            ));
+        }
+
         if !details {
             return s;
         }
 
-        let settings = &self.inner.borrow().settings;
+        let settings = server.settings();
         s.push_str(&format!(
             "\n\
                  {:indent$}homeserver: {}\n\
                  {:indent$}proxy: {}\n\
                  {:indent$}autoconnect: {}\n\
-                 {:indent$}username: {}\n",
+                 {:indent$}username: {}\n\
+                 {:indent$}markdown: {}\n\
+                 {:indent$}highlight_words: {}\n\
+                 {:indent$}ssl_ca_file: {}\n\
+                 {:indent$}ssl_verify: {}\n\
+                 {:indent$}timestamp_format: {}\n\
+                 {:indent$}autojoin_upgraded_rooms: {}\n\
+                 {:indent$}device_name: {}\n\
+                 {:indent$}autofetch_backlog: {}\n\
+                 {:indent$}highlight_notices: {}\n\
+                 {:indent$}membership_collapse_threshold: {}\n\
+                 {:indent$}timeline_limit: {}\n\
+                 {:indent$}excluded_event_types: {}\n\
+                 {:indent$}lazy_load_members: {}\n\
+                 {:indent$}initial_timeline_limit: {}\n\
+                 {:indent$}notify_command: {}\n\
+                 {:indent$}notify_interval: {}\n\
+                 {:indent$}close_removed_rooms: {}\n\
+                 {:indent$}url_previews: {}\n\
+                 {:indent$}confirm_redactions: {}\n\
+                 {:indent$}redaction_log: {}\n\
+                 {:indent$}unique_store: {}\n",
+            "",
+            settings.homeserver.as_ref().map_or("", |url| url.as_str()),
+            "",
+            settings.proxy.as_ref().map_or("", |url| url.as_str()),
+            "",
+            settings.autoconnect,
+            "",
+            settings.username,
+            "",
+            settings.markdown,
+            "",
+            settings.highlight_words.join(","),
+            "",
+            settings
+                .ssl_ca_file
+                .as_ref()
+                .map_or("".to_owned(), |p| p.display().to_string()),
+            "",
+            settings.ssl_verify,
+            "",
+            settings.timestamp_format.as_deref().unwrap_or_default(),
+            "",
+            settings.autojoin_upgraded_rooms,
+            "",
+            settings.device_name,
+            "",
+            settings.autofetch_backlog,
+            "",
+            settings.highlight_notices,
+            "",
+            settings.membership_collapse_threshold,
+            "",
+            settings.timeline_limit,
+            "",
+            settings.excluded_event_types.join(","),
+            "",
+            settings.lazy_load_members,
+            "",
+            settings.initial_timeline_limit,
             "",
-            settings.homeserver.as_ref().map_or("", |url| url.as_str()),
+            settings.notify_command.as_deref().unwrap_or_default(),
             "",
-            settings.proxy.as_ref().map_or("", |url| url.as_str()),
+            settings.notify_interval,
             "",
-            settings.autoconnect,
+            settings.close_removed_rooms,
             "",
-            settings.username,
+            settings.url_previews,
+            "",
+            settings.confirm_redactions,
+            "",
+            settings.redaction_log,
+            "",
+            settings.unique_store,
             indent = 8
         ));
         s
@@ -631,9 +2145,34 @@ impl Drop for InnerServer {
             .search_section_mut("server")
             .expect("Can't get server section");
 
-        for option_name in
-            &["homeserver", "autoconnect", "password", "proxy", "username"]
-        {
+        for option_name in &[
+            "homeserver",
+            "autoconnect",
+            "password",
+            "proxy",
+            "username",
+            "markdown",
+            "highlight_words",
+            "ssl_ca_file",
+            "ssl_verify",
+            "timestamp_format",
+            "autojoin_upgraded_rooms",
+            "device_name",
+            "autofetch_backlog",
+            "highlight_notices",
+            "membership_collapse_threshold",
+            "timeline_limit",
+            "excluded_event_types",
+            "lazy_load_members",
+            "initial_timeline_limit",
+            "notify_command",
+            "notify_interval",
+            "close_removed_rooms",
+            "url_previews",
+            "confirm_redactions",
+            "redaction_log",
+            "unique_store",
+        ] {
             let option_name = &format!("{}.{}", self.server_name, option_name);
             section.free_option(option_name).unwrap_or_else(|_| {
                 panic!(format!("Can't free option {}", option_name))
@@ -649,9 +2188,9 @@ impl InnerServer {
     ) -> &RoomHandle {
         if !self.rooms.contains_key(room_id) {
             let homeserver = self
-                .settings
+                .settings()
                 .homeserver
-                .as_ref()
+                .clone()
                 .expect("Creating room buffer while no homeserver");
             let login_state = self
                 .login_state
@@ -668,48 +2207,99 @@ impl InnerServer {
             let buffer = RoomHandle::new(
                 &self.connection,
                 self.config.inner.clone(),
+                self.settings.clone(),
+                self.highlight_patterns.clone(),
+                self.drafts.clone(),
                 room,
-                homeserver,
+                &homeserver,
                 room_id.clone(),
                 &login_state.user_id,
             );
+            buffer.set_notification_level(self.notification_level(room_id));
             self.rooms.insert(room_id.clone(), buffer);
         }
 
         self.rooms.get_mut(room_id).unwrap()
     }
 
-    pub fn settings(&self) -> &ServerSettings {
-        &self.settings
+    pub fn settings(&self) -> Ref<ServerSettings> {
+        self.settings.borrow()
     }
 
     pub fn rooms(&self) -> &HashMap<RoomId, RoomHandle> {
         &self.rooms
     }
 
+    pub fn invites(&self) -> &HashMap<RoomId, Invite> {
+        &self.invites
+    }
+
     pub fn config(&self) -> Ref<Config> {
         self.config.borrow()
     }
 
+    /// Get the words that complete to the rooms we know about.
+    ///
+    /// These are the aliases of the rooms we joined and the ids of the rooms
+    /// we got invited to, sorted alphabetically.
+    pub fn room_completions(&self) -> Vec<String> {
+        let words: BTreeSet<String> = self
+            .rooms
+            .values()
+            .flat_map(|r| r.aliases())
+            .chain(self.invites.keys().map(|r| r.to_string()))
+            .collect();
+
+        words.into_iter().collect()
+    }
+
+    /// Get the user ids of the users we share a room with, sorted
+    /// alphabetically.
+    pub fn user_completions(&self) -> Vec<String> {
+        let words: BTreeSet<String> = self
+            .rooms
+            .values()
+            .flat_map(|r| r.member_completions(true))
+            .collect();
+
+        words.into_iter().collect()
+    }
+
     pub async fn restore_room(&mut self, room: Arc<RwLock<Room>>) {
         let homeserver = self
-            .settings
+            .settings()
             .homeserver
-            .as_ref()
+            .clone()
             .expect("Creating room buffer while no homeserver");
 
         let buffer = RoomHandle::restore(
             room,
             &self.connection,
             self.config.inner.clone(),
-            homeserver,
+            self.settings.clone(),
+            self.highlight_patterns.clone(),
+            self.drafts.clone(),
+            &homeserver,
         )
         .await;
         let room_id = buffer.room_id().to_owned();
+        buffer.set_notification_level(self.notification_level(&room_id));
 
         self.rooms.insert(room_id, buffer);
     }
 
+    /// Apply the tags of the rooms that were restored from the store and sort
+    /// the room buffers once all of them are known.
+    pub fn receive_restored_tags(&mut self, tags: Vec<(RoomId, Tags)>) {
+        for (room_id, tags) in tags {
+            if let Some(room) = self.rooms.get(&room_id) {
+                room.update_tags(tags);
+            }
+        }
+
+        self.sort_buffers();
+    }
+
     fn create_server_buffer(&self) -> BufferHandle {
         let buffer_handle =
             BufferBuilder::new(&format!("server.{}", self.server_name))
@@ -722,7 +2312,7 @@ impl InnerServer {
 
         buffer.set_short_name(&self.server_name);
         buffer.set_localvar("type", "server");
-        buffer.set_localvar("nick", &self.settings.username);
+        buffer.set_localvar("nick", &self.settings().username);
         buffer.set_localvar("server", &self.server_name);
 
         buffer_handle
@@ -741,13 +2331,14 @@ impl InnerServer {
 
         // Check if the homeserver setting changed and swap our client if it
         // did.
-        if client.homeserver()
+        let homeserver_changed = client.homeserver()
             != self
-                .settings
+                .settings()
                 .homeserver
                 .as_ref()
-                .expect("Homeserver URL isn't set")
-        {
+                .expect("Homeserver URL isn't set");
+
+        if homeserver_changed {
             // TODO close all the room buffers of the server here, they don't
             // belong to our client anymore.
             self.create_client()
@@ -821,16 +2412,386 @@ impl InnerServer {
         &mut self,
         room_id: &RoomId,
         event: AnySyncRoomEvent,
+        thread_root: Option<EventId>,
     ) {
-        let room = self.get_or_create_room(room_id);
-        room.handle_sync_room_event(event).await
+        // We're receiving events for the room so we joined it in the meantime,
+        // possibly from another client.
+        self.invites.remove(room_id);
+
+        // The server only stops sending us new messages of ignored users,
+        // messages from before we ignored them can still show up.
+        if let AnySyncRoomEvent::Message(m) = &event {
+            if self.ignored_users.contains(m.sender()) {
+                return;
+            }
+        }
+
+        let room = self.get_or_create_room(room_id).clone();
+        room.handle_rejoin();
+
+        if let Some(notification) =
+            room.handle_sync_room_event(event, thread_root).await
+        {
+            self.notify(notification);
+        }
+
+        // A room we created showed up, open it and send out the initial
+        // message.
+        if let Some(message) = self.pending_rooms.remove(room_id) {
+            let room = self.get_or_create_room(room_id).clone();
+            room.switch_to_buffer();
+
+            if let Some(message) = message {
+                Weechat::spawn(async move { room.send_text(message).await })
+                    .detach();
+            }
+        }
+    }
+
+    pub async fn receive_backlog_event(
+        &mut self,
+        room_id: &RoomId,
+        event: AnySyncRoomEvent,
+        thread_root: Option<EventId>,
+    ) {
+        if let AnySyncRoomEvent::Message(m) = &event {
+            if self.ignored_users.contains(m.sender()) {
+                return;
+            }
+        }
+
+        let room = self.get_or_create_room(room_id).clone();
+        room.handle_backlog_event(event, thread_root).await;
+    }
+
+    /// Run the notify command for the given notification.
+    ///
+    /// The command runs at most once per configured interval, so a burst of
+    /// highlights doesn't spawn a process for each of them.
+    fn notify(&mut self, notification: Notification) {
+        let (command, interval) = {
+            let settings = self.settings();
+
+            match &settings.notify_command {
+                Some(c) => (
+                    c.clone(),
+                    Duration::from_secs(settings.notify_interval.into()),
+                ),
+                None => return,
+            }
+        };
+
+        let now = Instant::now();
+
+        if self
+            .last_notification
+            .map_or(false, |t| now.duration_since(t) < interval)
+        {
+            return;
+        }
+
+        self.last_notification = Some(now);
+
+        if let Err(e) = run_notify_command(&command, &notification) {
+            self.print_error(&format!(
+                "Error running the notify command: {}",
+                e
+            ));
+        }
+    }
+
+    pub fn receive_invite(
+        &mut self,
+        room_id: RoomId,
+        inviter: UserId,
+        room_name: String,
+    ) {
+        // The invite may show up again in a later sync response, only notify
+        // the user once.
+        if self.invites.contains_key(&room_id) {
+            return;
+        }
+
+        self.print_network(&format!(
+            "{}{}{} has invited you to {}{}{} ({}), use /accept {} or \
+             /reject {} to respond",
+            Weechat::color("chat_nick"),
+            inviter,
+            Weechat::color("reset"),
+            Weechat::color("chat_channel"),
+            room_name,
+            Weechat::color("reset"),
+            room_id,
+            room_id,
+            room_id,
+        ));
+
+        self.invites.insert(room_id, Invite { inviter, room_name });
+    }
+
+    pub fn receive_left_room(
+        &mut self,
+        room_id: &RoomId,
+        removal: Option<RoomRemoval>,
+    ) {
+        // If the invite is gone without us responding to it, it was
+        // retracted.
+        if let Some(invite) = self.invites.remove(room_id) {
+            self.print_network(&format!(
+                "The invite to {}{}{} ({}) from {} was retracted",
+                Weechat::color("chat_channel"),
+                invite.room_name,
+                Weechat::color("reset"),
+                room_id,
+                invite.inviter,
+            ));
+        }
+
+        let room = if let Some(r) = self.rooms.get(room_id) {
+            r.clone()
+        } else {
+            return;
+        };
+
+        room.handle_removal(removal.as_ref());
+
+        if self.settings().close_removed_rooms {
+            self.rooms.remove(room_id);
+            room.close_buffer();
+        }
+    }
+
+    pub fn receive_presence(&self, event: PresenceEvent) {
+        for room in self.rooms.values() {
+            room.update_presence(&event.sender, &event.content.presence);
+        }
+    }
+
+    pub fn receive_to_device_event(&self, event: AnyToDeviceEvent) {
+        match event {
+            // Room keys can arrive after the events that were encrypted with
+            // them, let the rooms retry to decrypt those events.
+            AnyToDeviceEvent::RoomKey(e) => {
+                self.receive_room_key(&e.content.room_id, &e.content.session_id)
+            }
+            AnyToDeviceEvent::ForwardedRoomKey(e) => {
+                self.receive_room_key(&e.content.room_id, &e.content.session_id)
+            }
+            AnyToDeviceEvent::RoomKeyRequest(e) => {
+                if let KeyRequestAction::Request = e.content.action {
+                    self.print_network(&format!(
+                        "Device {} of {} requested a room key",
+                        e.content.requesting_device_id, e.sender
+                    ));
+                }
+            }
+            AnyToDeviceEvent::KeyVerificationStart(e) => {
+                self.print_network(&format!(
+                    "{} wants to verify one of their devices with us, \
+                     interactive verification isn't supported yet",
+                    e.sender
+                ));
+            }
+            _ => (),
+        }
+    }
+
+    fn notification_level(&self, room_id: &RoomId) -> NotificationLevel {
+        self.notification_levels
+            .get(room_id)
+            .copied()
+            .unwrap_or_default()
+    }
+
+    fn receive_room_key(&self, room_id: &RoomId, session_id: &str) {
+        if let Some(room) = self.rooms.get(room_id).cloned() {
+            let session_id = session_id.to_owned();
+
+            Weechat::spawn(
+                async move { room.retry_decryption(&session_id).await },
+            )
+            .detach();
+        }
+    }
+
+    pub fn receive_account_data(&mut self, event: AnyBasicEvent) {
+        match event {
+            AnyBasicEvent::Direct(e) => self.update_direct_rooms(&e.content),
+            AnyBasicEvent::PushRules(e) => {
+                *self.highlight_patterns.borrow_mut() =
+                    highlight_patterns(&e.content.global);
+                self.notification_levels =
+                    notification_levels(&e.content.global);
+
+                for (room_id, room) in &self.rooms {
+                    room.set_notification_level(
+                        self.notification_level(room_id),
+                    );
+                }
+            }
+            AnyBasicEvent::IgnoredUserList(e) => {
+                self.ignored_users =
+                    e.content.ignored_users.into_iter().collect();
+            }
+            _ => (),
+        }
+    }
+
+    pub fn receive_room_account_data(
+        &mut self,
+        room_id: &RoomId,
+        event: AnyBasicEvent,
+    ) {
+        if let AnyBasicEvent::Tag(e) = event {
+            let room = self.get_or_create_room(room_id);
+
+            if room.update_tags(e.content.tags) {
+                self.sort_buffers();
+            }
+        }
+    }
+
+    /// Sort the room buffers of the server by the groups of the rooms.
+    ///
+    /// Favourite rooms are put at the top and low priority rooms at the
+    /// bottom, favourites and low priority rooms are further sorted by the
+    /// order of their tag. Otherwise the existing order of the buffers is
+    /// kept. The room buffers are kept together starting at the position of
+    /// the first one.
+    fn sort_buffers(&self) {
+        let mut buffers: Vec<_> = self
+            .rooms
+            .values()
+            .filter_map(|r| {
+                let (group, order) = r.group();
+                r.buffer_handle()
+                    .upgrade()
+                    .ok()
+                    .map(|b| (group, order, b.number(), r.buffer_handle()))
+            })
+            .collect();
+
+        let first = match buffers.iter().map(|b| b.2).min() {
+            Some(n) => n,
+            None => return,
+        };
+
+        buffers.sort_by(|a, b| {
+            a.0.cmp(&b.0)
+                .then_with(|| {
+                    // Rooms without an order go after the ordered ones.
+                    let a = a.1.unwrap_or(f64::INFINITY);
+                    let b = b.1.unwrap_or(f64::INFINITY);
+                    a.partial_cmp(&b).unwrap_or(Ordering::Equal)
+                })
+                .then_with(|| a.2.cmp(&b.2))
+        });
+
+        for (position, (_, _, _, buffer)) in buffers.into_iter().enumerate() {
+            let position = first + position as i32;
+
+            if let Ok(buffer) = buffer.upgrade() {
+                if buffer.number() != position {
+                    buffer
+                        .run_command(&format!("/buffer move {}", position))
+                        .ok();
+                }
+            }
+        }
+    }
+
+    /// Mark the rooms listed in the `m.direct` account data as direct rooms,
+    /// rooms that got removed from the list become regular rooms again.
+    fn update_direct_rooms(&mut self, content: &DirectEventContent) {
+        self.direct_rooms = content
+            .iter()
+            .map(|(user_id, rooms)| (user_id.clone(), rooms.clone()))
+            .collect();
+
+        for room in self.rooms.values() {
+            let target = content
+                .iter()
+                .find(|(_, rooms)| rooms.contains(room.room_id()))
+                .map(|(user_id, _)| user_id.clone());
+
+            room.set_direct_target(target);
+        }
+    }
+
+    pub fn receive_soft_logout(&mut self) {
+        self.print_error("The server logged us out, logging in again");
+        self.set_connection_state(ConnectionState::Connecting);
     }
 
     pub fn receive_login(&mut self, response: LoginResponse) {
         let login_state = LoginInfo {
             user_id: response.user_id,
+            device_id: response.device_id,
         };
         self.login_state = Some(login_state);
+        self.set_connection_state(ConnectionState::Syncing);
+    }
+
+    pub fn receive_offline_login(
+        &mut self,
+        user_id: UserId,
+        device_id: DeviceIdBox,
+    ) {
+        self.login_state = Some(LoginInfo { user_id, device_id });
+        self.set_connection_state(ConnectionState::Offline);
+    }
+
+    /// Get the current state of the connection to the server.
+    pub fn connection_state(&self) -> &ConnectionState {
+        &self.connection_state
+    }
+
+    /// The sync loop stopped for good without us disconnecting from the
+    /// server, e.g. because logging in failed.
+    ///
+    /// Returns the connection if it needs to be torn down, the caller should
+    /// drop it once it doesn't borrow the server anymore.
+    pub fn sync_loop_stopped(&mut self) -> Option<Connection> {
+        match &self.connection_state {
+            // Loading the rooms from the store finished, the rooms stay
+            // around until the user disconnects.
+            ConnectionState::Offline => return None,
+            // The reason was already printed out, e.g. logging in failed.
+            ConnectionState::Error(_) => (),
+            _ => {
+                self.print_error(
+                    "The connection to the server stopped, use /matrix \
+                     connect to connect again",
+                );
+                self.set_connection_state(ConnectionState::Disconnected);
+            }
+        }
+
+        self.server_info = None;
+        self.connection.borrow_mut().take()
+    }
+
+    /// Update the state of the connection and refresh the bar item showing
+    /// it.
+    ///
+    /// Messages that were queued up while the connection was down are sent
+    /// out once we're syncing again.
+    pub fn set_connection_state(&mut self, state: ConnectionState) {
+        if self.connection_state == state {
+            return;
+        }
+
+        self.connection_state = state;
+        Weechat::bar_item_update(CONNECTION_BAR_ITEM);
+
+        if self.connection_state == ConnectionState::Syncing {
+            for room in self.rooms.values().cloned() {
+                Weechat::spawn(
+                    async move { room.send_outgoing_messages().await },
+                )
+                .detach();
+            }
+        }
     }
 
     fn create_server_dir(&self) -> std::io::Result<()> {
@@ -839,18 +2800,39 @@ impl InnerServer {
     }
 
     pub fn get_server_path(&self) -> PathBuf {
-        let mut path = Weechat::home_dir();
-        let server_name: &str = &self.server_name;
-        path.push("matrix-rust");
-        path.push(server_name);
+        server_path(&self.server_name)
+    }
+
+    /// Get the directory of the crypto store of our client, the session the
+    /// store belongs to is stored there as well.
+    ///
+    /// This is the server directory unless the client uses a unique store.
+    pub fn get_store_path(&self) -> PathBuf {
+        self.unique_store
+            .as_ref()
+            .map(|s| s.path().to_owned())
+            .unwrap_or_else(|| self.get_server_path())
+    }
+
+    /// Remember the input of every room buffer and write the drafts out.
+    pub fn save_drafts(&self) {
+        for room in self.rooms.values() {
+            if let Ok(buffer) = room.buffer_handle().upgrade() {
+                self.drafts.set(room.room_id(), &buffer.input());
+            }
+        }
 
-        path
+        self.drafts.save();
     }
 
     pub fn create_client(&mut self) -> Result<Client, ServerError> {
         let homeserver =
-            self.settings.homeserver.as_ref().ok_or_else(|| {
-                ServerError::StartError("Homeserver not configured".to_owned())
+            self.settings().homeserver.clone().ok_or_else(|| {
+                ServerError::StartError(
+                    "Homeserver not configured, set it or use a full user id \
+                     as the username"
+                        .to_owned(),
+                )
             })?;
 
         self.create_server_dir().map_err(|e| {
@@ -860,20 +2842,254 @@ impl InnerServer {
             ))
         })?;
 
+        // The unique store is kept for the lifetime of the server, the
+        // client gets swapped if the homeserver changes but the session stays
+        // the same.
+        if self.settings().unique_store && self.unique_store.is_none() {
+            let store =
+                UniqueStore::claim(&self.get_server_path()).map_err(|e| {
+                    ServerError::IoError(format!(
+                        "Error claiming a crypto store: {}",
+                        e
+                    ))
+                })?;
+
+            self.unique_store = Some(store);
+        }
+
         let mut client_config =
-            ClientConfig::new().store_path(self.get_server_path());
+            ClientConfig::new().store_path(self.get_store_path());
+        let settings = self.settings();
+
+        if let Some(ca_file) = &settings.ssl_ca_file {
+            // The SDK can't be configured to trust a custom root certificate,
+            // use our own HTTP client in that case. The proxy and the
+            // certificate verification need to be configured on our client as
+            // well.
+            let client = HttpClient::new(
+                ca_file,
+                settings.proxy.as_ref(),
+                settings.ssl_verify,
+            )
+            .map_err(ServerError::StartError)?;
+
+            client_config = client_config.client(Arc::new(client));
+        } else {
+            if let Some(proxy) = &settings.proxy {
+                client_config =
+                    client_config.proxy(proxy.as_str()).map_err(|e| {
+                        ServerError::StartError(format!(
+                            "Invalid proxy {}: {}",
+                            proxy, e
+                        ))
+                    })?;
+            }
 
-        if let Some(proxy) = &self.settings.proxy {
-            client_config = client_config
-                .proxy(proxy.as_str())
-                .unwrap()
-                .disable_ssl_verification();
+            if !settings.ssl_verify {
+                client_config = client_config.disable_ssl_verification();
+            }
         }
 
-        let client =
-            Client::new_with_config(homeserver.clone(), client_config).unwrap();
+        drop(settings);
+
+        let client = Client::new_with_config(homeserver, client_config)
+            .map_err(|e| {
+                ServerError::StartError(format!(
+                    "Error creating the client: {}",
+                    e
+                ))
+            })?;
         self.client = Some(client.clone());
 
         Ok(client)
     }
 }
+
+#[async_trait(?Send)]
+impl ClientMessageHandler for InnerServer {
+    fn print_error(&self, message: &str) {
+        InnerServer::print_error(self, message)
+    }
+
+    fn receive_login(&mut self, response: LoginResponse) {
+        InnerServer::receive_login(self, response)
+    }
+
+    fn receive_offline_login(
+        &mut self,
+        user_id: UserId,
+        device_id: DeviceIdBox,
+    ) {
+        InnerServer::receive_offline_login(self, user_id, device_id)
+    }
+
+    fn receive_soft_logout(&mut self) {
+        InnerServer::receive_soft_logout(self)
+    }
+
+    fn set_connection_state(&mut self, state: ConnectionState) {
+        InnerServer::set_connection_state(self, state)
+    }
+
+    fn receive_joined_state_event(
+        &mut self,
+        room_id: &RoomId,
+        event: AnySyncStateEvent,
+    ) {
+        InnerServer::receive_joined_state_event(self, room_id, event)
+    }
+
+    async fn receive_joined_timeline_event(
+        &mut self,
+        room_id: &RoomId,
+        event: AnySyncRoomEvent,
+        thread_root: Option<EventId>,
+    ) {
+        InnerServer::receive_joined_timeline_event(
+            self,
+            room_id,
+            event,
+            thread_root,
+        )
+        .await
+    }
+
+    async fn receive_backlog_event(
+        &mut self,
+        room_id: &RoomId,
+        event: AnySyncRoomEvent,
+        thread_root: Option<EventId>,
+    ) {
+        InnerServer::receive_backlog_event(self, room_id, event, thread_root)
+            .await
+    }
+
+    async fn restore_room(&mut self, room: Arc<RwLock<Room>>) {
+        InnerServer::restore_room(self, room).await
+    }
+
+    fn receive_restored_tags(&mut self, tags: Vec<(RoomId, Tags)>) {
+        InnerServer::receive_restored_tags(self, tags)
+    }
+
+    fn receive_invite(
+        &mut self,
+        room_id: RoomId,
+        inviter: UserId,
+        room_name: String,
+    ) {
+        InnerServer::receive_invite(self, room_id, inviter, room_name)
+    }
+
+    fn receive_left_room(
+        &mut self,
+        room_id: &RoomId,
+        removal: Option<RoomRemoval>,
+    ) {
+        InnerServer::receive_left_room(self, room_id, removal)
+    }
+
+    fn receive_to_device_event(&self, event: AnyToDeviceEvent) {
+        InnerServer::receive_to_device_event(self, event)
+    }
+
+    fn receive_presence(&self, event: PresenceEvent) {
+        InnerServer::receive_presence(self, event)
+    }
+
+    fn receive_account_data(&mut self, event: AnyBasicEvent) {
+        InnerServer::receive_account_data(self, event)
+    }
+
+    fn receive_room_account_data(
+        &mut self,
+        room_id: &RoomId,
+        event: AnyBasicEvent,
+    ) {
+        InnerServer::receive_room_account_data(self, room_id, event)
+    }
+}
+
+/// Get the directory the data of the server with the given name is stored in.
+fn server_path(server_name: &str) -> PathBuf {
+    let mut path = Weechat::home_dir();
+    path.push("matrix-rust");
+    path.push(server_name);
+
+    path
+}
+
+/// Run the notify command in the background.
+///
+/// The command runs in a shell, the details of the notification are passed to
+/// it as positional arguments.
+fn run_notify_command(
+    command: &str,
+    notification: &Notification,
+) -> std::io::Result<()> {
+    let mut child = Command::new("sh")
+        .arg("-c")
+        .arg(command)
+        .arg(PLUGIN_NAME)
+        .arg(&notification.room_name)
+        .arg(&notification.sender)
+        .arg(&notification.body)
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .spawn()?;
+
+    // Reap the process once it's done so it doesn't linger around as a
+    // zombie.
+    std::thread::spawn(move || child.wait());
+
+    Ok(())
+}
+
+/// Get the patterns of the enabled content push rules that highlight us.
+///
+/// The patterns are globs that are matched against the words of a message.
+fn highlight_patterns(ruleset: &Ruleset) -> Vec<String> {
+    ruleset
+        .content
+        .iter()
+        .filter(|r| {
+            r.enabled
+                && r.actions.iter().any(|a| {
+                    matches!(a, Action::SetTweak(Tweak::Highlight(true)))
+                })
+        })
+        .map(|r| r.pattern.to_lowercase())
+        .collect()
+}
+
+/// Get the notification levels of the rooms that have push rules which keep
+/// them from notifying us.
+///
+/// A room is muted by an override rule and set to only notify us about
+/// mentions by a room rule, both named after the room id.
+fn notification_levels(
+    ruleset: &Ruleset,
+) -> HashMap<RoomId, NotificationLevel> {
+    let dont_notify = |actions: &[Action]| {
+        actions.iter().any(|a| matches!(a, Action::DontNotify))
+    };
+    let room_id = |rule_id: &str| RoomId::try_from(rule_id).ok();
+
+    let mentions = ruleset
+        .room
+        .iter()
+        .filter(|r| r.enabled && dont_notify(&r.actions))
+        .filter_map(|r| room_id(&r.rule_id))
+        .map(|r| (r, NotificationLevel::Mentions));
+
+    let muted = ruleset
+        .override_
+        .iter()
+        .filter(|r| r.enabled && dont_notify(&r.actions))
+        .filter_map(|r| room_id(&r.rule_id))
+        .map(|r| (r, NotificationLevel::None));
+
+    // Override rules take precedence over room rules.
+    mentions.chain(muted).collect()
+}
@@ -0,0 +1,291 @@
+//! Stored login sessions.
+//!
+//! The device id we get when we log in for the first time is stored, later
+//! logins reuse it so our end-to-end encryption keys stay valid.
+//!
+//! Every session is stored as a JSON file in the server directory, the file is
+//! named after the full user id so users with the same localpart on different
+//! homeservers don't share a device id. Older versions stored the bare device
+//! id in a `<username>.device_id` file, such files are still read and replaced
+//! once we log in again.
+//!
+//! Servers that use unique stores keep a store directory per session in the
+//! `stores` directory of the server, every store holds its own session files.
+//! A store is claimed by a session with a lock file, stores that aren't
+//! claimed are reused so we don't log in as a new device every time.
+
+use std::{
+    convert::TryFrom,
+    fmt::Write,
+    fs::OpenOptions,
+    io,
+    path::{Path, PathBuf},
+};
+
+use serde_json::{json, Value};
+use tracing::debug;
+use url::Url;
+use uuid::Uuid;
+
+use matrix_sdk::identifiers::UserId;
+
+const SESSION_EXTENSION: &str = "session.json";
+const LEGACY_EXTENSION: &str = "device_id";
+const STORES_DIR: &str = "stores";
+const STORE_LOCK_FILE: &str = "weechat.lock";
+
+/// The user and device we logged in as on a homeserver.
+#[derive(Debug, Clone)]
+pub struct StoredSession {
+    pub user_id: UserId,
+    pub device_id: String,
+    pub homeserver: Url,
+}
+
+impl StoredSession {
+    /// Find the stored session of the given user.
+    ///
+    /// # Arguments
+    ///
+    /// * `server_path` - The directory of the server the user belongs to.
+    ///
+    /// * `username` - The username as configured, either a full user id or
+    /// the localpart of one.
+    ///
+    /// * `homeserver` - The homeserver the user logs in on, a localpart only
+    /// matches sessions of the same homeserver.
+    pub fn find(
+        server_path: &Path,
+        username: &str,
+        homeserver: Option<&Url>,
+    ) -> io::Result<Option<StoredSession>> {
+        let entries = match std::fs::read_dir(server_path) {
+            Ok(e) => e,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e),
+        };
+
+        for entry in entries {
+            let path = entry?.path();
+
+            let is_session = path
+                .file_name()
+                .and_then(|n| n.to_str())
+                .map_or(false, |n| n.ends_with(SESSION_EXTENSION));
+
+            if !is_session {
+                continue;
+            }
+
+            let session = if let Some(s) = StoredSession::load(&path)? {
+                s
+            } else {
+                continue;
+            };
+
+            let matches = if username.starts_with('@') {
+                session.user_id.as_str() == username
+            } else {
+                session.user_id.localpart() == username
+                    && homeserver.map_or(false, |h| h == &session.homeserver)
+            };
+
+            if matches {
+                return Ok(Some(session));
+            }
+        }
+
+        Ok(None)
+    }
+
+    /// Load a session file, returns `None` if the file doesn't contain a
+    /// valid session.
+    fn load(path: &Path) -> io::Result<Option<StoredSession>> {
+        let data = std::fs::read(path)?;
+        let session: Value = match serde_json::from_slice(&data) {
+            Ok(s) => s,
+            Err(_) => return Ok(None),
+        };
+
+        let field = |name| session.get(name).and_then(Value::as_str);
+
+        let user_id = field("user_id").and_then(|u| UserId::try_from(u).ok());
+        let homeserver = field("homeserver").and_then(|h| Url::parse(h).ok());
+        let device_id = field("device_id").filter(|d| !d.is_empty());
+
+        Ok(match (user_id, device_id, homeserver) {
+            (Some(user_id), Some(device_id), Some(homeserver)) => {
+                Some(StoredSession {
+                    user_id,
+                    device_id: device_id.to_owned(),
+                    homeserver,
+                })
+            }
+            _ => None,
+        })
+    }
+
+    /// Write the session out into the given server directory.
+    ///
+    /// The legacy device id file of the username is removed since it's
+    /// superseded by the session file.
+    pub fn save(&self, server_path: &Path, username: &str) -> io::Result<()> {
+        let session = json!({
+            "user_id": self.user_id.as_str(),
+            "device_id": self.device_id,
+            "homeserver": self.homeserver.as_str(),
+        });
+
+        std::fs::create_dir_all(server_path)?;
+        std::fs::write(
+            session_path(server_path, &self.user_id),
+            serde_json::to_vec_pretty(&session)?,
+        )?;
+
+        match std::fs::remove_file(legacy_path(server_path, username)) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Read the device id out of the file older versions stored it in.
+pub fn load_legacy_device_id(
+    server_path: &Path,
+    username: &str,
+) -> io::Result<Option<String>> {
+    match std::fs::read_to_string(legacy_path(server_path, username)) {
+        Ok(d) if d.is_empty() => Ok(None),
+        Ok(d) => Ok(Some(d)),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+fn legacy_path(server_path: &Path, username: &str) -> PathBuf {
+    let mut path = server_path.join(username);
+    path.set_extension(LEGACY_EXTENSION);
+    path
+}
+
+/// Build the path of the session file for the given user.
+///
+/// Characters that might not be valid in a file name are percent encoded, so
+/// every user id maps to a different file.
+fn session_path(server_path: &Path, user_id: &UserId) -> PathBuf {
+    let mut name = String::new();
+
+    for c in user_id.as_str().chars() {
+        if c.is_ascii_alphanumeric() || "-_.".contains(c) {
+            name.push(c);
+        } else {
+            for b in c.to_string().bytes() {
+                write!(name, "%{:02X}", b).expect("Can't write to a string");
+            }
+        }
+    }
+
+    server_path.join(format!("{}.{}", name, SESSION_EXTENSION))
+}
+
+/// A crypto store that is used by a single session at a time.
+///
+/// The store stays claimed until this is dropped.
+#[derive(Debug)]
+pub struct UniqueStore {
+    path: PathBuf,
+}
+
+impl UniqueStore {
+    /// Claim a store in the given server directory.
+    ///
+    /// A store of an earlier session is reused if no other session claimed
+    /// it, otherwise a new store is created.
+    pub fn claim(server_path: &Path) -> io::Result<UniqueStore> {
+        let stores = server_path.join(STORES_DIR);
+        std::fs::create_dir_all(&stores)?;
+
+        for entry in std::fs::read_dir(&stores)? {
+            let path = entry?.path();
+
+            if path.is_dir() && UniqueStore::lock(&path)? {
+                return Ok(UniqueStore { path });
+            }
+        }
+
+        let path = stores.join(Uuid::new_v4().to_string());
+        std::fs::create_dir_all(&path)?;
+
+        if UniqueStore::lock(&path)? {
+            Ok(UniqueStore { path })
+        } else {
+            Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                "the new store was claimed by another session",
+            ))
+        }
+    }
+
+    /// Get the directory of the store.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Try to create the lock file of the given store, returns false if the
+    /// store is claimed by another session.
+    fn lock(path: &Path) -> io::Result<bool> {
+        let lock_path = path.join(STORE_LOCK_FILE);
+
+        match OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&lock_path)
+        {
+            Ok(mut file) => {
+                let pid = std::process::id().to_string();
+                io::Write::write_all(&mut file, pid.as_bytes())?;
+                Ok(true)
+            }
+            Err(e) if e.kind() == io::ErrorKind::AlreadyExists => {
+                if lock_is_stale(&lock_path) {
+                    std::fs::remove_file(&lock_path)?;
+                    UniqueStore::lock(path)
+                } else {
+                    Ok(false)
+                }
+            }
+            Err(e) => Err(e),
+        }
+    }
+}
+
+impl Drop for UniqueStore {
+    fn drop(&mut self) {
+        let lock_path = self.path.join(STORE_LOCK_FILE);
+
+        if let Err(e) = std::fs::remove_file(&lock_path) {
+            debug!("Error releasing the store {}: {}", self.path.display(), e);
+        }
+    }
+}
+
+/// Check if the process that claimed a store is gone, e.g. because it
+/// crashed.
+///
+/// Only Linux lets us check this cheaply, elsewhere such stores stay claimed.
+fn lock_is_stale(lock_path: &Path) -> bool {
+    if !cfg!(target_os = "linux") {
+        return false;
+    }
+
+    match std::fs::read_to_string(lock_path)
+        .ok()
+        .and_then(|p| p.trim().parse::<u32>().ok())
+    {
+        Some(pid) => {
+            pid != std::process::id()
+                && !Path::new("/proc").join(pid.to_string()).exists()
+        }
+        None => false,
+    }
+}
@@ -0,0 +1,67 @@
+//! Thread module.
+//!
+//! Messages in a thread carry an `m.thread` relation pointing to the root
+//! message of the thread. The relation isn't part of the typed event content,
+//! it's read out of the raw event and threaded messages are sent out as raw
+//! content.
+
+use std::convert::TryFrom;
+
+use serde_json::{json, Value};
+
+use matrix_sdk::{
+    events::{
+        custom::CustomEventContent, room::message::FormattedBody,
+        AnyMessageEventContent, AnySyncRoomEvent,
+    },
+    identifiers::EventId,
+    Raw,
+};
+
+/// The relation type of messages in a thread.
+const THREAD_REL_TYPE: &str = "m.thread";
+
+/// Get the event id of the thread root if the event is part of a thread.
+pub fn thread_root(event: &Raw<AnySyncRoomEvent>) -> Option<EventId> {
+    let event: Value = serde_json::from_str(event.json().get()).ok()?;
+    let relates_to = event.get("content")?.get("m.relates_to")?;
+
+    if relates_to.get("rel_type")?.as_str()? != THREAD_REL_TYPE {
+        return None;
+    }
+
+    EventId::try_from(relates_to.get("event_id")?.as_str()?).ok()
+}
+
+/// Create the content of a message in the thread with the given root.
+///
+/// Clients without thread support will show the message as a reply to the
+/// thread root.
+pub fn thread_content(
+    root: &EventId,
+    body: String,
+    formatted: Option<FormattedBody>,
+) -> AnyMessageEventContent {
+    let mut json = json!({
+        "msgtype": "m.text",
+        "body": body,
+        "m.relates_to": {
+            "rel_type": THREAD_REL_TYPE,
+            "event_id": root,
+            "is_falling_back": true,
+            "m.in_reply_to": {
+                "event_id": root,
+            },
+        },
+    });
+
+    if let Some(formatted) = formatted {
+        json["format"] = json!("org.matrix.custom.html");
+        json["formatted_body"] = json!(formatted.body);
+    }
+
+    AnyMessageEventContent::Custom(CustomEventContent {
+        event_type: "m.room.message".to_owned(),
+        json,
+    })
+}